@@ -8,3 +8,37 @@ CREATE TABLE `files` (
 	UNIQUE KEY `id` (`id`),
 	UNIQUE KEY `path` (`path`) USING HASH
 );
+
+CREATE TABLE `checksums` (
+	`path` text NOT NULL,
+	`size` bigint(20) unsigned NOT NULL,
+	`modified` bigint(20) unsigned DEFAULT NULL,
+	`sha256` char(64) NOT NULL,
+	`sha1` char(40) DEFAULT NULL,
+	`md5` char(32) DEFAULT NULL,
+	UNIQUE KEY `path` (`path`) USING HASH
+);
+
+CREATE TABLE `download_stats` (
+	`path` text NOT NULL,
+	`day` date NOT NULL,
+	`referrer` varchar(255) NOT NULL DEFAULT '',
+	`country` char(2) NOT NULL DEFAULT '',
+	`downloads` int(11) unsigned NOT NULL,
+	`bytes` bigint(20) unsigned NOT NULL,
+	UNIQUE KEY `download` (`path`, `day`, `referrer`, `country`) USING HASH,
+	KEY `day` (`day`)
+);
+
+CREATE TABLE `audit_log` (
+	`id` bigint(20) unsigned NOT NULL AUTO_INCREMENT,
+	`time` timestamp NOT NULL DEFAULT current_timestamp(),
+	`actor` varchar(255) NOT NULL,
+	`ip` varchar(64) NOT NULL,
+	`action` varchar(16) NOT NULL,
+	`path` text NOT NULL,
+	`status` smallint(5) unsigned NOT NULL,
+	PRIMARY KEY (`id`),
+	KEY `actor` (`actor`),
+	KEY `time` (`time`)
+);
@@ -1,4 +1,4 @@
-use std::{fs, path::Path};
+use std::path::Path;
 
 use bcrypt::verify;
 use rocket::{
@@ -16,12 +16,14 @@ use rocket_db_pools::{
 use rocket_dyn_templates::{context, Template};
 
 use crate::{
+    audit::Audit,
     config::CONFIG,
     db::{add_rememberme_token, delete_session, Db},
     guards::XForwardedFor,
     jwt::{create_jwt, JWT},
     responders::IndexResult,
     settings::Settings,
+    storage::{create_dir_all, STORAGE},
     utils::{add_token_cookie, get_root_domain},
     Host, IndexResponse, Language, TranslationStore,
 };
@@ -198,6 +200,7 @@ fn login_page(
 }
 
 #[post("/login?<next>", data = "<user>")]
+#[allow(clippy::too_many_arguments)]
 async fn login(
     db: Connection<Db>,
     db2: Connection<Db>,
@@ -209,6 +212,7 @@ async fn login(
     lang: Language,
     host: Host<'_>,
     settings: Settings<'_>,
+    audit: Audit<'_>,
 ) -> IndexResult {
     if let Some(db_user) = MarmakUser::login(db, &user.username, &user.password, &ip.0).await {
         if !settings.nooverride {
@@ -248,9 +252,13 @@ async fn login(
             "Login for user {} from {} succeeded",
             &db_user.username, &ip.0
         );
+        audit
+            .record(&db_user.username, "login", "", Status::Ok)
+            .await;
 
-        if !Path::new(&format!("files/private/{}", &db_user.username)).exists() {
-            let _ = fs::create_dir(format!("files/private/{}", &db_user.username));
+        let private_folder = Path::new("private").join(&db_user.username);
+        if !STORAGE.exists(&private_folder).await {
+            let _ = create_dir_all(&private_folder).await;
         }
 
         let redirect_url = next.map(|n| {
@@ -272,6 +280,9 @@ async fn login(
             "Failed login attempt to user {} with from {}",
             &user.username, &ip.0
         );
+        audit
+            .record(&user.username, "login", "", Status::Unauthorized)
+            .await;
 
         Ok(IndexResponse::Template(Template::render(
             if settings.plain {
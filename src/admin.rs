@@ -1,19 +1,38 @@
+use std::path::Path;
+
 use ::sysinfo::{Disks, System};
 use rocket::{
     fairing::AdHoc,
+    form::Form,
     http::{CookieJar, Status},
+    response::Redirect,
+    State,
 };
+use rocket_db_pools::Connection;
 use rocket_dyn_templates::{context, Template};
 
 use crate::{
+    audit::{self, AuditFilter},
     config::CONFIG,
+    db::{
+        delete_file_by_id, find_orphaned_files, get_audit_log, get_download_stats,
+        purge_orphaned_files, relink_file, FileDb,
+    },
     jwt::JWT,
     responders::IndexResult,
     settings::Settings,
+    stats,
+    storage::{storage_key, STORAGE},
+    upstream::{self, Upstreams},
     utils::{add_token_cookie, get_root_domain},
-    Disk, Host, IndexResponse, Language, TranslationStore,
+    Disk, FileSizes, Host, IndexResponse, Language, TranslationStore,
 };
 
+#[derive(FromForm)]
+struct RelinkForm {
+    path: String,
+}
+
 #[get("/sysinfo")]
 fn sysinfo(
     jar: &CookieJar<'_>,
@@ -123,8 +142,343 @@ fn admin(
     )));
 }
 
+#[get("/orphans")]
+#[allow(clippy::too_many_arguments)]
+async fn orphans(
+    mut db: Connection<FileDb>,
+    jar: &CookieJar<'_>,
+    translations: &State<TranslationStore>,
+    lang: Language,
+    host: Host<'_>,
+    token: Result<JWT, Status>,
+    settings: Settings<'_>,
+    sizes: &State<FileSizes>,
+) -> IndexResult {
+    let token = token?;
+
+    if let Some(t) = token.token {
+        add_token_cookie(&t, host.0, jar);
+    }
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    let strings = translations.get_translation(&lang.0);
+
+    let orphans = {
+        let sizes = sizes.read().await;
+        find_orphaned_files(&mut db, &sizes).await
+    };
+
+    Ok(IndexResponse::Template(Template::render(
+        if settings.plain {
+            "plain/orphans"
+        } else {
+            "orphans"
+        },
+        context! {
+            title: strings.get("orphaned_files").unwrap_or(&("orphaned_files".into())),
+            lang,
+            strings,
+            root_domain: get_root_domain(host.0),
+            host: host.0,
+            config: (*CONFIG).clone(),
+            is_logged_in: true,
+            username: token.claims.sub,
+            admin: token.claims.perms == 0,
+            orphans,
+            settings,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+    )))
+}
+
+#[post("/orphans/<id>/relink", data = "<form>")]
+async fn relink_orphan(
+    db: Connection<FileDb>,
+    id: &str,
+    form: Form<RelinkForm>,
+    token: Result<JWT, Status>,
+) -> Result<Redirect, Status> {
+    let token = token?;
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    let path = storage_key(Path::new(form.path.trim_start_matches('/')));
+
+    if path.as_os_str().is_empty() || !STORAGE.exists(&path).await {
+        return Err(Status::BadRequest);
+    }
+
+    if !relink_file(db, id, &path.display().to_string()).await {
+        return Err(Status::Conflict);
+    }
+
+    Ok(Redirect::to("/admin/orphans"))
+}
+
+#[post("/orphans/<id>/purge")]
+async fn purge_orphan(
+    db: Connection<FileDb>,
+    id: &str,
+    token: Result<JWT, Status>,
+) -> Result<Redirect, Status> {
+    let token = token?;
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    delete_file_by_id(db, id).await;
+
+    Ok(Redirect::to("/admin/orphans"))
+}
+
+#[post("/orphans/purge")]
+async fn purge_orphans(
+    db: Connection<FileDb>,
+    token: Result<JWT, Status>,
+) -> Result<Redirect, Status> {
+    let token = token?;
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    purge_orphaned_files(db).await;
+
+    Ok(Redirect::to("/admin/orphans"))
+}
+
+#[get("/stats?<days>")]
+#[allow(clippy::too_many_arguments)]
+async fn download_stats(
+    mut db: Connection<FileDb>,
+    days: Option<u32>,
+    jar: &CookieJar<'_>,
+    translations: &State<TranslationStore>,
+    lang: Language,
+    host: Host<'_>,
+    token: Result<JWT, Status>,
+    settings: Settings<'_>,
+) -> IndexResult {
+    let token = token?;
+
+    if let Some(t) = token.token {
+        add_token_cookie(&t, host.0, jar);
+    }
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    let strings = translations.get_translation(&lang.0);
+    let stats = get_download_stats(&mut db, stats::period(days), 20)
+        .await
+        .ok_or(Status::InternalServerError)?;
+    let peak = stats
+        .daily
+        .iter()
+        .map(|day| day.downloads)
+        .max()
+        .unwrap_or(0);
+
+    Ok(IndexResponse::Template(Template::render(
+        if settings.plain {
+            "plain/stats"
+        } else {
+            "stats"
+        },
+        context! {
+            title: strings.get("download_stats").unwrap_or(&("download_stats".into())),
+            lang,
+            strings,
+            root_domain: get_root_domain(host.0),
+            host: host.0,
+            config: (*CONFIG).clone(),
+            is_logged_in: true,
+            username: token.claims.sub,
+            admin: token.claims.perms == 0,
+            stats,
+            peak,
+            settings,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+    )))
+}
+
+#[get("/audit?<filter..>")]
+#[allow(clippy::too_many_arguments)]
+async fn audit_log(
+    db: Connection<FileDb>,
+    filter: AuditFilter,
+    jar: &CookieJar<'_>,
+    translations: &State<TranslationStore>,
+    lang: Language,
+    host: Host<'_>,
+    token: Result<JWT, Status>,
+    settings: Settings<'_>,
+) -> IndexResult {
+    let token = token?;
+
+    if let Some(t) = token.token {
+        add_token_cookie(&t, host.0, jar);
+    }
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    let strings = translations.get_translation(&lang.0);
+    let entries = get_audit_log(db, &filter, audit::PAGE_SIZE)
+        .await
+        .ok_or(Status::InternalServerError)?;
+
+    Ok(IndexResponse::Template(Template::render(
+        if settings.plain {
+            "plain/audit"
+        } else {
+            "audit"
+        },
+        context! {
+            title: strings.get("audit_log").unwrap_or(&("audit_log".into())),
+            lang,
+            strings,
+            root_domain: get_root_domain(host.0),
+            host: host.0,
+            config: (*CONFIG).clone(),
+            is_logged_in: true,
+            username: token.claims.sub,
+            admin: token.claims.perms == 0,
+            page: filter.page.unwrap_or(0),
+            has_more: entries.len() == audit::PAGE_SIZE as usize,
+            query: filter.query(),
+            actions: audit::ACTIONS,
+            filter,
+            entries,
+            settings,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+    )))
+}
+
+#[get("/audit/export?<format>&<filter..>")]
+async fn export_audit_log(
+    db: Connection<FileDb>,
+    format: &str,
+    filter: AuditFilter,
+    token: Result<JWT, Status>,
+) -> IndexResult {
+    let token = token?;
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    let filter = AuditFilter {
+        page: None,
+        ..filter
+    };
+    let entries = get_audit_log(db, &filter, audit::EXPORT_LIMIT)
+        .await
+        .ok_or(Status::InternalServerError)?;
+
+    audit::export(&entries, format)
+}
+
+#[get("/upstreams")]
+async fn upstreams(
+    jar: &CookieJar<'_>,
+    translations: &State<TranslationStore>,
+    lang: Language,
+    host: Host<'_>,
+    token: Result<JWT, Status>,
+    settings: Settings<'_>,
+    upstreams: &State<Upstreams>,
+) -> IndexResult {
+    let token = token?;
+
+    if let Some(t) = token.token {
+        add_token_cookie(&t, host.0, jar);
+    }
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    let strings = translations.get_translation(&lang.0);
+    let upstreams = upstreams.read().await.clone();
+
+    Ok(IndexResponse::Template(Template::render(
+        if settings.plain {
+            "plain/upstreams"
+        } else {
+            "upstreams"
+        },
+        context! {
+            title: strings.get("upstream_sync").unwrap_or(&("upstream_sync".into())),
+            lang,
+            strings,
+            root_domain: get_root_domain(host.0),
+            host: host.0,
+            config: (*CONFIG).clone(),
+            is_logged_in: true,
+            username: token.claims.sub,
+            admin: token.claims.perms == 0,
+            upstreams,
+            settings,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        },
+    )))
+}
+
+#[post("/upstreams/<index>/run")]
+async fn run_upstream(
+    index: usize,
+    token: Result<JWT, Status>,
+    upstreams: &State<Upstreams>,
+    sizes: &State<FileSizes>,
+) -> Result<Redirect, Status> {
+    let token = token?;
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    if index >= CONFIG.upstreams.len() {
+        return Err(Status::NotFound);
+    }
+
+    let upstreams = upstreams.inner().clone();
+    let sizes = sizes.inner().clone();
+
+    tokio::spawn(async move { upstream::run(&upstreams, &sizes, index).await });
+
+    Ok(Redirect::to("/admin/upstreams"))
+}
+
 pub fn build() -> AdHoc {
-    AdHoc::on_ignite("Admin", |rocket| async {
-        rocket.mount("/admin", routes![sysinfo, admin])
+    AdHoc::on_ignite("Admin", |mut rocket| async {
+        rocket = rocket.mount("/admin", routes![sysinfo, admin, upstreams, run_upstream]);
+
+        if CONFIG.enable_file_db {
+            rocket = rocket.mount(
+                "/admin",
+                routes![
+                    orphans,
+                    relink_orphan,
+                    purge_orphan,
+                    purge_orphans,
+                    download_stats,
+                    audit_log,
+                    export_audit_log
+                ],
+            );
+        }
+
+        rocket
     })
 }
@@ -1,9 +1,9 @@
 use std::{
     collections::HashMap,
-    fs::{self, create_dir, remove_dir, remove_dir_all, remove_file},
-    io::{Cursor, ErrorKind, Read, Write},
-    ops::Deref,
-    path::{Path, PathBuf},
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
 };
 
 use ::sysinfo::{Disks, RefreshKind, System};
@@ -15,19 +15,35 @@ use rocket::{
     serde::json::Json,
     Data, Request, State,
 };
-use rocket_db_pools::Connection;
+use rocket_db_pools::{
+    sqlx::{pool::PoolConnection, MySql},
+    Connection,
+};
 use rocket_multipart_form_data::{
-    MultipartFormData, MultipartFormDataField, MultipartFormDataOptions, Repetition,
+    MultipartFormData, MultipartFormDataError, MultipartFormDataField, MultipartFormDataOptions,
 };
-use zip::write::SimpleFileOptions;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use uuid::Uuid;
 
 use crate::{
+    archiver::{self, ArchiveFormat, Viewer},
+    audit::{self, Audit},
+    checksums,
     config::CONFIG,
-    db::{delete_file, FileDb},
+    db::{get_download_stats, move_file, FileDb},
+    file_index,
+    inspector::{self, ArchiveKind, ArchiveNode},
+    jobs::{self, Job, Jobs},
     jwt::JWT,
-    read_files, refresh_file_sizes,
-    responders::{ApiResponse, ApiResult},
-    utils::{add_path_to_zip, map_io_error_to_status, read_dirs_async},
+    manifest::SharedManifest,
+    metrics::METRICS,
+    quota::{self, Reservations},
+    read_files,
+    responders::{ApiResponse, ApiResult, IndexResponse, IndexResult},
+    stats,
+    storage::{self, storage_key, STORAGE},
+    trash, tus,
+    utils::{map_io_error_to_status, read_dirs_async, upload_form_options},
     Disk, FileSizes, Host, MirrorFile, MirrorFileInternal, Sysinfo,
 };
 
@@ -55,12 +71,18 @@ pub struct MirrorFileWrapper {
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct UploadFile {
     name: String,
-    url: Option<String>,
+    pub url: Option<String>,
     error: Option<String>,
     icon: Option<String>,
     size: Option<u64>,
 }
 
+#[derive(serde::Serialize)]
+pub struct ArchiveFile {
+    file: MirrorFile,
+    entries: Vec<ArchiveNode>,
+}
+
 #[derive(serde::Serialize)]
 pub struct MusicFile {
     file: MirrorFile,
@@ -140,6 +162,8 @@ pub struct UploadLimits {
     upload_limit: u64,
     private_folder_quota: u64,
     private_folder_usage: u64,
+    /// Space held for uploads that are still in progress.
+    private_folder_reserved: u64,
 }
 
 #[derive(serde::Deserialize)]
@@ -150,6 +174,26 @@ struct NameRequest {
     name: String,
 }
 
+#[derive(serde::Deserialize)]
+struct TransferRequest {
+    source: String,
+    destination: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ExtractRequest {
+    path: String,
+    /// Defaults to a folder next to the archive, named after it.
+    target: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct CompressRequest {
+    paths: Vec<String>,
+    /// The archive to create; its extension picks the format.
+    target: String,
+}
+
 #[derive(serde::Serialize, PartialOrd, serde::Deserialize)]
 pub struct SearchFile {
     pub name: String,
@@ -187,19 +231,24 @@ async fn listing(
 
     let path = MirrorFile::get_real_path(&file, username.to_string())?.0;
 
-    if path.is_file() {
+    if !STORAGE
+        .stat(&storage_key(&path))
+        .await
+        .map_err(map_io_error_to_status)?
+        .is_dir
+    {
         return Err(Status::NotAcceptable);
     }
 
     let path = path.display().to_string();
 
-    let mut file_list = read_files(&path).map_err(map_io_error_to_status)?;
+    let mut file_list = read_files(&path).await.map_err(map_io_error_to_status)?;
     let mut dir_list = read_dirs_async(&path, sizes)
         .await
         .map_err(map_io_error_to_status)?;
 
     if CONFIG.enable_login {
-        if MirrorFile::is_restricted(&Path::new("files/").join(&file), token.is_ok()) {
+        if MirrorFile::is_restricted(&Path::new("files/").join(&file), token.is_ok()).await {
             return Err(Status::Forbidden);
         }
     }
@@ -239,11 +288,11 @@ async fn search(
         let mut results: Vec<SearchFile> = sizes
             .read()
             .await
-            .iter()
+            .entries()
             .map(|x| SearchFile {
                 name: MirrorFile::get_name_from_path(&Path::new(&x.file).to_path_buf()),
                 full_path: MirrorFile::get_virtual_path(&x.file),
-                icon: if Path::new(&x.file).is_dir() {
+                icon: if x.file.ends_with('/') {
                     "folder".into()
                 } else {
                     MirrorFile::get_icon(&MirrorFile::get_name_from_path(
@@ -256,7 +305,13 @@ async fn search(
 
         results.retain(|x| !CONFIG.hidden_files.contains(&x.name));
         results.retain(|x| x.name.contains(q));
-        results.retain(|x| !MirrorFile::is_hidden_path_str(&x.full_path, perms));
+        let mut visible = Vec::with_capacity(results.len());
+        for result in results {
+            if !MirrorFile::is_hidden_path_str(&result.full_path, perms).await {
+                visible.push(result);
+            }
+        }
+        let mut results = visible;
         results.retain(|x| !x.full_path.starts_with("/private/"));
 
         if results.len() == 0 {
@@ -299,7 +354,7 @@ async fn file(
 }
 
 async fn display_file(
-    db: Option<Connection<FileDb>>,
+    mut db: Option<Connection<FileDb>>,
     path: PathBuf,
     token: Result<JWT, Status>,
 ) -> ApiResult {
@@ -311,19 +366,42 @@ async fn display_file(
     let file = path.display().to_string();
     let path = MirrorFile::get_real_path(&path, username.to_string())?.0;
 
-    let mirror_file = if let Some(db) = db {
+    if !STORAGE.exists(&storage_key(&path)).await {
+        if let Some((archive, inner)) = inspector::find_archive(&path).await {
+            if MirrorFile::is_restricted(&archive, token.is_ok()).await {
+                return Err(Status::Forbidden);
+            }
+
+            let entry = inspector::inspect(&archive)
+                .await
+                .map_err(map_io_error_to_status)?;
+
+            return Ok(ApiResponse::ArchiveEntry(Json(
+                inspector::find_node(entry, &inner).ok_or(Status::NotFound)?,
+            )));
+        }
+    }
+
+    let checksums = match db.as_mut() {
+        Some(db) => checksums::lookup(db, &path).await,
+        None => None,
+    };
+
+    let mut mirror_file = if let Some(db) = db {
         MirrorFileInternal::load(db, &path)
             .await
             .ok_or(Status::NotFound)?
             .mirror_file
     } else {
-        MirrorFile::load(&path).ok_or(Status::NotFound)?
+        MirrorFile::load(&path).await.ok_or(Status::NotFound)?
     };
 
     if mirror_file.is_dir() {
         return Err(Status::NotAcceptable);
     }
 
+    mirror_file.checksums = checksums;
+
     if mirror_file.ext == "mp3"
         || mirror_file.ext == "m4a"
         || mirror_file.ext == "m4b"
@@ -367,6 +445,15 @@ async fn display_file(
         ))));
     }
 
+    if inspector::can_inspect(&path) {
+        if let Ok(entries) = inspector::inspect(&path).await {
+            return Ok(ApiResponse::ArchiveFile(Json(ArchiveFile {
+                file: mirror_file,
+                entries,
+            })));
+        }
+    }
+
     Ok(ApiResponse::File(Json(MirrorFileWrapper {
         file: mirror_file,
     })))
@@ -378,9 +465,10 @@ async fn rename_db(
     segments: Segments<'_, rocket::http::uri::fmt::Path>,
     rename_req: Json<NameRequest>,
     token: Result<JWT, Status>,
+    audit: Audit<'_>,
 ) -> ApiResult {
     let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
-    perform_rename(Some(db), file, rename_req, token).await
+    perform_rename(Some(db), file, rename_req, token, audit).await
 }
 
 #[patch("/<segments..>", data = "<rename_req>")]
@@ -388,9 +476,10 @@ async fn rename(
     segments: Segments<'_, rocket::http::uri::fmt::Path>,
     rename_req: Json<NameRequest>,
     token: Result<JWT, Status>,
+    audit: Audit<'_>,
 ) -> ApiResult {
     let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
-    perform_rename(None, file, rename_req, token).await
+    perform_rename(None, file, rename_req, token, audit).await
 }
 
 async fn perform_rename(
@@ -398,282 +487,925 @@ async fn perform_rename(
     file: PathBuf,
     rename_req: Json<NameRequest>,
     token: Result<JWT, Status>,
+    audit: Audit<'_>,
+) -> ApiResult {
+    let actor = audit::actor(&token);
+    let path = format!(
+        "{} -> {}",
+        file.display(),
+        file.with_file_name(&rename_req.name).display()
+    );
+
+    let result = rename_item(db, file, rename_req, token).await;
+    audit
+        .record(&actor, "rename", &path, audit::status_of(&result))
+        .await;
+
+    result
+}
+
+async fn rename_item(
+    db: Option<Connection<FileDb>>,
+    file: PathBuf,
+    rename_req: Json<NameRequest>,
+    token: Result<JWT, Status>,
 ) -> ApiResult {
     let token = token?;
 
     let path = MirrorFile::get_real_path_with_perms(&file, token.claims.sub, token.claims.perms)?.0;
 
-    if !path.exists() {
+    if !STORAGE.exists(&storage_key(&path)).await {
         return Err(Status::NotFound);
     }
 
-    if let Some(db) = db {
-        delete_file(db, &path.display().to_string().replacen("files/", "", 1)).await;
-    }
-
     let parent = path.parent().ok_or(Status::InternalServerError)?;
     let new_path = parent.join(&rename_req.name);
 
-    fs::rename(&path, &new_path).map_err(map_io_error_to_status)?;
+    STORAGE
+        .rename(&storage_key(&path), &storage_key(&new_path))
+        .await
+        .map_err(map_io_error_to_status)?;
+
+    if let Some(mut db) = db {
+        move_file(
+            &mut db,
+            &storage_key(&path).display().to_string(),
+            &storage_key(&new_path).display().to_string(),
+        )
+        .await;
+    }
 
-    let mirror_file = MirrorFile::load(&new_path).ok_or(Status::NotFound)?;
+    let mirror_file = MirrorFile::load(&new_path).await.ok_or(Status::NotFound)?;
 
     Ok(ApiResponse::File(Json(MirrorFileWrapper {
         file: mirror_file,
     })))
 }
 
-#[delete("/<segments..>?<recurse>")]
-async fn delete_db<'a>(
+#[post("/move", data = "<transfer_req>")]
+async fn move_item_db(
     db: Connection<FileDb>,
-    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    transfer_req: Json<TransferRequest>,
     token: Result<JWT, Status>,
     sizes: &State<FileSizes>,
-    recurse: Option<bool>,
+    reservations: &State<Reservations>,
 ) -> ApiResult {
-    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
-    perform_delete(Some(db), file, token, sizes, recurse).await
+    perform_transfer(Some(db), transfer_req, token, sizes, reservations, false).await
 }
 
-#[delete("/<segments..>?<recurse>")]
-async fn delete<'a>(
-    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+#[post("/move", data = "<transfer_req>")]
+async fn move_item(
+    transfer_req: Json<TransferRequest>,
     token: Result<JWT, Status>,
     sizes: &State<FileSizes>,
-    recurse: Option<bool>,
+    reservations: &State<Reservations>,
 ) -> ApiResult {
-    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
-    perform_delete(None, file, token, sizes, recurse).await
+    perform_transfer(None, transfer_req, token, sizes, reservations, false).await
 }
 
-async fn perform_delete(
-    db: Option<Connection<FileDb>>,
-    file: PathBuf,
+#[post("/copy", data = "<transfer_req>")]
+async fn copy_item(
+    transfer_req: Json<TransferRequest>,
     token: Result<JWT, Status>,
     sizes: &State<FileSizes>,
-    recurse: Option<bool>,
+    reservations: &State<Reservations>,
+) -> ApiResult {
+    perform_transfer(None, transfer_req, token, sizes, reservations, true).await
+}
+
+/// Bytes a user may still add to their private folder, besides what unfinished
+/// uploads and jobs hold, or `None` when it has no quota.
+pub(crate) async fn private_quota_left(
+    token: &JWT,
+    sizes: &FileSizes,
+    reservations: &Reservations,
+) -> Option<u64> {
+    let (_, folder_quota) = upload_limits(token);
+
+    if folder_quota == 0 {
+        return None;
+    }
+
+    let held =
+        private_usage(token, sizes).await + quota::reserved(reservations, &token.claims.sub).await;
+
+    Some(folder_quota.saturating_sub(held))
+}
+
+#[post("/extract", data = "<extract_req>")]
+async fn extract(
+    extract_req: Json<ExtractRequest>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    jobs: &State<Jobs>,
+    reservations: &State<Reservations>,
 ) -> ApiResult {
     let token = token?;
 
-    let path = MirrorFile::get_real_path_with_perms(&file, token.claims.sub, token.claims.perms)?.0;
+    let source = parse_virtual_path(&extract_req.path)?;
+    let (archive, _) = MirrorFile::get_real_path(&source, token.claims.sub.clone())?;
 
-    if !path.exists() {
-        return Err(Status::NotFound);
-    }
+    let md = STORAGE
+        .stat(&storage_key(&archive))
+        .await
+        .map_err(|_| Status::NotFound)?;
 
-    if let Some(recurse) = recurse {
-        if recurse
-            && path
-                .display()
-                .to_string()
-                .starts_with(format!("files/private/").as_str())
-        {
-            return match remove_dir_all(path) {
-                Ok(_) => {
-                    {
-                        let mut state_lock = sizes.write().await;
-                        *state_lock = refresh_file_sizes().await;
-                    }
-                    Err(Status::NoContent)
-                }
-                Err(e) => Ok(ApiResponse::MessageStatus((
-                    Status::InternalServerError,
-                    Json(ApiInfoResponse {
-                        message: e.to_string(),
-                    }),
-                ))),
-            };
-        }
+    if md.is_dir || ArchiveKind::from_path(&archive).is_none() {
+        return Err(Status::NotAcceptable);
     }
 
-    if path.is_dir() {
-        if path
-            .read_dir()
-            .map(|mut i| i.next().is_none())
-            .unwrap_or(false)
-        {
-            return match remove_dir(path) {
-                Ok(_) => Err(Status::NoContent),
-                Err(e) => Ok(ApiResponse::MessageStatus((
-                    Status::InternalServerError,
-                    Json(ApiInfoResponse {
-                        message: format!("An error occured: {}", e),
-                    }),
-                ))),
-            };
-        } else {
-            return Ok(ApiResponse::MessageStatus((
-                Status::Conflict,
-                Json(ApiInfoResponse {
-                    message: "Directory is not empty!".to_string(),
-                }),
-            )));
+    let target = match &extract_req.target {
+        Some(target) => parse_virtual_path(target)?,
+        None => {
+            let name = MirrorFile::get_name_from_path(&archive);
+            let stem = [".tar.gz", ".tgz", ".tar", ".zip", ".7z"]
+                .iter()
+                .find(|ext| name.to_lowercase().ends_with(*ext))
+                .map(|ext| &name[..name.len() - ext.len()])
+                .unwrap_or(&name);
+
+            source.with_file_name(stem)
         }
-    }
+    };
 
-    return match remove_file(&path) {
-        Ok(_) => {
-            {
-                let mut state_lock = sizes.write().await;
-                *state_lock = refresh_file_sizes().await;
-            }
-            if let Some(db) = db {
-                delete_file(db, &path.display().to_string().replacen("files/", "", 1)).await;
-            }
-            Err(Status::NoContent)
-        }
-        Err(e) => Ok(ApiResponse::MessageStatus((
-            Status::InternalServerError,
+    let (target_path, target_private) = MirrorFile::get_real_path_with_perms(
+        &target,
+        token.claims.sub.clone(),
+        token.claims.perms,
+    )?;
+
+    if STORAGE.exists(&storage_key(&target_path)).await {
+        return Ok(ApiResponse::MessageStatus((
+            Status::Conflict,
             Json(ApiInfoResponse {
-                message: e.to_string(),
+                message: "A file with the same name already exists!".to_string(),
             }),
-        ))),
+        )));
+    }
+
+    let total: u64 = inspector::flatten(
+        inspector::inspect(&archive)
+            .await
+            .map_err(|_| Status::UnprocessableEntity)?,
+    )
+    .iter()
+    .filter(|node| !node.is_dir)
+    .map(|node| node.size)
+    .sum();
+
+    let budget = if target_private {
+        private_quota_left(&token, sizes, reservations).await
+    } else {
+        None
     };
+
+    if budget.is_some_and(|b| total >= b) {
+        return Err(Status::InsufficientStorage);
+    }
+
+    let job = Job::new(
+        "extract",
+        &token.claims.sub,
+        &Path::new("/").join(&target).display().to_string(),
+        total,
+    );
+    let reservation = format!("job/{}", job.id);
+
+    if target_private {
+        quota::reserve(reservations, sizes, &token, &reservation, total).await?;
+    }
+
+    jobs::add(jobs, job.clone()).await;
+
+    let jobs = jobs.inner().clone();
+    let sizes = sizes.inner().clone();
+    let reservations = reservations.inner().clone();
+    let id = job.id.clone();
+
+    tokio::spawn(async move {
+        jobs::extract(jobs, sizes, id, archive, target_path, budget).await;
+        quota::release(&reservations, &token.claims.sub, &reservation).await;
+    });
+
+    Ok(ApiResponse::Job((Status::Accepted, Json(job))))
 }
 
-#[post("/<segments..>")]
-async fn share(
-    db: Connection<FileDb>,
-    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+#[post("/compress", data = "<compress_req>")]
+async fn compress(
+    compress_req: Json<CompressRequest>,
     token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    jobs: &State<Jobs>,
+    reservations: &State<Reservations>,
 ) -> ApiResult {
     let token = token?;
 
-    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
+    if compress_req.paths.is_empty() {
+        return Err(Status::BadRequest);
+    }
 
-    let path = MirrorFile::get_real_path_with_perms(&file, token.claims.sub, token.claims.perms)?.0;
+    let mut paths = Vec::new();
+    for path in &compress_req.paths {
+        let path = parse_virtual_path(path)?;
+        paths.push(MirrorFile::get_real_path(&path, token.claims.sub.clone())?.0);
+    }
+
+    let target = parse_virtual_path(&compress_req.target)?;
+    let format = ArchiveFormat::from_path(&target).ok_or(Status::BadRequest)?;
+
+    let (target_path, target_private) = MirrorFile::get_real_path_with_perms(
+        &target,
+        token.claims.sub.clone(),
+        token.claims.perms,
+    )?;
+
+    if STORAGE.exists(&storage_key(&target_path)).await {
+        return Ok(ApiResponse::MessageStatus((
+            Status::Conflict,
+            Json(ApiInfoResponse {
+                message: "A file with the same name already exists!".to_string(),
+            }),
+        )));
+    }
+
+    let entries = archiver::collect(&paths, &Viewer::new(Some(&token)))
+        .await
+        .map_err(map_io_error_to_status)?;
 
-    if !path.exists() {
+    if entries.is_empty() {
         return Err(Status::NotFound);
     }
 
-    let ext = if path.is_dir() {
-        "folder".to_string()
+    let total = entries.iter().map(|entry| entry.size).sum();
+
+    let budget = if target_private {
+        private_quota_left(&token, sizes, reservations).await
     } else {
-        MirrorFile::get_extension_from_path(&path)
+        None
     };
 
-    if let Some(mirror_file) = MirrorFileInternal::load_and_share(db, &path).await {
-        if let Some(id) = mirror_file.id {
-            Ok(ApiResponse::ShareResponse((
-                Status::Created,
-                Json(ApiShareResponse { id, ext }),
-            )))
-        } else {
-            Err(Status::InternalServerError)
-        }
-    } else {
-        Err(Status::InternalServerError)
+    if budget == Some(0) {
+        return Err(Status::InsufficientStorage);
+    }
+
+    let job = Job::new(
+        "compress",
+        &token.claims.sub,
+        &Path::new("/").join(&target).display().to_string(),
+        total,
+    );
+    let reservation = format!("job/{}", job.id);
+
+    // The archive's size is only known once it's written, so hold as much as the
+    // files it packs, up to what's left
+    if let Some(budget) = budget {
+        quota::reserve(reservations, sizes, &token, &reservation, total.min(budget)).await?;
     }
+
+    jobs::add(jobs, job.clone()).await;
+
+    let jobs = jobs.inner().clone();
+    let sizes = sizes.inner().clone();
+    let reservations = reservations.inner().clone();
+    let id = job.id.clone();
+
+    tokio::spawn(async move {
+        jobs::compress(jobs, sizes, id, entries, format, target_path, budget).await;
+        quota::release(&reservations, &token.claims.sub, &reservation).await;
+    });
+
+    Ok(ApiResponse::Job((Status::Accepted, Json(job))))
 }
 
-#[put("/<segments..>", data = "<name_req>")]
-async fn create_folder<'a>(
-    segments: Segments<'_, rocket::http::uri::fmt::Path>,
-    token: Result<JWT, Status>,
-    name_req: Option<Json<NameRequest>>,
-) -> ApiResult {
+#[get("/jobs/<id>")]
+async fn job_status(id: &str, token: Result<JWT, Status>, jobs: &State<Jobs>) -> ApiResult {
     let token = token?;
 
-    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
-
-    let path = MirrorFile::get_real_path_with_perms(&file, token.claims.sub, token.claims.perms)?.0;
+    let job = jobs.read().await.get(id).cloned().ok_or(Status::NotFound)?;
 
-    if !path.exists() && !name_req.is_some() {
-        return match create_dir(path) {
-            Ok(_) => Err(Status::Created),
-            Err(e) => Ok(ApiResponse::MessageStatus((
-                match e.kind() {
-                    ErrorKind::NotFound => Status::NotFound,
-                    ErrorKind::PermissionDenied => Status::Forbidden,
-                    ErrorKind::StorageFull => Status::InsufficientStorage,
-                    _ => Status::InternalServerError,
-                },
-                Json(ApiInfoResponse {
-                    message: e.to_string(),
-                }),
-            ))),
-        };
-    } else if let Some(name) = name_req {
-        return match create_dir(path.join(&name.name)) {
-            Ok(_) => Err(Status::Created),
-            Err(e) => Ok(ApiResponse::MessageStatus((
-                match e.kind() {
-                    ErrorKind::NotFound => Status::NotFound,
-                    ErrorKind::PermissionDenied => Status::Forbidden,
-                    ErrorKind::StorageFull => Status::InsufficientStorage,
-                    _ => Status::InternalServerError,
-                },
-                Json(ApiInfoResponse {
-                    message: e.to_string(),
-                }),
-            ))),
-        };
-    } else {
-        Err(Status::BadRequest)
+    if job.owner != token.claims.sub && token.claims.perms != 0 {
+        return Err(Status::NotFound);
     }
+
+    Ok(ApiResponse::Job((Status::Ok, Json(job))))
 }
 
-#[get("/sysinfo")]
-fn sysinfo(token: Result<JWT, Status>) -> ApiResult {
+/// Lists every public file with its size, mtime and SHA-256. With `since`, only
+/// what changed or was removed after that time is listed, when it's still known.
+#[get("/manifest?<since>")]
+async fn manifest(since: Option<i64>, manifest: &State<SharedManifest>) -> ApiResult {
+    let manifest = manifest.read().await;
+    let manifest = manifest.as_ref().ok_or(Status::ServiceUnavailable)?;
+
+    Ok(ApiResponse::Manifest(Json(match since {
+        Some(since) => manifest.since(since),
+        None => manifest.clone(),
+    })))
+}
+
+/// Download statistics of the last `days` days, with the `limit` busiest files,
+/// folders, referrers and countries.
+#[get("/stats?<days>&<limit>")]
+async fn download_stats(
+    mut db: Connection<FileDb>,
+    days: Option<u32>,
+    limit: Option<u32>,
+    token: Result<JWT, Status>,
+) -> ApiResult {
     let token = token?;
 
     if token.claims.perms != 0 {
         return Err(Status::Forbidden);
     }
 
-    let mut sys = System::new_all();
+    let stats = get_download_stats(
+        &mut db,
+        stats::period(days),
+        limit.unwrap_or(10).clamp(1, 1000),
+    )
+    .await
+    .ok_or(Status::InternalServerError)?;
 
-    sys.refresh_specifics(RefreshKind::without_processes(RefreshKind::without_cpu(
-        RefreshKind::everything(),
-    )));
+    Ok(ApiResponse::Stats(Json(stats)))
+}
 
-    let total_mem = sys.total_memory();
-    let used_mem = sys.used_memory();
-    let mut disks: Vec<Disk> = Vec::new();
+fn parse_virtual_path(path: &str) -> Result<PathBuf, Status> {
+    let path = Path::new(path.trim_matches('/'));
 
-    let sys_disks = Disks::new_with_refreshed_list();
-    for disk in &sys_disks {
-        if disk.total_space() != 0 {
-            disks.push(Disk {
-                fs: disk.file_system().to_str().unwrap_or("unknown").to_string(),
-                used_space: disk.total_space() - disk.available_space(),
-                total_space: disk.total_space(),
-                mount_point: disk.mount_point().display().to_string(),
-            });
-        }
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        return Err(Status::BadRequest);
     }
 
-    Ok(ApiResponse::Sysinfo(Json(Sysinfo {
-        total_mem: total_mem,
-        used_mem: used_mem,
-        disks: disks,
-    })))
+    Ok(path.to_path_buf())
 }
 
-#[post("/upload?<path>&<share>", data = "<data>")]
-async fn upload(
-    content_type: &ContentType,
-    data: Data<'_>,
-    host: Host<'_>,
-    path: Option<&str>,
-    share: Option<&str>,
+async fn perform_transfer(
+    db: Option<Connection<FileDb>>,
+    transfer_req: Json<TransferRequest>,
     token: Result<JWT, Status>,
     sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    copy: bool,
 ) -> ApiResult {
-    perform_upload(None, path, share, content_type, data, host, token, sizes).await
-}
+    let token = token?;
 
-#[post("/upload?<path>&<share>", data = "<data>")]
-async fn upload_db(
-    db: Connection<FileDb>,
-    content_type: &ContentType,
-    data: Data<'_>,
-    host: Host<'_>,
+    let source = parse_virtual_path(&transfer_req.source)?;
+    let destination = parse_virtual_path(&transfer_req.destination)?;
+
+    if source == Path::new("private") {
+        return Err(Status::Forbidden);
+    }
+
+    let (source_path, source_private) = MirrorFile::get_real_path_with_perms(
+        &source,
+        token.claims.sub.clone(),
+        token.claims.perms,
+    )?;
+    let (mut destination_path, destination_private) = MirrorFile::get_real_path_with_perms(
+        &destination,
+        token.claims.sub.clone(),
+        token.claims.perms,
+    )?;
+
+    let source_key = storage_key(&source_path);
+    let md = STORAGE
+        .stat(&source_key)
+        .await
+        .map_err(|_| Status::NotFound)?;
+
+    if let Ok(destination_md) = STORAGE.stat(&storage_key(&destination_path)).await {
+        if destination_md.is_dir {
+            destination_path = destination_path.join(MirrorFile::get_name_from_path(&source_path));
+        }
+    }
+
+    let destination_key = storage_key(&destination_path);
+
+    if destination_key.starts_with(&source_key) {
+        return Ok(ApiResponse::MessageStatus((
+            Status::BadRequest,
+            Json(ApiInfoResponse {
+                message: "Cannot move a folder into itself!".to_string(),
+            }),
+        )));
+    }
+
+    if STORAGE.exists(&destination_key).await {
+        return Ok(ApiResponse::MessageStatus((
+            Status::Conflict,
+            Json(ApiInfoResponse {
+                message: "A file with the same name already exists!".to_string(),
+            }),
+        )));
+    }
+
+    let reservation = format!("transfer/{}", Uuid::new_v4());
+
+    if destination_private && (copy || !source_private) {
+        let size = if md.is_dir {
+            STORAGE
+                .walk(&source_key)
+                .await
+                .map_err(map_io_error_to_status)?
+                .iter()
+                .map(|(_, entry)| entry.size)
+                .sum()
+        } else {
+            md.size
+        };
+
+        quota::reserve(reservations, sizes, &token, &reservation, size).await?;
+    }
+
+    let result = async {
+        if let Some(parent) = destination_key.parent() {
+            storage::create_dir_all(parent).await?;
+        }
+
+        if copy {
+            STORAGE.copy(&source_key, &destination_key).await
+        } else {
+            STORAGE.rename(&source_key, &destination_key).await
+        }
+    }
+    .await;
+
+    quota::release(reservations, &token.claims.sub, &reservation).await;
+    result.map_err(map_io_error_to_status)?;
+
+    if let Some(mut db) = db {
+        move_file(
+            &mut db,
+            &source_key.display().to_string(),
+            &destination_key.display().to_string(),
+        )
+        .await;
+    }
+
+    if !copy {
+        file_index::refresh(sizes, &source_path).await;
+    }
+    file_index::refresh(sizes, &destination_path).await;
+
+    let mirror_file = MirrorFile::load(&destination_path)
+        .await
+        .ok_or(Status::NotFound)?;
+
+    Ok(ApiResponse::File(Json(MirrorFileWrapper {
+        file: mirror_file,
+    })))
+}
+
+#[delete("/<segments..>?<recurse>")]
+async fn delete_db<'a>(
+    db: Connection<FileDb>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    recurse: Option<bool>,
+    audit: Audit<'_>,
+) -> ApiResult {
+    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
+    perform_delete(Some(db), file, token, sizes, recurse, audit).await
+}
+
+#[delete("/<segments..>?<recurse>")]
+async fn delete<'a>(
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    recurse: Option<bool>,
+    audit: Audit<'_>,
+) -> ApiResult {
+    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
+    perform_delete(None, file, token, sizes, recurse, audit).await
+}
+
+async fn perform_delete(
+    db: Option<Connection<FileDb>>,
+    file: PathBuf,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    recurse: Option<bool>,
+    audit: Audit<'_>,
+) -> ApiResult {
+    let actor = audit::actor(&token);
+    let path = file.display().to_string();
+
+    let result = delete_item(db, file, token, sizes, recurse).await;
+    audit
+        .record(&actor, "delete", &path, audit::status_of(&result))
+        .await;
+
+    result
+}
+
+async fn delete_item(
+    db: Option<Connection<FileDb>>,
+    file: PathBuf,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    recurse: Option<bool>,
+) -> ApiResult {
+    let token = token?;
+
+    let path =
+        MirrorFile::get_real_path_with_perms(&file, token.claims.sub.clone(), token.claims.perms)?
+            .0;
+    let key = storage_key(&path);
+
+    let md = STORAGE.stat(&key).await.map_err(|_| Status::NotFound)?;
+
+    let recurse =
+        recurse.unwrap_or(false) && path.display().to_string().starts_with("files/private/");
+
+    if md.is_dir
+        && !recurse
+        && !STORAGE
+            .list(&key)
+            .await
+            .map_err(map_io_error_to_status)?
+            .is_empty()
+    {
+        return Ok(ApiResponse::MessageStatus((
+            Status::Conflict,
+            Json(ApiInfoResponse {
+                message: "Directory is not empty!".to_string(),
+            }),
+        )));
+    }
+
+    return match trash::move_to_trash(&token.claims.sub, &path).await {
+        Ok(entry) => {
+            file_index::refresh(sizes, &path).await;
+            // The rows go with the item, so a restore brings back its share link and downloads
+            if let Some(mut db) = db {
+                move_file(
+                    &mut db,
+                    &key.display().to_string(),
+                    &entry.item_key().display().to_string(),
+                )
+                .await;
+            }
+            Err(Status::NoContent)
+        }
+        Err(e) => Ok(ApiResponse::MessageStatus((
+            Status::InternalServerError,
+            Json(ApiInfoResponse {
+                message: format!("An error occured: {}", e),
+            }),
+        ))),
+    };
+}
+
+#[get("/trash")]
+async fn trash_list(token: Result<JWT, Status>) -> ApiResult {
+    let token = token?;
+
+    let owner = if token.claims.perms == 0 {
+        None
+    } else {
+        Some(token.claims.sub.as_str())
+    };
+
+    let entries = trash::list(owner).await.map_err(map_io_error_to_status)?;
+
+    Ok(ApiResponse::Trash(Json(entries)))
+}
+
+#[post("/trash/<id>/restore")]
+async fn trash_restore_db(
+    db: Connection<FileDb>,
+    id: &str,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+) -> ApiResult {
+    perform_trash_restore(Some(db), id, token, sizes).await
+}
+
+#[post("/trash/<id>/restore")]
+async fn trash_restore(
+    id: &str,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+) -> ApiResult {
+    perform_trash_restore(None, id, token, sizes).await
+}
+
+async fn perform_trash_restore(
+    db: Option<Connection<FileDb>>,
+    id: &str,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+) -> ApiResult {
+    let token = token?;
+
+    let entry = trash::get(id).await.ok_or(Status::NotFound)?;
+
+    if entry.owner != token.claims.sub && token.claims.perms != 0 {
+        return Err(Status::NotFound);
+    }
+
+    let path = match trash::restore(&entry).await {
+        Ok(path) => path,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            return Ok(ApiResponse::MessageStatus((
+                Status::Conflict,
+                Json(ApiInfoResponse {
+                    message: e.to_string(),
+                }),
+            )))
+        }
+        Err(e) => return Err(map_io_error_to_status(e)),
+    };
+
+    file_index::refresh(sizes, &path).await;
+
+    if let Some(mut db) = db {
+        move_file(
+            &mut db,
+            &entry.item_key().display().to_string(),
+            &entry.original_key().display().to_string(),
+        )
+        .await;
+    }
+
+    let mirror_file = MirrorFile::load(&path).await.ok_or(Status::NotFound)?;
+
+    Ok(ApiResponse::File(Json(MirrorFileWrapper {
+        file: mirror_file,
+    })))
+}
+
+#[delete("/trash/<id>")]
+async fn trash_purge_db(
+    mut db: Connection<FileDb>,
+    id: &str,
+    token: Result<JWT, Status>,
+) -> ApiResult {
+    perform_trash_purge(Some(&mut db), id, token).await
+}
+
+#[delete("/trash/<id>")]
+async fn trash_purge(id: &str, token: Result<JWT, Status>) -> ApiResult {
+    perform_trash_purge(None, id, token).await
+}
+
+async fn perform_trash_purge(
+    db: Option<&mut PoolConnection<MySql>>,
+    id: &str,
+    token: Result<JWT, Status>,
+) -> ApiResult {
+    let token = token?;
+
+    let entry = trash::get(id).await.ok_or(Status::NotFound)?;
+
+    if entry.owner != token.claims.sub && token.claims.perms != 0 {
+        return Err(Status::NotFound);
+    }
+
+    trash::purge(&entry, db)
+        .await
+        .map_err(map_io_error_to_status)?;
+
+    Err(Status::NoContent)
+}
+
+#[delete("/trash")]
+async fn trash_empty_db(mut db: Connection<FileDb>, token: Result<JWT, Status>) -> ApiResult {
+    perform_trash_empty(Some(&mut db), token).await
+}
+
+#[delete("/trash")]
+async fn trash_empty(token: Result<JWT, Status>) -> ApiResult {
+    perform_trash_empty(None, token).await
+}
+
+async fn perform_trash_empty(
+    mut db: Option<&mut PoolConnection<MySql>>,
+    token: Result<JWT, Status>,
+) -> ApiResult {
+    let token = token?;
+
+    let entries = trash::list(Some(&token.claims.sub))
+        .await
+        .map_err(map_io_error_to_status)?;
+
+    for entry in entries {
+        trash::purge(&entry, db.as_deref_mut())
+            .await
+            .map_err(map_io_error_to_status)?;
+    }
+
+    Err(Status::NoContent)
+}
+
+#[post("/<segments..>")]
+async fn share(
+    db: Connection<FileDb>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    token: Result<JWT, Status>,
+    audit: Audit<'_>,
+) -> ApiResult {
+    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
+    let actor = audit::actor(&token);
+    let path = file.display().to_string();
+
+    let result = share_item(db, file, token).await;
+    audit
+        .record(&actor, "share", &path, audit::status_of(&result))
+        .await;
+
+    result
+}
+
+async fn share_item(
+    db: Connection<FileDb>,
+    file: PathBuf,
+    token: Result<JWT, Status>,
+) -> ApiResult {
+    let token = token?;
+
+    let path = MirrorFile::get_real_path_with_perms(&file, token.claims.sub, token.claims.perms)?.0;
+
+    let md = STORAGE
+        .stat(&storage_key(&path))
+        .await
+        .map_err(|_| Status::NotFound)?;
+
+    let ext = if md.is_dir {
+        "folder".to_string()
+    } else {
+        MirrorFile::get_extension_from_path(&path)
+    };
+
+    if let Some(mirror_file) = MirrorFileInternal::load_and_share(db, &path).await {
+        if let Some(id) = mirror_file.id {
+            Ok(ApiResponse::ShareResponse((
+                Status::Created,
+                Json(ApiShareResponse { id, ext }),
+            )))
+        } else {
+            Err(Status::InternalServerError)
+        }
+    } else {
+        Err(Status::InternalServerError)
+    }
+}
+
+#[put("/<segments..>", data = "<name_req>")]
+async fn create_folder<'a>(
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    token: Result<JWT, Status>,
+    name_req: Option<Json<NameRequest>>,
+    audit: Audit<'_>,
+) -> ApiResult {
+    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
+    let actor = audit::actor(&token);
+    let path = match &name_req {
+        Some(name) => file.join(&name.name),
+        None => file.clone(),
+    };
+
+    let result = make_folder(file, token, name_req).await;
+    audit
+        .record(
+            &actor,
+            "mkdir",
+            &path.display().to_string(),
+            audit::status_of(&result),
+        )
+        .await;
+
+    result
+}
+
+async fn make_folder(
+    file: PathBuf,
+    token: Result<JWT, Status>,
+    name_req: Option<Json<NameRequest>>,
+) -> ApiResult {
+    let token = token?;
+
+    let path = MirrorFile::get_real_path_with_perms(&file, token.claims.sub, token.claims.perms)?.0;
+
+    if !STORAGE.exists(&storage_key(&path)).await && !name_req.is_some() {
+        return match STORAGE.mkdir(&storage_key(&path)).await {
+            Ok(_) => Err(Status::Created),
+            Err(e) => Ok(ApiResponse::MessageStatus((
+                match e.kind() {
+                    ErrorKind::NotFound => Status::NotFound,
+                    ErrorKind::PermissionDenied => Status::Forbidden,
+                    ErrorKind::StorageFull => Status::InsufficientStorage,
+                    _ => Status::InternalServerError,
+                },
+                Json(ApiInfoResponse {
+                    message: e.to_string(),
+                }),
+            ))),
+        };
+    } else if let Some(name) = name_req {
+        return match STORAGE.mkdir(&storage_key(&path.join(&name.name))).await {
+            Ok(_) => Err(Status::Created),
+            Err(e) => Ok(ApiResponse::MessageStatus((
+                match e.kind() {
+                    ErrorKind::NotFound => Status::NotFound,
+                    ErrorKind::PermissionDenied => Status::Forbidden,
+                    ErrorKind::StorageFull => Status::InsufficientStorage,
+                    _ => Status::InternalServerError,
+                },
+                Json(ApiInfoResponse {
+                    message: e.to_string(),
+                }),
+            ))),
+        };
+    } else {
+        Err(Status::BadRequest)
+    }
+}
+
+#[get("/sysinfo")]
+fn sysinfo(token: Result<JWT, Status>) -> ApiResult {
+    let token = token?;
+
+    if token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    let mut sys = System::new_all();
+
+    sys.refresh_specifics(RefreshKind::without_processes(RefreshKind::without_cpu(
+        RefreshKind::everything(),
+    )));
+
+    let total_mem = sys.total_memory();
+    let used_mem = sys.used_memory();
+    let mut disks: Vec<Disk> = Vec::new();
+
+    let sys_disks = Disks::new_with_refreshed_list();
+    for disk in &sys_disks {
+        if disk.total_space() != 0 {
+            disks.push(Disk {
+                fs: disk.file_system().to_str().unwrap_or("unknown").to_string(),
+                used_space: disk.total_space() - disk.available_space(),
+                total_space: disk.total_space(),
+                mount_point: disk.mount_point().display().to_string(),
+            });
+        }
+    }
+
+    Ok(ApiResponse::Sysinfo(Json(Sysinfo {
+        total_mem: total_mem,
+        used_mem: used_mem,
+        disks: disks,
+    })))
+}
+
+#[post("/upload?<path>&<share>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn upload(
+    content_type: &ContentType,
+    data: Data<'_>,
+    host: Host<'_>,
+    path: Option<&str>,
+    share: Option<&str>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
+) -> ApiResult {
+    perform_upload(
+        None,
+        path,
+        share,
+        content_type,
+        data,
+        host,
+        token,
+        sizes,
+        reservations,
+        audit,
+    )
+    .await
+}
+
+#[post("/upload?<path>&<share>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn upload_db(
+    db: Connection<FileDb>,
+    content_type: &ContentType,
+    data: Data<'_>,
+    host: Host<'_>,
     path: Option<&str>,
     share: Option<&str>,
     token: Result<JWT, Status>,
     sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
 ) -> ApiResult {
     perform_upload(
         Some(db),
@@ -684,10 +1416,13 @@ async fn upload_db(
         host,
         token,
         sizes,
+        reservations,
+        audit,
     )
     .await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn perform_upload(
     db: Option<Connection<FileDb>>,
     path: Option<&str>,
@@ -697,144 +1432,114 @@ async fn perform_upload(
     host: Host<'_>,
     token: Result<JWT, Status>,
     sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
 ) -> ApiResult {
     let token = token?;
 
-    let max_size = CONFIG
-        .max_upload_sizes
-        .get(&token.claims.perms.to_string())
-        .unwrap_or(&(104857600 as u64));
+    let (max_size, _) = upload_limits(&token);
 
-    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
-        MultipartFormDataField::file("files")
-            .repetition(Repetition::infinite())
-            .size_limit(*max_size),
-        MultipartFormDataField::text("path"),
-    ]);
+    let quota_left = private_quota_left(&token, sizes, reservations)
+        .await
+        .unwrap_or(u64::MAX);
+
+    if quota_left == 0 {
+        return Err(Status::InsufficientStorage);
+    }
+
+    let options = upload_form_options(max_size.min(quota_left));
 
     let form_data = match MultipartFormData::parse(content_type, data, options).await {
         Ok(data) => data,
+        Err(MultipartFormDataError::DataTooLargeError(_)) => {
+            let status = if quota_left < max_size {
+                Status::InsufficientStorage
+            } else {
+                Status::PayloadTooLarge
+            };
+
+            audit
+                .record(&token.claims.sub, "upload", path.unwrap_or(""), status)
+                .await;
+            return Err(status);
+        }
         Err(err) => {
             eprintln!("Failed to parse multipart form data: {:?}", err);
             return Err(Status::BadRequest);
         }
     };
 
-    if !Path::new("files/").exists() && !Path::new("files/").exists() {
-        let result = fs::create_dir("files/").map_err(map_io_error_to_status);
-        if let Err(error) = result {
-            return Err(error);
-        }
-    }
-
-    let mut user_path = form_data
+    let form_path = form_data
         .texts
         .get("path")
-        .and_then(|paths| paths.first().map(|p| p.text.trim_matches('/').to_string()))
-        .unwrap_or(String::new());
-
-    if user_path.is_empty() {
-        user_path = if token.claims.perms == 0 {
-            "uploads"
-        } else {
-            "private"
-        }
-        .to_string();
-    }
-
-    if let Some(query_path) = path {
-        user_path = query_path.trim_matches('/').to_string();
-    }
-
-    user_path = urlencoding::decode(&user_path)
-        .unwrap_or((&user_path).into())
-        .to_string();
-
-    let is_private = user_path.starts_with("private");
-    if !is_private && token.claims.perms != 0 {
-        return Err(Status::Forbidden);
-    }
-
-    let base_path = if is_private {
-        format!(
-            "files/private/{}/{}",
-            &token.claims.sub,
-            user_path.trim_start_matches("private")
-        )
-    } else {
-        format!("files/{}", user_path)
-    };
+        .and_then(|paths| paths.first().map(|p| p.text.clone()));
 
-    if !Path::new(&base_path).exists() {
-        let result = fs::create_dir_all(&base_path).map_err(map_io_error_to_status);
-        if let Err(error) = result {
-            return Err(error);
-        }
-    }
-
-    let folder_quota = *(CONFIG
-        .private_folder_quotas
-        .get(&token.claims.perms.to_string())
-        .unwrap_or(&1_u64));
+    let (user_path, base_path) = upload_target(path.map(str::to_string).or(form_path), &token)?;
 
-    let folder_usage = sizes
-        .read()
+    storage::create_dir_all(&storage_key(Path::new(&base_path)))
         .await
-        .iter()
-        .find(|entry| {
-            entry.file.strip_suffix("/").unwrap_or_default().to_string()
-                == format!("files/private/{}", &token.claims.sub)
-        })
-        .map(|entry| entry.size)
-        .unwrap_or(0);
-
-    if folder_quota != 0 && folder_usage >= folder_quota {
-        return Err(Status::InsufficientStorage);
-    }
+        .map_err(map_io_error_to_status)?;
 
     let mut uploaded_files: Vec<UploadFile> = Vec::new();
 
     if let Some(file_fields) = form_data.files.get("files") {
-        for file_field in file_fields {
-            if let Some(file_name) = &file_field.file_name {
-                let normalized_path = file_name.replace('\\', "/");
-                let file_name =
-                    &MirrorFile::get_name_from_path(&Path::new(&normalized_path).to_path_buf());
-
-                let upload_path = format!("{}/{}", base_path, file_name);
-
-                let mut file =
-                    std::fs::File::create(&upload_path).map_err(map_io_error_to_status)?;
-                let mut temp_file =
-                    std::fs::File::open(&file_field.path).map_err(map_io_error_to_status)?;
-
-                let size = temp_file.metadata().map_err(map_io_error_to_status)?.len();
+        let upload_size: u64 = file_fields
+            .iter()
+            .filter_map(|file_field| std::fs::metadata(&file_field.path).ok())
+            .map(|md| md.len())
+            .sum();
 
-                if folder_quota != 0 && folder_usage + size >= folder_quota {
-                    return Err(Status::InsufficientStorage);
-                }
+        let upload = format!("upload/{}", Uuid::new_v4());
 
-                let mut buffer = Vec::new();
-                let _ = temp_file.read_to_end(&mut buffer);
+        if base_path.starts_with("files/private/") {
+            quota::reserve(reservations, sizes, &token, &upload, upload_size).await?;
+        }
 
-                let _ = file.write_all(&buffer);
-                {
-                    let mut state_lock = sizes.write().await;
-                    *state_lock = refresh_file_sizes().await;
+        let result = async {
+            for file_field in file_fields {
+                if let Some(file_name) = &file_field.file_name {
+                    let normalized_path = file_name.replace('\\', "/");
+                    let file_name =
+                        &MirrorFile::get_name_from_path(&Path::new(&normalized_path).to_path_buf());
+
+                    let upload_path = format!("{}/{}", base_path, file_name);
+                    let audit_path = format!("{}/{}", user_path, file_name);
+
+                    let written = STORAGE
+                        .import(&storage_key(Path::new(&upload_path)), &file_field.path)
+                        .await
+                        .map_err(map_io_error_to_status);
+                    audit
+                        .record(
+                            &token.claims.sub,
+                            "upload",
+                            &audit_path,
+                            written.err().unwrap_or(Status::Created),
+                        )
+                        .await;
+                    let written = written?;
+                    METRICS.uploaded(written);
+                    file_index::refresh(sizes, Path::new(&upload_path)).await;
+
+                    uploaded_files.push(UploadFile {
+                        name: file_name.to_string(),
+                        url: Some(format!("http://{}/{}/{}", host.0, user_path, file_name)),
+                        icon: Some(MirrorFile::get_icon(file_name)),
+                        error: None,
+                        size: Some(written),
+                    });
+                } else {
+                    eprintln!("A file was uploaded without a name, skipping.");
+                    continue;
                 }
-
-                uploaded_files.push(UploadFile {
-                    name: file_name.to_string(),
-                    url: Some(format!("http://{}/{}/{}", host.0, user_path, file_name)),
-                    icon: Some(MirrorFile::get_icon(file_name)),
-                    error: None,
-                    size: Some(file.metadata().unwrap().len()),
-                });
-            } else {
-                eprintln!("A file was uploaded without a name, skipping.");
-                continue;
             }
+
+            Ok::<(), Status>(())
         }
+        .await;
+
+        quota::release(reservations, &token.claims.sub, &upload).await;
+        result?;
 
         if let Some(db) = db {
             if match share.unwrap_or("true") {
@@ -866,7 +1571,11 @@ async fn perform_upload(
 }
 
 #[get("/upload")]
-async fn upload_info(token: Result<JWT, Status>, sizes: &State<FileSizes>) -> ApiResult {
+async fn upload_info(
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+) -> ApiResult {
     let token = token?;
 
     let upload_limit = *(CONFIG
@@ -878,26 +1587,117 @@ async fn upload_info(token: Result<JWT, Status>, sizes: &State<FileSizes>) -> Ap
         .get(&token.claims.perms.to_string())
         .unwrap_or(&1_u64));
 
-    let private_folder_usage = sizes
-        .read()
-        .await
-        .iter()
-        .find(|entry| {
-            entry.file.strip_suffix("/").unwrap_or_default().to_string()
-                == format!("files/private/{}", &token.claims.sub)
-        })
-        .map(|entry| entry.size)
-        .unwrap_or(0);
+    let private_folder_usage = private_usage(&token, sizes).await;
+    let private_folder_reserved = quota::reserved(reservations, &token.claims.sub).await;
 
     Ok(ApiResponse::UploadLimits(Json(UploadLimits {
         perms: token.claims.perms,
         upload_limit,
         private_folder_quota,
         private_folder_usage,
+        private_folder_reserved,
     })))
 }
 
+/// Resolves where an upload goes, returning the path as shown in the listing and
+/// the `files/...` folder it's written to. Only admins may upload outside `private`.
+pub(crate) fn upload_target(path: Option<String>, token: &JWT) -> Result<(String, String), Status> {
+    let mut user_path = path
+        .map(|p| p.trim_matches('/').to_string())
+        .unwrap_or_default();
+
+    if user_path.is_empty() {
+        user_path = if token.claims.perms == 0 {
+            "uploads"
+        } else {
+            "private"
+        }
+        .to_string();
+    }
+
+    user_path = urlencoding::decode(&user_path)
+        .unwrap_or((&user_path).into())
+        .to_string();
+
+    if user_path.split(['/', '\\']).any(|c| c == "..") {
+        return Err(Status::Forbidden);
+    }
+
+    let is_private = user_path.starts_with("private");
+    if !is_private && token.claims.perms != 0 {
+        return Err(Status::Forbidden);
+    }
+
+    let base_path = if is_private {
+        format!(
+            "files/private/{}/{}",
+            &token.claims.sub,
+            user_path.trim_start_matches("private")
+        )
+    } else {
+        format!("files/{}", user_path)
+    };
+
+    Ok((user_path, base_path))
+}
+
+/// Largest single upload and private folder quota (0 for none) for the token's user.
+pub(crate) fn upload_limits(token: &JWT) -> (u64, u64) {
+    let perms = token.claims.perms.to_string();
+
+    let max_size = *CONFIG.max_upload_sizes.get(&perms).unwrap_or(&104857600);
+    let folder_quota = *CONFIG.private_folder_quotas.get(&perms).unwrap_or(&1_u64);
+
+    (max_size, folder_quota)
+}
+
+/// Space the token's user takes in their private folder, counting what they deleted
+/// from it that's still in the trash.
+pub(crate) async fn private_usage(token: &JWT, sizes: &FileSizes) -> u64 {
+    let stored = sizes
+        .read()
+        .await
+        .size(&Path::new("files/private").join(&token.claims.sub))
+        .unwrap_or(0);
+
+    stored + trash::usage(&token.claims.sub).await
+}
+
+/// Describes a finished upload, sharing it first unless `share` is `false`.
+pub(crate) async fn finish_upload(
+    db: Option<Connection<FileDb>>,
+    share: Option<&str>,
+    host: &Host<'_>,
+    user_path: &str,
+    base_path: &str,
+    file_name: &str,
+    size: u64,
+) -> UploadFile {
+    let mut url = format!("http://{}/{}/{}", host.0, user_path, file_name);
+
+    if let Some(db) = db {
+        if share != Some("false") {
+            if let Some(id) =
+                MirrorFileInternal::load_and_share(db, &Path::new(base_path).join(file_name))
+                    .await
+                    .and_then(|f| f.id)
+            {
+                url = format!("http://{}/share/{}", host.0, id);
+            }
+        }
+    }
+
+    UploadFile {
+        name: file_name.to_string(),
+        url: Some(url),
+        icon: Some(MirrorFile::get_icon(file_name)),
+        error: None,
+        size: Some(size),
+    }
+}
+
 #[post("/upload_chunked?<path>&<share>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
 async fn upload_chunked(
     path: Option<&str>,
     share: Option<&str>,
@@ -906,11 +1706,26 @@ async fn upload_chunked(
     host: Host<'_>,
     token: Result<JWT, Status>,
     sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
 ) -> ApiResult {
-    perform_upload_chunked(None, path, share, content_type, data, host, token, sizes).await
+    perform_upload_chunked(
+        None,
+        path,
+        share,
+        content_type,
+        data,
+        host,
+        token,
+        sizes,
+        reservations,
+        audit,
+    )
+    .await
 }
 
 #[post("/upload_chunked?<path>&<share>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
 async fn upload_chunked_db(
     db: Connection<FileDb>,
     path: Option<&str>,
@@ -920,6 +1735,8 @@ async fn upload_chunked_db(
     host: Host<'_>,
     token: Result<JWT, Status>,
     sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
 ) -> ApiResult {
     perform_upload_chunked(
         Some(db),
@@ -930,10 +1747,19 @@ async fn upload_chunked_db(
         host,
         token,
         sizes,
+        reservations,
+        audit,
     )
     .await
 }
 
+/// Throws away a chunked upload that can't be finished, with the space it was holding.
+async fn discard_chunks(reservations: &Reservations, user: &str, upload: &str, chunk_dir: &str) {
+    let _ = std::fs::remove_dir_all(chunk_dir);
+    quota::release(reservations, user, upload).await;
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn perform_upload_chunked(
     db: Option<Connection<FileDb>>,
     path: Option<&str>,
@@ -943,6 +1769,8 @@ async fn perform_upload_chunked(
     host: Host<'_>,
     token: Result<JWT, Status>,
     sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
 ) -> ApiResult {
     let token = token?;
 
@@ -953,49 +1781,19 @@ async fn perform_upload_chunked(
         MultipartFormDataField::text("filename"),
         MultipartFormDataField::text("chunkindex"),
         MultipartFormDataField::text("totalchunks"),
+        MultipartFormDataField::text("totalsize"),
     ]);
 
     let form_data = MultipartFormData::parse(content_type, data, options)
         .await
         .map_err(|_| Status::BadRequest)?;
 
-    let mut user_path = form_data
+    let form_path = form_data
         .texts
         .get("path")
-        .and_then(|paths| paths.first().map(|p| p.text.trim_matches('/').to_string()))
-        .unwrap_or(String::new());
-
-    if user_path.is_empty() {
-        user_path = if token.claims.perms == 0 {
-            "uploads"
-        } else {
-            "private"
-        }
-        .to_string();
-    }
-
-    if let Some(query_path) = path {
-        user_path = query_path.trim_matches('/').to_string();
-    }
-
-    user_path = urlencoding::decode(&user_path)
-        .unwrap_or((&user_path).into())
-        .to_string();
-
-    let is_private = user_path.starts_with("private");
-    if !is_private && token.claims.perms != 0 {
-        return Err(Status::Forbidden);
-    }
+        .and_then(|paths| paths.first().map(|p| p.text.clone()));
 
-    let base_path = if is_private {
-        format!(
-            "files/private/{}/{}",
-            &token.claims.sub,
-            user_path.trim_start_matches("private")
-        )
-    } else {
-        format!("files/{}", user_path)
-    };
+    let (user_path, base_path) = upload_target(path.map(str::to_string).or(form_path), &token)?;
 
     let file_id = &form_data.texts["fileid"][0].text;
     let file_name = &form_data.texts["filename"][0].text;
@@ -1007,37 +1805,36 @@ async fn perform_upload_chunked(
         .text
         .parse()
         .map_err(|_| Status::BadRequest)?;
+    let total_size: u64 = form_data
+        .texts
+        .get("totalsize")
+        .and_then(|size| size[0].text.parse().ok())
+        .ok_or(Status::BadRequest)?;
+
+    if chunk_index >= total_chunks
+        || file_id.is_empty()
+        || !file_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(Status::BadRequest);
+    }
 
-    let max_size = CONFIG
-        .max_upload_sizes
-        .get(&token.claims.perms.to_string())
-        .unwrap_or(&(104857600 as u64));
+    let (max_size, _) = upload_limits(&token);
 
-    if (total_chunks as u64) * 94371840 > *max_size {
+    if total_size > max_size {
         return Err(Status::PayloadTooLarge);
     }
 
-    let folder_quota = *(CONFIG
-        .private_folder_quotas
-        .get(&token.claims.perms.to_string())
-        .unwrap_or(&1_u64));
-
-    let folder_usage = sizes
-        .read()
-        .await
-        .iter()
-        .find(|entry| {
-            entry.file.strip_suffix("/").unwrap_or_default().to_string()
-                == format!("files/private/{}", &token.claims.sub)
-        })
-        .map(|entry| entry.size)
-        .unwrap_or(0);
+    let is_private = base_path.starts_with("files/private/");
+    let upload = format!("chunked/{}", file_id);
 
-    if folder_quota != 0 && folder_usage + ((total_chunks as u64) * 94371840) >= folder_quota {
-        return Err(Status::InsufficientStorage);
+    if is_private {
+        quota::reserve(reservations, sizes, &token, &upload, total_size).await?;
     }
 
     let chunk_dir = format!(".chunks/{}/{}", &token.claims.sub, file_id);
+
     std::fs::create_dir_all(&chunk_dir).map_err(map_io_error_to_status)?;
 
     let chunk_path = format!("{}/{:05}.part", chunk_dir, chunk_index);
@@ -1046,78 +1843,85 @@ async fn perform_upload_chunked(
     let mut src = std::fs::File::open(&file_field.path).map_err(map_io_error_to_status)?;
     let mut dst = std::fs::File::create(&chunk_path).map_err(map_io_error_to_status)?;
 
-    std::io::copy(&mut src, &mut dst).map_err(map_io_error_to_status)?;
+    let copied = std::io::copy(&mut src, &mut dst).map_err(map_io_error_to_status)?;
+    METRICS.uploaded(copied);
 
-    let received_chunks = std::fs::read_dir(&chunk_dir)
-        .map_err(map_io_error_to_status)?
-        .count();
+    let mut received_chunks = 0;
+    let mut final_size: u64 = 0;
+
+    for part in std::fs::read_dir(&chunk_dir).map_err(map_io_error_to_status)? {
+        received_chunks += 1;
+        final_size += part
+            .and_then(|part| part.metadata())
+            .map_err(map_io_error_to_status)?
+            .len();
+    }
+
+    // More data than was declared means the handshake can't be trusted
+    if final_size > total_size {
+        discard_chunks(reservations, &token.claims.sub, &upload, &chunk_dir).await;
+        return Err(Status::PayloadTooLarge);
+    }
 
     if received_chunks < total_chunks {
         return Ok(ApiResponse::UploadFiles(Json(Vec::new())));
     }
 
-    std::fs::create_dir_all(&base_path).map_err(map_io_error_to_status)?;
+    if final_size != total_size {
+        discard_chunks(reservations, &token.claims.sub, &upload, &chunk_dir).await;
+        return Err(Status::BadRequest);
+    }
 
-    let final_path = format!("{}/{}", base_path, file_name);
-    let mut final_file = std::fs::File::create(&final_path).map_err(map_io_error_to_status)?;
-    let mut final_size: u64 = 0;
+    if is_private {
+        if let Err(status) = quota::reserve(reservations, sizes, &token, &upload, final_size).await
+        {
+            discard_chunks(reservations, &token.claims.sub, &upload, &chunk_dir).await;
+            return Err(status);
+        }
+    }
+
+    storage::create_dir_all(&storage_key(Path::new(&base_path)))
+        .await
+        .map_err(map_io_error_to_status)?;
+
+    let mut final_file: Pin<Box<dyn AsyncRead + Send>> = Box::pin(tokio::io::empty());
 
     for i in 0..total_chunks {
         let part_path = format!("{}/{:05}.part", chunk_dir, i);
-        let mut part = std::fs::File::open(&part_path).map_err(map_io_error_to_status)?;
+        let part = tokio::fs::File::open(&part_path)
+            .await
+            .map_err(map_io_error_to_status)?;
 
-        let bytes_copied =
-            std::io::copy(&mut part, &mut final_file).map_err(map_io_error_to_status)?;
+        final_file = Box::pin(final_file.chain(part));
+    }
 
-        final_size += bytes_copied;
+    let final_path = format!("{}/{}", base_path, file_name);
 
-        if final_size > *max_size {
-            drop(final_file);
-            let _ = std::fs::remove_file(&final_path);
-            let _ = std::fs::remove_dir_all(&chunk_dir);
-            return Err(Status::PayloadTooLarge);
-        }
-    }
+    let written = STORAGE
+        .write(&storage_key(Path::new(&final_path)), &mut final_file)
+        .await
+        .map_err(map_io_error_to_status);
+    audit
+        .record(
+            &token.claims.sub,
+            "upload",
+            &format!("{}/{}", user_path, file_name),
+            written.err().unwrap_or(Status::Created),
+        )
+        .await;
+    written?;
 
     std::fs::remove_dir_all(&chunk_dir).map_err(map_io_error_to_status)?;
 
-    {
-        let mut state_lock = sizes.write().await;
-        *state_lock = refresh_file_sizes().await;
-    }
-
-    if let Some(db) = db {
-        if match share.unwrap_or("true") {
-            "true" => true,
-            "false" => false,
-            _ => true,
-        } {
-            if let Some(mirror_file) = MirrorFileInternal::load_and_share(
-                db,
-                &Path::new(&base_path).join(&file_name).to_path_buf(),
-            )
-            .await
-            {
-                if let Some(id) = mirror_file.id {
-                    return Ok(ApiResponse::UploadFiles(Json(vec![UploadFile {
-                        name: file_name.clone(),
-                        url: Some(format!("http://{}/share/{}", host.0, id)),
-                        icon: Some(MirrorFile::get_icon(file_name)),
-                        error: None,
-                        size: Some(final_file.metadata().unwrap().len()),
-                    }])));
-                }
-            }
-        }
-    }
+    file_index::refresh(sizes, Path::new(&final_path)).await;
+    quota::release(reservations, &token.claims.sub, &upload).await;
 
-    Ok(ApiResponse::UploadFiles(Json(vec![UploadFile {
-        name: file_name.clone(),
-        url: Some(format!("http://{}/{}/{}", host.0, user_path, file_name)),
-        icon: Some(MirrorFile::get_icon(file_name)),
-        error: None,
-        size: Some(final_file.metadata().unwrap().len()),
-    }])))
+    Ok(ApiResponse::UploadFiles(Json(vec![
+        finish_upload(
+            db, share, &host, &user_path, &base_path, file_name, final_size,
+        )
+        .await,
+    ])))
 }
 
 #[post("/zip", data = "<data>")]
@@ -1125,8 +1929,8 @@ async fn download_zip(
     content_type: &ContentType,
     data: Data<'_>,
     token: Result<JWT, Status>,
-) -> Result<Option<(ContentType, Vec<u8>)>, Status> {
-    let _token = token?;
+) -> IndexResult {
+    let token = token?;
 
     let mut options = MultipartFormDataOptions::new();
     options
@@ -1135,34 +1939,43 @@ async fn download_zip(
 
     let multipart_form_data = MultipartFormData::parse(content_type, data, options)
         .await
-        .ok()
-        .unwrap();
-    let files_field = multipart_form_data.raw.get("files").unwrap();
-
-    let file_json = String::from_utf8(files_field[0].raw.clone()).ok().unwrap();
-    let file_list: FileList = serde_json::from_str(&file_json).ok().unwrap();
+        .map_err(|_| Status::BadRequest)?;
+    let files_field = multipart_form_data
+        .raw
+        .get("files")
+        .and_then(|fields| fields.first())
+        .ok_or(Status::BadRequest)?;
 
-    let mut zip_buf = Cursor::new(Vec::new());
-    let mut zip_writer = zip::ZipWriter::new(&mut zip_buf);
-    let zip_options =
-        SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let file_list: FileList =
+        serde_json::from_slice(&files_field.raw).map_err(|_| Status::BadRequest)?;
 
-    let root_base = PathBuf::from("files");
+    let mut paths = Vec::new();
     for path_encoded in file_list.0 {
-        let path_decoded = urlencoding::decode(&path_encoded).ok().unwrap();
-        let full_path = format!("files{}", path_decoded.deref());
-        let fs_path = PathBuf::from(&full_path);
-        if fs_path.exists() {
-            if let Err(e) = add_path_to_zip(&mut zip_writer, &root_base, &fs_path, zip_options) {
-                eprintln!("Failed to add {:?} to zip: {}", fs_path, e);
-            }
-        }
+        let path_decoded = urlencoding::decode(&path_encoded).map_err(|_| Status::BadRequest)?;
+        let file: PathBuf = Path::new(path_decoded.as_ref())
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+
+        paths.push(MirrorFile::get_real_path(&file, token.claims.sub.clone())?.0);
+    }
+
+    let entries = archiver::collect(&paths, &Viewer::new(Some(&token)))
+        .await
+        .map_err(map_io_error_to_status)?;
+
+    let limit = archiver::size_limit(Some(token.claims.perms));
+    if limit != 0 && entries.iter().map(|entry| entry.size).sum::<u64>() > limit {
+        return Err(Status::PayloadTooLarge);
     }
 
-    zip_writer.finish().ok().unwrap();
-    let zip_bytes = zip_buf.into_inner();
+    let format = ArchiveFormat::Zip;
 
-    Ok(Some((ContentType::new("application", "zip"), zip_bytes)))
+    Ok(IndexResponse::Attachment(
+        archiver::stream(entries, format),
+        format.content_type(),
+        format!("download.{}", format.extension()),
+    ))
 }
 
 #[get("/")]
@@ -1184,7 +1997,19 @@ pub fn build_api() -> AdHoc {
         rocket = rocket
             .mount(
                 "/api",
-                routes![index, listing, sysinfo, search, upload_info, create_folder,],
+                routes![
+                    index,
+                    listing,
+                    sysinfo,
+                    search,
+                    upload_info,
+                    create_folder,
+                    trash_list,
+                    copy_item,
+                    extract,
+                    compress,
+                    job_status,
+                ],
             )
             .register("/api", catchers![default]);
 
@@ -1196,14 +2021,29 @@ pub fn build_api() -> AdHoc {
                     share,
                     delete_db,
                     rename_db,
+                    move_item_db,
                     upload_db,
-                    upload_chunked_db
+                    upload_chunked_db,
+                    trash_restore_db,
+                    trash_purge_db,
+                    trash_empty_db,
+                    download_stats
                 ],
             )
         } else {
             rocket = rocket.mount(
                 "/api",
-                routes![file, delete, rename, upload, upload_chunked],
+                routes![
+                    file,
+                    delete,
+                    rename,
+                    move_item,
+                    upload,
+                    upload_chunked,
+                    trash_restore,
+                    trash_purge,
+                    trash_empty
+                ],
             )
         }
 
@@ -1211,6 +2051,12 @@ pub fn build_api() -> AdHoc {
             rocket = rocket.mount("/api", routes![download_zip])
         }
 
+        if CONFIG.enable_manifest {
+            rocket = rocket.mount("/api", routes![manifest])
+        }
+
+        rocket = rocket.mount("/api", tus::routes());
+
         rocket
     })
 }
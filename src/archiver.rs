@@ -0,0 +1,410 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufWriter, Cursor, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use rocket::{futures::stream, http::ContentType, time::OffsetDateTime};
+use tar::{EntryType, Header};
+use tokio::{runtime::Handle, sync::mpsc};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    config::CONFIG,
+    jwt::JWT,
+    storage::{storage_key, StorageEntry, StorageReader, STORAGE},
+    trash::is_trash_key,
+    MirrorFile,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tar" => Some(ArchiveFormat::Tar),
+            "tar.gz" | "tgz" => Some(ArchiveFormat::TarGz),
+            _ => None,
+        }
+    }
+
+    /// Picks the format from an archive's file name.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            ArchiveFormat::Zip => ContentType::ZIP,
+            ArchiveFormat::Tar => ContentType::TAR,
+            ArchiveFormat::TarGz => ContentType::GZIP,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub key: PathBuf,
+    /// Path inside the archive, using `/` as the separator.
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<u64>,
+}
+
+/// Who an archive is collected for, which decides what is left out of it.
+pub struct Viewer<'a> {
+    /// Name of the user, `None` for visitors who aren't logged in.
+    pub user: Option<&'a str>,
+    pub perms: Option<i32>,
+    /// Whether folders marked `RESTRICTED` are left out.
+    pub skip_restricted: bool,
+}
+
+impl<'a> Viewer<'a> {
+    pub fn new(token: Option<&'a JWT>) -> Self {
+        Viewer {
+            user: token.map(|token| token.claims.sub.as_str()),
+            perms: token.map(|token| token.claims.perms),
+            skip_restricted: CONFIG.enable_login && token.is_none(),
+        }
+    }
+
+    /// Someone downloading a share of `key`, who sees what its owner would,
+    /// apart from hidden folders.
+    pub fn shared(key: &'a Path) -> Self {
+        Viewer {
+            user: key
+                .strip_prefix("private")
+                .ok()
+                .and_then(|rest| rest.iter().next())
+                .and_then(|owner| owner.to_str()),
+            perms: None,
+            skip_restricted: false,
+        }
+    }
+
+    /// Private files can only be taken from the user's own private folder,
+    /// the folder holding everyone's is filtered file by file.
+    pub(crate) fn can_see_private(&self, key: &Path) -> bool {
+        !key.starts_with("private")
+            || key == Path::new("private")
+            || self.user.is_some_and(|user| {
+                user != "Nobody" && key.starts_with(Path::new("private").join(user))
+            })
+    }
+}
+
+/// Folders among `files` holding a marker file named `marker`.
+fn marked_dirs<'f>(files: &'f [(PathBuf, StorageEntry)], marker: &str) -> HashSet<&'f Path> {
+    files
+        .iter()
+        .filter(|(_, entry)| entry.name == marker)
+        .filter_map(|(file, _)| file.parent())
+        .collect()
+}
+
+/// Collects the files below each of `paths` (`files/...` paths), named relative
+/// to the folder they were picked from. Anything a listing wouldn't show to `viewer`
+/// is left out: hidden names, folders marked `HIDDEN` or `RESTRICTED`, other users'
+/// private folders, and the trash.
+pub async fn collect(paths: &[PathBuf], viewer: &Viewer<'_>) -> io::Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+
+    for path in paths {
+        let key = storage_key(path);
+        let name = match key.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => "files".to_string(),
+        };
+
+        if is_trash_key(&key)
+            || !viewer.can_see_private(&key)
+            || CONFIG.hidden_files.contains(&name)
+            || MirrorFile::is_hidden(path, viewer.perms).await
+        {
+            continue;
+        }
+
+        if viewer.skip_restricted {
+            let mut restricted = false;
+            for dir in path.ancestors() {
+                if MirrorFile::has_marker(dir, "RESTRICTED").await {
+                    restricted = true;
+                    break;
+                }
+            }
+
+            if restricted {
+                continue;
+            }
+        }
+
+        let md = match STORAGE.stat(&key).await {
+            Ok(md) => md,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        if !md.is_dir {
+            entries.push(ArchiveEntry {
+                key,
+                name,
+                is_dir: false,
+                size: md.size,
+                modified: md.modified,
+            });
+            continue;
+        }
+
+        entries.push(ArchiveEntry {
+            key: key.clone(),
+            name: format!("{}/", name),
+            is_dir: true,
+            size: 0,
+            modified: md.modified,
+        });
+
+        let files = STORAGE.walk(&key).await?;
+
+        let hidden_dirs = if viewer.perms == Some(0) {
+            HashSet::new()
+        } else {
+            marked_dirs(&files, "HIDDEN")
+        };
+        let restricted_dirs = if viewer.skip_restricted {
+            marked_dirs(&files, "RESTRICTED")
+        } else {
+            HashSet::new()
+        };
+
+        for (file, entry) in &files {
+            let Ok(relative) = file.strip_prefix(&key) else {
+                continue;
+            };
+
+            let hidden = is_trash_key(file)
+                || !viewer.can_see_private(file)
+                || relative.components().any(|c| {
+                    CONFIG
+                        .hidden_files
+                        .iter()
+                        .any(|hidden| c.as_os_str() == hidden.as_str())
+                })
+                || file
+                    .ancestors()
+                    .any(|dir| hidden_dirs.contains(dir) || restricted_dirs.contains(dir));
+
+            if hidden {
+                continue;
+            }
+
+            let relative = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            entries.push(ArchiveEntry {
+                key: file.clone(),
+                name: format!("{}/{}", name, relative),
+                is_dir: false,
+                size: entry.size,
+                modified: entry.modified,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Largest total size a user with `perms` may download as a single archive, 0 for no limit.
+/// Visitors who aren't logged in get the limit of regular users.
+pub fn size_limit(perms: Option<i32>) -> u64 {
+    *CONFIG
+        .max_archive_sizes
+        .get(&perms.unwrap_or(1).to_string())
+        .unwrap_or(&0)
+}
+
+fn zip_options(entry: &ArchiveEntry) -> SimpleFileOptions {
+    let mut options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(entry.size >= u32::MAX as u64);
+
+    if let Some(modified) = entry
+        .modified
+        .and_then(|m| OffsetDateTime::from_unix_timestamp(m as i64).ok())
+        .and_then(|m| zip::DateTime::try_from(m).ok())
+    {
+        options = options.last_modified_time(modified);
+    }
+
+    options
+}
+
+fn write_zip(entries: Vec<ArchiveEntry>, writer: impl Write, handle: &Handle) -> io::Result<()> {
+    let mut zip = ZipWriter::new_stream(writer);
+
+    for entry in entries {
+        if entry.is_dir {
+            zip.add_directory(&entry.name, zip_options(&entry))?;
+            continue;
+        }
+
+        let reader = handle.block_on(STORAGE.open(&entry.key, None))?;
+
+        zip.start_file(&entry.name, zip_options(&entry))?;
+        io::copy(
+            &mut SyncIoBridge::new_with_handle(reader, handle.clone()),
+            &mut zip,
+        )?;
+    }
+
+    zip.finish()?.into_inner().flush()
+}
+
+/// Reads exactly `left` bytes, the size a tar header was written with. A file that
+/// got shorter since it was listed fails the archive instead of corrupting it, and
+/// anything it grew by is left out.
+struct Exact<R> {
+    reader: R,
+    left: u64,
+}
+
+impl<R: Read> Read for Exact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            return Ok(0);
+        }
+
+        let max = self.left.min(buf.len() as u64) as usize;
+        let read = self.reader.read(&mut buf[..max])?;
+
+        if read == 0 && max > 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "file got shorter while being archived",
+            ));
+        }
+
+        self.left -= read as u64;
+        Ok(read)
+    }
+}
+
+fn write_tar(entries: Vec<ArchiveEntry>, writer: impl Write, handle: &Handle) -> io::Result<()> {
+    let mut tar = tar::Builder::new(writer);
+
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_mtime(entry.modified.unwrap_or(0));
+
+        if entry.is_dir {
+            header.set_entry_type(EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            tar.append_data(&mut header, &entry.name, io::empty())?;
+            continue;
+        }
+
+        let reader = handle.block_on(STORAGE.open(&entry.key, None))?;
+
+        header.set_entry_type(EntryType::Regular);
+        header.set_mode(0o644);
+        header.set_size(entry.size);
+        tar.append_data(
+            &mut header,
+            &entry.name,
+            Exact {
+                reader: SyncIoBridge::new_with_handle(reader, handle.clone()),
+                left: entry.size,
+            },
+        )?;
+    }
+
+    tar.into_inner()?.flush()
+}
+
+pub fn write_archive(
+    entries: Vec<ArchiveEntry>,
+    format: ArchiveFormat,
+    writer: impl Write,
+    handle: &Handle,
+) -> io::Result<()> {
+    match format {
+        ArchiveFormat::Zip => write_zip(entries, writer, handle),
+        ArchiveFormat::Tar => write_tar(entries, writer, handle),
+        ArchiveFormat::TarGz => {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            write_tar(entries, &mut encoder, handle)?;
+            encoder.finish()?.flush()
+        }
+    }
+}
+
+/// Hands what the archive writer produces over to [`stream`] in chunks.
+struct ChunkWriter(mpsc::Sender<io::Result<Cursor<Vec<u8>>>>);
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Cursor::new(buf.to_vec())))
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds an archive of `entries` on a blocking thread while the returned reader
+/// is being sent, so only a small buffer is ever held in memory. ZIP entries are
+/// stored uncompressed and switch to ZIP64 where needed. If building it fails
+/// partway, so does the reader, and the response is cut off instead of ending
+/// like a complete download.
+pub fn stream(entries: Vec<ArchiveEntry>, format: ArchiveFormat) -> StorageReader {
+    let (sender, mut receiver) = mpsc::channel(4);
+    let handle = Handle::current();
+
+    tokio::task::spawn_blocking(move || {
+        let writer = BufWriter::with_capacity(64 * 1024, ChunkWriter(sender.clone()));
+
+        if let Err(e) = write_archive(entries, format, writer, &handle) {
+            eprintln!("Failed to stream {} archive: {:?}", format.extension(), e);
+            let _ = sender.blocking_send(Err(e));
+        }
+    });
+
+    Box::pin(StreamReader::new(stream::poll_fn(move |cx| {
+        receiver.poll_recv(cx)
+    })))
+}
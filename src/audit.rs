@@ -0,0 +1,209 @@
+use std::io::Cursor;
+
+use rocket::{
+    fairing::AdHoc,
+    http::{ContentType, Status},
+    request::{FromRequest, Outcome},
+    time::OffsetDateTime,
+    Request,
+};
+use rocket_db_pools::{sqlx::MySqlPool, Database};
+use serde::Serialize;
+
+use crate::{
+    db::{add_audit_entry, FileDb},
+    guards::XForwardedFor,
+    jwt::JWT,
+    responders::{ApiResponse, ApiResult, IndexResponse},
+};
+
+/// Entries shown on a page of the admin log.
+pub const PAGE_SIZE: u32 = 100;
+
+/// The most entries an export holds.
+pub const EXPORT_LIMIT: u32 = 100000;
+
+/// Actions that are logged, for filtering.
+pub const ACTIONS: [&str; 6] = ["upload", "delete", "rename", "mkdir", "share", "login"];
+
+/// Where audit entries are written, managed when the file database is set up.
+pub struct AuditLog(MySqlPool);
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub time: i64,
+    pub actor: String,
+    pub ip: String,
+    pub action: String,
+    pub path: String,
+    pub status: u16,
+}
+
+#[derive(FromForm, Serialize, Default, Debug)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub action: Option<String>,
+    /// Part of the path to look for.
+    pub path: Option<String>,
+    pub page: Option<u32>,
+}
+
+/// Records what a request changes in the audit log.
+pub struct Audit<'r> {
+    log: Option<&'r AuditLog>,
+    ip: &'r str,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Audit<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let ip = match request.guard::<XForwardedFor<'r>>().await {
+            Outcome::Success(ip) => ip.0,
+            _ => "(unknown)",
+        };
+
+        Outcome::Success(Audit {
+            log: request.rocket().state::<AuditLog>(),
+            ip,
+        })
+    }
+}
+
+impl Audit<'_> {
+    /// Logs an action on `path` by `actor`, with the status it ended with.
+    pub async fn record(&self, actor: &str, action: &str, path: &str, status: Status) {
+        let Some(AuditLog(pool)) = self.log else {
+            return;
+        };
+
+        let mut db = match pool.acquire().await {
+            Ok(db) => db,
+            Err(error) => {
+                eprintln!("Database error (Audit::record): {:?}", error);
+                return;
+            }
+        };
+
+        // Failed logins and forwarded addresses can be anything, so they're cut to fit
+        let actor: String = actor.chars().take(255).collect();
+        let ip: String = self.ip.chars().take(64).collect();
+
+        add_audit_entry(&mut db, &actor, &ip, action, path, status.code).await;
+    }
+}
+
+/// Who sent a request, for requests that may not have logged in.
+pub fn actor(token: &Result<JWT, Status>) -> String {
+    match token {
+        Ok(token) => token.claims.sub.clone(),
+        Err(_) => "Nobody".into(),
+    }
+}
+
+/// The status an API response goes out with. Some successes are sent as an `Err`
+/// with a bodiless status, like `201 Created`.
+pub fn status_of(result: &ApiResult) -> Status {
+    match result {
+        Ok(ApiResponse::MessageStatus((status, _))) => *status,
+        Ok(ApiResponse::ShareResponse((status, _))) => *status,
+        Ok(_) => Status::Ok,
+        Err(status) => *status,
+    }
+}
+
+impl AuditFilter {
+    /// The filter as a query string without the page, for links to other pages and exports.
+    pub fn query(&self) -> String {
+        [
+            ("actor", &self.actor),
+            ("ip", &self.ip),
+            ("action", &self.action),
+            ("path", &self.path),
+        ]
+        .iter()
+        .filter_map(|(name, value)| {
+            value
+                .as_ref()
+                .filter(|value| !value.is_empty())
+                .map(|value| format!("{}={}", name, urlencoding::encode(value)))
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+    }
+}
+
+fn format_time(timestamp: i64) -> String {
+    let time = OffsetDateTime::from_unix_timestamp(timestamp).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+        time.year(),
+        time.month() as u8,
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+/// Quotes a value for CSV. Values a spreadsheet would run as a formula get a `'`
+/// in front, since paths and failed login names can be anything.
+pub(crate) fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Sends entries as an `audit.csv` or `audit.json` download.
+pub fn export(entries: &[AuditEntry], format: &str) -> Result<IndexResponse, Status> {
+    let (content_type, body) = match format {
+        "csv" => {
+            let mut csv = String::from("time,actor,ip,action,path,status\r\n");
+
+            for entry in entries {
+                csv += &format!(
+                    "{},{},{},{},{},{}\r\n",
+                    format_time(entry.time),
+                    csv_field(&entry.actor),
+                    csv_field(&entry.ip),
+                    csv_field(&entry.action),
+                    csv_field(&entry.path),
+                    entry.status
+                );
+            }
+
+            (ContentType::CSV, csv.into_bytes())
+        }
+        "json" => (
+            ContentType::JSON,
+            serde_json::to_vec(entries).map_err(|_| Status::InternalServerError)?,
+        ),
+        _ => return Err(Status::BadRequest),
+    };
+
+    Ok(IndexResponse::Attachment(
+        Box::pin(Cursor::new(body)),
+        content_type,
+        format!("audit.{}", format),
+    ))
+}
+
+pub fn build() -> AdHoc {
+    AdHoc::on_ignite("Audit log", |rocket| async {
+        match FileDb::fetch(&rocket).map(|db| MySqlPool::clone(db)) {
+            Some(pool) => rocket.manage(AuditLog(pool)),
+            None => rocket,
+        }
+    })
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use md5::Md5;
+use once_cell::sync::Lazy;
+use rocket::fairing::AdHoc;
+use rocket_db_pools::{
+    sqlx::{pool::PoolConnection, MySql, MySqlPool},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    config::CONFIG,
+    db::{get_checksums, purge_checksums, set_checksums, FileDb},
+    file_index,
+    storage::{storage_key, StorageEntry, STORAGE},
+    trash::is_trash_key,
+    utils::WorkQueue,
+    FileSizes,
+};
+
+/// Files waiting to be hashed ahead of the background sweep, as storage keys.
+static QUEUE: Lazy<WorkQueue> = Lazy::new(WorkQueue::default);
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, PartialOrd)]
+pub struct Checksums {
+    pub sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+}
+
+/// Hashes a file with SHA-256, and SHA-1 and MD5 when they're in `extra_checksums`.
+pub async fn compute(key: &Path) -> io::Result<Checksums> {
+    let extra = |name: &str| CONFIG.extra_checksums.iter().any(|e| e == name);
+
+    let mut reader = STORAGE.open(key, None).await?;
+    let mut sha256 = Sha256::new();
+    let mut sha1 = extra("sha1").then(Sha1::new);
+    let mut md5 = extra("md5").then(Md5::new);
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let read = reader.read(&mut buffer).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        sha256.update(&buffer[..read]);
+
+        if let Some(sha1) = sha1.as_mut() {
+            sha1.update(&buffer[..read]);
+        }
+
+        if let Some(md5) = md5.as_mut() {
+            md5.update(&buffer[..read]);
+        }
+    }
+
+    Ok(Checksums {
+        sha256: format!("{:x}", sha256.finalize()),
+        sha1: sha1.map(|sha1| format!("{:x}", sha1.finalize())),
+        md5: md5.map(|md5| format!("{:x}", md5.finalize())),
+    })
+}
+
+/// Cached checksums for a file (a `files/...` path). Files without them are queued
+/// to be hashed next, so they show up on a later request.
+pub async fn lookup(db: &mut PoolConnection<MySql>, path: &Path) -> Option<Checksums> {
+    if !CONFIG.enable_checksums {
+        return None;
+    }
+
+    let key = storage_key(path);
+    let entry = STORAGE
+        .stat(&key)
+        .await
+        .ok()
+        .filter(|entry| !entry.is_dir)?;
+
+    let checksums = get_checksums(db, &key.display().to_string(), entry.size, entry.modified).await;
+
+    if checksums.is_none() {
+        QUEUE.push(key);
+    }
+
+    checksums
+}
+
+/// Checksums for a file (a storage key) as of `size` and `modified`, taken from the
+/// cache when it has them. Otherwise the file is hashed now and cached, so others
+/// asking for the same file don't hash it again.
+pub async fn cached(
+    pool: Option<&MySqlPool>,
+    key: &Path,
+    size: u64,
+    modified: Option<u64>,
+) -> io::Result<Checksums> {
+    let mut db = match pool {
+        Some(pool) => pool.acquire().await.ok(),
+        None => None,
+    };
+    let path = key.display().to_string();
+
+    if let Some(db) = db.as_mut() {
+        if let Some(checksums) = get_checksums(db, &path, size, modified).await {
+            return Ok(checksums);
+        }
+    }
+
+    let checksums = compute(key).await?;
+
+    if let Some(db) = db.as_mut() {
+        set_checksums(db, &path, size, modified, &checksums).await;
+    }
+
+    Ok(checksums)
+}
+
+/// Formats a `SHA256SUMS` file for the files in a folder. Files that haven't been
+/// hashed yet are left out.
+pub async fn sums(db: &mut PoolConnection<MySql>, dir: &Path) -> io::Result<String> {
+    let key = storage_key(dir);
+    let mut entries: Vec<StorageEntry> = STORAGE
+        .list(&key)
+        .await?
+        .into_iter()
+        .filter(|entry| !entry.is_dir && !CONFIG.hidden_files.contains(&entry.name))
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut sums = String::new();
+
+    for entry in entries {
+        if let Some(checksums) = lookup(db, &dir.join(&entry.name)).await {
+            sums.push_str(&format!("{}  {}\n", checksums.sha256, entry.name));
+        }
+    }
+
+    Ok(sums)
+}
+
+/// Makes sure a file's cached checksums match its current contents.
+async fn update(pool: &MySqlPool, key: &Path) {
+    let Ok(entry) = STORAGE.stat(key).await else {
+        return;
+    };
+
+    if entry.is_dir {
+        return;
+    }
+
+    let mut db = match pool.acquire().await {
+        Ok(db) => db,
+        Err(error) => {
+            eprintln!("Database error (checksums::update): {:?}", error);
+            return;
+        }
+    };
+
+    let path = key.display().to_string();
+
+    if get_checksums(&mut db, &path, entry.size, entry.modified)
+        .await
+        .is_some()
+    {
+        return;
+    }
+
+    match compute(key).await {
+        Ok(checksums) => {
+            set_checksums(&mut db, &path, entry.size, entry.modified, &checksums).await
+        }
+        Err(e) => eprintln!("Failed to hash {}: {:?}", path, e),
+    }
+}
+
+/// Size and modification time of every indexed file, by storage key.
+pub(crate) type Swept = HashMap<PathBuf, (u64, Option<u64>)>;
+
+/// Files that are new or changed in `files` compared to the last sweep, in order.
+pub(crate) fn changed(swept: &Swept, files: &Swept) -> Vec<PathBuf> {
+    let mut keys: Vec<PathBuf> = files
+        .iter()
+        .filter(|(key, entry)| swept.get(*key) != Some(entry))
+        .map(|(key, _)| key.clone())
+        .collect();
+    keys.sort();
+
+    keys
+}
+
+/// Hashes queued files as they come in, and files the index saw change since the
+/// last sweep. Nothing is swept before the first scan, as the index may still be
+/// missing files whose checksums would be purged.
+async fn work(pool: MySqlPool, index: FileSizes) {
+    let mut swept_at = None;
+    let mut swept = Swept::new();
+
+    loop {
+        while let Some(key) = QUEUE.pop() {
+            update(&pool, &key).await;
+        }
+
+        let changes = index.read().await.changes();
+
+        if file_index::is_scanned() && swept_at != Some(changes) {
+            let files: Swept = index
+                .read()
+                .await
+                .files()
+                .filter(|(key, _, _)| !is_trash_key(key))
+                .map(|(key, size, modified)| (key.to_path_buf(), (size, modified)))
+                .collect();
+
+            for key in changed(&swept, &files) {
+                update(&pool, &key).await;
+
+                // Files someone is waiting for go first
+                while let Some(key) = QUEUE.pop() {
+                    update(&pool, &key).await;
+                }
+            }
+
+            let removed = swept_at.is_none() || swept.keys().any(|key| !files.contains_key(key));
+
+            if removed {
+                if let Ok(mut db) = pool.acquire().await {
+                    let keep: HashSet<String> =
+                        files.keys().map(|key| key.display().to_string()).collect();
+                    purge_checksums(&mut db, &keep).await;
+                }
+            }
+
+            swept = files;
+            swept_at = Some(changes);
+        }
+
+        QUEUE.wait(Duration::from_secs(60)).await;
+    }
+}
+
+pub fn build() -> AdHoc {
+    AdHoc::on_liftoff("Checksums", |rocket| {
+        Box::pin(async move {
+            let (Some(pool), Some(index)) = (
+                FileDb::fetch(rocket).map(|db| MySqlPool::clone(db)),
+                rocket.state::<FileSizes>().cloned(),
+            ) else {
+                return;
+            };
+
+            tokio::spawn(work(pool, index));
+        })
+    })
+}
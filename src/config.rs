@@ -4,16 +4,20 @@ use once_cell::sync::Lazy;
 use rocket::data::ToByteUnit;
 use serde::{Deserialize, Serialize};
 
-use crate::utils::parse_bool;
+use crate::{peers::PeerConfig, storage::S3Config, upstream::UpstreamConfig, utils::parse_bool};
 
 pub static CONFIG: Lazy<Config> = Lazy::new(Config::load);
 
+/// Settings from `config.toml`. Anything the file leaves out comes from the
+/// `MIRROR_*` environment variables or their defaults, so older files keep working.
 #[derive(Debug, Deserialize, Clone, Serialize)]
+#[serde(default)]
 pub struct Config {
     pub extensions: Vec<String>,
     pub hidden_files: Vec<String>,
     pub enable_login: bool,
     pub enable_api: bool,
+    pub enable_dav: bool,
     pub marmak_link: Option<String>,
     pub instance_info: String,
     pub x_sendfile_header: String,
@@ -30,12 +34,37 @@ pub struct Config {
     pub show_account_link: bool,
     pub max_upload_sizes: HashMap<String, u64>,
     pub private_folder_quotas: HashMap<String, u64>,
+    pub max_archive_sizes: HashMap<String, u64>,
+    pub trash_retention_days: u64,
+    pub upload_expiry_hours: u64,
+    pub index_cache: String,
+    pub enable_manifest: bool,
+    pub manifest_file: String,
+    pub enable_checksums: bool,
+    pub extra_checksums: Vec<String>,
+    pub enable_torrents: bool,
+    pub torrent_min_size: u64,
+    pub torrent_trackers: Vec<String>,
+    pub region: String,
+    pub weight: u32,
+    pub geoip_database: String,
+    pub stats_buckets: Vec<String>,
+    pub enable_metrics: bool,
+    pub metrics_allowlist: Vec<String>,
+    pub trusted_proxies: Vec<String>,
+    pub s3: Option<S3Config>,
+    pub upstreams: Vec<UpstreamConfig>,
+    pub peers: Vec<PeerConfig>,
 }
 
 impl Config {
     pub fn load() -> Self {
         let config_str = fs::read_to_string("config.toml").unwrap_or_default();
-        toml::from_str(&config_str).unwrap_or_default()
+
+        toml::from_str(&config_str).unwrap_or_else(|e| {
+            eprintln!("Failed to parse config.toml, using the defaults: {}", e);
+            Config::default()
+        })
     }
 }
 
@@ -66,6 +95,7 @@ impl Default for Config {
             ]),
             enable_login: parse_bool(&env::var("MIRROR_ENABLE_LOGIN").unwrap_or("false".into())),
             enable_api: parse_bool(&env::var("MIRROR_ENABLE_API").unwrap_or("true".into())),
+            enable_dav: parse_bool(&env::var("MIRROR_ENABLE_DAV").unwrap_or("false".into())),
             marmak_link:  env::var("MIRROR_MARMAK_LINK").ok(),
             instance_info: env::var("MIRROR_INSTANCE_INFO").unwrap_or("My Mirror Instance!".into()),
             x_sendfile_header: env::var("MIRROR_X_SENDFILE_HEADER").unwrap_or("X-Send-File".into()),
@@ -90,6 +120,31 @@ impl Default for Config {
                 ("1".into(), 2.gigabytes().as_u64()),
                 ("2".into(), 50.gigabytes().as_u64()),
             ]),
+            max_archive_sizes: HashMap::from([
+                ("0".into(), 0_u64),
+                ("1".into(), 10.gigabytes().as_u64()),
+                ("2".into(), 50.gigabytes().as_u64()),
+            ]),
+            trash_retention_days: env::var("MIRROR_TRASH_RETENTION_DAYS").unwrap_or("30".into()).parse::<u64>().unwrap_or(30),
+            upload_expiry_hours: env::var("MIRROR_UPLOAD_EXPIRY_HOURS").unwrap_or("24".into()).parse::<u64>().unwrap_or(24),
+            index_cache: env::var("MIRROR_INDEX_CACHE").unwrap_or("file_index.json".into()),
+            enable_manifest: parse_bool(&env::var("MIRROR_ENABLE_MANIFEST").unwrap_or("false".into())),
+            manifest_file: env::var("MIRROR_MANIFEST_FILE").unwrap_or("manifest.json.gz".into()),
+            enable_checksums: parse_bool(&env::var("MIRROR_ENABLE_CHECKSUMS").unwrap_or("false".into())),
+            extra_checksums: serde_json::from_str(&env::var("MIRROR_EXTRA_CHECKSUMS").unwrap_or("[]".into())).unwrap_or_default(),
+            enable_torrents: parse_bool(&env::var("MIRROR_ENABLE_TORRENTS").unwrap_or("false".into())),
+            torrent_min_size: env::var("MIRROR_TORRENT_MIN_SIZE").unwrap_or("100000000".into()).parse::<u64>().unwrap_or(100000000),
+            torrent_trackers: serde_json::from_str(&env::var("MIRROR_TORRENT_TRACKERS").unwrap_or("[]".into())).unwrap_or_default(),
+            region: env::var("MIRROR_REGION").unwrap_or_default(),
+            weight: env::var("MIRROR_WEIGHT").unwrap_or("1".into()).parse::<u32>().unwrap_or(1),
+            geoip_database: env::var("MIRROR_GEOIP_DATABASE").unwrap_or_default(),
+            stats_buckets: serde_json::from_str(&env::var("MIRROR_STATS_BUCKETS").unwrap_or("[]".into())).unwrap_or_default(),
+            enable_metrics: parse_bool(&env::var("MIRROR_ENABLE_METRICS").unwrap_or("false".into())),
+            metrics_allowlist: serde_json::from_str(&env::var("MIRROR_METRICS_ALLOWLIST").unwrap_or("[]".into())).unwrap_or_default(),
+            trusted_proxies: serde_json::from_str(&env::var("MIRROR_TRUSTED_PROXIES").unwrap_or("[]".into())).unwrap_or_default(),
+            s3: serde_json::from_str(&env::var("MIRROR_S3").unwrap_or_default()).ok(),
+            upstreams: serde_json::from_str(&env::var("MIRROR_UPSTREAMS").unwrap_or_default()).unwrap_or_default(),
+            peers: serde_json::from_str(&env::var("MIRROR_PEERS").unwrap_or_default()).unwrap_or_default(),
         }
     }
 }
@@ -0,0 +1,1239 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use quick_xml::{
+    escape::{escape, unescape},
+    events::{BytesStart, Event},
+    name::ResolveResult,
+    NsReader,
+};
+use rocket::{
+    data::ToByteUnit,
+    fairing::AdHoc,
+    http::{uri::Segments, ContentType, HeaderMap, Status},
+    request::{FromRequest, Outcome},
+    Data, Request, State,
+};
+use rocket_db_pools::Connection;
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::{
+    api::{private_usage, upload_limits},
+    audit::Audit,
+    config::CONFIG,
+    db::{move_file, FileDb},
+    file_index,
+    jwt::JWT,
+    metrics::METRICS,
+    mirrorfile::{MirrorFile, MirrorFileInternal},
+    quota::{self, Reservations},
+    responders::{DavResponse, IndexResponse},
+    stats::{self, Visitor},
+    storage::{self, storage_key, StorageEntry, STORAGE},
+    trash::{self, is_trash_key},
+    utils::{http_date, map_io_error_to_status, UPLOAD_TEMP_DIR},
+    FileSizes,
+};
+
+pub const DAV_CLASSES: &str = "1, 2";
+
+const DAV_METHODS: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, PROPPATCH, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+/// Lock lifetime in seconds when the client doesn't ask for one, and the longest it can get.
+const LOCK_TIMEOUT: u64 = 3600;
+
+type DavResult = Result<DavResponse, DavResponse>;
+
+struct DavLock {
+    token: String,
+    /// Storage key of the locked resource.
+    key: PathBuf,
+    href: String,
+    user: String,
+    exclusive: bool,
+    infinite: bool,
+    owner: String,
+    timeout: u64,
+    expires: u64,
+}
+
+impl DavLock {
+    fn covers(&self, key: &Path) -> bool {
+        self.key == key || (self.infinite && key.starts_with(&self.key))
+    }
+
+    fn xml(&self) -> String {
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:{}/></D:lockscope>\
+<D:depth>{}</D:depth><D:owner>{}</D:owner><D:timeout>Second-{}</D:timeout>\
+<D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+            if self.exclusive { "exclusive" } else { "shared" },
+            if self.infinite { "infinity" } else { "0" },
+            escape(self.owner.as_str()),
+            self.timeout,
+            self.token,
+            self.href
+        )
+    }
+}
+
+/// Locks only live in memory, so they go away on restart like clients expect of a timeout.
+static LOCKS: Lazy<Mutex<HashMap<String, DavLock>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn active_locks() -> MutexGuard<'static, HashMap<String, DavLock>> {
+    let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    let now = now();
+    locks.retain(|_, lock| lock.expires > now);
+    locks
+}
+
+/// Fails with `Locked` if someone else's lock applies to `key` (or, with `recursive`,
+/// to anything inside it) and its token wasn't sent in the `If` header.
+fn check_locks(key: &Path, headers: &DavHeaders, recursive: bool) -> Result<(), Status> {
+    let submitted = headers.lock_tokens();
+
+    let locked = active_locks().values().any(|lock| {
+        (lock.covers(key) || (recursive && lock.key.starts_with(key)))
+            && !submitted.contains(&lock.token)
+    });
+
+    if locked {
+        Err(Status::Locked)
+    } else {
+        Ok(())
+    }
+}
+
+fn remove_locks(key: &Path) {
+    active_locks().retain(|_, lock| !lock.key.starts_with(key));
+}
+
+fn lock_discovery(key: &Path) -> String {
+    active_locks()
+        .values()
+        .filter(|lock| lock.covers(key))
+        .map(DavLock::xml)
+        .collect()
+}
+
+pub struct DavHeaders<'r>(&'r HeaderMap<'r>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DavHeaders<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(DavHeaders(request.headers()))
+    }
+}
+
+impl DavHeaders<'_> {
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0.get_one(name).map(str::trim)
+    }
+
+    /// `Depth: infinity` is treated like 1 for listings, which clients accept.
+    fn shallow(&self) -> bool {
+        self.get("Depth") == Some("0")
+    }
+
+    fn overwrite(&self) -> bool {
+        !self
+            .get("Overwrite")
+            .is_some_and(|o| o.eq_ignore_ascii_case("F"))
+    }
+
+    fn timeout(&self) -> u64 {
+        self.get("Timeout")
+            .and_then(|t| t.split(',').find_map(|t| t.trim().strip_prefix("Second-")))
+            .and_then(|t| t.parse().ok())
+            .map(|t: u64| t.clamp(1, LOCK_TIMEOUT))
+            .unwrap_or(LOCK_TIMEOUT)
+    }
+
+    /// Lock tokens submitted in the `If` header.
+    fn lock_tokens(&self) -> Vec<String> {
+        self.get("If")
+            .unwrap_or_default()
+            .split('<')
+            .filter_map(|part| part.split_once('>'))
+            .map(|(token, _)| token.to_string())
+            .filter(|token| token.starts_with("opaquelocktoken:"))
+            .collect()
+    }
+
+    /// The path under `/dav` that `Destination` points to.
+    fn destination(&self) -> Result<PathBuf, Status> {
+        let destination = self.get("Destination").ok_or(Status::BadRequest)?;
+
+        let path = match destination.split_once("://") {
+            Some((_, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or("/"),
+            None => destination,
+        };
+
+        let path = path
+            .strip_prefix("/dav")
+            .filter(|p| p.is_empty() || p.starts_with('/'))
+            .ok_or(Status::BadGateway)?;
+
+        let mut result = PathBuf::new();
+
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            let segment = urlencoding::decode(segment).map_err(|_| Status::BadRequest)?;
+
+            if segment == "." || segment == ".." || segment.contains('/') {
+                return Err(Status::BadRequest);
+            }
+
+            result.push(segment.as_ref());
+        }
+
+        Ok(result)
+    }
+}
+
+/// An element of a request body.
+struct Element {
+    namespace: String,
+    name: String,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn new(namespace: ResolveResult, start: &BytesStart) -> Self {
+        Element {
+            namespace: match namespace {
+                ResolveResult::Bound(ns) => String::from_utf8_lossy(ns.0).to_string(),
+                _ => String::new(),
+            },
+            name: String::from_utf8_lossy(start.local_name().as_ref()).to_string(),
+            text: String::new(),
+            children: Vec::new(),
+        }
+    }
+
+    fn is(&self, name: &str) -> bool {
+        self.namespace == "DAV:" && self.name == name
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.is(name))
+    }
+
+    fn text_content(&self) -> String {
+        self.children
+            .iter()
+            .fold(self.text.trim().to_string(), |text, child| {
+                text + &child.text_content()
+            })
+    }
+
+    /// Writes the element back out as an empty property, for `propstat` lists.
+    fn empty_xml(&self) -> String {
+        if self.namespace == "DAV:" {
+            format!("<D:{}/>", self.name)
+        } else {
+            format!(
+                "<P:{} xmlns:P=\"{}\"/>",
+                self.name,
+                escape(self.namespace.as_str())
+            )
+        }
+    }
+}
+
+fn parse_xml(body: &str) -> Result<Option<Element>, Status> {
+    if body.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let mut reader = NsReader::from_str(body);
+    let mut stack: Vec<Element> = Vec::new();
+
+    loop {
+        let (namespace, event) = reader
+            .read_resolved_event()
+            .map_err(|_| Status::BadRequest)?;
+
+        let finished = match event {
+            Event::Start(start) => {
+                stack.push(Element::new(namespace, &start));
+                None
+            }
+            Event::Empty(start) => Some(Element::new(namespace, &start)),
+            Event::End(_) => Some(stack.pop().ok_or(Status::BadRequest)?),
+            Event::Text(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element
+                        .text
+                        .push_str(&text.decode().map_err(|_| Status::BadRequest)?);
+                }
+                None
+            }
+            Event::GeneralRef(reference) => {
+                let reference = reference.decode().map_err(|_| Status::BadRequest)?;
+
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(
+                        &unescape(&format!("&{};", reference)).map_err(|_| Status::BadRequest)?,
+                    );
+                }
+                None
+            }
+            Event::Eof => return Err(Status::BadRequest),
+            _ => None,
+        };
+
+        if let Some(element) = finished {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(element),
+                None => return Ok(Some(element)),
+            }
+        }
+    }
+}
+
+fn dav_path(segments: Segments<'_, rocket::http::uri::fmt::Path>) -> Result<PathBuf, Status> {
+    segments.to_path_buf(true).map_err(|_| Status::BadRequest)
+}
+
+fn href(path: &Path, is_dir: bool) -> String {
+    let mut href = String::from("/dav");
+
+    for segment in path.iter() {
+        href.push('/');
+        href.push_str(&urlencoding::encode(&segment.to_string_lossy()));
+    }
+
+    if is_dir && !href.ends_with('/') {
+        href.push('/');
+    }
+
+    href
+}
+
+/// Maps a `/dav` path to the tree, with the same rules as browsing for reads and
+/// as the API for changes. `HIDDEN` folders don't exist for non-admins.
+async fn resolve(path: &PathBuf, token: &JWT, write: bool) -> Result<PathBuf, Status> {
+    let username = token.claims.sub.clone();
+
+    let real = if write {
+        // The root and the private folder itself can't be replaced or removed
+        if path.as_os_str().is_empty() || path == Path::new("private") {
+            return Err(Status::Forbidden);
+        }
+
+        MirrorFile::get_real_path_with_perms(path, username, token.claims.perms)?.0
+    } else {
+        MirrorFile::get_real_path(path, username)?.0
+    };
+
+    if MirrorFile::is_hidden(&real, Some(token.claims.perms)).await {
+        return Err(Status::NotFound);
+    }
+
+    Ok(real)
+}
+
+async fn stat(key: &Path) -> Option<StorageEntry> {
+    if key.as_os_str().is_empty() {
+        return Some(StorageEntry {
+            name: String::new(),
+            is_dir: true,
+            size: 0,
+            modified: None,
+        });
+    }
+
+    STORAGE.stat(key).await.ok()
+}
+
+/// New resources need their parent to exist, apart from the user's private folder,
+/// which is created the first time something goes into it.
+async fn parent_exists(key: &Path, token: &JWT) -> bool {
+    let Some(parent) = key.parent() else {
+        return false;
+    };
+
+    if parent == Path::new("private").join(&token.claims.sub) {
+        return storage::create_dir_all(parent).await.is_ok();
+    }
+
+    stat(parent).await.is_some_and(|entry| entry.is_dir)
+}
+
+enum PropRequest {
+    All,
+    Names,
+    Props(Vec<Element>),
+}
+
+struct Resource {
+    path: PathBuf,
+    key: PathBuf,
+    entry: StorageEntry,
+    /// Used and available bytes, for the root of a private folder.
+    quota: Option<(u64, Option<u64>)>,
+}
+
+impl Resource {
+    fn live_props(&self) -> Vec<(&'static str, String)> {
+        let entry = &self.entry;
+        let mut props = vec![
+            ("displayname", escape(entry.name.as_str()).to_string()),
+            (
+                "resourcetype",
+                if entry.is_dir {
+                    "<D:collection/>".to_string()
+                } else {
+                    String::new()
+                },
+            ),
+        ];
+
+        if !entry.is_dir {
+            let content_type = Path::new(&entry.name)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(ContentType::from_extension)
+                .unwrap_or(ContentType::Binary);
+
+            props.push(("getcontentlength", entry.size.to_string()));
+            props.push((
+                "getcontenttype",
+                escape(content_type.to_string()).to_string(),
+            ));
+            props.push((
+                "getetag",
+                format!(
+                    "&quot;{:x}-{:x}&quot;",
+                    entry.size,
+                    entry.modified.unwrap_or(0)
+                ),
+            ));
+        }
+
+        if let Some(modified) = entry.modified {
+            props.push(("getlastmodified", http_date(modified as i64)));
+        }
+
+        props.push((
+            "supportedlock",
+            "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+<D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>"
+                .to_string(),
+        ));
+        props.push(("lockdiscovery", lock_discovery(&self.key)));
+
+        if let Some((used, available)) = self.quota {
+            props.push(("quota-used-bytes", used.to_string()));
+
+            if let Some(available) = available {
+                props.push(("quota-available-bytes", available.to_string()));
+            }
+        }
+
+        props
+    }
+
+    fn response_xml(&self, request: &PropRequest) -> String {
+        let props = self.live_props();
+        let mut found = String::new();
+        let mut missing = String::new();
+
+        match request {
+            PropRequest::All => {
+                for (name, value) in props {
+                    found += &format!("<D:{0}>{1}</D:{0}>", name, value);
+                }
+            }
+            PropRequest::Names => {
+                for (name, _) in props {
+                    found += &format!("<D:{}/>", name);
+                }
+            }
+            PropRequest::Props(requested) => {
+                for prop in requested {
+                    match props.iter().find(|(name, _)| prop.is(name)) {
+                        Some((name, value)) => found += &format!("<D:{0}>{1}</D:{0}>", name, value),
+                        None => missing += &prop.empty_xml(),
+                    }
+                }
+            }
+        }
+
+        let mut xml = format!(
+            "<D:response><D:href>{}</D:href>",
+            href(&self.path, self.entry.is_dir)
+        );
+
+        if !found.is_empty() {
+            xml += &propstat(&found, Status::Ok);
+        }
+
+        if !missing.is_empty() {
+            xml += &propstat(&missing, Status::NotFound);
+        }
+
+        xml + "</D:response>"
+    }
+}
+
+fn propstat(props: &str, status: Status) -> String {
+    format!(
+        "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>",
+        props, status
+    )
+}
+
+fn multistatus(responses: String) -> DavResponse {
+    DavResponse::new(Status::MultiStatus).xml(format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+        responses
+    ))
+}
+
+async fn propfind(
+    path: &PathBuf,
+    body: &str,
+    headers: &DavHeaders<'_>,
+    token: &JWT,
+    sizes: &FileSizes,
+) -> DavResult {
+    let real = resolve(path, token, false).await?;
+    let key = storage_key(&real);
+    let private_root = path == Path::new("private");
+
+    if private_root {
+        storage::create_dir_all(&key)
+            .await
+            .map_err(map_io_error_to_status)?;
+    }
+
+    let mut entry = stat(&key).await.ok_or(Status::NotFound)?;
+
+    let request = match parse_xml(body)? {
+        None => PropRequest::All,
+        Some(root) if root.is("propfind") => {
+            if root.child("propname").is_some() {
+                PropRequest::Names
+            } else {
+                match root.children.into_iter().find(|c| c.is("prop")) {
+                    Some(prop) => PropRequest::Props(prop.children),
+                    None => PropRequest::All,
+                }
+            }
+        }
+        Some(_) => return Err(Status::BadRequest.into()),
+    };
+
+    if private_root {
+        entry.name = "private".to_string();
+    }
+
+    let quota = if private_root {
+        let used = private_usage(token, sizes).await;
+        let (_, folder_quota) = upload_limits(token);
+
+        Some((
+            used,
+            (folder_quota != 0).then(|| folder_quota.saturating_sub(used)),
+        ))
+    } else {
+        None
+    };
+
+    let mut resources = vec![];
+
+    if entry.is_dir && !headers.shallow() {
+        let mut children = STORAGE.list(&key).await.map_err(map_io_error_to_status)?;
+
+        let mut visible = Vec::with_capacity(children.len());
+        for child in children {
+            let child_path = real.join(&child.name);
+
+            let hidden = token.claims.perms != 0 && CONFIG.hidden_files.contains(&child.name);
+
+            if !(hidden
+                || is_trash_key(&storage_key(&child_path))
+                || MirrorFile::is_hidden(&child_path, Some(token.claims.perms)).await)
+            {
+                visible.push(child);
+            }
+        }
+        children = visible;
+
+        // Everyone sees their own private folder under the root, never the whole private tree
+        if path.as_os_str().is_empty() {
+            children.retain(|child| child.name != "private");
+
+            if token.claims.sub != "Nobody" {
+                children.push(StorageEntry {
+                    name: "private".to_string(),
+                    is_dir: true,
+                    size: 0,
+                    modified: None,
+                });
+            }
+        }
+
+        for child in children {
+            resources.push(Resource {
+                path: path.join(&child.name),
+                key: key.join(&child.name),
+                entry: child,
+                quota: None,
+            });
+        }
+    }
+
+    resources.insert(
+        0,
+        Resource {
+            path: path.clone(),
+            key,
+            entry,
+            quota,
+        },
+    );
+
+    Ok(multistatus(
+        resources
+            .iter()
+            .map(|resource| resource.response_xml(&request))
+            .collect(),
+    ))
+}
+
+/// Properties can't be stored, but Windows insists on setting its file times, so
+/// those are acknowledged and everything else is refused.
+async fn proppatch(path: &PathBuf, body: &str, headers: &DavHeaders<'_>, token: &JWT) -> DavResult {
+    let real = resolve(path, token, true).await?;
+    let key = storage_key(&real);
+    let entry = stat(&key).await.ok_or(Status::NotFound)?;
+
+    check_locks(&key, headers, false)?;
+
+    let root = parse_xml(body)?.ok_or(Status::BadRequest)?;
+
+    if !root.is("propertyupdate") {
+        return Err(Status::BadRequest.into());
+    }
+
+    let mut accepted = String::new();
+    let mut refused = String::new();
+
+    for prop in root
+        .children
+        .iter()
+        .filter(|c| c.is("set") || c.is("remove"))
+        .filter_map(|c| c.child("prop"))
+        .flat_map(|p| p.children.iter())
+    {
+        if prop.namespace == "urn:schemas-microsoft-com:" {
+            accepted += &prop.empty_xml();
+        } else {
+            refused += &prop.empty_xml();
+        }
+    }
+
+    let mut response = format!("<D:response><D:href>{}</D:href>", href(path, entry.is_dir));
+
+    if !accepted.is_empty() {
+        response += &propstat(&accepted, Status::Ok);
+    }
+
+    if !refused.is_empty() {
+        response += &propstat(&refused, Status::Forbidden);
+    }
+
+    Ok(multistatus(response + "</D:response>"))
+}
+
+async fn mkcol(path: &PathBuf, body: &str, headers: &DavHeaders<'_>, token: &JWT) -> DavResult {
+    let real = resolve(path, token, true).await?;
+    let key = storage_key(&real);
+
+    if !body.is_empty() {
+        return Err(Status::UnsupportedMediaType.into());
+    }
+
+    if stat(&key).await.is_some() {
+        return Err(DavResponse::new(Status::MethodNotAllowed).header("Allow", DAV_METHODS));
+    }
+
+    check_locks(&key, headers, false)?;
+
+    if !parent_exists(&key, token).await {
+        return Err(Status::Conflict.into());
+    }
+
+    STORAGE.mkdir(&key).await.map_err(map_io_error_to_status)?;
+
+    Ok(DavResponse::new(Status::Created))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn transfer(
+    db: Option<Connection<FileDb>>,
+    path: &PathBuf,
+    headers: &DavHeaders<'_>,
+    token: &JWT,
+    sizes: &FileSizes,
+    reservations: &Reservations,
+    copy: bool,
+) -> DavResult {
+    // Copying only reads the source, same as downloading and uploading it again
+    let source_path = resolve(path, token, !copy).await?;
+    let destination = headers.destination()?;
+    let destination_path = resolve(&destination, token, true).await?;
+
+    let source_key = storage_key(&source_path);
+    let destination_key = storage_key(&destination_path);
+
+    if destination_key.starts_with(&source_key) {
+        return Err(Status::Forbidden.into());
+    }
+
+    let md = stat(&source_key).await.ok_or(Status::NotFound)?;
+    let existing = stat(&destination_key).await;
+
+    if !copy {
+        check_locks(&source_key, headers, true)?;
+    }
+    check_locks(&destination_key, headers, true)?;
+
+    if existing.is_some() && !headers.overwrite() {
+        return Err(Status::PreconditionFailed.into());
+    }
+
+    if !parent_exists(&destination_key, token).await {
+        return Err(Status::Conflict.into());
+    }
+
+    let destination_private = destination_path.starts_with("files/private");
+    let reservation = format!("dav/{}", Uuid::new_v4());
+
+    if destination_private && (copy || !source_path.starts_with("files/private")) {
+        let size = if md.is_dir {
+            STORAGE
+                .walk(&source_key)
+                .await
+                .map_err(map_io_error_to_status)?
+                .iter()
+                .map(|(_, entry)| entry.size)
+                .sum()
+        } else {
+            md.size
+        };
+
+        quota::reserve(reservations, sizes, token, &reservation, size).await?;
+    }
+
+    let mut trashed = None;
+
+    let result = async {
+        if existing.is_some() {
+            trashed = Some(
+                trash::move_to_trash(&token.claims.sub, &destination_path)
+                    .await
+                    .map_err(map_io_error_to_status)?,
+            );
+            file_index::refresh(sizes, &destination_path).await;
+            remove_locks(&destination_key);
+        }
+
+        if copy {
+            STORAGE.copy(&source_key, &destination_key).await
+        } else {
+            STORAGE.rename(&source_key, &destination_key).await
+        }
+        .map_err(map_io_error_to_status)?;
+
+        if !copy {
+            remove_locks(&source_key);
+            file_index::refresh(sizes, &source_path).await;
+        }
+        file_index::refresh(sizes, &destination_path).await;
+
+        Ok::<(), Status>(())
+    }
+    .await;
+
+    quota::release(reservations, &token.claims.sub, &reservation).await;
+    result?;
+
+    if let Some(mut db) = db {
+        let source_key = source_key.display().to_string();
+        let destination_key = destination_key.display().to_string();
+
+        // What was overwritten keeps its rows in the trash, like a delete
+        if let Some(trashed) = trashed {
+            move_file(
+                &mut db,
+                &destination_key,
+                &trashed.item_key().display().to_string(),
+            )
+            .await;
+        }
+
+        if !copy {
+            move_file(&mut db, &source_key, &destination_key).await;
+        }
+    }
+
+    Ok(DavResponse::new(if existing.is_some() {
+        Status::NoContent
+    } else {
+        Status::Created
+    }))
+}
+
+fn lock_body(lock: &DavLock) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>",
+        lock.xml()
+    )
+}
+
+async fn lock(path: &PathBuf, body: &str, headers: &DavHeaders<'_>, token: &JWT) -> DavResult {
+    let real = resolve(path, token, true).await?;
+    let key = storage_key(&real);
+    let timeout = headers.timeout();
+
+    let Some(info) = parse_xml(body)? else {
+        // An empty body refreshes a lock the client already holds
+        let submitted = headers.lock_tokens();
+        let mut locks = active_locks();
+
+        let lock = locks
+            .values_mut()
+            .find(|lock| {
+                lock.covers(&key)
+                    && lock.user == token.claims.sub
+                    && submitted.contains(&lock.token)
+            })
+            .ok_or(Status::PreconditionFailed)?;
+
+        lock.timeout = timeout;
+        lock.expires = now() + timeout;
+
+        return Ok(DavResponse::new(Status::Ok).xml(lock_body(lock)));
+    };
+
+    if !info.is("lockinfo") {
+        return Err(Status::BadRequest.into());
+    }
+
+    let exclusive = info
+        .child("lockscope")
+        .is_none_or(|scope| scope.child("shared").is_none());
+    let infinite = !headers.shallow();
+
+    let conflict = active_locks().values().any(|lock| {
+        (lock.covers(&key) || (infinite && lock.key.starts_with(&key)))
+            && (lock.exclusive || exclusive)
+    });
+
+    if conflict {
+        return Err(Status::Locked.into());
+    }
+
+    // Locking a missing resource reserves its name with an empty file
+    let existing = stat(&key).await;
+
+    let status = if existing.is_some() {
+        Status::Ok
+    } else {
+        if !parent_exists(&key, token).await {
+            return Err(Status::Conflict.into());
+        }
+
+        STORAGE
+            .write(&key, &mut tokio::io::empty())
+            .await
+            .map_err(map_io_error_to_status)?;
+
+        Status::Created
+    };
+
+    let lock = DavLock {
+        token: format!("opaquelocktoken:{}", Uuid::new_v4()),
+        href: href(path, existing.is_some_and(|entry| entry.is_dir)),
+        key,
+        user: token.claims.sub.clone(),
+        exclusive,
+        infinite,
+        owner: info
+            .child("owner")
+            .map(Element::text_content)
+            .unwrap_or_default(),
+        timeout,
+        expires: now() + timeout,
+    };
+
+    let response = DavResponse::new(status)
+        .header("Lock-Token", format!("<{}>", lock.token))
+        .xml(lock_body(&lock));
+
+    active_locks().insert(lock.token.clone(), lock);
+
+    Ok(response)
+}
+
+async fn unlock(path: &PathBuf, headers: &DavHeaders<'_>, token: &JWT) -> DavResult {
+    let real = resolve(path, token, true).await?;
+    let key = storage_key(&real);
+
+    let lock_token = headers
+        .get("Lock-Token")
+        .map(|t| t.trim_start_matches('<').trim_end_matches('>'))
+        .ok_or(Status::BadRequest)?;
+
+    let mut locks = active_locks();
+
+    match locks.get(lock_token) {
+        Some(lock)
+            if lock.covers(&key) && (lock.user == token.claims.sub || token.claims.perms == 0) =>
+        {
+            locks.remove(lock_token);
+            Ok(DavResponse::new(Status::NoContent))
+        }
+        _ => Err(Status::Conflict.into()),
+    }
+}
+
+#[options("/<_segments..>")]
+fn dav_options(_segments: Segments<'_, rocket::http::uri::fmt::Path>) -> DavResponse {
+    DavResponse::new(Status::Ok)
+        .header("Allow", DAV_METHODS)
+        .header("MS-Author-Via", "DAV")
+}
+
+#[get("/<segments..>")]
+async fn dav_get_db(
+    db: Connection<FileDb>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    token: Result<JWT, Status>,
+    visitor: Visitor,
+) -> Result<IndexResponse, DavResponse> {
+    perform_get(Some((db, visitor)), segments, token).await
+}
+
+#[get("/<segments..>")]
+async fn dav_get(
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    token: Result<JWT, Status>,
+) -> Result<IndexResponse, DavResponse> {
+    perform_get(None, segments, token).await
+}
+
+async fn perform_get(
+    db: Option<(Connection<FileDb>, Visitor)>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    token: Result<JWT, Status>,
+) -> Result<IndexResponse, DavResponse> {
+    let token = token?;
+    let path = dav_path(segments)?;
+    let real = resolve(&path, &token, false).await?;
+    let key = storage_key(&real);
+
+    match stat(&key).await {
+        Some(entry) if entry.is_dir => {
+            Err(DavResponse::new(Status::MethodNotAllowed).header("Allow", DAV_METHODS))
+        }
+        Some(entry) => {
+            if let Some((mut db, visitor)) = db {
+                stats::record(&mut db, &key, entry.size, &visitor).await;
+            }
+
+            Ok(MirrorFileInternal::open_file(real, "private").await?)
+        }
+        None => Err(Status::NotFound.into()),
+    }
+}
+
+#[put("/<segments..>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn dav_put(
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    data: Data<'_>,
+    headers: DavHeaders<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
+) -> DavResult {
+    let token = token?;
+    let path = dav_path(segments)?;
+    let real = resolve(&path, &token, true).await?;
+    let key = storage_key(&real);
+
+    let existing = stat(&key).await;
+
+    if existing.as_ref().is_some_and(|entry| entry.is_dir) {
+        return Err(DavResponse::new(Status::MethodNotAllowed).header("Allow", DAV_METHODS));
+    }
+
+    check_locks(&key, &headers, false)?;
+
+    if !parent_exists(&key, &token).await {
+        return Err(Status::Conflict.into());
+    }
+
+    let (max_size, _) = upload_limits(&token);
+    let private = real.starts_with("files/private");
+    let upload = format!("dav/{}", Uuid::new_v4());
+    let temp_path = Path::new(UPLOAD_TEMP_DIR).join(&upload);
+
+    let stored = async {
+        if let Some(length) = headers.get("Content-Length").and_then(|l| l.parse().ok()) {
+            if length > max_size {
+                return Err(Status::PayloadTooLarge);
+            }
+
+            if private {
+                quota::reserve(reservations, sizes, &token, &upload, length).await?;
+            }
+        }
+
+        fs::create_dir_all(Path::new(UPLOAD_TEMP_DIR).join("dav"))
+            .await
+            .map_err(map_io_error_to_status)?;
+
+        let written = data
+            .open((max_size + 1).bytes())
+            .into_file(&temp_path)
+            .await
+            .map_err(map_io_error_to_status)?;
+
+        METRICS.uploaded(written.n.written);
+        if !written.is_complete() || written.n.written > max_size {
+            return Err(Status::PayloadTooLarge);
+        }
+
+        // The body may have had no Content-Length, or a wrong one
+        if private {
+            quota::reserve(reservations, sizes, &token, &upload, written.n.written).await?;
+        }
+
+        STORAGE
+            .import(&key, &temp_path)
+            .await
+            .map_err(map_io_error_to_status)?;
+        file_index::refresh(sizes, &real).await;
+
+        Ok(())
+    }
+    .await;
+
+    quota::release(reservations, &token.claims.sub, &upload).await;
+    let _ = fs::remove_file(&temp_path).await;
+
+    let status = match stored {
+        Ok(()) if existing.is_some() => Status::NoContent,
+        Ok(()) => Status::Created,
+        Err(status) => status,
+    };
+    audit
+        .record(
+            &token.claims.sub,
+            "upload",
+            &path.display().to_string(),
+            status,
+        )
+        .await;
+    stored?;
+
+    Ok(DavResponse::new(status))
+}
+
+#[delete("/<segments..>")]
+async fn dav_delete_db(
+    db: Connection<FileDb>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    headers: DavHeaders<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+) -> DavResult {
+    perform_delete(Some(db), segments, headers, token, sizes).await
+}
+
+#[delete("/<segments..>")]
+async fn dav_delete(
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    headers: DavHeaders<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+) -> DavResult {
+    perform_delete(None, segments, headers, token, sizes).await
+}
+
+async fn perform_delete(
+    db: Option<Connection<FileDb>>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    headers: DavHeaders<'_>,
+    token: Result<JWT, Status>,
+    sizes: &FileSizes,
+) -> DavResult {
+    let token = token?;
+    let path = dav_path(segments)?;
+    let real = resolve(&path, &token, true).await?;
+    let key = storage_key(&real);
+
+    let entry = stat(&key).await.ok_or(Status::NotFound)?;
+
+    check_locks(&key, &headers, true)?;
+
+    // Like the API, only private folders can be deleted with everything inside them
+    if entry.is_dir
+        && !real.starts_with("files/private")
+        && !STORAGE
+            .list(&key)
+            .await
+            .map_err(map_io_error_to_status)?
+            .is_empty()
+    {
+        return Err(Status::Forbidden.into());
+    }
+
+    let trashed = match trash::move_to_trash(&token.claims.sub, &real).await {
+        Ok(trashed) => trashed,
+        Err(e) => {
+            eprintln!("Failed to delete {}: {:?}", real.display(), e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    file_index::refresh(sizes, &real).await;
+    remove_locks(&key);
+
+    if let Some(mut db) = db {
+        move_file(
+            &mut db,
+            &key.display().to_string(),
+            &trashed.item_key().display().to_string(),
+        )
+        .await;
+    }
+
+    Ok(DavResponse::new(Status::NoContent))
+}
+
+/// Rocket only routes standard HTTP methods, so the reverse proxy sends the other
+/// WebDAV ones as `POST` with the real method in `X-HTTP-Method-Override`.
+#[post("/<segments..>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn dav_method_db(
+    db: Connection<FileDb>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    data: Data<'_>,
+    headers: DavHeaders<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+) -> DavResult {
+    perform_method(
+        Some(db),
+        segments,
+        data,
+        headers,
+        token,
+        sizes,
+        reservations,
+    )
+    .await
+}
+
+#[post("/<segments..>", data = "<data>")]
+async fn dav_method(
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    data: Data<'_>,
+    headers: DavHeaders<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+) -> DavResult {
+    perform_method(None, segments, data, headers, token, sizes, reservations).await
+}
+
+async fn perform_method(
+    db: Option<Connection<FileDb>>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    data: Data<'_>,
+    headers: DavHeaders<'_>,
+    token: Result<JWT, Status>,
+    sizes: &FileSizes,
+    reservations: &Reservations,
+) -> DavResult {
+    let token = token?;
+    let path = dav_path(segments)?;
+
+    let body = data
+        .open(64.kibibytes())
+        .into_string()
+        .await
+        .map_err(map_io_error_to_status)?;
+
+    if !body.is_complete() {
+        return Err(Status::PayloadTooLarge.into());
+    }
+
+    let method = headers
+        .get("X-HTTP-Method-Override")
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+
+    match method.as_str() {
+        "PROPFIND" => propfind(&path, &body, &headers, &token, sizes).await,
+        "PROPPATCH" => proppatch(&path, &body, &headers, &token).await,
+        "MKCOL" => mkcol(&path, &body, &headers, &token).await,
+        "COPY" => transfer(db, &path, &headers, &token, sizes, reservations, true).await,
+        "MOVE" => transfer(db, &path, &headers, &token, sizes, reservations, false).await,
+        "LOCK" => lock(&path, &body, &headers, &token).await,
+        "UNLOCK" => unlock(&path, &headers, &token).await,
+        _ => Err(DavResponse::new(Status::MethodNotAllowed).header("Allow", DAV_METHODS)),
+    }
+}
+
+#[catch(default)]
+fn default(status: Status, _req: &Request) -> DavResponse {
+    DavResponse::new(status)
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    if CONFIG.enable_file_db {
+        routes![
+            dav_options,
+            dav_get_db,
+            dav_put,
+            dav_delete_db,
+            dav_method_db
+        ]
+    } else {
+        routes![dav_options, dav_get, dav_put, dav_delete, dav_method]
+    }
+}
+
+pub fn build() -> AdHoc {
+    AdHoc::on_ignite("WebDAV", |rocket| async {
+        rocket
+            .mount("/dav", routes())
+            .register("/dav", catchers![default])
+    })
+}
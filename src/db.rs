@@ -1,9 +1,26 @@
+use std::{collections::HashSet, path::Path};
+
 use rand::{distr::Alphanumeric, RngExt};
+use rocket::{
+    fairing::AdHoc,
+    request::{FromRequest, Outcome},
+    Request,
+};
 use rocket_db_pools::{sqlx, Connection, Database};
-use sqlx::Row;
+use sqlx::{pool::PoolConnection, MySql, MySqlPool, Row};
 
 use uuid::Uuid;
 
+use crate::{
+    audit::{AuditEntry, AuditFilter},
+    checksums::Checksums,
+    config::CONFIG,
+    file_index::FileIndex,
+    stats::{DownloadStats, StatsEntry},
+    storage::STORAGE,
+    MirrorFile,
+};
+
 #[cfg(not(test))]
 use crate::account::MarmakUser;
 
@@ -15,6 +32,40 @@ pub struct Db(sqlx::MySqlPool);
 #[database("mirror")]
 pub struct FileDb(sqlx::MySqlPool);
 
+/// The database pools that are in use, fetched only when enabled so the
+/// missing ones aren't complained about.
+pub struct Pools<'r> {
+    pub db: Option<&'r MySqlPool>,
+    pub file_db: Option<&'r MySqlPool>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Pools<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let rocket = request.rocket();
+
+        Outcome::Success(Pools {
+            db: CONFIG
+                .enable_login
+                .then(|| Db::fetch(rocket))
+                .flatten()
+                .map(|db| &**db),
+            file_db: CONFIG
+                .enable_file_db
+                .then(|| FileDb::fetch(rocket))
+                .flatten()
+                .map(|db| &**db),
+        })
+    }
+}
+
+/// Checks that a pool can hand out a working connection.
+pub async fn ping(pool: &MySqlPool) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT 1").execute(pool).await.map(|_| ())
+}
+
 pub async fn add_download(mut db: Connection<FileDb>, path: &str) -> () {
     let id = Uuid::new_v4().to_string();
 
@@ -28,9 +79,39 @@ pub async fn add_download(mut db: Connection<FileDb>, path: &str) -> () {
     }
 }
 
-pub async fn delete_file(mut db: Connection<FileDb>, path: &str) -> () {
-    if let Err(error) = sqlx::query("DELETE FROM files WHERE path = ?")
+/// `LIKE` pattern matching everything below `path`, with its wildcards escaped.
+pub fn like_below(path: &str) -> String {
+    format!(
+        "{}/%",
+        path.replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+/// Points the row of a file, or the rows of everything below a folder, at `to`.
+pub async fn move_file(db: &mut PoolConnection<MySql>, from: &str, to: &str) -> () {
+    let pattern = like_below(from);
+
+    if let Err(error) = sqlx::query(
+        "UPDATE files SET path = CONCAT(?, SUBSTRING(path, ?)) WHERE path = ? OR path LIKE ?",
+    )
+    .bind(to)
+    .bind(from.chars().count() as u64 + 1)
+    .bind(from)
+    .bind(pattern)
+    .execute(&mut **db)
+    .await
+    {
+        eprintln!("Database error (move_file): {:?}", error);
+    }
+}
+
+/// Removes the row of a file, or the rows of everything below a folder.
+pub async fn delete_file(db: &mut PoolConnection<MySql>, path: &str) -> () {
+    if let Err(error) = sqlx::query("DELETE FROM files WHERE path = ? OR path LIKE ?")
         .bind(path)
+        .bind(like_below(path))
         .execute(&mut **db)
         .await
     {
@@ -38,6 +119,399 @@ pub async fn delete_file(mut db: Connection<FileDb>, path: &str) -> () {
     }
 }
 
+#[derive(serde::Serialize)]
+pub struct OrphanedFile {
+    pub id: String,
+    pub path: String,
+    pub downloads: i32,
+    pub shared: bool,
+    pub candidates: Vec<String>,
+}
+
+/// Finds `files` rows whose path no longer exists. Files and folders from `sizes`
+/// with the same name and no row of their own are suggested for re-linking.
+pub async fn find_orphaned_files(
+    db: &mut PoolConnection<MySql>,
+    sizes: &FileIndex,
+) -> Vec<OrphanedFile> {
+    let rows = match sqlx::query("SELECT id, path, downloads, shared FROM files")
+        .fetch_all(&mut **db)
+        .await
+    {
+        Ok(rows) => rows,
+        Err(error) => {
+            eprintln!("Database error (find_orphaned_files): {:?}", error);
+            return Vec::new();
+        }
+    };
+
+    let known_paths: HashSet<String> = rows
+        .iter()
+        .filter_map(|row| row.try_get::<String, _>("path").ok())
+        .collect();
+
+    let mut orphans = Vec::new();
+
+    for row in rows {
+        let (Ok(id), Ok(path)) = (
+            row.try_get::<String, _>("id"),
+            row.try_get::<String, _>("path"),
+        ) else {
+            continue;
+        };
+
+        if STORAGE.exists(Path::new(&path)).await {
+            continue;
+        }
+
+        let candidates = relink_candidates(&path, sizes, &known_paths);
+
+        orphans.push(OrphanedFile {
+            id,
+            path: format!("/{}", path),
+            downloads: row.try_get::<i32, _>("downloads").unwrap_or(0),
+            shared: row.try_get::<bool, _>("shared").unwrap_or(false),
+            candidates,
+        });
+    }
+
+    orphans
+}
+
+/// Files and folders in `sizes` named like `path` that no row points to yet.
+pub fn relink_candidates(
+    path: &str,
+    sizes: &FileIndex,
+    known_paths: &HashSet<String>,
+) -> Vec<String> {
+    let name = MirrorFile::get_name_from_path(&Path::new(path).to_path_buf());
+
+    sizes
+        .entries()
+        .map(|entry| {
+            entry
+                .file
+                .trim_start_matches("files/")
+                .trim_end_matches('/')
+                .to_string()
+        })
+        .filter(|candidate| {
+            MirrorFile::get_name_from_path(&Path::new(candidate).to_path_buf()) == name
+                && !known_paths.contains(candidate)
+        })
+        .map(|candidate| format!("/{}", candidate))
+        .collect()
+}
+
+pub async fn relink_file(mut db: Connection<FileDb>, id: &str, path: &str) -> bool {
+    if let Err(error) = sqlx::query("UPDATE files SET path = ? WHERE id = ?")
+        .bind(path)
+        .bind(id)
+        .execute(&mut **db)
+        .await
+    {
+        eprintln!("Database error (relink_file): {:?}", error);
+        false
+    } else {
+        true
+    }
+}
+
+pub async fn delete_file_by_id(mut db: Connection<FileDb>, id: &str) -> () {
+    if let Err(error) = sqlx::query("DELETE FROM files WHERE id = ?")
+        .bind(id)
+        .execute(&mut **db)
+        .await
+    {
+        eprintln!("Database error (delete_file_by_id): {:?}", error);
+    }
+}
+
+pub async fn purge_orphaned_files(mut db: Connection<FileDb>) -> () {
+    for orphan in find_orphaned_files(&mut db, &FileIndex::default()).await {
+        if let Err(error) = sqlx::query("DELETE FROM files WHERE id = ?")
+            .bind(&orphan.id)
+            .execute(&mut **db)
+            .await
+        {
+            eprintln!("Database error (purge_orphaned_files): {:?}", error);
+        }
+    }
+}
+
+/// Reports orphaned `files` rows once the mirror has started.
+pub fn reconcile_files() -> AdHoc {
+    AdHoc::on_liftoff("Orphaned files", |rocket| {
+        Box::pin(async move {
+            let Some(pool) = FileDb::fetch(rocket).map(|db| db.0.clone()) else {
+                return;
+            };
+
+            tokio::spawn(async move {
+                let mut db = match pool.acquire().await {
+                    Ok(db) => db,
+                    Err(error) => {
+                        eprintln!("Database error (reconcile_files): {:?}", error);
+                        return;
+                    }
+                };
+
+                let orphans = find_orphaned_files(&mut db, &FileIndex::default()).await;
+
+                if !orphans.is_empty() {
+                    println!(
+                        "Found {} orphaned file records, review them at /admin/orphans",
+                        orphans.len()
+                    );
+                }
+            });
+        })
+    })
+}
+
+/// Cached checksums for a file, if they were computed for this size and mtime.
+pub async fn get_checksums(
+    db: &mut PoolConnection<MySql>,
+    path: &str,
+    size: u64,
+    modified: Option<u64>,
+) -> Option<Checksums> {
+    let query_result = sqlx::query(
+        "SELECT sha256, sha1, md5 FROM checksums WHERE path = ? AND size = ? AND modified <=> ?",
+    )
+    .bind(path)
+    .bind(size)
+    .bind(modified)
+    .fetch_optional(&mut **db)
+    .await;
+
+    match query_result {
+        Ok(row) => {
+            let row = row?;
+
+            Some(Checksums {
+                sha256: row.try_get::<String, _>("sha256").ok()?,
+                sha1: row.try_get::<Option<String>, _>("sha1").ok()?,
+                md5: row.try_get::<Option<String>, _>("md5").ok()?,
+            })
+        }
+        Err(error) => {
+            eprintln!("Database error (get_checksums): {:?}", error);
+            None
+        }
+    }
+}
+
+pub async fn set_checksums(
+    db: &mut PoolConnection<MySql>,
+    path: &str,
+    size: u64,
+    modified: Option<u64>,
+    checksums: &Checksums,
+) -> () {
+    if let Err(error) = sqlx::query("INSERT INTO checksums (path, size, modified, sha256, sha1, md5) VALUES (?, ?, ?, ?, ?, ?) ON DUPLICATE KEY UPDATE size = VALUES(size), modified = VALUES(modified), sha256 = VALUES(sha256), sha1 = VALUES(sha1), md5 = VALUES(md5)")
+        .bind(path)
+        .bind(size)
+        .bind(modified)
+        .bind(&checksums.sha256)
+        .bind(&checksums.sha1)
+        .bind(&checksums.md5)
+        .execute(&mut **db)
+        .await
+    {
+        eprintln!("Database error (set_checksums): {:?}", error);
+    }
+}
+
+/// Removes cached checksums of files that aren't in `keep` anymore.
+pub async fn purge_checksums(db: &mut PoolConnection<MySql>, keep: &HashSet<String>) -> () {
+    let paths: Vec<String> = match sqlx::query("SELECT path FROM checksums")
+        .fetch_all(&mut **db)
+        .await
+    {
+        Ok(rows) => rows
+            .iter()
+            .filter_map(|row| row.try_get::<String, _>("path").ok())
+            .filter(|path| !keep.contains(path))
+            .collect(),
+        Err(error) => {
+            eprintln!("Database error (purge_checksums): {:?}", error);
+            return;
+        }
+    };
+
+    for path in paths {
+        if let Err(error) = sqlx::query("DELETE FROM checksums WHERE path = ?")
+            .bind(&path)
+            .execute(&mut **db)
+            .await
+        {
+            eprintln!("Database error (purge_checksums): {:?}", error);
+        }
+    }
+}
+
+/// Counts a download in today's row for the file and bucket.
+pub async fn record_download(
+    db: &mut PoolConnection<MySql>,
+    path: &str,
+    bytes: u64,
+    referrer: &str,
+    country: &str,
+) -> () {
+    if let Err(error) = sqlx::query("INSERT INTO download_stats (path, day, referrer, country, downloads, bytes) VALUES (?, UTC_DATE(), ?, ?, 1, ?) ON DUPLICATE KEY UPDATE downloads = downloads + 1, bytes = bytes + VALUES(bytes)")
+        .bind(path)
+        .bind(referrer)
+        .bind(country)
+        .bind(bytes)
+        .execute(&mut **db)
+        .await
+    {
+        eprintln!("Database error (record_download): {:?}", error);
+    }
+}
+
+/// Downloads and bytes of the last `days` days summed up by `column`, an SQL
+/// expression, busiest first unless ordered by `column` itself.
+async fn sum_downloads(
+    db: &mut PoolConnection<MySql>,
+    column: &str,
+    days: u32,
+    by_column: bool,
+    limit: u32,
+) -> Result<Vec<StatsEntry>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} AS name, CAST(SUM(downloads) AS UNSIGNED) AS downloads, CAST(SUM(bytes) AS UNSIGNED) AS bytes FROM download_stats WHERE day > UTC_DATE() - INTERVAL ? DAY GROUP BY name HAVING name != '' ORDER BY {} LIMIT ?",
+        column,
+        if by_column { "name" } else { "downloads DESC, name" }
+    ))
+    .bind(days)
+    .bind(limit)
+    .fetch_all(&mut **db)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| StatsEntry {
+            name: row.try_get::<String, _>("name").unwrap_or_default(),
+            downloads: row.try_get::<u64, _>("downloads").unwrap_or(0),
+            bytes: row.try_get::<u64, _>("bytes").unwrap_or(0),
+        })
+        .collect())
+}
+
+/// Download statistics of the last `days` days, with up to `limit` entries per list.
+pub async fn get_download_stats(
+    db: &mut PoolConnection<MySql>,
+    days: u32,
+    limit: u32,
+) -> Option<DownloadStats> {
+    let stats = async {
+        let daily = sum_downloads(db, "DATE_FORMAT(day, '%Y-%m-%d')", days, true, days).await?;
+
+        Ok::<_, sqlx::Error>(DownloadStats {
+            days,
+            downloads: daily.iter().map(|day| day.downloads).sum(),
+            bytes: daily.iter().map(|day| day.bytes).sum(),
+            files: sum_downloads(db, "path", days, false, limit).await?,
+            folders: sum_downloads(
+                db,
+                "IF(LOCATE('/', path) > 0, SUBSTRING_INDEX(path, '/', 1), '/')",
+                days,
+                false,
+                limit,
+            )
+            .await?,
+            referrers: sum_downloads(db, "referrer", days, false, limit).await?,
+            countries: sum_downloads(db, "country", days, false, limit).await?,
+            daily,
+        })
+    };
+
+    match stats.await {
+        Ok(stats) => Some(stats),
+        Err(error) => {
+            eprintln!("Database error (get_download_stats): {:?}", error);
+            None
+        }
+    }
+}
+
+pub async fn add_audit_entry(
+    db: &mut PoolConnection<MySql>,
+    actor: &str,
+    ip: &str,
+    action: &str,
+    path: &str,
+    status: u16,
+) -> () {
+    if let Err(error) = sqlx::query(
+        "INSERT INTO audit_log (actor, ip, action, path, status) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(actor)
+    .bind(ip)
+    .bind(action)
+    .bind(path)
+    .bind(status)
+    .execute(&mut **db)
+    .await
+    {
+        eprintln!("Database error (add_audit_entry): {:?}", error);
+    }
+}
+
+/// Audit log entries matching `filter`, newest first. `limit` entries are skipped
+/// for every page before `filter.page`.
+pub async fn get_audit_log(
+    mut db: Connection<FileDb>,
+    filter: &AuditFilter,
+    limit: u32,
+) -> Option<Vec<AuditEntry>> {
+    let like = |value: &str| {
+        format!(
+            "%{}%",
+            value
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        )
+    };
+    let given = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+
+    let query_result = sqlx::query("SELECT CAST(UNIX_TIMESTAMP(time) AS SIGNED) AS time, actor, ip, action, path, status FROM audit_log WHERE (? IS NULL OR actor = ?) AND (? IS NULL OR ip = ?) AND (? IS NULL OR action = ?) AND (? IS NULL OR path LIKE ?) ORDER BY id DESC LIMIT ? OFFSET ?")
+        .bind(given(&filter.actor))
+        .bind(given(&filter.actor))
+        .bind(given(&filter.ip))
+        .bind(given(&filter.ip))
+        .bind(given(&filter.action))
+        .bind(given(&filter.action))
+        .bind(given(&filter.path))
+        .bind(given(&filter.path).map(|path| like(&path)))
+        .bind(limit)
+        .bind(filter.page.unwrap_or(0) as u64 * limit as u64)
+        .fetch_all(&mut **db)
+        .await;
+
+    match query_result {
+        Ok(rows) => Some(
+            rows.iter()
+                .map(|row| AuditEntry {
+                    time: row.try_get::<i64, _>("time").unwrap_or(0),
+                    actor: row.try_get::<String, _>("actor").unwrap_or_default(),
+                    ip: row.try_get::<String, _>("ip").unwrap_or_default(),
+                    action: row.try_get::<String, _>("action").unwrap_or_default(),
+                    path: row.try_get::<String, _>("path").unwrap_or_default(),
+                    status: row.try_get::<u16, _>("status").unwrap_or(0),
+                })
+                .collect(),
+        ),
+        Err(error) => {
+            eprintln!("Database error (get_audit_log): {:?}", error);
+            None
+        }
+    }
+}
+
 pub async fn get_file_by_id(mut db: Connection<FileDb>, path: &str) -> Option<String> {
     let query_result = sqlx::query("SELECT path FROM files WHERE path = ? OR id = ?")
         .bind(path)
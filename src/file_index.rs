@@ -0,0 +1,552 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::{self, UnboundedSender},
+    time::sleep,
+};
+
+use crate::{
+    config::CONFIG,
+    metrics::METRICS,
+    storage::{storage_key, STORAGE},
+    trash::is_trash_key,
+    FileEntry, FileSizes,
+};
+
+/// Set once the first full scan has finished.
+static SCANNED: AtomicBool = AtomicBool::new(false);
+
+/// Marker files that hide or restrict the folder they're in.
+const MARKERS: [&str; 2] = ["HIDDEN", "RESTRICTED"];
+
+/// Whether the marker files asked about so far exist. Every folder above a listed
+/// path is checked, so the answers are kept and updated as the index sees markers
+/// come and go, instead of asking storage each time.
+static MARKER_CACHE: Lazy<Mutex<HashMap<PathBuf, bool>>> = Lazy::new(Default::default);
+
+fn is_marker(key: &Path) -> bool {
+    key.file_name()
+        .is_some_and(|name| MARKERS.iter().any(|marker| name == *marker))
+}
+
+fn set_marker(key: &Path, exists: bool) {
+    if is_marker(key) {
+        MARKER_CACHE
+            .lock()
+            .unwrap()
+            .insert(key.to_path_buf(), exists);
+    }
+}
+
+/// Whether a marker file (a storage key like `folder/HIDDEN`) exists.
+pub async fn has_marker(key: &Path) -> bool {
+    if let Some(exists) = MARKER_CACHE.lock().unwrap().get(key) {
+        return *exists;
+    }
+
+    let exists = STORAGE.exists(key).await;
+
+    // Trash isn't indexed, so nothing would tell the cache about changes there
+    if is_trash_key(key) {
+        return exists;
+    }
+
+    // The index may have seen the marker change while storage was asked
+    *MARKER_CACHE
+        .lock()
+        .unwrap()
+        .entry(key.to_path_buf())
+        .or_insert(exists)
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Size of the file, or the total size of everything below the folder.
+    size: u64,
+    modified: Option<u64>,
+    is_dir: bool,
+    children: HashSet<OsString>,
+}
+
+/// Tree of every file and folder in storage, keyed by storage key so any path
+/// can be looked up directly. Folder sizes are kept up to date as files change.
+#[derive(Debug, Default)]
+pub struct FileIndex {
+    nodes: HashMap<PathBuf, Node>,
+    /// Bumped on every change, so the cache is only rewritten when needed.
+    changes: u64,
+}
+
+/// On-disk copy of the index. Only files are stored, folders are rebuilt from their paths.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    files: Vec<SnapshotFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    path: String,
+    size: u64,
+    modified: Option<u64>,
+}
+
+/// Result of reading part of the tree from storage, applied to the index afterwards
+/// so the lock isn't held during I/O.
+enum Update {
+    Removed,
+    File(u64, Option<u64>),
+    Dir(Vec<(PathBuf, u64, Option<u64>)>),
+}
+
+impl FileIndex {
+    /// Loads a snapshot written by [`save`].
+    pub fn load(path: &Path) -> io::Result<FileIndex> {
+        let snapshot: Snapshot = serde_json::from_slice(&fs::read(path)?)?;
+        let mut index = FileIndex::default();
+
+        for file in snapshot.files {
+            index.insert_file(Path::new(&file.path), file.size, file.modified);
+        }
+
+        Ok(index)
+    }
+
+    /// Loads the index saved at `index_cache`, or starts empty.
+    pub fn load_cache() -> FileIndex {
+        if CONFIG.index_cache.is_empty() {
+            return FileIndex::default();
+        }
+
+        match FileIndex::load(Path::new(&CONFIG.index_cache)) {
+            Ok(index) => index,
+            Err(e) if e.kind() == ErrorKind::NotFound => FileIndex::default(),
+            Err(e) => {
+                eprintln!("Failed to load {}: {:?}", CONFIG.index_cache, e);
+                FileIndex::default()
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            files: self
+                .nodes
+                .iter()
+                .filter(|(_, node)| !node.is_dir)
+                .filter_map(|(key, node)| {
+                    Some(SnapshotFile {
+                        path: key.to_str()?.to_string(),
+                        size: node.size,
+                        modified: node.modified,
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the size of a file or folder (a `files/...` path).
+    pub fn size(&self, path: &Path) -> Option<u64> {
+        self.nodes.get(&storage_key(path)).map(|node| node.size)
+    }
+
+    /// Lists every file and folder as `files/...` paths, with folders ending in `/`.
+    pub fn entries(&self) -> impl Iterator<Item = FileEntry> + '_ {
+        self.nodes
+            .iter()
+            .filter(|(key, _)| !key.as_os_str().is_empty())
+            .map(|(key, node)| {
+                let file = Path::new("files").join(key).display().to_string();
+
+                FileEntry {
+                    size: node.size,
+                    file: if node.is_dir { file + "/" } else { file },
+                }
+            })
+    }
+
+    /// Lists every file by storage key, with its size and modification time.
+    pub fn files(&self) -> impl Iterator<Item = (&Path, u64, Option<u64>)> + '_ {
+        self.nodes
+            .iter()
+            .filter(|(_, node)| !node.is_dir)
+            .map(|(key, node)| (key.as_path(), node.size, node.modified))
+    }
+
+    /// Counts changes made to the index, so readers can tell when it's worth looking again.
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// Adds or updates a file, adjusting the sizes of the folders above it.
+    pub fn insert_file(&mut self, key: &Path, size: u64, modified: Option<u64>) {
+        if is_trash_key(key) {
+            return;
+        }
+
+        let old = match self.nodes.get(key) {
+            Some(node) if !node.is_dir => node.size,
+            Some(_) => {
+                self.remove(key);
+                0
+            }
+            None => 0,
+        };
+
+        self.link(key);
+
+        let node = self.nodes.entry(key.to_path_buf()).or_default();
+        node.size = size;
+        node.modified = modified;
+        node.is_dir = false;
+
+        set_marker(key, true);
+        self.propagate(key, old, size);
+        self.changes += 1;
+    }
+
+    pub fn insert_dir(&mut self, key: &Path) {
+        if is_trash_key(key) {
+            return;
+        }
+
+        match self.nodes.get(key) {
+            Some(node) if node.is_dir => return,
+            Some(_) => self.remove(key),
+            None => {}
+        }
+
+        self.link(key);
+
+        self.nodes.insert(
+            key.to_path_buf(),
+            Node {
+                is_dir: true,
+                ..Default::default()
+            },
+        );
+        self.changes += 1;
+    }
+
+    /// Removes a file or a folder with everything inside it.
+    pub fn remove(&mut self, key: &Path) {
+        let Some(size) = self.nodes.get(key).map(|node| node.size) else {
+            return;
+        };
+
+        let mut stack = vec![key.to_path_buf()];
+        while let Some(current) = stack.pop() {
+            if let Some(node) = self.nodes.remove(&current) {
+                stack.extend(node.children.iter().map(|child| current.join(child)));
+
+                if !node.is_dir {
+                    set_marker(&current, false);
+                }
+            }
+        }
+
+        if let (Some(parent), Some(name)) = (key.parent(), key.file_name()) {
+            if let Some(parent) = self.nodes.get_mut(parent) {
+                parent.children.remove(name);
+            }
+        }
+
+        self.propagate(key, size, 0);
+        self.changes += 1;
+    }
+
+    /// Makes sure every folder above `key` exists and lists its child.
+    fn link(&mut self, key: &Path) {
+        let mut current = key;
+
+        while let (Some(parent), Some(name)) = (current.parent(), current.file_name()) {
+            if self.nodes.get(parent).is_some_and(|node| !node.is_dir) {
+                self.remove(parent);
+            }
+
+            let node = self
+                .nodes
+                .entry(parent.to_path_buf())
+                .or_insert_with(|| Node {
+                    is_dir: true,
+                    ..Default::default()
+                });
+
+            if !node.children.insert(name.to_os_string()) {
+                break;
+            }
+
+            current = parent;
+        }
+    }
+
+    /// Replaces `old` with `new` in the sizes of every folder above `key`.
+    fn propagate(&mut self, key: &Path, old: u64, new: u64) {
+        for ancestor in key.ancestors().skip(1) {
+            if let Some(node) = self.nodes.get_mut(ancestor) {
+                node.size = node.size.saturating_sub(old) + new;
+            }
+        }
+    }
+
+    /// Lists the keys of every file below a folder.
+    fn files_below(&self, key: &Path) -> HashSet<PathBuf> {
+        let mut files = HashSet::new();
+        let mut stack = vec![key.to_path_buf()];
+
+        while let Some(current) = stack.pop() {
+            match self.nodes.get(&current) {
+                Some(node) if node.is_dir => {
+                    stack.extend(node.children.iter().map(|child| current.join(child)))
+                }
+                Some(_) => {
+                    files.insert(current);
+                }
+                None => {}
+            }
+        }
+
+        files
+    }
+
+    /// Removes empty folders below `key` and returns whether `key` itself is empty.
+    fn prune(&mut self, key: &Path) -> bool {
+        let children: Vec<PathBuf> = match self.nodes.get(key) {
+            Some(node) if node.is_dir => node.children.iter().map(|c| key.join(c)).collect(),
+            _ => return false,
+        };
+
+        for child in children {
+            if self.prune(&child) {
+                self.remove(&child);
+            }
+        }
+
+        self.nodes
+            .get(key)
+            .is_some_and(|node| node.children.is_empty())
+    }
+
+    fn apply(&mut self, key: &Path, update: Update) {
+        match update {
+            Update::Removed => self.remove(key),
+            Update::File(size, modified) => self.insert_file(key, size, modified),
+            Update::Dir(files) => {
+                self.insert_dir(key);
+
+                // Only touch what changed, so a rescan of a loaded snapshot
+                // doesn't rewrite the whole tree.
+                let mut stale = self.files_below(key);
+
+                for (file, size, modified) in files {
+                    stale.remove(&file);
+
+                    let unchanged = self.nodes.get(&file).is_some_and(|node| {
+                        !node.is_dir && node.size == size && node.modified == modified
+                    });
+
+                    if !unchanged {
+                        self.insert_file(&file, size, modified);
+                    }
+                }
+
+                for file in stale {
+                    self.remove(&file);
+                }
+
+                self.prune(key);
+            }
+        }
+    }
+}
+
+async fn read(key: &Path) -> Option<Update> {
+    if !key.as_os_str().is_empty() {
+        match STORAGE.stat(key).await {
+            Ok(entry) if !entry.is_dir => return Some(Update::File(entry.size, entry.modified)),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Some(Update::Removed),
+            Err(e) => {
+                eprintln!("Failed to index {}: {:?}", key.display(), e);
+                return None;
+            }
+        }
+    }
+
+    match STORAGE.walk(key).await {
+        Ok(entries) => Some(Update::Dir(
+            entries
+                .into_iter()
+                .map(|(file, entry)| (file, entry.size, entry.modified))
+                .collect(),
+        )),
+        Err(e) => {
+            eprintln!("Failed to walk {}: {:?}", key.display(), e);
+            None
+        }
+    }
+}
+
+/// Re-reads a file or folder (a `files/...` path) from storage and updates the index.
+pub async fn refresh(index: &FileSizes, path: &Path) {
+    refresh_key(index, &storage_key(path)).await;
+}
+
+async fn refresh_key(index: &FileSizes, key: &Path) {
+    if is_trash_key(key) {
+        return;
+    }
+
+    if let Some(update) = read(key).await {
+        index.write().await.apply(key, update);
+    }
+}
+
+/// Rebuilds the whole index from storage.
+pub async fn rescan(index: &FileSizes) {
+    let started = Instant::now();
+
+    let Some(update) = read(Path::new("")).await else {
+        return;
+    };
+    index.write().await.apply(Path::new(""), update);
+    SCANNED.store(true, Ordering::Relaxed);
+
+    let files = index.read().await.files().count();
+    METRICS.indexed(started.elapsed(), files);
+}
+
+/// Whether a full scan has finished, so the index matches what's in storage.
+pub fn is_scanned() -> bool {
+    SCANNED.load(Ordering::Relaxed)
+}
+
+/// Writes the index to `path`, going through a temporary file so a crash
+/// never leaves a half-written snapshot behind.
+pub async fn save(index: &FileSizes, path: &Path) -> io::Result<()> {
+    let snapshot = index.read().await.snapshot();
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(&snapshot)?)?;
+        fs::rename(&temp_path, &path)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Saves the index to `index_cache` every minute when it has changed.
+pub async fn persist(index: FileSizes) {
+    if CONFIG.index_cache.is_empty() {
+        return;
+    }
+
+    let mut saved = index.read().await.changes;
+
+    loop {
+        sleep(Duration::from_secs(60)).await;
+
+        let changes = index.read().await.changes;
+        if changes == saved {
+            continue;
+        }
+
+        match save(&index, Path::new(&CONFIG.index_cache)).await {
+            Ok(_) => saved = changes,
+            Err(e) => eprintln!("Failed to save {}: {:?}", CONFIG.index_cache, e),
+        }
+    }
+}
+
+fn start_watcher(
+    root: &Path,
+    tx: UnboundedSender<notify::Result<Event>>,
+) -> Option<RecommendedWatcher> {
+    let mut watcher = match notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("Failed to start the file watcher: {:?}", e);
+            return None;
+        }
+    };
+
+    if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+        eprintln!("Failed to watch {}: {:?}", root.display(), e);
+        return None;
+    }
+
+    Some(watcher)
+}
+
+/// Keeps the index in sync with storage. The index loaded at launch is reconciled
+/// with a full scan first, then local trees are watched with inotify and only the
+/// changed paths are re-read; a full rescan happens when events were lost.
+/// Other backends are rescanned every minute.
+pub async fn watch(index: FileSizes) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    let watcher = STORAGE
+        .local_path(Path::new(""))
+        .and_then(|root| root.canonicalize().ok())
+        .and_then(|root| start_watcher(&root, tx).map(|watcher| (root, watcher)));
+
+    rescan(&index).await;
+
+    let Some((root, _watcher)) = watcher else {
+        loop {
+            sleep(Duration::from_secs(60)).await;
+            rescan(&index).await;
+        }
+    };
+
+    while let Some(event) = rx.recv().await {
+        let mut needs_rescan = false;
+        let mut keys = HashSet::new();
+
+        let mut next = Some(event);
+        while let Some(event) = next {
+            match event {
+                Ok(event) if event.need_rescan() => needs_rescan = true,
+                Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+                Ok(event) => keys.extend(
+                    event
+                        .paths
+                        .iter()
+                        .filter_map(|path| path.strip_prefix(&root).ok())
+                        .map(Path::to_path_buf),
+                ),
+                Err(e) => {
+                    eprintln!("File watcher error: {:?}", e);
+                    needs_rescan = true;
+                }
+            }
+
+            next = rx.try_recv().ok();
+        }
+
+        if needs_rescan {
+            rescan(&index).await;
+            continue;
+        }
+
+        for key in keys {
+            refresh_key(&index, &key).await;
+        }
+    }
+}
@@ -4,7 +4,7 @@ use rocket::{
     Request, Response,
 };
 
-use crate::config::CONFIG;
+use crate::{config::CONFIG, peers};
 pub struct HeaderFile(pub String, pub String);
 
 impl<'r> Responder<'r, 'r> for HeaderFile {
@@ -48,16 +48,26 @@ impl<'r> FromRequest<'r> for XForwardedFor<'r> {
     }
 }
 
-pub struct Host<'r>(pub &'r str);
+/// The `Host` the request was sent to, and the scheme the client used to reach it.
+pub struct Host<'r>(pub &'r str, pub &'static str);
+
+impl Host<'_> {
+    /// Where this mirror can be reached, for links that leave the page.
+    pub fn base_url(&self) -> String {
+        format!("{}://{}", self.1, self.0)
+    }
+}
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Host<'r> {
     type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let scheme = peers::forwarded_scheme(request, &CONFIG.trusted_proxies);
+
         match request.headers().get_one("Host") {
-            Some(value) => Outcome::Success(Host(value)),
-            None => Outcome::Success(Host("127.0.0.1")),
+            Some(value) => Outcome::Success(Host(value, scheme)),
+            None => Outcome::Success(Host("127.0.0.1", scheme)),
         }
     }
 }
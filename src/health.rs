@@ -0,0 +1,159 @@
+use std::{path::Path, time::Duration};
+
+use rocket::{fairing::AdHoc, http::Status, serde::json::Json, State};
+use rocket_db_pools::sqlx::MySqlPool;
+use rocket_dyn_templates::Metadata;
+use serde::Serialize;
+use tokio::{io, time::timeout};
+use uuid::Uuid;
+
+use crate::{
+    db::{ping, Pools},
+    file_index,
+    i18n::TranslationStore,
+    storage::{self, STORAGE},
+    trash::TRASH_DIR,
+};
+
+/// How long a database gets to answer before it's reported as down.
+const DB_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    /// What went wrong, when something did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub status: &'static str,
+    pub checks: Vec<Check>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<(), String>) -> Self {
+        Check {
+            name,
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+/// Only a short reason goes in the response, since `/readyz` is public. The
+/// details are logged.
+async fn check_db(name: &str, pool: &MySqlPool) -> Result<(), String> {
+    match timeout(DB_TIMEOUT, ping(pool)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => {
+            eprintln!("Readiness check of {} failed: {:?}", name, e);
+            Err("unreachable".into())
+        }
+        Err(_) => {
+            eprintln!("Readiness check of {} timed out", name);
+            Err("timed out".into())
+        }
+    }
+}
+
+/// Lists the root of storage and writes and removes a file in a folder of the
+/// trash, which the file index ignores. Each probe gets its own file, so probes
+/// running at the same time don't remove each other's.
+pub(crate) async fn check_storage() -> Result<(), String> {
+    if let Err(e) = STORAGE.list(Path::new("")).await {
+        eprintln!("Readiness check of storage failed to list: {:?}", e);
+        return Err("not readable".into());
+    }
+
+    let dir = Path::new(TRASH_DIR).join(".readyz");
+    let probe = dir.join(Uuid::new_v4().to_string());
+
+    let written = async {
+        storage::create_dir_all(&dir).await?;
+        STORAGE.write(&probe, &mut io::empty()).await?;
+        STORAGE.delete(&probe, false).await
+    }
+    .await;
+
+    written.map_err(|e| {
+        eprintln!("Readiness check of storage failed to write: {:?}", e);
+        "not writable".into()
+    })
+}
+
+/// Answers as long as the server is running.
+#[get("/healthz")]
+fn healthz() -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        checks: Vec::new(),
+    })
+}
+
+/// Checks everything needed to serve requests properly, answering
+/// `503 Service Unavailable` when something isn't.
+#[get("/readyz")]
+async fn readyz(
+    pools: Pools<'_>,
+    translations: Option<&State<TranslationStore>>,
+    templates: Option<Metadata<'_>>,
+) -> (Status, Json<Health>) {
+    let mut checks = Vec::new();
+
+    if let Some(pool) = pools.db {
+        checks.push(Check::new("db", check_db("db", pool).await));
+    }
+    if let Some(pool) = pools.file_db {
+        checks.push(Check::new("file_db", check_db("file_db", pool).await));
+    }
+
+    checks.push(Check::new("storage", check_storage().await));
+
+    checks.push(Check::new(
+        "translations",
+        match translations {
+            Some(store) if !store.available_languages().is_empty() => Ok(()),
+            _ => Err("no languages loaded".into()),
+        },
+    ));
+
+    checks.push(Check::new(
+        "templates",
+        match templates {
+            Some(templates) if templates.contains_template("index") => Ok(()),
+            _ => Err("templates failed to load".into()),
+        },
+    ));
+
+    checks.push(Check::new(
+        "file_index",
+        if file_index::is_scanned() {
+            Ok(())
+        } else {
+            Err("the first scan hasn't finished".into())
+        },
+    ));
+
+    let ready = checks.iter().all(|check| check.ok);
+
+    (
+        if ready {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        },
+        Json(Health {
+            status: if ready { "ok" } else { "unavailable" },
+            checks,
+        }),
+    )
+}
+
+pub fn build() -> AdHoc {
+    AdHoc::on_ignite("Health checks", |rocket| async {
+        rocket.mount("/", routes![healthz, readyz])
+    })
+}
@@ -0,0 +1,733 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+};
+
+use flate2::read::GzDecoder;
+use once_cell::sync::Lazy;
+use rocket::time::OffsetDateTime;
+use sevenz_rust::SevenZMethod;
+use tar::EntryType;
+use tokio::{io::AsyncReadExt, runtime::Handle, sync::oneshot};
+use tokio_util::io::SyncIoBridge;
+use uuid::Uuid;
+use zip::ZipArchive;
+
+use crate::{
+    storage::{storage_key, StorageReader, STORAGE},
+    utils::UPLOAD_TEMP_DIR,
+    MirrorFile,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+    SevenZ,
+}
+
+impl ArchiveKind {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveKind::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveKind::Tar)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveKind::Zip)
+        } else if name.ends_with(".7z") {
+            Some(ArchiveKind::SevenZ)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ArchiveNode {
+    pub name: String,
+    /// Full path inside the archive, using `/` as the separator.
+    pub path: String,
+    pub icon: String,
+    pub is_dir: bool,
+    pub depth: usize,
+    pub size: u64,
+    /// Not known for tar members or files in solid 7z blocks.
+    pub compressed_size: Option<u64>,
+    pub modified: Option<i64>,
+    pub encrypted: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<ArchiveNode>,
+}
+
+struct RawEntry {
+    path: String,
+    is_dir: bool,
+    size: u64,
+    compressed_size: Option<u64>,
+    modified: Option<i64>,
+    encrypted: bool,
+}
+
+/// Normalizes a member name to `/`-separated components, dropping anything
+/// that could point outside the archive.
+fn clean_path(name: &str) -> String {
+    name.split(['/', '\\'])
+        .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[derive(Default)]
+struct TreeBuilder {
+    entry: Option<RawEntry>,
+    is_dir: bool,
+    children: BTreeMap<String, TreeBuilder>,
+}
+
+impl TreeBuilder {
+    fn insert(&mut self, entry: RawEntry) {
+        let path = clean_path(&entry.path);
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+
+        let Some((last, parents)) = components.split_last() else {
+            return;
+        };
+
+        let mut node = self;
+        for component in parents {
+            node = node.children.entry(component.to_string()).or_default();
+            node.is_dir = true;
+        }
+
+        let node = node.children.entry(last.to_string()).or_default();
+        node.is_dir |= entry.is_dir;
+        node.entry = Some(entry);
+    }
+
+    fn build(self, prefix: &str, depth: usize) -> Vec<ArchiveNode> {
+        let mut nodes: Vec<ArchiveNode> = self
+            .children
+            .into_iter()
+            .map(|(name, child)| {
+                let path = format!("{}{}", prefix, name);
+                let is_dir = child.is_dir;
+                let entry = child.entry.as_ref();
+
+                let mut node = ArchiveNode {
+                    icon: if is_dir {
+                        "folder".into()
+                    } else {
+                        MirrorFile::get_icon(&name)
+                    },
+                    name,
+                    path: path.clone(),
+                    is_dir,
+                    depth,
+                    size: entry.map(|e| e.size).unwrap_or(0),
+                    compressed_size: entry.and_then(|e| e.compressed_size),
+                    modified: entry.and_then(|e| e.modified),
+                    encrypted: entry.map(|e| e.encrypted).unwrap_or(false),
+                    children: child.build(&format!("{}/", path), depth + 1),
+                };
+
+                if is_dir {
+                    node.size = node.children.iter().map(|c| c.size).sum();
+                    node.encrypted = node.encrypted || node.children.iter().any(|c| c.encrypted);
+                }
+
+                node
+            })
+            .collect();
+
+        nodes.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+        nodes
+    }
+}
+
+fn read_zip(source: &Source) -> io::Result<Vec<RawEntry>> {
+    let mut zip = ZipArchive::new(source.open()?)?;
+    let mut entries = Vec::with_capacity(zip.len());
+
+    for i in 0..zip.len() {
+        let file = zip.by_index_raw(i)?;
+
+        entries.push(RawEntry {
+            path: file.name().to_string(),
+            is_dir: file.is_dir(),
+            size: file.size(),
+            compressed_size: Some(file.compressed_size()),
+            modified: file
+                .last_modified()
+                .and_then(|m| OffsetDateTime::try_from(m).ok())
+                .map(|m| m.unix_timestamp()),
+            encrypted: file.encrypted(),
+        });
+    }
+
+    Ok(entries)
+}
+
+fn read_tar(reader: impl io::Read) -> io::Result<Vec<RawEntry>> {
+    let mut tar = tar::Archive::new(reader);
+    let mut entries = Vec::new();
+
+    for entry in tar.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+
+        let is_dir = match header.entry_type() {
+            EntryType::Directory => true,
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => false,
+            _ => continue,
+        };
+
+        entries.push(RawEntry {
+            path: entry.path()?.to_string_lossy().to_string(),
+            is_dir,
+            size: if is_dir { 0 } else { entry.size() },
+            compressed_size: None,
+            modified: header.mtime().ok().map(|m| m as i64),
+            encrypted: false,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn read_7z(source: &Source) -> io::Result<Vec<RawEntry>> {
+    let mut reader = source.open()?;
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.rewind()?;
+
+    let archive = sevenz_rust::Archive::read(&mut reader, len, &[]).map_err(|e| match e {
+        sevenz_rust::Error::PasswordRequired => {
+            io::Error::new(ErrorKind::PermissionDenied, "7z headers are encrypted")
+        }
+        sevenz_rust::Error::Io(e, _) => e,
+        e => io::Error::new(ErrorKind::InvalidData, e.to_string()),
+    })?;
+
+    let mut folder_files = vec![0usize; archive.folders.len()];
+    for folder in archive.stream_map.file_folder_index.iter().flatten() {
+        folder_files[*folder] += 1;
+    }
+
+    let mut entries = Vec::with_capacity(archive.files.len());
+
+    for (i, file) in archive.files.iter().enumerate() {
+        let folder = archive
+            .stream_map
+            .file_folder_index
+            .get(i)
+            .copied()
+            .flatten();
+
+        let encrypted = folder
+            .and_then(|f| archive.folders.get(f))
+            .map(|f| {
+                f.coders
+                    .iter()
+                    .any(|c| c.decompression_method_id() == SevenZMethod::ID_AES256SHA256)
+            })
+            .unwrap_or(false);
+
+        // Packed sizes are per block, so they only belong to a single file when the block isn't solid
+        let compressed_size = folder.filter(|f| folder_files[*f] == 1).and_then(|f| {
+            let first = *archive.stream_map.folder_first_pack_stream_index.get(f)?;
+            let count = archive.folders[f].packed_streams.len();
+            Some(archive.pack_sizes.get(first..first + count)?.iter().sum())
+        });
+
+        entries.push(RawEntry {
+            path: file.name().to_string(),
+            is_dir: file.is_directory(),
+            size: file.size(),
+            compressed_size: if file.has_stream() {
+                compressed_size
+            } else {
+                Some(0)
+            },
+            modified: file
+                .has_last_modified_date
+                .then(|| file.last_modified_date().to_unix_time()),
+            encrypted,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn read_entries(kind: ArchiveKind, source: &Source) -> io::Result<Vec<RawEntry>> {
+    match kind {
+        ArchiveKind::Zip => read_zip(source),
+        ArchiveKind::Tar => read_tar(source.open()?),
+        ArchiveKind::TarGz => read_tar(GzDecoder::new(source.open()?)),
+        ArchiveKind::SevenZ => read_7z(source),
+    }
+}
+
+fn build_tree(entries: Vec<RawEntry>) -> Vec<ArchiveNode> {
+    let mut builder = TreeBuilder::default();
+
+    for entry in entries {
+        builder.insert(entry);
+    }
+
+    builder.build("", 0)
+}
+
+fn read_tree(kind: ArchiveKind, source: &Source) -> io::Result<Vec<ArchiveNode>> {
+    Ok(build_tree(read_entries(kind, source)?))
+}
+
+/// Smallest and largest amount fetched at once by [`RangeReader`].
+const MIN_READ_AHEAD: u64 = 64 * 1024;
+const MAX_READ_AHEAD: u64 = 8 * 1024 * 1024;
+
+trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Blocking reader over a file in storage that only fetches the parts being read,
+/// so zip and 7z can seek around an archive in a bucket without copying all of it.
+/// Reads ahead further the longer they go on in a row. Only for blocking tasks.
+pub(crate) struct RangeReader {
+    key: PathBuf,
+    len: u64,
+    pos: u64,
+    handle: Handle,
+    /// Bytes fetched last, starting at `buffer_start`.
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    read_ahead: u64,
+}
+
+impl RangeReader {
+    pub(crate) fn new(key: PathBuf, len: u64, handle: Handle) -> Self {
+        RangeReader {
+            key,
+            len,
+            pos: 0,
+            handle,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            read_ahead: MIN_READ_AHEAD,
+        }
+    }
+
+    fn fetch(&mut self) -> io::Result<()> {
+        let sequential = self.pos == self.buffer_start + self.buffer.len() as u64;
+        self.read_ahead = if sequential {
+            (self.read_ahead * 2).min(MAX_READ_AHEAD)
+        } else {
+            MIN_READ_AHEAD
+        };
+
+        let end = (self.pos + self.read_ahead).min(self.len) - 1;
+        let mut buffer = Vec::with_capacity((end + 1 - self.pos) as usize);
+
+        self.handle.block_on(async {
+            let mut reader = STORAGE.open(&self.key, Some((self.pos, Some(end)))).await?;
+            reader.read_to_end(&mut buffer).await
+        })?;
+
+        if buffer.is_empty() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        self.buffer = buffer;
+        self.buffer_start = self.pos;
+        Ok(())
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+
+        let buffered = self.pos >= self.buffer_start
+            && self.pos < self.buffer_start + self.buffer.len() as u64;
+
+        if !buffered {
+            self.fetch()?;
+        }
+
+        let offset = (self.pos - self.buffer_start) as usize;
+        let read = buf.len().min(self.buffer.len() - offset);
+        buf[..read].copy_from_slice(&self.buffer[offset..offset + read]);
+        self.pos += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "seek before the start"))?;
+
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+/// Where an archive is read from: straight from disk when storage is local,
+/// otherwise with ranged reads through [`RangeReader`].
+enum Source {
+    Local(PathBuf),
+    Stored(PathBuf, u64, Handle),
+}
+
+impl Source {
+    /// Picks the source for an archive (a storage key) of `len` bytes.
+    fn new(key: &Path, len: u64) -> Self {
+        match STORAGE.local_path(key) {
+            Some(local) => Source::Local(local),
+            None => Source::Stored(key.to_path_buf(), len, Handle::current()),
+        }
+    }
+
+    fn open(&self) -> io::Result<Box<dyn ReadSeek>> {
+        Ok(match self {
+            Source::Local(path) => Box::new(BufReader::new(File::open(path)?)),
+            Source::Stored(key, len, handle) => {
+                Box::new(RangeReader::new(key.clone(), *len, handle.clone()))
+            }
+        })
+    }
+}
+
+/// Returns a local path for an archive (a `files/...` path). Archives that aren't
+/// on local disk are copied to a temporary file first, for RAR listings and extract
+/// jobs that read all of it anyway; the flag tells the caller to remove it when done.
+pub async fn local_copy(path: &Path) -> io::Result<(PathBuf, bool)> {
+    let key = storage_key(path);
+
+    if let Some(local) = STORAGE.local_path(&key) {
+        return Ok((local, false));
+    }
+
+    tokio::fs::create_dir_all(UPLOAD_TEMP_DIR).await?;
+    let temp = PathBuf::from(UPLOAD_TEMP_DIR).join(Uuid::new_v4().to_string());
+
+    let result = async {
+        let mut reader = STORAGE.open(&key, None).await?;
+        let mut file = tokio::fs::File::create(&temp).await?;
+        tokio::io::copy(&mut reader, &mut file).await?;
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => Ok((temp, true)),
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&temp).await;
+            Err(e)
+        }
+    }
+}
+
+fn is_rar(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("rar"))
+}
+
+/// Whether [`inspect`] can list an archive. RAR archives can be listed but
+/// not opened or extracted.
+pub fn can_inspect(path: &Path) -> bool {
+    ArchiveKind::from_path(path).is_some() || is_rar(path)
+}
+
+/// Turns the output of `7z l -slt`, used for RAR archives, into a tree.
+pub fn read_7z_listing(output: &str) -> Vec<ArchiveNode> {
+    build_tree(read_7z_entries(output))
+}
+
+fn read_7z_entries(output: &str) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+
+    // Members are listed after the dashes, one block of `Key = Value` lines each
+    let Some((_, members)) = output.split_once("\n----------") else {
+        return entries;
+    };
+
+    for block in members.split("\n\n") {
+        let fields: HashMap<&str, &str> = block
+            .lines()
+            .filter_map(|line| line.split_once(" = "))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+
+        let Some(path) = fields.get("Path") else {
+            continue;
+        };
+
+        entries.push(RawEntry {
+            path: path.to_string(),
+            is_dir: fields.get("Folder") == Some(&"+"),
+            size: fields.get("Size").and_then(|s| s.parse().ok()).unwrap_or(0),
+            compressed_size: fields.get("Packed Size").and_then(|s| s.parse().ok()),
+            modified: fields.get("Modified").and_then(|m| parse_7z_time(m)),
+            encrypted: fields.get("Encrypted") == Some(&"+"),
+        });
+    }
+
+    entries
+}
+
+/// Parses a `2024-01-31 12:00:00` time from a 7z listing, ignoring any fraction.
+fn parse_7z_time(time: &str) -> Option<i64> {
+    let (date, time) = time.split_once(' ')?;
+    let mut date = date.split('-').map(|part| part.parse::<i32>().ok());
+    let mut time = time.split(['.', ':']).map(|part| part.parse::<u8>().ok());
+
+    let date = rocket::time::Date::from_calendar_date(
+        date.next()??,
+        rocket::time::Month::try_from(date.next()?? as u8).ok()?,
+        date.next()?? as u8,
+    )
+    .ok()?;
+    let time = rocket::time::Time::from_hms(time.next()??, time.next()??, time.next()??).ok()?;
+
+    Some(date.with_time(time).assume_utc().unix_timestamp())
+}
+
+/// Lists a RAR archive with the `7z` command, like the mirror always has.
+fn read_rar_tree(path: &Path) -> io::Result<Vec<ArchiveNode>> {
+    let output = Command::new("7z")
+        .args(["l", "-slt", "--"])
+        .arg(path)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "7z couldn't list the archive",
+        ));
+    }
+
+    Ok(read_7z_listing(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// An archive's size and modification time, with its listing.
+type Listing = (u64, Option<u64>, Vec<ArchiveNode>);
+
+/// Listings by storage key, kept while the archive's size and modification time stay the same.
+static LISTINGS: Lazy<Mutex<HashMap<PathBuf, Listing>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Most listings kept in [`LISTINGS`].
+const MAX_LISTINGS: usize = 64;
+
+/// Lists the contents of an archive (a `files/...` path) as a tree.
+pub async fn inspect(path: &Path) -> io::Result<Vec<ArchiveNode>> {
+    let kind = ArchiveKind::from_path(path);
+    if kind.is_none() && !is_rar(path) {
+        return Err(io::Error::new(ErrorKind::Unsupported, "not an archive"));
+    }
+
+    let key = storage_key(path);
+    let md = STORAGE.stat(&key).await?;
+
+    if let Some((size, modified, nodes)) =
+        LISTINGS.lock().unwrap_or_else(|e| e.into_inner()).get(&key)
+    {
+        if *size == md.size && *modified == md.modified {
+            return Ok(nodes.clone());
+        }
+    }
+
+    let result = match kind {
+        Some(kind) => {
+            let source = Source::new(&key, md.size);
+            tokio::task::spawn_blocking(move || read_tree(kind, &source))
+                .await
+                .map_err(io::Error::other)?
+        }
+        // RAR goes through the 7z command, which needs a file on disk
+        None => {
+            let (local, temp) = local_copy(path).await?;
+
+            let result = {
+                let local = local.clone();
+                tokio::task::spawn_blocking(move || read_rar_tree(&local))
+                    .await
+                    .map_err(io::Error::other)?
+            };
+
+            if temp {
+                let _ = tokio::fs::remove_file(&local).await;
+            }
+
+            result
+        }
+    };
+
+    if let Ok(nodes) = &result {
+        let mut listings = LISTINGS.lock().unwrap_or_else(|e| e.into_inner());
+
+        if listings.len() >= MAX_LISTINGS && !listings.contains_key(&key) {
+            listings.clear();
+        }
+        listings.insert(key, (md.size, md.modified, nodes.clone()));
+    }
+
+    result
+}
+
+/// Looks up a node by its path inside the archive.
+pub fn find_node(nodes: Vec<ArchiveNode>, path: &str) -> Option<ArchiveNode> {
+    let path = clean_path(path);
+
+    flatten(nodes).into_iter().find(|node| node.path == path)
+}
+
+/// Splits a `files/...` path that points into an archive, such as
+/// `files/foo.zip/docs/README.md`, into the archive and the member path.
+pub async fn find_archive(path: &Path) -> Option<(PathBuf, String)> {
+    for archive in path.ancestors().skip(1) {
+        if ArchiveKind::from_path(archive).is_none() {
+            continue;
+        }
+
+        match STORAGE.stat(&storage_key(archive)).await {
+            Ok(md) if !md.is_dir => {
+                let inner = path.strip_prefix(archive).ok()?;
+                return Some((archive.to_path_buf(), clean_path(&inner.to_string_lossy())));
+            }
+            _ => continue,
+        }
+    }
+
+    None
+}
+
+fn write_entry(
+    kind: ArchiveKind,
+    source: &Source,
+    inner: &str,
+    mut writer: impl Write,
+    found: &mut Option<oneshot::Sender<io::Result<()>>>,
+) -> io::Result<()> {
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = ZipArchive::new(source.open()?)?;
+
+            let index = (0..zip.len())
+                .find(|i| zip.name_for_index(*i).map(clean_path).as_deref() == Some(inner))
+                .ok_or(ErrorKind::NotFound)?;
+
+            let raw = zip.by_index_raw(index)?;
+            if raw.is_dir() {
+                return Err(ErrorKind::NotFound.into());
+            }
+            if raw.encrypted() {
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+            drop(raw);
+
+            let mut entry = zip.by_index(index)?;
+
+            if let Some(found) = found.take() {
+                let _ = found.send(Ok(()));
+            }
+
+            io::copy(&mut entry, &mut writer)?;
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let reader: Box<dyn Read> = if kind == ArchiveKind::TarGz {
+                Box::new(GzDecoder::new(source.open()?))
+            } else {
+                Box::new(source.open()?)
+            };
+
+            let mut tar = tar::Archive::new(reader);
+            let mut entries = tar.entries()?;
+
+            let mut entry = loop {
+                let entry = entries.next().ok_or(ErrorKind::NotFound)??;
+
+                if matches!(
+                    entry.header().entry_type(),
+                    EntryType::Regular | EntryType::Continuous
+                ) && clean_path(&entry.path()?.to_string_lossy()) == inner
+                {
+                    break entry;
+                }
+            };
+
+            if let Some(found) = found.take() {
+                let _ = found.send(Ok(()));
+            }
+
+            io::copy(&mut entry, &mut writer)?;
+        }
+        ArchiveKind::SevenZ => return Err(ErrorKind::Unsupported.into()),
+    }
+
+    writer.flush()
+}
+
+/// Streams a single member out of a zip or tar archive. Errors finding the member
+/// are returned here; anything after that just cuts the response short.
+pub async fn open_entry(archive: &Path, inner: &str) -> io::Result<StorageReader> {
+    let kind = ArchiveKind::from_path(archive)
+        .ok_or_else(|| io::Error::new(ErrorKind::Unsupported, "not an archive"))?;
+
+    if kind == ArchiveKind::SevenZ {
+        return Err(ErrorKind::Unsupported.into());
+    }
+
+    let key = storage_key(archive);
+    let source = Source::new(&key, STORAGE.stat(&key).await?.size);
+    let inner = clean_path(inner);
+
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    let (found_tx, found_rx) = oneshot::channel();
+    let handle = Handle::current();
+
+    tokio::task::spawn_blocking(move || {
+        let writer = SyncIoBridge::new_with_handle(writer, handle);
+        let mut found = Some(found_tx);
+
+        let result = write_entry(kind, &source, &inner, writer, &mut found);
+
+        if let Err(e) = result {
+            match found.take() {
+                Some(found) => {
+                    let _ = found.send(Err(e));
+                }
+                None => eprintln!("Failed to stream {} from archive: {:?}", inner, e),
+            }
+        }
+    });
+
+    found_rx.await.map_err(io::Error::other)??;
+
+    Ok(Box::pin(reader))
+}
+
+/// Turns a tree into a depth-first list for templates, which show it as an indented table.
+pub fn flatten(nodes: Vec<ArchiveNode>) -> Vec<ArchiveNode> {
+    let mut list = Vec::new();
+
+    for mut node in nodes {
+        let children = std::mem::take(&mut node.children);
+        list.push(node);
+        list.extend(flatten(children));
+    }
+
+    list
+}
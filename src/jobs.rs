@@ -0,0 +1,369 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use flate2::read::GzDecoder;
+use rocket::time::OffsetDateTime;
+use serde::Serialize;
+use tar::EntryType;
+use tokio::{runtime::Handle, sync::RwLock};
+use uuid::Uuid;
+use zip::ZipArchive;
+
+use crate::{
+    archiver::{self, ArchiveEntry, ArchiveFormat},
+    file_index,
+    inspector::{self, ArchiveKind},
+    storage::{self, storage_key, STORAGE},
+    utils::UPLOAD_TEMP_DIR,
+    FileSizes,
+};
+
+/// Finished jobs are forgotten after this many seconds.
+const JOB_RETENTION: i64 = 3600;
+
+pub type Jobs = Arc<RwLock<HashMap<String, Job>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub kind: &'static str,
+    #[serde(skip)]
+    pub owner: String,
+    pub state: JobState,
+    /// Bytes written so far, out of `total`.
+    pub done: u64,
+    pub total: u64,
+    /// Folder or archive the job writes to, as a path in the listing.
+    pub target: String,
+    pub error: Option<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+impl Job {
+    pub fn new(kind: &'static str, owner: &str, target: &str, total: u64) -> Self {
+        Job {
+            id: Uuid::new_v4().to_string(),
+            kind,
+            owner: owner.to_string(),
+            state: JobState::Running,
+            done: 0,
+            total,
+            target: target.to_string(),
+            error: None,
+            started_at: OffsetDateTime::now_utc().unix_timestamp(),
+            finished_at: None,
+        }
+    }
+}
+
+/// Registers a job, dropping finished ones that have been kept long enough.
+pub async fn add(jobs: &Jobs, job: Job) {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    let mut jobs = jobs.write().await;
+    jobs.retain(|_, job| job.finished_at.is_none_or(|f| now - f < JOB_RETENTION));
+    jobs.insert(job.id.clone(), job);
+}
+
+async fn set_progress(jobs: &Jobs, id: &str, done: u64) {
+    if let Some(job) = jobs.write().await.get_mut(id) {
+        job.done = done;
+    }
+}
+
+async fn finish(jobs: &Jobs, id: &str, result: io::Result<()>) {
+    if let Some(job) = jobs.write().await.get_mut(id) {
+        job.finished_at = Some(OffsetDateTime::now_utc().unix_timestamp());
+
+        match result {
+            Ok(()) => {
+                job.state = JobState::Done;
+                job.done = job.total;
+            }
+            Err(e) => {
+                job.state = JobState::Failed;
+                job.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/// Turns a member name into a relative path, refusing names that are absolute
+/// or climb out of the target folder.
+fn safe_path(name: &str) -> io::Result<PathBuf> {
+    let unsafe_path = || {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Archive contains an unsafe path: {}", name),
+        )
+    };
+
+    if name.starts_with(['/', '\\']) {
+        return Err(unsafe_path());
+    }
+
+    let mut path = PathBuf::new();
+
+    for component in name.split(['/', '\\']) {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(unsafe_path()),
+            c => path.push(c),
+        }
+    }
+
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(unsafe_path());
+    }
+
+    Ok(path)
+}
+
+struct Extractor {
+    handle: Handle,
+    jobs: Jobs,
+    id: String,
+    target: PathBuf,
+    /// Bytes that may still be written before the quota runs out.
+    budget: Option<u64>,
+    written: u64,
+}
+
+impl Extractor {
+    fn add(&mut self, name: &str, is_dir: bool, reader: &mut dyn Read) -> io::Result<()> {
+        let relative = safe_path(name)?;
+
+        if relative.as_os_str().is_empty() {
+            return Ok(());
+        }
+
+        let key = self.target.join(&relative);
+
+        if is_dir {
+            return self.handle.block_on(storage::create_dir_all(&key));
+        }
+
+        if let Some(parent) = key.parent() {
+            self.handle.block_on(storage::create_dir_all(parent))?;
+        }
+
+        std::fs::create_dir_all(UPLOAD_TEMP_DIR)?;
+        let temp = Path::new(UPLOAD_TEMP_DIR).join(Uuid::new_v4().to_string());
+
+        let result = (|| {
+            let limit = self
+                .budget
+                .map(|b| b - self.written + 1)
+                .unwrap_or(u64::MAX);
+            let size = io::copy(&mut reader.take(limit), &mut File::create(&temp)?)?;
+
+            if self.budget.is_some_and(|b| self.written + size > b) {
+                return Err(io::Error::new(
+                    ErrorKind::StorageFull,
+                    "Extracting this archive would exceed your quota",
+                ));
+            }
+
+            self.handle.block_on(STORAGE.import(&key, &temp))
+        })();
+
+        let _ = std::fs::remove_file(&temp);
+
+        self.written += result?;
+        self.handle
+            .block_on(set_progress(&self.jobs, &self.id, self.written));
+
+        Ok(())
+    }
+}
+
+fn extract_zip(path: &Path, extractor: &mut Extractor) -> io::Result<()> {
+    let mut zip = ZipArchive::new(BufReader::new(File::open(path)?))?;
+
+    for i in 0..zip.len() {
+        if zip.by_index_raw(i)?.encrypted() {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "Encrypted archives can't be extracted",
+            ));
+        }
+
+        let mut file = zip.by_index(i)?;
+        let name = file.name().to_string();
+        let is_dir = file.is_dir();
+
+        extractor.add(&name, is_dir, &mut file)?;
+    }
+
+    Ok(())
+}
+
+fn extract_tar(reader: impl Read, extractor: &mut Extractor) -> io::Result<()> {
+    let mut tar = tar::Archive::new(reader);
+
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+
+        // Links are left out, so nothing extracted can point outside the target
+        let is_dir = match entry.header().entry_type() {
+            EntryType::Directory => true,
+            EntryType::Regular | EntryType::Continuous => false,
+            _ => continue,
+        };
+
+        let name = entry.path()?.to_string_lossy().to_string();
+
+        extractor.add(&name, is_dir, &mut entry)?;
+    }
+
+    Ok(())
+}
+
+fn extract_7z(path: &Path, extractor: &mut Extractor) -> io::Result<()> {
+    let mut reader = sevenz_rust::SevenZReader::open(path, sevenz_rust::Password::empty())
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+    let mut error = None;
+
+    let result = reader.for_each_entries(|entry, reader| {
+        match extractor.add(entry.name(), entry.is_directory(), reader) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                error = Some(e);
+                Ok(false)
+            }
+        }
+    });
+
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    result.map_err(|e| match e {
+        sevenz_rust::Error::PasswordRequired => io::Error::new(
+            ErrorKind::PermissionDenied,
+            "Encrypted archives can't be extracted",
+        ),
+        e => io::Error::new(ErrorKind::InvalidData, e.to_string()),
+    })
+}
+
+/// Unpacks `archive` into the `target` folder (both `files/...` paths). The folder
+/// is removed again if anything goes wrong, so a failed job leaves nothing behind.
+pub async fn extract(
+    jobs: Jobs,
+    sizes: FileSizes,
+    id: String,
+    archive: PathBuf,
+    target: PathBuf,
+    budget: Option<u64>,
+) {
+    let target_key = storage_key(&target);
+
+    let result = async {
+        let kind = ArchiveKind::from_path(&archive)
+            .ok_or_else(|| io::Error::new(ErrorKind::Unsupported, "Not an archive"))?;
+        let (local, temp) = inspector::local_copy(&archive).await?;
+
+        storage::create_dir_all(&target_key).await?;
+
+        let mut extractor = Extractor {
+            handle: Handle::current(),
+            jobs: Arc::clone(&jobs),
+            id: id.clone(),
+            target: target_key.clone(),
+            budget,
+            written: 0,
+        };
+
+        let local_path = local.clone();
+        let result = tokio::task::spawn_blocking(move || match kind {
+            ArchiveKind::Zip => extract_zip(&local_path, &mut extractor),
+            ArchiveKind::Tar => {
+                extract_tar(BufReader::new(File::open(&local_path)?), &mut extractor)
+            }
+            ArchiveKind::TarGz => extract_tar(
+                GzDecoder::new(BufReader::new(File::open(&local_path)?)),
+                &mut extractor,
+            ),
+            ArchiveKind::SevenZ => extract_7z(&local_path, &mut extractor),
+        })
+        .await
+        .map_err(io::Error::other)?;
+
+        if temp {
+            let _ = tokio::fs::remove_file(&local).await;
+        }
+
+        result
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = STORAGE.delete(&target_key, true).await;
+    }
+
+    file_index::refresh(&sizes, &target).await;
+    finish(&jobs, &id, result).await;
+}
+
+/// Packs `entries` into a new archive at `target` (a `files/...` path). The archive
+/// is built in a temporary file first, so a failed job never leaves a truncated one.
+pub async fn compress(
+    jobs: Jobs,
+    sizes: FileSizes,
+    id: String,
+    entries: Vec<ArchiveEntry>,
+    format: ArchiveFormat,
+    target: PathBuf,
+    budget: Option<u64>,
+) {
+    let target_key = storage_key(&target);
+    let temp = Path::new(UPLOAD_TEMP_DIR).join(Uuid::new_v4().to_string());
+
+    let result = async {
+        tokio::fs::create_dir_all(UPLOAD_TEMP_DIR).await?;
+
+        let handle = Handle::current();
+        let temp_path = temp.clone();
+        tokio::task::spawn_blocking(move || {
+            archiver::write_archive(entries, format, File::create(&temp_path)?, &handle)
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        let size = tokio::fs::metadata(&temp).await?.len();
+        if budget.is_some_and(|b| size > b) {
+            return Err(io::Error::new(
+                ErrorKind::StorageFull,
+                "The archive would exceed your quota",
+            ));
+        }
+
+        if let Some(parent) = target_key.parent() {
+            storage::create_dir_all(parent).await?;
+        }
+
+        STORAGE.import(&target_key, &temp).await.map(|_| ())
+    }
+    .await;
+
+    let _ = tokio::fs::remove_file(&temp).await;
+
+    file_index::refresh(&sizes, &target).await;
+    finish(&jobs, &id, result).await;
+}
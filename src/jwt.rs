@@ -1,5 +1,7 @@
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+#[cfg(not(test))]
+use base64::{engine::general_purpose::STANDARD, Engine};
 use jsonwebtoken::{encode, errors::Error, Algorithm, EncodingKey, Header};
 
 #[cfg(not(test))]
@@ -71,7 +73,10 @@ impl<'r> FromRequest<'r> for JWT {
         }
 
         async fn get_token<'r>(req: &'r Request<'_>) -> Option<(String, bool)> {
-            if let Some(token) = req.headers().get_one("authorization") {
+            if let Some(header) = req.headers().get_one("authorization") {
+                let token = basic_password(header).unwrap_or_else(|| header.to_string());
+                let token = token.as_str();
+
                 if validate_token(token).is_ok() {
                     return Some((token.to_string(), false));
                 } else {
@@ -195,3 +200,15 @@ pub fn decode_jwt(token: &str) -> Result<Claims, ErrorKind> {
         Err(err) => Err(err.kind().to_owned()),
     }
 }
+
+/// WebDAV clients can only send Basic credentials, so the password stands in for
+/// the token or remember-me code and the username is ignored.
+#[cfg(not(test))]
+fn basic_password(header: &str) -> Option<String> {
+    let credentials = STANDARD
+        .decode(header.strip_prefix("Basic ")?.trim())
+        .ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+
+    Some(credentials.split_once(':')?.1.to_string())
+}
@@ -5,55 +5,83 @@ use rocket::{
     Data, Request, State,
 };
 use rocket_db_pools::{Connection, Database};
-use rocket_multipart_form_data::{
-    MultipartFormData, MultipartFormDataField, MultipartFormDataOptions, Repetition,
-};
+use rocket_multipart_form_data::{MultipartFormData, MultipartFormDataError};
 use serde::Serialize;
 use std::{
     collections::HashMap,
     ffi::OsStr,
     fs,
-    io::{Read, Write},
     path::{Path, PathBuf},
-    process::Command,
     sync::Arc,
 };
-use tokio::{sync::RwLock, time::sleep};
+use tokio::{io::AsyncReadExt, sync::RwLock};
 use utils::{create_cookie, read_dirs, read_files};
-use walkdir::WalkDir;
+use uuid::Uuid;
 
 use rocket_dyn_templates::{context, Template};
 
 use crate::{
     account::MarmakUser,
-    api::{MusicFile, SearchFile, VideoFile},
+    api::{
+        private_quota_left, private_usage, upload_limits, upload_target, MusicFile, SearchFile,
+        VideoFile,
+    },
+    archiver::{ArchiveFormat, Viewer},
     config::CONFIG,
-    db::{add_download, get_file_by_id, Db, FileDb},
+    db::{add_download, get_file_by_id, reconcile_files, Db, FileDb},
+    file_index::FileIndex,
     guards::{FullUri, Host},
     i18n::{Language, TranslationStore},
+    inspector::ArchiveKind,
+    jobs::Jobs,
     jwt::JWT,
+    manifest::SharedManifest,
     mirrorfile::{MirrorFile, MirrorFileInternal},
+    peers::{PeerContext, Peers},
+    quota::Reservations,
     responders::{Cached, IndexResponse, IndexResult},
     settings::{FormSettings, Settings},
+    stats::Visitor,
+    storage::{storage_key, STORAGE},
+    upstream::Upstreams,
     utils::{
         add_token_cookie, format_size_filter, get_root_domain, map_io_error_to_status,
-        parse_7z_output, read_dirs_async,
+        read_dirs_async, upload_form_options,
     },
 };
 
 mod account;
 mod admin;
 mod api;
+mod archiver;
+mod audit;
+mod checksums;
 mod config;
+mod dav;
 mod db;
+mod file_index;
 mod guards;
+mod health;
 mod i18n;
+mod inspector;
+mod jobs;
 mod jwt;
+mod manifest;
+mod metrics;
 mod mirrorfile;
+mod peers;
+mod quota;
+mod ranged;
 mod responders;
 mod settings;
+mod stats;
+mod storage;
 #[cfg(test)]
 mod tests;
+mod torrent;
+mod trash;
+mod tus;
+mod upstream;
 mod utils;
 
 #[macro_use]
@@ -74,7 +102,7 @@ struct Sysinfo {
     disks: Vec<Disk>,
 }
 
-type FileSizes = Arc<RwLock<Vec<FileEntry>>>;
+type FileSizes = Arc<RwLock<FileIndex>>;
 
 #[derive(Debug, Serialize, Clone)]
 pub struct FileEntry {
@@ -163,11 +191,12 @@ async fn poster(
             .await;
         }
     } else {
-        if !path.exists() {
-            return Err(Status::NotFound);
-        }
+        let md = STORAGE
+            .stat(&storage_key(&path))
+            .await
+            .map_err(|_| Status::NotFound)?;
 
-        let icon = if path.is_dir() {
+        let icon = if md.is_dir {
             "folder".into()
         } else {
             MirrorFile::get_icon(&MirrorFile::get_name_from_path(&path))
@@ -226,7 +255,11 @@ async fn share(
             file = path.to_string_lossy().into_owned();
         }
 
-        if Path::new("files/").join(&file).is_file() {
+        if STORAGE
+            .stat(&storage_key(Path::new(&file)))
+            .await
+            .is_ok_and(|md| !md.is_dir)
+        {
             display_file(
                 Some(db2),
                 Path::new("/").join(&file).to_path_buf(),
@@ -277,8 +310,9 @@ async fn share(
 #[get("/share/<segments..>?download")]
 async fn download_share(
     db: Connection<FileDb>,
-    db2: Connection<FileDb>,
+    mut db2: Connection<FileDb>,
     segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    visitor: Visitor,
 ) -> IndexResult {
     let file_path = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
 
@@ -307,10 +341,14 @@ async fn download_share(
             .join(&full_path.trim_start_matches("/"))
             .to_path_buf();
 
-        if real_path.is_dir() {
+        let key = storage_key(&real_path);
+        let md = STORAGE.stat(&key).await.map_err(map_io_error_to_status)?;
+
+        if md.is_dir {
             return Err(Status::NotAcceptable);
         }
 
+        stats::record(&mut db2, &key, md.size, &visitor).await;
         file.add_download(db2).await;
         MirrorFileInternal::open_file(real_path, &MirrorFile::get_cache_control(false)).await
     } else {
@@ -318,6 +356,142 @@ async fn download_share(
     }
 }
 
+#[get("/share/<segments..>?<archive>")]
+async fn share_archive(
+    db: Connection<FileDb>,
+    mut db2: Connection<FileDb>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    archive: &str,
+    visitor: Visitor,
+) -> IndexResult {
+    let format = ArchiveFormat::from_name(archive).ok_or(Status::BadRequest)?;
+    let file_path = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
+
+    let mut iter = file_path.iter();
+    let file_name = iter
+        .next()
+        .ok_or(Status::NotFound)?
+        .to_str()
+        .ok_or(Status::BadRequest)?;
+    let id = file_name.split(".").next().ok_or(Status::BadRequest)?;
+
+    let file = get_file_by_id(db, id).await.ok_or(Status::NotFound)?;
+
+    let mut path = Path::new("files/").join(file.trim_start_matches("/"));
+    for segment in iter {
+        path.push(segment);
+    }
+
+    let key = storage_key(&path);
+    let response = send_archive(path, &Viewer::shared(&key), format).await?;
+    stats::record(&mut db2, &key, 0, &visitor).await;
+
+    Ok(response)
+}
+
+#[get("/<segments..>?<archive>")]
+async fn download_archive_db(
+    db: Connection<FileDb>,
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    archive: &str,
+    token: Result<JWT, Status>,
+    host: Host<'_>,
+    jar: &CookieJar<'_>,
+    visitor: Visitor,
+) -> IndexResult {
+    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
+    perform_archive_download(Some((db, visitor)), file, archive, token, host, jar).await
+}
+
+#[get("/<segments..>?<archive>")]
+async fn download_archive(
+    segments: Segments<'_, rocket::http::uri::fmt::Path>,
+    archive: &str,
+    token: Result<JWT, Status>,
+    host: Host<'_>,
+    jar: &CookieJar<'_>,
+) -> IndexResult {
+    let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
+    perform_archive_download(None, file, archive, token, host, jar).await
+}
+
+async fn perform_archive_download(
+    stats: Option<(Connection<FileDb>, Visitor)>,
+    file: PathBuf,
+    archive: &str,
+    token: Result<JWT, Status>,
+    host: Host<'_>,
+    jar: &CookieJar<'_>,
+) -> IndexResult {
+    let format = ArchiveFormat::from_name(archive).ok_or(Status::BadRequest)?;
+
+    let jwt = token.clone().unwrap_or_default();
+
+    if let Some(t) = &jwt.token {
+        add_token_cookie(t, host.0, jar);
+    }
+
+    let (path, _) = MirrorFile::get_real_path(&file, jwt.claims.sub).map_err(|e| {
+        if e == Status::Forbidden {
+            Status::Unauthorized
+        } else {
+            e
+        }
+    })?;
+
+    if MirrorFile::is_restricted(&path, token.is_ok()).await {
+        return Err(Status::Unauthorized);
+    }
+
+    let key = storage_key(&path);
+    let response = send_archive(path, &Viewer::new(token.as_ref().ok()), format).await?;
+
+    if let Some((mut db, visitor)) = stats {
+        stats::record(&mut db, &key, 0, &visitor).await;
+    }
+
+    Ok(response)
+}
+
+/// Streams a folder (a `files/...` path) as an archive, leaving out whatever
+/// a listing would hide from `viewer`.
+async fn send_archive(path: PathBuf, viewer: &Viewer<'_>, format: ArchiveFormat) -> IndexResult {
+    let key = storage_key(&path);
+
+    if !STORAGE
+        .stat(&key)
+        .await
+        .map_err(|_| Status::NotFound)?
+        .is_dir
+    {
+        return Err(Status::NotAcceptable);
+    }
+
+    if MirrorFile::is_hidden(&path, viewer.perms).await {
+        return Err(Status::NotFound);
+    }
+
+    let entries = archiver::collect(&[path], viewer)
+        .await
+        .map_err(map_io_error_to_status)?;
+
+    let limit = archiver::size_limit(viewer.perms);
+    if limit != 0 && entries.iter().map(|entry| entry.size).sum::<u64>() > limit {
+        return Err(Status::PayloadTooLarge);
+    }
+
+    let name = match key.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => "files".to_string(),
+    };
+
+    Ok(IndexResponse::Attachment(
+        archiver::stream(entries, format),
+        format.content_type(),
+        format!("{}.{}", name, format.extension()),
+    ))
+}
+
 #[get("/<segments..>?download")]
 async fn download_db(
     db: Connection<FileDb>,
@@ -325,9 +499,11 @@ async fn download_db(
     token: Result<JWT, Status>,
     host: Host<'_>,
     jar: &CookieJar<'_>,
+    peers: PeerContext<'_>,
+    visitor: Visitor,
 ) -> IndexResult {
     let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
-    perform_download(Some(db), file, token, host, jar).await
+    perform_download(Some((db, visitor)), file, token, host, jar, peers).await
 }
 
 #[get("/<segments..>?download")]
@@ -336,17 +512,19 @@ async fn download(
     token: Result<JWT, Status>,
     host: Host<'_>,
     jar: &CookieJar<'_>,
+    peers: PeerContext<'_>,
 ) -> IndexResult {
     let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
-    perform_download(None, file, token, host, jar).await
+    perform_download(None, file, token, host, jar, peers).await
 }
 
 async fn perform_download(
-    db: Option<Connection<FileDb>>,
+    mut db: Option<(Connection<FileDb>, Visitor)>,
     file: PathBuf,
     token: Result<JWT, Status>,
     host: Host<'_>,
     jar: &CookieJar<'_>,
+    peers: PeerContext<'_>,
 ) -> IndexResult {
     let username = if let Ok(token) = token.as_ref() {
         if let Some(t) = &token.token {
@@ -360,36 +538,90 @@ async fn perform_download(
 
     let (path, is_private) = MirrorFile::get_real_path(&file, username.to_string())?;
 
+    let key = storage_key(&path);
+
     if is_private {
+        if let (Some((db, visitor)), Ok(md)) = (&mut db, STORAGE.stat(&key).await) {
+            if !md.is_dir {
+                stats::record(db, &key, md.size, visitor).await;
+            }
+        }
+
         return MirrorFileInternal::open_file(path, "private").await;
     }
 
     let file = file.display().to_string();
 
-    if !path.exists() {
-        return Err(Status::NotFound);
-    }
+    let md = match STORAGE.stat(&key).await {
+        Ok(md) => md,
+        Err(_) => {
+            let (archive, inner) = inspector::find_archive(&path)
+                .await
+                .ok_or(Status::NotFound)?;
+
+            if MirrorFile::is_restricted(&archive, token.is_ok()).await {
+                return Err(Status::Unauthorized);
+            }
+
+            if let Some((db, visitor)) = &mut db {
+                let size = inspector::inspect(&archive)
+                    .await
+                    .map(|nodes| {
+                        inspector::flatten(nodes)
+                            .into_iter()
+                            .find(|node| node.path == inner)
+                            .map_or(0, |node| node.size)
+                    })
+                    .unwrap_or(0);
+
+                stats::record(db, &key, size, visitor).await;
+            }
 
-    if MirrorFile::is_restricted(&path, token.is_ok()) {
+            return send_archive_entry(&archive, &inner).await;
+        }
+    };
+
+    if MirrorFile::is_restricted(&path, token.is_ok()).await {
         return Err(Status::Unauthorized);
     }
 
-    let ext = if path.is_file() {
+    let ext = if !md.is_dir {
         path.extension().and_then(OsStr::to_str).unwrap_or("")
     } else {
         "folder"
     }
     .to_lowercase();
 
-    if !CONFIG.extensions.contains(&ext) {
-        return MirrorFileInternal::open_file(path, &MirrorFile::get_cache_control(is_private))
-            .await;
-    } else if &ext == "folder" {
+    let counted = CONFIG.extensions.contains(&ext);
+
+    if counted && &ext == "folder" {
         return Err(Status::Forbidden);
     }
 
-    if let Some(db) = db {
-        add_download(db, &file).await;
+    let peer_url = if md.is_dir {
+        None
+    } else {
+        peers.redirect(&key, md.size).await
+    };
+
+    if let Some((mut db, visitor)) = db {
+        if !md.is_dir {
+            let bytes = if peer_url.is_some() { 0 } else { md.size };
+            stats::record(&mut db, &key, bytes, &visitor).await;
+        }
+
+        if counted {
+            add_download(db, &file).await;
+        }
+    }
+
+    if let Some(url) = peer_url {
+        return Ok(IndexResponse::Redirect(Redirect::to(url)));
+    }
+
+    if !counted {
+        return MirrorFileInternal::open_file(path, &MirrorFile::get_cache_control(is_private))
+            .await;
     }
 
     MirrorFileInternal::open_file(path, "private").await
@@ -406,9 +638,14 @@ async fn static_files(file: PathBuf) -> IndexResult {
     MirrorFileInternal::open_file(path, &MirrorFile::get_static_cache_control()).await
 }
 
+#[get("/manifest.json.gz")]
+async fn manifest_file() -> IndexResult {
+    MirrorFileInternal::open_file(PathBuf::from(&CONFIG.manifest_file), "no-cache").await
+}
+
 #[get("/<segments..>", rank = 10)]
 async fn index_db(
-    db: Connection<FileDb>,
+    mut db: Connection<FileDb>,
     segments: Segments<'_, rocket::http::uri::fmt::Path>,
     jar: &CookieJar<'_>,
     translations: &rocket::State<TranslationStore>,
@@ -420,7 +657,7 @@ async fn index_db(
     settings: Settings<'_>,
 ) -> IndexResult {
     let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
-    if !Path::new("files").join(&file).exists()
+    if !STORAGE.exists(&file).await
         && (&file.display().to_string() == "robots.txt"
             || &file.display().to_string() == "favicon.ico")
     {
@@ -451,22 +688,34 @@ async fn index_db(
         return Err(Status::UnprocessableEntity);
     }
 
-    if !path.exists() {
-        return Err(Status::NotFound);
-    }
+    let md = match STORAGE.stat(&storage_key(&path)).await {
+        Ok(md) => md,
+        Err(_) if CONFIG.enable_checksums && path.ends_with("SHA256SUMS") => {
+            return send_sums(db, &path, token.as_ref().ok().map(|t| t.claims.perms)).await;
+        }
+        Err(_) => {
+            if let Some(response) =
+                torrent::send(Some(&mut db), &path, &host.base_url(), token.is_ok()).await
+            {
+                return response;
+            }
 
-    if MirrorFile::is_restricted(&path, token.is_ok()) {
+            return display_archive_entry(path, file, strings, lang.0, host, token, settings).await;
+        }
+    };
+
+    if MirrorFile::is_restricted(&path, token.is_ok()).await {
         return Err(Status::Unauthorized);
     }
 
-    if path.is_dir() && !uri.0.ends_with("/") {
+    if md.is_dir && !uri.0.ends_with("/") {
         return Ok(IndexResponse::Redirect(Redirect::moved(format!(
             "{}/",
             uri.0
         ))));
     }
 
-    if path.is_dir() {
+    if md.is_dir {
         display_folder(
             file, strings, lang.0, host, token, settings, sizes, false, None,
         )
@@ -501,7 +750,7 @@ async fn index(
     settings: Settings<'_>,
 ) -> IndexResult {
     let file = segments.to_path_buf(true).map_err(|_| Status::BadRequest)?;
-    if !Path::new("files").join(&file).exists()
+    if !STORAGE.exists(&file).await
         && (&file.display().to_string() == "robots.txt"
             || &file.display().to_string() == "favicon.ico")
     {
@@ -532,22 +781,31 @@ async fn index(
         return Err(Status::UnprocessableEntity);
     }
 
-    if !path.exists() {
-        return Err(Status::NotFound);
-    }
+    let md = match STORAGE.stat(&storage_key(&path)).await {
+        Ok(md) => md,
+        Err(_) => {
+            if let Some(response) =
+                torrent::send(None, &path, &host.base_url(), token.is_ok()).await
+            {
+                return response;
+            }
+
+            return display_archive_entry(path, file, strings, lang.0, host, token, settings).await;
+        }
+    };
 
-    if MirrorFile::is_restricted(&path, token.is_ok()) {
+    if MirrorFile::is_restricted(&path, token.is_ok()).await {
         return Err(Status::Unauthorized);
     }
 
-    if path.is_dir() && !uri.0.ends_with("/") {
+    if md.is_dir && !uri.0.ends_with("/") {
         return Ok(IndexResponse::Redirect(Redirect::moved(format!(
             "{}/",
             uri.0
         ))));
     }
 
-    if path.is_dir() {
+    if md.is_dir {
         display_folder(
             file, strings, lang.0, host, token, settings, sizes, false, None,
         )
@@ -560,8 +818,34 @@ async fn index(
     }
 }
 
+/// Serves a generated `SHA256SUMS` for folders that don't have one of their own.
+async fn send_sums(mut db: Connection<FileDb>, path: &Path, perms: Option<i32>) -> IndexResult {
+    let dir = path.parent().ok_or(Status::NotFound)?;
+
+    if MirrorFile::is_hidden(dir, perms).await {
+        return Err(Status::NotFound);
+    }
+
+    if MirrorFile::is_restricted(dir, perms.is_some()).await {
+        return Err(Status::Unauthorized);
+    }
+
+    let sums = checksums::sums(&mut db, dir)
+        .await
+        .map_err(map_io_error_to_status)?;
+
+    Ok(IndexResponse::DirectFile(
+        (ContentType::Plain, sums.into_bytes()),
+        if dir.starts_with("files/private") {
+            "private".into()
+        } else {
+            "no-cache".into()
+        },
+    ))
+}
+
 async fn display_file(
-    db: Option<Connection<FileDb>>,
+    mut db: Option<Connection<FileDb>>,
     file: PathBuf,
     strings: &HashMap<String, String>,
     lang: String,
@@ -585,7 +869,12 @@ async fn display_file(
         MirrorFile::get_real_path(&file, jwt.claims.sub.clone())?
     };
 
-    let ext = if path.is_file() {
+    let md = STORAGE
+        .stat(&storage_key(&path))
+        .await
+        .map_err(|_| Status::NotFound)?;
+
+    let ext = if !md.is_dir {
         path.extension().and_then(OsStr::to_str).unwrap_or("")
     } else {
         if is_private {
@@ -600,13 +889,18 @@ async fn display_file(
 
     let root_domain = get_root_domain(&host.0);
 
+    let checksums = match db.as_mut() {
+        Some(db) if CONFIG.extensions.contains(&ext) => checksums::lookup(db, &path).await,
+        _ => None,
+    };
+
     let mirror_file = if let Some(db) = db {
         MirrorFileInternal::load(db, &path)
             .await
             .ok_or(Status::NotFound)?
             .mirror_file
     } else {
-        MirrorFile::load(&path).ok_or(Status::NotFound)?
+        MirrorFile::load(&path).await.ok_or(Status::NotFound)?
     };
 
     let title_path = if let Some(ref p) = share_path {
@@ -617,7 +911,7 @@ async fn display_file(
 
     match ext.as_str() {
         "md" => {
-            let markdown_text = fs::read_to_string(&path).unwrap_or_else(|e| {
+            let markdown_text = storage::read_to_string(&path).await.unwrap_or_else(|e| {
                 format!(
                     "{} {:?}",
                     strings
@@ -648,17 +942,15 @@ async fn display_file(
                 },
             )))
         }
-        "7z" | "rar" | "zip" => {
+        "7z" | "zip" | "tar" | "tgz" | "gz" | "rar" if inspector::can_inspect(&path) => {
             if !settings.viewers {
                 return MirrorFileInternal::open_file(path, "private").await;
             }
 
-            let output = Command::new("7z")
-                .args(["l", &path.display().to_string()])
-                .output()
-                .map_err(map_io_error_to_status)?;
-
-            let files = parse_7z_output(&String::from_utf8(output.stdout).unwrap_or_default());
+            let files = match inspector::inspect(&path).await {
+                Ok(tree) => inspector::flatten(tree),
+                Err(_) => return MirrorFileInternal::open_file(path, "private").await,
+            };
 
             Ok(IndexResponse::Template(Template::render(
                 if settings.plain { "plain/zip" } else { "zip" },
@@ -671,6 +963,7 @@ async fn display_file(
                     config: (*CONFIG).clone(),
                     path: Path::new("/").join(&file).display().to_string(),
                     files,
+                    links: ArchiveKind::from_path(&path).is_some_and(|kind| kind != ArchiveKind::SevenZ),
                     is_logged_in: token.is_ok(),
                     admin: jwt.claims.perms == 0,
                     settings,
@@ -876,6 +1169,9 @@ async fn display_file(
         }
         _ => {
             if CONFIG.extensions.contains(&ext) {
+                let torrent_offered = !share && torrent::is_offered(&path, md.size);
+                let torrent_ready = torrent_offered && torrent::is_ready(&path).await;
+
                 Ok(IndexResponse::Template(Template::render(
                     if settings.plain {
                         "plain/details"
@@ -893,7 +1189,10 @@ async fn display_file(
                         is_logged_in: token.is_ok(),
                         admin: jwt.claims.perms == 0,
                         filename: MirrorFile::get_name_from_path(&path),
-                        filesize: fs::metadata(&path).unwrap().len(),
+                        filesize: md.size,
+                        checksums,
+                        torrent_offered,
+                        torrent_ready,
                         settings,
                         share: use_share_template,
                         version: env!("CARGO_PKG_VERSION").to_string(),
@@ -908,6 +1207,114 @@ async fn display_file(
     }
 }
 
+/// Shows a file from inside an archive, such as `/foo.zip/docs/README.md`.
+/// Markdown and audio get their viewers, everything else is sent as is.
+async fn display_archive_entry(
+    path: PathBuf,
+    file: PathBuf,
+    strings: &HashMap<String, String>,
+    lang: String,
+    host: Host<'_>,
+    token: Result<JWT, Status>,
+    settings: Settings<'_>,
+) -> IndexResult {
+    let (archive, inner) = inspector::find_archive(&path)
+        .await
+        .ok_or(Status::NotFound)?;
+
+    if MirrorFile::is_restricted(&archive, token.is_ok()).await {
+        return Err(Status::Unauthorized);
+    }
+
+    let jwt = token.clone().unwrap_or_default();
+    let path = Path::new("/").join(&file).display().to_string();
+    let ext = MirrorFile::get_extension_from_filename(&inner)
+        .unwrap_or("")
+        .to_lowercase();
+
+    match ext.as_str() {
+        "md" if settings.viewers => {
+            let mut markdown_text = String::new();
+            inspector::open_entry(&archive, &inner)
+                .await
+                .map_err(map_io_error_to_status)?
+                .read_to_string(&mut markdown_text)
+                .await
+                .map_err(map_io_error_to_status)?;
+
+            Ok(IndexResponse::Template(Template::render(
+                if settings.plain { "plain/md" } else { "md" },
+                context! {
+                    title: format!("{} {}", strings.get("reading_markdown").unwrap_or(&("reading_markdown".into())), path),
+                    lang,
+                    strings,
+                    root_domain: get_root_domain(host.0),
+                    host: host.0,
+                    config: (*CONFIG).clone(),
+                    path,
+                    is_logged_in: token.is_ok(),
+                    admin: jwt.claims.perms == 0,
+                    markdown: markdown::to_html(&markdown_text),
+                    settings,
+                    share: false,
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    downloads: None::<i32>,
+                    share_path: None::<String>,
+                },
+            )))
+        }
+        "mp3" | "m4a" | "m4b" | "flac" | "wav" if settings.audio_player => {
+            Ok(IndexResponse::Template(Template::render(
+                if settings.plain {
+                    "plain/audio"
+                } else {
+                    "audio"
+                },
+                context! {
+                    title: format!("{} {}", strings.get("listening").unwrap_or(&("listening".into())), path),
+                    lang,
+                    strings,
+                    root_domain: get_root_domain(host.0),
+                    host: host.0,
+                    config: (*CONFIG).clone(),
+                    poster: urlencoding::encode(&format!("/poster{}", path)).replace("%2F", "/"),
+                    path,
+                    audiotitle: MirrorFile::get_name_from_path(&file),
+                    is_logged_in: token.is_ok(),
+                    admin: jwt.claims.perms == 0,
+                    artist: "N/A",
+                    year: "N/A",
+                    album: "N/A",
+                    genre: "N/A",
+                    track: None::<u16>,
+                    settings,
+                    share: false,
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    downloads: None::<i32>,
+                    share_path: None::<String>,
+                },
+            )))
+        }
+        _ => send_archive_entry(&archive, &inner).await,
+    }
+}
+
+async fn send_archive_entry(archive: &Path, inner: &str) -> IndexResult {
+    let reader = inspector::open_entry(archive, inner)
+        .await
+        .map_err(map_io_error_to_status)?;
+
+    let content_type = MirrorFile::get_extension_from_filename(inner)
+        .and_then(ContentType::from_extension)
+        .unwrap_or(ContentType::Binary);
+
+    Ok(IndexResponse::StreamedFile(
+        reader,
+        content_type,
+        "private".into(),
+    ))
+}
+
 async fn display_folder(
     file: PathBuf,
     strings: &HashMap<String, String>,
@@ -945,7 +1352,9 @@ async fn display_folder(
                 } else {
                     None
                 },
-            ) {
+            )
+            .await
+            {
                 return Err(Status::NotFound);
             }
 
@@ -959,8 +1368,9 @@ async fn display_folder(
                 .display()
                 .to_string();
 
-            let mut files =
-                read_files(&path.display().to_string()).map_err(map_io_error_to_status)?;
+            let mut files = read_files(&path.display().to_string())
+                .await
+                .map_err(map_io_error_to_status)?;
             let mut dirs = read_dirs_async(&path.display().to_string(), sizes)
                 .await
                 .map_err(map_io_error_to_status)?;
@@ -983,16 +1393,18 @@ async fn display_folder(
                 .iter()
                 .any(|f| f.name == format!("README.{}.md", lang))
             {
-                let md = fs::read_to_string(
-                    Path::new(&("files".to_string() + &path_str))
+                let md = storage::read_to_string(
+                    &Path::new(&("files".to_string() + &path_str))
                         .join(format!("README.{}.md", lang)),
                 )
+                .await
                 .unwrap_or_default();
                 markdown = markdown::to_html(&md);
             } else if files.iter().any(|f| f.name == "README.md") {
-                let md = fs::read_to_string(
-                    Path::new(&("files".to_string() + &path_str)).join("README.md"),
+                let md = storage::read_to_string(
+                    &Path::new(&("files".to_string() + &path_str)).join("README.md"),
                 )
+                .await
                 .unwrap_or_default();
                 markdown = markdown::to_html(&md);
             }
@@ -1027,22 +1439,14 @@ async fn display_folder(
         "privatefolder" => {
             let mut markdown = String::new();
 
-            let mut files =
-                read_files(&path.display().to_string()).map_err(map_io_error_to_status)?;
+            let mut files = read_files(&path.display().to_string())
+                .await
+                .map_err(map_io_error_to_status)?;
             let mut dirs = read_dirs_async(&path.display().to_string(), sizes)
                 .await
                 .map_err(map_io_error_to_status)?;
 
-            let folder_usage = sizes
-                .read()
-                .await
-                .iter()
-                .find(|entry| {
-                    entry.file.strip_suffix("/").unwrap_or_default().to_string()
-                        == format!("files/private/{}", &jwt.claims.sub)
-                })
-                .map(|entry| entry.size)
-                .unwrap_or(0);
+            let folder_usage = private_usage(&jwt, sizes).await;
             let folder_quota = CONFIG
                 .private_folder_quotas
                 .get(&jwt.claims.perms.to_string())
@@ -1055,15 +1459,18 @@ async fn display_folder(
                 .iter()
                 .any(|f| f.name == format!("README.{}.md", lang))
             {
-                let md = fs::read_to_string(
-                    Path::new(&path.display().to_string()).join(format!("README.{}.md", lang)),
+                let md = storage::read_to_string(
+                    &Path::new(&path.display().to_string()).join(format!("README.{}.md", lang)),
                 )
+                .await
                 .unwrap_or_default();
                 markdown = markdown::to_html(&md);
             } else if files.iter().any(|f| f.name == "README.md") {
-                let md =
-                    fs::read_to_string(Path::new(&path.display().to_string()).join("README.md"))
-                        .unwrap_or_default();
+                let md = storage::read_to_string(
+                    &Path::new(&path.display().to_string()).join("README.md"),
+                )
+                .await
+                .unwrap_or_default();
                 markdown = markdown::to_html(&md);
             }
 
@@ -1346,13 +1753,13 @@ async fn iframe(
 
     let path = MirrorFile::get_real_path(&file, username.to_string())?.0;
 
-    if MirrorFile::is_restricted(&path, token.is_ok()) {
+    if MirrorFile::is_restricted(&path, token.is_ok()).await {
         return Err(Status::Unauthorized);
     }
 
     let path = path.display().to_string();
 
-    let mut dirs = read_dirs(&path).map_err(map_io_error_to_status)?;
+    let mut dirs = read_dirs(&path).await.map_err(map_io_error_to_status)?;
 
     dirs.retain(|x| !CONFIG.hidden_files.contains(&x.name));
 
@@ -1412,8 +1819,8 @@ async fn scripts(
 
 #[get("/sitemap.xml")]
 async fn sitemap(sizes: &State<FileSizes>, host: Host<'_>) -> Result<Cached<Template>, Status> {
-    let files = sizes.read().await;
-    let mut files = files.clone();
+    let mut files: Vec<FileEntry> = sizes.read().await.entries().collect();
+    files.sort_by(|a, b| a.file.cmp(&b.file));
 
     files.retain(|file| {
         !CONFIG
@@ -1483,6 +1890,7 @@ fn uploader(
 }
 
 #[post("/upload?<path>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
 async fn upload(
     content_type: &ContentType,
     data: Data<'_>,
@@ -1494,81 +1902,67 @@ async fn upload(
     path: Option<&str>,
     settings: Settings<'_>,
     sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
 ) -> IndexResult {
     let token = token?;
 
-    if let Some(t) = token.token {
-        add_token_cookie(&t, &host.0, jar);
+    if let Some(t) = &token.token {
+        add_token_cookie(t, &host.0, jar);
     }
 
-    let max_size = CONFIG
-        .max_upload_sizes
-        .get(&token.claims.perms.to_string())
-        .unwrap_or(&(104857600 as u64));
+    let (max_size, _) = upload_limits(&token);
 
-    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
-        MultipartFormDataField::file("files")
-            .repetition(Repetition::infinite())
-            .size_limit(*max_size),
-        MultipartFormDataField::text("path"),
-    ]);
+    let quota_left = private_quota_left(&token, sizes, reservations)
+        .await
+        .unwrap_or(u64::MAX);
+
+    if quota_left == 0 {
+        return Err(Status::InsufficientStorage);
+    }
+
+    let options = upload_form_options(max_size.min(quota_left));
 
     let form_data = match MultipartFormData::parse(content_type, data, options).await {
         Ok(data) => data,
+        Err(MultipartFormDataError::DataTooLargeError(_)) => {
+            return Err(if quota_left < max_size {
+                Status::InsufficientStorage
+            } else {
+                Status::PayloadTooLarge
+            });
+        }
         Err(err) => {
             eprintln!("Failed to parse multipart form data: {:?}", err);
             return Err(Status::BadRequest);
         }
     };
 
-    let mut user_path = form_data
+    let form_path = form_data
         .texts
         .get("path")
-        .and_then(|paths| paths.first().map(|p| p.text.trim_matches('/').to_string()))
-        .unwrap_or("uploads".to_string());
-
-    if user_path.is_empty() {
-        user_path = "uploads".to_string();
-    }
-
-    let is_private = user_path.starts_with("private");
-    if !is_private && token.claims.perms != 0 {
-        return Err(Status::Forbidden);
-    }
-
-    let base_path = if is_private {
-        format!(
-            "files/private/{}/{}",
-            &token.claims.sub,
-            user_path.trim_start_matches("private")
-        )
-    } else {
-        format!("files/{}", user_path)
-    };
+        .and_then(|paths| paths.first().map(|p| p.text.clone()));
 
-    let folder_quota = *(CONFIG
-        .private_folder_quotas
-        .get(&token.claims.perms.to_string())
-        .unwrap_or(&1_u64));
+    let (user_path, base_path) = upload_target(form_path, &token)?;
 
-    let folder_usage = sizes
-        .read()
+    storage::create_dir_all(&storage_key(Path::new(&base_path)))
         .await
-        .iter()
-        .find(|entry| {
-            entry.file.strip_suffix("/").unwrap_or_default().to_string()
-                == format!("files/private/{}", &token.claims.sub)
-        })
-        .map(|entry| entry.size)
-        .unwrap_or(0);
-
-    if folder_quota != 0 && folder_usage >= folder_quota {
-        return Err(Status::InsufficientStorage);
-    }
+        .map_err(map_io_error_to_status)?;
 
     let mut uploaded_files: Vec<MirrorFile> = Vec::new();
 
     if let Some(file_fields) = form_data.files.get("files") {
+        let upload_size: u64 = file_fields
+            .iter()
+            .filter_map(|file_field| std::fs::metadata(&file_field.path).ok())
+            .map(|md| md.len())
+            .sum();
+
+        let upload = format!("upload/{}", Uuid::new_v4());
+
+        if base_path.starts_with("files/private/") {
+            quota::reserve(reservations, sizes, &token, &upload, upload_size).await?;
+        }
+
         for file_field in file_fields {
             if let Some(file_name) = &file_field.file_name {
                 let normalized_path = file_name.replace('\\', "/");
@@ -1577,60 +1971,36 @@ async fn upload(
 
                 let upload_path = format!("{}/{}", base_path, file_name);
 
-                match std::fs::File::create(&upload_path) {
-                    Ok(mut file) => {
-                        if let Ok(mut temp_file) = std::fs::File::open(&file_field.path) {
-                            let size = temp_file.metadata().map_err(map_io_error_to_status)?.len();
-
-                            if folder_quota != 0 && folder_usage + size >= folder_quota {
-                                return Err(Status::InsufficientStorage);
-                            }
-
-                            let mut buffer = Vec::new();
-                            let _ = temp_file.read_to_end(&mut buffer);
-
-                            let _ = file.write_all(&buffer);
-
-                            if token.claims.perms == 0 {
-                                let mut mirror_file =
-                                    MirrorFile::load(&Path::new(&upload_path).to_path_buf())
-                                        .unwrap_or(MirrorFile::new(&file_name));
-                                mirror_file.ext = format!(
-                                    "/{}/{}",
-                                    user_path,
-                                    MirrorFile::get_name_from_path(
-                                        &Path::new(&normalized_path).to_path_buf()
-                                    )
-                                );
-
-                                uploaded_files.push(mirror_file);
-                            } else {
-                                let mut mirror_file =
-                                    MirrorFile::load(&Path::new(&upload_path).to_path_buf())
-                                        .unwrap_or(MirrorFile::new(&file_name));
-                                mirror_file.ext = format!(
-                                    "/{}/{}",
-                                    user_path.replacen(
-                                        format!("/{}", &token.claims.sub).as_str(),
-                                        "",
-                                        1
-                                    ),
-                                    MirrorFile::get_name_from_path(
-                                        &Path::new(&normalized_path).to_path_buf()
-                                    )
-                                );
-
-                                uploaded_files.push(mirror_file);
-                            }
-                        } else {
-                            eprintln!("Failed to open temp file for: {}", file_name);
-                            return Err(Status::InternalServerError);
-                        }
-                    }
-                    Err(err) => {
-                        eprintln!("Failed to create target file {}: {:?}", upload_path, err);
-                        continue;
-                    }
+                if let Err(err) = STORAGE
+                    .import(&storage_key(Path::new(&upload_path)), &file_field.path)
+                    .await
+                {
+                    eprintln!("Failed to create target file {}: {:?}", upload_path, err);
+                    continue;
+                }
+
+                if token.claims.perms == 0 {
+                    let mut mirror_file = MirrorFile::load(&Path::new(&upload_path).to_path_buf())
+                        .await
+                        .unwrap_or(MirrorFile::new(&file_name));
+                    mirror_file.ext = format!(
+                        "/{}/{}",
+                        user_path,
+                        MirrorFile::get_name_from_path(&Path::new(&normalized_path).to_path_buf())
+                    );
+
+                    uploaded_files.push(mirror_file);
+                } else {
+                    let mut mirror_file = MirrorFile::load(&Path::new(&upload_path).to_path_buf())
+                        .await
+                        .unwrap_or(MirrorFile::new(&file_name));
+                    mirror_file.ext = format!(
+                        "/{}/{}",
+                        user_path.replacen(format!("/{}", &token.claims.sub).as_str(), "", 1),
+                        MirrorFile::get_name_from_path(&Path::new(&normalized_path).to_path_buf())
+                    );
+
+                    uploaded_files.push(mirror_file);
                 }
             } else {
                 eprintln!("A file was uploaded without a name, skipping.");
@@ -1638,12 +2008,10 @@ async fn upload(
             }
         }
 
-        let strings = translations.get_translation(&lang.0);
+        file_index::refresh(sizes, Path::new(&base_path)).await;
+        quota::release(reservations, &token.claims.sub, &upload).await;
 
-        {
-            let mut state_lock = sizes.write().await;
-            *state_lock = refresh_file_sizes().await;
-        }
+        let strings = translations.get_translation(&lang.0);
 
         return Ok(IndexResponse::Template(Template::render(
             if settings.plain {
@@ -1721,11 +2089,11 @@ async fn search(
         let mut results: Vec<SearchFile> = sizes
             .read()
             .await
-            .iter()
+            .entries()
             .map(|x| SearchFile {
                 name: MirrorFile::get_name_from_path(&Path::new(&x.file).to_path_buf()),
                 full_path: MirrorFile::get_virtual_path(&x.file),
-                icon: if Path::new(&x.file).is_dir() {
+                icon: if x.file.ends_with('/') {
                     "folder".into()
                 } else {
                     MirrorFile::get_icon(&MirrorFile::get_name_from_path(
@@ -1738,16 +2106,18 @@ async fn search(
 
         results.retain(|x| !CONFIG.hidden_files.contains(&x.name));
         results.retain(|x| x.name.to_lowercase().contains(&q.to_lowercase()));
-        results.retain(|x| {
-            !MirrorFile::is_hidden_path_str(
-                &x.full_path,
-                if token.is_ok() {
-                    Some(jwt.claims.perms)
-                } else {
-                    None
-                },
-            )
-        });
+        let perms = if token.is_ok() {
+            Some(jwt.claims.perms)
+        } else {
+            None
+        };
+        let mut visible = Vec::with_capacity(results.len());
+        for result in results {
+            if !MirrorFile::is_hidden_path_str(&result.full_path, perms).await {
+                visible.push(result);
+            }
+        }
+        let mut results = visible;
         results.retain(|x| !x.full_path.starts_with("/private/"));
 
         if results.len() == 0 {
@@ -1956,53 +2326,6 @@ fn forbidden(req: &Request) -> Redirect {
     Redirect::to(format!("/account/login?next={}", req.uri()))
 }
 
-async fn calculate_sizes(state: FileSizes) {
-    loop {
-        {
-            let mut state_lock = state.write().await;
-            *state_lock = refresh_file_sizes().await;
-        }
-
-        sleep(tokio::time::Duration::from_secs(60)).await;
-    }
-}
-
-pub async fn refresh_file_sizes() -> Vec<FileEntry> {
-    let mut file_sizes = Vec::new();
-    let mut dir_sizes: HashMap<String, u64> = HashMap::new();
-
-    for entry in WalkDir::new("files").into_iter().filter_map(Result::ok) {
-        let path = entry.path().to_path_buf();
-        if let Ok(metadata) = fs::metadata(&path) {
-            let size = metadata.len();
-            let path_str = path.display().to_string();
-
-            if metadata.is_file() {
-                file_sizes.push(FileEntry {
-                    size,
-                    file: path_str,
-                });
-
-                let mut current = path.as_path();
-                while let Some(parent) = current.parent() {
-                    let parent_str = parent.display().to_string();
-                    *dir_sizes.entry(parent_str).or_insert(0) += size;
-                    current = parent;
-                }
-            }
-        }
-    }
-
-    let mut all_entries: Vec<FileEntry> = file_sizes;
-
-    all_entries.extend(dir_sizes.into_iter().map(|(dir, size)| FileEntry {
-        size,
-        file: format!("{}/", dir),
-    }));
-
-    all_entries
-}
-
 #[cfg(test)]
 fn mount_extra_routes(rocket: rocket::Rocket<rocket::Build>) -> rocket::Rocket<rocket::Build> {
     rocket.mount("/test", routes![strings,])
@@ -2033,10 +2356,26 @@ fn load_shared_icons() -> HashMap<String, String> {
 #[launch]
 #[tokio::main]
 async fn rocket() -> _ {
-    let size_state: FileSizes = Arc::new(RwLock::new(Vec::new()));
-
-    let background_size_state = Arc::clone(&size_state);
-    tokio::spawn(calculate_sizes(background_size_state));
+    let size_state: FileSizes = Arc::new(RwLock::new(FileIndex::load_cache()));
+    let reservations = Reservations::default();
+    let manifest = SharedManifest::default();
+    let upstreams: Upstreams = upstream::statuses();
+    let peers: Peers = peers::statuses();
+
+    tokio::spawn(file_index::watch(Arc::clone(&size_state)));
+    tokio::spawn(file_index::persist(Arc::clone(&size_state)));
+
+    tokio::spawn(tus::clean_uploads(Arc::clone(&reservations)));
+    if CONFIG.enable_torrents {
+        tokio::spawn(torrent::work(Arc::clone(&size_state)));
+    }
+    if !CONFIG.peers.is_empty() {
+        tokio::spawn(peers::keep_checked(Arc::clone(&peers)));
+    }
+    tokio::spawn(upstream::schedule(
+        Arc::clone(&upstreams),
+        Arc::clone(&size_state),
+    ));
 
     let mut rocket = rocket::build()
         .attach(Template::custom(|engine| {
@@ -2048,6 +2387,13 @@ async fn rocket() -> _ {
         }))
         .manage(TranslationStore::new())
         .manage(size_state)
+        .manage(Jobs::default())
+        .manage(reservations)
+        .manage(manifest)
+        .manage(upstreams)
+        .manage(peers)
+        .attach(health::build())
+        .attach(trash::build())
         .register("/", catchers![default, unprocessable_entry, forbidden])
         .mount(
             "/",
@@ -2073,10 +2419,32 @@ async fn rocket() -> _ {
             .mount("/", routes![fetch_settings, sync_settings,]);
     }
 
+    if CONFIG.enable_zip_downloads {
+        if CONFIG.enable_file_db {
+            rocket = rocket.mount("/", routes![download_archive_db, share_archive]);
+        } else {
+            rocket = rocket.mount("/", routes![download_archive]);
+        }
+    }
+
+    if CONFIG.enable_manifest {
+        rocket = rocket.attach(manifest::build());
+
+        if !CONFIG.manifest_file.is_empty() {
+            rocket = rocket.mount("/", routes![manifest_file]);
+        }
+    }
+
     if CONFIG.enable_file_db {
         rocket = rocket
             .attach(FileDb::init())
-            .mount("/", routes![share, download_share, download_db, index_db])
+            .attach(reconcile_files())
+            .attach(audit::build())
+            .mount("/", routes![share, download_share, download_db, index_db]);
+
+        if CONFIG.enable_checksums {
+            rocket = rocket.attach(checksums::build());
+        }
     } else {
         rocket = rocket.mount("/", routes![download, index])
     }
@@ -2085,6 +2453,14 @@ async fn rocket() -> _ {
         rocket = rocket.attach(api::build_api());
     }
 
+    if CONFIG.enable_dav {
+        rocket = rocket.attach(dav::build());
+    }
+
+    if CONFIG.enable_metrics {
+        rocket = rocket.attach(metrics::build());
+    }
+
     rocket = mount_extra_routes(rocket);
 
     rocket
@@ -0,0 +1,295 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use rocket::{fairing::AdHoc, time::OffsetDateTime};
+use rocket_db_pools::{sqlx::MySqlPool, Database};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, time::sleep};
+
+use crate::{
+    checksums, config::CONFIG, db::FileDb, file_index, trash::is_trash_key, FileSizes, MirrorFile,
+};
+
+/// How long removed files are remembered, so `since` deltas can report them.
+const REMOVED_RETENTION: i64 = 30 * 24 * 60 * 60;
+
+/// The latest manifest, or `None` until the first one has been generated.
+pub type SharedManifest = Arc<RwLock<Option<Manifest>>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestFile {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<u64>,
+    pub sha256: String,
+    /// When the manifest first listed this version of the file, which `since` is compared to.
+    pub changed: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RemovedFile {
+    pub path: String,
+    pub removed: i64,
+}
+
+/// Every public file in the tree, with paths relative to its root. Removals are
+/// only remembered from `tracked_since`, so older `since` deltas get everything.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Manifest {
+    pub generated: i64,
+    pub tracked_since: i64,
+    /// Whether only changes since a given time are listed.
+    #[serde(default)]
+    pub delta: bool,
+    pub files: Vec<ManifestFile>,
+    pub removed: Vec<RemovedFile>,
+}
+
+impl Manifest {
+    /// Files changed and removed after `since`, or the whole manifest if removals
+    /// from back then aren't known anymore.
+    pub fn since(&self, since: i64) -> Manifest {
+        if since < self.tracked_since {
+            return self.clone();
+        }
+
+        Manifest {
+            generated: self.generated,
+            tracked_since: self.tracked_since,
+            delta: true,
+            files: self
+                .files
+                .iter()
+                .filter(|file| file.changed > since)
+                .cloned()
+                .collect(),
+            removed: self
+                .removed
+                .iter()
+                .filter(|file| file.removed > since)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Whether a folder can be browsed without logging in. Results are kept in `dirs`,
+/// since every file in the folder asks again.
+async fn is_public_dir(dir: &Path, dirs: &mut HashMap<PathBuf, bool>) -> bool {
+    if let Some(&public) = dirs.get(dir) {
+        return public;
+    }
+
+    let local = Path::new("files").join(dir);
+
+    let public = match dir.parent() {
+        Some(parent) => Box::pin(is_public_dir(parent, dirs)).await,
+        None => true,
+    } && dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_none_or(|name| !CONFIG.hidden_files.iter().any(|hidden| hidden == name))
+        && !MirrorFile::has_marker(&local, "HIDDEN").await
+        && !(CONFIG.enable_login && MirrorFile::has_marker(&local, "RESTRICTED").await);
+
+    dirs.insert(dir.to_path_buf(), public);
+    public
+}
+
+async fn is_public(key: &Path, dirs: &mut HashMap<PathBuf, bool>) -> bool {
+    if key.starts_with("private") || is_trash_key(key) {
+        return false;
+    }
+
+    let visible_name = key
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| !CONFIG.hidden_files.iter().any(|hidden| hidden == name));
+
+    match key.parent() {
+        Some(parent) => visible_name && is_public_dir(parent, dirs).await,
+        None => visible_name,
+    }
+}
+
+/// Builds a manifest of the public files in the index. Only files that are new or
+/// have a different size or mtime than in `previous` are looked at again, and their
+/// SHA-256 comes from the checksum cache when there's a database.
+pub async fn generate(
+    index: &FileSizes,
+    previous: Option<&Manifest>,
+    pool: Option<&MySqlPool>,
+) -> Manifest {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    let mut candidates: Vec<(PathBuf, u64, Option<u64>)> = index
+        .read()
+        .await
+        .files()
+        .map(|(key, size, modified)| (key.to_path_buf(), size, modified))
+        .collect();
+    candidates.sort();
+
+    let mut dirs = HashMap::new();
+    let mut public = Vec::with_capacity(candidates.len());
+
+    for candidate in candidates {
+        if is_public(&candidate.0, &mut dirs).await {
+            public.push(candidate);
+        }
+    }
+    let candidates = public;
+
+    let known: HashMap<&str, &ManifestFile> = previous
+        .map(|manifest| {
+            manifest
+                .files
+                .iter()
+                .map(|file| (file.path.as_str(), file))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut files = Vec::with_capacity(candidates.len());
+
+    for (key, size, modified) in candidates {
+        let Some(path) = key.to_str() else {
+            continue;
+        };
+
+        match known.get(path) {
+            Some(file) if file.size == size && file.modified == modified => {
+                files.push((*file).clone())
+            }
+            _ => match checksums::cached(pool, &key, size, modified).await {
+                Ok(checksums) => files.push(ManifestFile {
+                    path: path.to_string(),
+                    size,
+                    modified,
+                    sha256: checksums.sha256,
+                    changed: now,
+                }),
+                Err(e) => eprintln!("Failed to hash {}: {:?}", path, e),
+            },
+        }
+    }
+
+    let listed: HashSet<&str> = files.iter().map(|file| file.path.as_str()).collect();
+    let mut removed = Vec::new();
+    let mut tracked_since = now;
+
+    if let Some(previous) = previous {
+        tracked_since = previous.tracked_since.max(now - REMOVED_RETENTION);
+
+        removed.extend(
+            previous
+                .removed
+                .iter()
+                .filter(|file| {
+                    file.removed >= tracked_since && !listed.contains(file.path.as_str())
+                })
+                .cloned(),
+        );
+        removed.extend(
+            previous
+                .files
+                .iter()
+                .filter(|file| !listed.contains(file.path.as_str()))
+                .map(|file| RemovedFile {
+                    path: file.path.clone(),
+                    removed: now,
+                }),
+        );
+    }
+
+    Manifest {
+        generated: now,
+        tracked_since,
+        delta: false,
+        files,
+        removed,
+    }
+}
+
+pub fn load(path: &Path) -> io::Result<Manifest> {
+    Ok(serde_json::from_reader(GzDecoder::new(File::open(path)?))?)
+}
+
+/// Writes the manifest gzipped to `path`, through a temporary file like the index cache.
+pub async fn save(manifest: &Manifest, path: &Path) -> io::Result<()> {
+    let json = serde_json::to_vec(manifest)?;
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let temp_path = path.with_extension("tmp");
+        let mut encoder = GzEncoder::new(File::create(&temp_path)?, Compression::default());
+        encoder.write_all(&json)?;
+        encoder.finish()?;
+        fs::rename(&temp_path, &path)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Regenerates the manifest every minute when the index has changed, saving it to
+/// `manifest_file` for clients that fetch it as a static file. Nothing is generated
+/// before the first scan, or files missing from the index would be marked removed.
+async fn keep_updated(index: FileSizes, manifest: SharedManifest, pool: Option<MySqlPool>) {
+    let path = Path::new(&CONFIG.manifest_file);
+
+    if !CONFIG.manifest_file.is_empty() {
+        match load(path) {
+            Ok(saved) => *manifest.write().await = Some(saved),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Failed to load {}: {:?}", CONFIG.manifest_file, e),
+        }
+    }
+
+    let mut generated_at = None;
+
+    loop {
+        let changes = index.read().await.changes();
+
+        if file_index::is_scanned() && generated_at != Some(changes) {
+            let previous = manifest.read().await.clone();
+            let generated = generate(&index, previous.as_ref(), pool.as_ref()).await;
+
+            if !CONFIG.manifest_file.is_empty() {
+                if let Err(e) = save(&generated, path).await {
+                    eprintln!("Failed to save {}: {:?}", CONFIG.manifest_file, e);
+                }
+            }
+
+            *manifest.write().await = Some(generated);
+            generated_at = Some(changes);
+        }
+
+        sleep(Duration::from_secs(60)).await;
+    }
+}
+
+pub fn build() -> AdHoc {
+    AdHoc::on_liftoff("Manifest", |rocket| {
+        Box::pin(async move {
+            let (Some(index), Some(manifest)) = (
+                rocket.state::<FileSizes>().cloned(),
+                rocket.state::<SharedManifest>().cloned(),
+            ) else {
+                return;
+            };
+            let pool = CONFIG
+                .enable_file_db
+                .then(|| FileDb::fetch(rocket).map(|db| MySqlPool::clone(db)))
+                .flatten();
+
+            tokio::spawn(keep_updated(index, manifest, pool));
+        })
+    })
+}
@@ -0,0 +1,447 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs,
+    net::IpAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use rocket::{
+    fairing::{AdHoc, Fairing, Info, Kind},
+    http::{ContentType, Method, Status},
+    request::{FromRequest, Outcome},
+    Data, Request, Response, State,
+};
+use rocket_db_pools::sqlx::MySqlPool;
+use sysinfo::Disks;
+
+use crate::{
+    config::CONFIG, db::Pools, jwt::JWT, peers::client_ip, utils::UPLOAD_TEMP_DIR, FileSizes,
+};
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Upper bounds of the request latency histogram, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct RouteMetrics {
+    /// Requests by status code.
+    requests: BTreeMap<u16, u64>,
+    /// Requests that took at most each of [`BUCKETS`].
+    buckets: [u64; BUCKETS.len()],
+    seconds: f64,
+    count: u64,
+}
+
+/// Counters collected while the server runs, shown at `/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    routes: Mutex<BTreeMap<(String, String), RouteMetrics>>,
+    bytes_served: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    index_refreshes: AtomicU64,
+    /// Duration of the last full index refresh, in microseconds.
+    index_refresh_micros: AtomicU64,
+    index_refresh_files: AtomicU64,
+}
+
+impl Metrics {
+    fn request(&self, route: &str, method: Method, status: Status, elapsed: Duration) {
+        let Ok(mut routes) = self.routes.lock() else {
+            return;
+        };
+
+        let route = routes
+            .entry((route.to_string(), method.as_str().to_string()))
+            .or_default();
+        let seconds = elapsed.as_secs_f64();
+
+        *route.requests.entry(status.code).or_default() += 1;
+        for (count, bound) in route.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+        route.seconds += seconds;
+        route.count += 1;
+    }
+
+    /// Counts bytes received in an upload.
+    pub fn uploaded(&self, bytes: u64) {
+        self.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records a full refresh of the file index and the number of files it found.
+    pub fn indexed(&self, elapsed: Duration, files: usize) {
+        self.index_refreshes.fetch_add(1, Ordering::Relaxed);
+        self.index_refresh_micros
+            .store(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.index_refresh_files
+            .store(files as u64, Ordering::Relaxed);
+    }
+}
+
+/// Times every request and counts the bytes sent back.
+struct RequestTimer;
+
+#[derive(Clone, Copy)]
+struct Started(Option<Instant>);
+
+#[rocket::async_trait]
+impl Fairing for RequestTimer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| Started(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Started(Some(started)) = *request.local_cache(|| Started(None)) else {
+            return;
+        };
+
+        let route = request
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unmatched");
+
+        METRICS.request(
+            route,
+            request.method(),
+            response.status(),
+            started.elapsed(),
+        );
+
+        if request.method() == Method::Head {
+            return;
+        }
+
+        // Streamed downloads only say how long they are in the header
+        let size = response
+            .body()
+            .preset_size()
+            .map(|size| size as u64)
+            .or_else(|| {
+                response
+                    .headers()
+                    .get_one("Content-Length")
+                    .and_then(|length| length.parse().ok())
+            });
+
+        if let Some(size) = size {
+            METRICS.bytes_served.fetch_add(size, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Lets in admins and addresses listed in `metrics_allowlist`.
+pub struct MetricsAccess;
+
+/// Whether the client is at an address in `allowlist`. Headers only count when a
+/// proxy in `trusted_proxies` sent them, since anyone can send `X-Forwarded-For`.
+pub(crate) fn is_allowlisted(request: &Request<'_>, allowlist: &[String]) -> bool {
+    client_ip(request).is_some_and(|client| {
+        allowlist
+            .iter()
+            .filter_map(|entry| entry.parse::<IpAddr>().ok())
+            .any(|entry| entry == client)
+    })
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAccess {
+    type Error = Status;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if is_allowlisted(request, &CONFIG.metrics_allowlist) {
+            return Outcome::Success(MetricsAccess);
+        }
+
+        match request.guard::<JWT>().await {
+            Outcome::Success(token) if token.claims.perms == 0 => Outcome::Success(MetricsAccess),
+            Outcome::Success(_) => Outcome::Error((Status::Forbidden, Status::Forbidden)),
+            _ => Outcome::Error((Status::Unauthorized, Status::Unauthorized)),
+        }
+    }
+}
+
+/// Puts a label value in quotes, escaped the way the text format wants.
+fn label(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Counts unfinished uploads of the `upload_chunked` endpoint and of tus.
+fn active_uploads() -> (usize, usize) {
+    let chunked = fs::read_dir(".chunks")
+        .map(|users| {
+            users
+                .flatten()
+                .filter_map(|user| fs::read_dir(user.path()).ok())
+                .map(|uploads| uploads.count())
+                .sum()
+        })
+        .unwrap_or(0);
+
+    let tus = fs::read_dir(Path::new(UPLOAD_TEMP_DIR).join("tus"))
+        .map(|uploads| {
+            uploads
+                .flatten()
+                .filter(|upload| upload.path().extension().is_some_and(|ext| ext == "json"))
+                .count()
+        })
+        .unwrap_or(0);
+
+    (chunked, tus)
+}
+
+fn pool_usage(out: &mut String, name: &str, pool: Option<&MySqlPool>) {
+    if let Some(pool) = pool {
+        let idle = pool.num_idle() as u32;
+
+        let _ = writeln!(
+            out,
+            "mirror_db_connections{{pool={},state=\"idle\"}} {}",
+            label(name),
+            idle
+        );
+        let _ = writeln!(
+            out,
+            "mirror_db_connections{{pool={},state=\"busy\"}} {}",
+            label(name),
+            pool.size().saturating_sub(idle)
+        );
+    }
+}
+
+#[get("/metrics")]
+async fn metrics(
+    _access: MetricsAccess,
+    pools: Pools<'_>,
+    sizes: &State<FileSizes>,
+) -> (ContentType, String) {
+    let mut out = String::new();
+
+    if let Ok(routes) = METRICS.routes.lock() {
+        let labels = |(route, method): &(String, String)| {
+            format!("route={},method={}", label(route), label(method))
+        };
+
+        header(
+            &mut out,
+            "mirror_http_requests_total",
+            "counter",
+            "Requests handled, by route and status.",
+        );
+        for (key, metrics) in routes.iter() {
+            for (status, count) in &metrics.requests {
+                let _ = writeln!(
+                    out,
+                    "mirror_http_requests_total{{{},status=\"{}\"}} {}",
+                    labels(key),
+                    status,
+                    count
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "mirror_http_request_duration_seconds",
+            "histogram",
+            "Time taken to answer requests, by route.",
+        );
+        for (key, metrics) in routes.iter() {
+            let labels = labels(key);
+
+            for (bound, count) in BUCKETS.iter().zip(metrics.buckets) {
+                let _ = writeln!(
+                    out,
+                    "mirror_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "mirror_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, metrics.count
+            );
+            let _ = writeln!(
+                out,
+                "mirror_http_request_duration_seconds_sum{{{}}} {}",
+                labels, metrics.seconds
+            );
+            let _ = writeln!(
+                out,
+                "mirror_http_request_duration_seconds_count{{{}}} {}",
+                labels, metrics.count
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "mirror_served_bytes_total",
+        "counter",
+        "Bytes sent in response bodies.",
+    );
+    let _ = writeln!(
+        out,
+        "mirror_served_bytes_total {}",
+        METRICS.bytes_served.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut out,
+        "mirror_uploaded_bytes_total",
+        "counter",
+        "Bytes received in uploads.",
+    );
+    let _ = writeln!(
+        out,
+        "mirror_uploaded_bytes_total {}",
+        METRICS.bytes_uploaded.load(Ordering::Relaxed)
+    );
+
+    let (chunked, tus) = active_uploads();
+    header(
+        &mut out,
+        "mirror_active_uploads",
+        "gauge",
+        "Chunked uploads that have started but not finished.",
+    );
+    let _ = writeln!(
+        out,
+        "mirror_active_uploads{{protocol=\"chunked\"}} {}",
+        chunked
+    );
+    let _ = writeln!(out, "mirror_active_uploads{{protocol=\"tus\"}} {}", tus);
+
+    header(
+        &mut out,
+        "mirror_index_refreshes_total",
+        "counter",
+        "Full refreshes of the file index.",
+    );
+    let _ = writeln!(
+        out,
+        "mirror_index_refreshes_total {}",
+        METRICS.index_refreshes.load(Ordering::Relaxed)
+    );
+    header(
+        &mut out,
+        "mirror_index_refresh_duration_seconds",
+        "gauge",
+        "Time the last full refresh of the file index took.",
+    );
+    let _ = writeln!(
+        out,
+        "mirror_index_refresh_duration_seconds {}",
+        METRICS.index_refresh_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    );
+    header(
+        &mut out,
+        "mirror_index_refresh_files",
+        "gauge",
+        "Files found by the last full refresh of the file index.",
+    );
+    let _ = writeln!(
+        out,
+        "mirror_index_refresh_files {}",
+        METRICS.index_refresh_files.load(Ordering::Relaxed)
+    );
+    header(
+        &mut out,
+        "mirror_index_files",
+        "gauge",
+        "Files currently in the file index.",
+    );
+    let _ = writeln!(
+        out,
+        "mirror_index_files {}",
+        sizes.read().await.files().count()
+    );
+
+    header(
+        &mut out,
+        "mirror_db_connections",
+        "gauge",
+        "Open database connections, by pool and whether they're in use.",
+    );
+    pool_usage(&mut out, "Db", pools.db);
+    pool_usage(&mut out, "FileDb", pools.file_db);
+
+    let disks: Vec<(String, u64, u64)> = Disks::new_with_refreshed_list()
+        .iter()
+        .filter(|disk| disk.total_space() != 0)
+        .map(|disk| {
+            (
+                label(&disk.mount_point().display().to_string()),
+                disk.total_space(),
+                disk.total_space() - disk.available_space(),
+            )
+        })
+        .collect();
+
+    header(
+        &mut out,
+        "mirror_disk_total_bytes",
+        "gauge",
+        "Size of each disk.",
+    );
+    for (mount_point, total, _) in &disks {
+        let _ = writeln!(
+            out,
+            "mirror_disk_total_bytes{{mount_point={}}} {}",
+            mount_point, total
+        );
+    }
+    header(
+        &mut out,
+        "mirror_disk_used_bytes",
+        "gauge",
+        "Space used on each disk.",
+    );
+    for (mount_point, _, used) in &disks {
+        let _ = writeln!(
+            out,
+            "mirror_disk_used_bytes{{mount_point={}}} {}",
+            mount_point, used
+        );
+    }
+
+    (
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        out,
+    )
+}
+
+pub fn build() -> AdHoc {
+    AdHoc::on_ignite("Metrics", |rocket| async {
+        rocket.attach(RequestTimer).mount("/", routes![metrics])
+    })
+}
@@ -2,12 +2,11 @@ use std::{
     cmp::Ordering,
     collections::HashMap,
     ffi::OsStr,
-    fs,
     path::{Path, PathBuf},
 };
 
 use once_cell::sync::Lazy;
-use rocket::{fs::NamedFile, http::Status};
+use rocket::http::Status;
 use rocket_db_pools::{
     sqlx::{self, Row},
     Connection,
@@ -15,10 +14,16 @@ use rocket_db_pools::{
 use uuid::Uuid;
 
 use crate::{
+    checksums::Checksums,
     config::CONFIG,
     db::FileDb,
+    file_index,
     guards::HeaderFile,
+    ranged::RangedFile,
     responders::{IndexResponse, IndexResult},
+    storage::{storage_key, StorageEntry, STORAGE},
+    trash::is_trash_key,
+    utils::map_io_error_to_status,
 };
 
 static SHARED_ICONS: Lazy<HashMap<String, String>> = Lazy::new(crate::load_shared_icons);
@@ -54,6 +59,7 @@ impl Default for MirrorFileInternal {
                 icon: "default".into(),
                 size: 0,
                 downloads: None,
+                checksums: None,
             },
             id: None,
             path: "files/".into(),
@@ -63,9 +69,9 @@ impl Default for MirrorFileInternal {
 
 impl MirrorFileInternal {
     pub async fn load(mut db: Connection<FileDb>, path: &PathBuf) -> Option<Self> {
-        let md = fs::metadata(&path).ok()?;
+        let md = STORAGE.stat(&storage_key(path)).await.ok()?;
         let name = MirrorFile::get_name_from_path(&path);
-        let ext = if md.is_file() {
+        let ext = if !md.is_dir {
             MirrorFile::get_extension_from_path(&path)
         } else {
             "folder".into()
@@ -93,8 +99,9 @@ impl MirrorFileInternal {
                 name,
                 ext,
                 icon,
-                size: md.len(),
+                size: md.size,
                 downloads,
+                checksums: None,
             },
             id,
             path: path.display().to_string().replacen("files/", "/", 1),
@@ -102,9 +109,9 @@ impl MirrorFileInternal {
     }
 
     pub async fn load_and_share(mut db: Connection<FileDb>, path: &PathBuf) -> Option<Self> {
-        let md = fs::metadata(&path).ok()?;
+        let md = STORAGE.stat(&storage_key(path)).await.ok()?;
         let name = MirrorFile::get_name_from_path(&path);
-        let ext = if md.is_file() {
+        let ext = if !md.is_dir {
             MirrorFile::get_extension_from_path(&path)
         } else {
             "folder".into()
@@ -146,8 +153,9 @@ impl MirrorFileInternal {
                 name,
                 ext,
                 icon,
-                size: md.len(),
+                size: md.size,
                 downloads,
+                checksums: None,
             },
             id: Some(id),
             path: path.display().to_string().replacen("files/", "/", 1),
@@ -175,9 +183,9 @@ impl MirrorFileInternal {
 
         let file_path = Path::new(&path).to_path_buf();
 
-        let md = fs::metadata(&file_path).ok()?;
+        let md = STORAGE.stat(&storage_key(&file_path)).await.ok()?;
         let name = MirrorFile::get_name_from_path(&file_path);
-        let ext = if md.is_file() {
+        let ext = if !md.is_dir {
             MirrorFile::get_extension_from_path(&file_path)
         } else {
             "folder".into()
@@ -189,8 +197,9 @@ impl MirrorFileInternal {
                 name,
                 ext,
                 icon,
-                size: md.len(),
+                size: md.size,
                 downloads,
+                checksums: None,
             },
             id: Some(id.to_string()),
             path: file_path.display().to_string().replacen("files/", "/", 1),
@@ -209,13 +218,30 @@ impl MirrorFileInternal {
     }
 
     pub async fn open_file(path: PathBuf, cache_control: &str) -> IndexResult {
+        let path = if path.starts_with("files") {
+            let key = storage_key(&path);
+
+            match STORAGE.local_path(&key) {
+                Some(local_path) => local_path,
+                // Nothing in front can read the bucket, so ranges are answered here
+                None => {
+                    return RangedFile::stored(&key, cache_control)
+                        .await
+                        .map(IndexResponse::RangedFile)
+                        .map_err(map_io_error_to_status)
+                }
+            }
+        } else {
+            path
+        };
+
         if !path.exists() {
             return Err(Status::NotFound);
         }
 
         if CONFIG.standalone {
-            match NamedFile::open(&path).await {
-                Ok(f) => Ok(IndexResponse::NamedFile(f, cache_control.to_string())),
+            match RangedFile::open(&path, cache_control).await {
+                Ok(f) => Ok(IndexResponse::RangedFile(f)),
                 Err(_) => Err(Status::InternalServerError),
             }
         } else {
@@ -259,6 +285,8 @@ pub struct MirrorFile {
     pub icon: String,
     pub size: u64,
     pub downloads: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksums: Option<Checksums>,
 }
 
 impl Eq for MirrorFile {}
@@ -283,6 +311,7 @@ impl Default for MirrorFile {
             icon: "default".into(),
             size: 0,
             downloads: None,
+            checksums: None,
         }
     }
 }
@@ -296,10 +325,10 @@ impl MirrorFile {
         }
     }
 
-    pub fn load(path: &PathBuf) -> Option<Self> {
-        let md = fs::metadata(&path).ok()?;
+    pub async fn load(path: &PathBuf) -> Option<Self> {
+        let md = STORAGE.stat(&storage_key(path)).await.ok()?;
         let name = MirrorFile::get_name_from_path(&path);
-        let ext = if md.is_file() {
+        let ext = if !md.is_dir {
             MirrorFile::get_extension_from_path(&path)
         } else {
             "folder".into()
@@ -310,11 +339,22 @@ impl MirrorFile {
             name,
             ext,
             icon,
-            size: md.len(),
+            size: md.size,
             downloads: None,
+            checksums: None,
         })
     }
 
+    pub fn from_entry(entry: &StorageEntry) -> Self {
+        if entry.is_dir {
+            return MirrorFile::new_folder(&entry.name);
+        }
+
+        let mut mirror_file = MirrorFile::new(&entry.name);
+        mirror_file.size = entry.size;
+        mirror_file
+    }
+
     pub fn new(file_name: &str) -> Self {
         let ext = MirrorFile::get_extension_from_filename(file_name).unwrap_or_default();
         let icon = MirrorFile::get_icon(file_name);
@@ -325,6 +365,7 @@ impl MirrorFile {
             icon,
             size: 0,
             downloads: None,
+            checksums: None,
         }
     }
 
@@ -335,6 +376,7 @@ impl MirrorFile {
             icon: "folder".into(),
             size: 0,
             downloads: None,
+            checksums: None,
         }
     }
 
@@ -400,7 +442,14 @@ impl MirrorFile {
             .to_string()
     }
 
-    pub fn is_restricted(path: &Path, is_logged_in: bool) -> bool {
+    /// Whether a folder (a `files/...` path) holds a marker file like `RESTRICTED`,
+    /// looked up in storage so markers in a bucket count too. Answers are cached
+    /// by the file index.
+    pub async fn has_marker(dir: &Path, marker: &str) -> bool {
+        file_index::has_marker(&storage_key(&dir.join(marker))).await
+    }
+
+    pub async fn is_restricted(path: &Path, is_logged_in: bool) -> bool {
         if !CONFIG.enable_login {
             return false;
         }
@@ -414,7 +463,7 @@ impl MirrorFile {
         let mut current = Some(path);
 
         while let Some(p) = current {
-            if p.join("RESTRICTED").exists() {
+            if Self::has_marker(p, "RESTRICTED").await {
                 return !is_logged_in;
             }
             current = p.parent();
@@ -423,11 +472,11 @@ impl MirrorFile {
         false
     }
 
-    pub fn is_hidden(path: &Path, perms: Option<i32>) -> bool {
+    pub async fn is_hidden(path: &Path, perms: Option<i32>) -> bool {
         let mut current = Some(path);
 
         while let Some(p) = current {
-            if p.join("HIDDEN").exists() {
+            if Self::has_marker(p, "HIDDEN").await {
                 if let Some(perms) = perms {
                     return perms != 0;
                 } else {
@@ -459,6 +508,8 @@ impl MirrorFile {
                     .join(rest),
                 true,
             ))
+        } else if is_trash_key(file) {
+            Err(Status::NotFound)
         } else {
             Ok((Path::new("files/").join(&file), false))
         }
@@ -484,13 +535,16 @@ impl MirrorFile {
         } else {
             if perms != 0 {
                 Err(Status::Forbidden)
+            } else if is_trash_key(file) {
+                Err(Status::NotFound)
             } else {
                 Ok((Path::new("files/").join(&file), false))
             }
         }
     }
 
-    pub fn is_hidden_path(path: &Path, perms: Option<i32>) -> bool {
+    /// Like [`is_hidden`](Self::is_hidden), for a path as users see it (`/folder/file`).
+    pub async fn is_hidden_path(path: &Path, perms: Option<i32>) -> bool {
         let mut current = Some(path);
 
         while let Some(p) = current {
@@ -504,14 +558,11 @@ impl MirrorFile {
                     return true;
                 }
             }
-            if Path::new("files/")
-                .join(
-                    p.join("RESTRICTED")
-                        .display()
-                        .to_string()
-                        .replacen("/", "", 1),
-                )
-                .exists()
+            if Self::has_marker(
+                &Path::new("files/").join(p.strip_prefix("/").unwrap_or(p)),
+                "RESTRICTED",
+            )
+            .await
             {
                 return !perms.is_some();
             }
@@ -521,7 +572,7 @@ impl MirrorFile {
         false
     }
 
-    pub fn is_hidden_path_str(path: &str, perms: Option<i32>) -> bool {
-        Self::is_hidden_path(Path::new(path), perms)
+    pub async fn is_hidden_path_str(path: &str, perms: Option<i32>) -> bool {
+        Self::is_hidden_path(Path::new(path), perms).await
     }
 }
@@ -0,0 +1,418 @@
+use std::{collections::HashMap, net::IpAddr, path::Path, sync::Arc, time::Duration};
+
+use maxminddb::{geoip2, Reader};
+use once_cell::sync::Lazy;
+use rand::RngExt;
+use reqwest::Client;
+use rocket::{
+    request::{FromRequest, Outcome},
+    time::OffsetDateTime,
+    Request,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, time::sleep};
+
+use crate::{
+    config::CONFIG,
+    manifest::{Manifest, SharedManifest},
+};
+
+static GEOIP: Lazy<Option<Reader<Vec<u8>>>> = Lazy::new(|| {
+    if CONFIG.geoip_database.is_empty() {
+        return None;
+    }
+
+    Reader::open_readfile(&CONFIG.geoip_database)
+        .map_err(|e| eprintln!("Failed to open {}: {:?}", CONFIG.geoip_database, e))
+        .ok()
+});
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PeerConfig {
+    pub name: String,
+    /// Base URL of the peer, such as `https://mirror2.example.com`.
+    pub url: String,
+    /// Country (`PL`) or continent (`EU`) code of where the peer is.
+    #[serde(default)]
+    pub region: String,
+    /// Share of the downloads the peer gets among equally close peers.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PeerStatus {
+    pub name: String,
+    pub url: String,
+    pub healthy: bool,
+    pub last_checked: Option<i64>,
+    pub last_error: Option<String>,
+    /// When the peer generated the manifest we have, for fetching deltas.
+    #[serde(skip)]
+    generated: i64,
+    /// Sizes and SHA-256 hashes of the peer's files, from its manifest.
+    #[serde(skip)]
+    files: HashMap<String, (u64, String)>,
+}
+
+/// Health and files of every peer in `peers`, in the same order.
+pub type Peers = Arc<RwLock<Vec<PeerStatus>>>;
+
+impl PeerStatus {
+    pub(crate) fn new(peer: &PeerConfig) -> Self {
+        PeerStatus {
+            name: peer.name.clone(),
+            url: peer.url.clone(),
+            healthy: false,
+            last_checked: None,
+            last_error: None,
+            generated: 0,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Takes in a manifest fetched from the peer, either whole or a delta.
+    pub(crate) fn apply(&mut self, manifest: Manifest) {
+        if !manifest.delta {
+            self.files.clear();
+        }
+
+        for file in manifest.removed {
+            self.files.remove(&file.path);
+        }
+
+        for file in manifest.files {
+            self.files.insert(file.path, (file.size, file.sha256));
+        }
+
+        self.generated = manifest.generated;
+    }
+}
+
+pub fn statuses() -> Peers {
+    Arc::new(RwLock::new(
+        CONFIG.peers.iter().map(PeerStatus::new).collect(),
+    ))
+}
+
+/// What a download needs to be sent to a peer: where the client is, and what's
+/// known about the peers.
+pub struct PeerContext<'r> {
+    client: Option<IpAddr>,
+    /// Whether a peer already sent the client here, so it mustn't be sent on again.
+    redirected: bool,
+    peers: &'r Peers,
+    manifest: &'r SharedManifest,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for PeerContext<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (Some(peers), Some(manifest)) = (
+            request.rocket().state::<Peers>(),
+            request.rocket().state::<SharedManifest>(),
+        ) else {
+            return Outcome::Forward(rocket::http::Status::InternalServerError);
+        };
+
+        Outcome::Success(PeerContext {
+            client: client_ip(request),
+            redirected: is_from_peer(request),
+            peers,
+            manifest,
+        })
+    }
+}
+
+/// Whether a peer sent the client here, which it marks with a `peer` query.
+pub(crate) fn is_from_peer(request: &Request<'_>) -> bool {
+    request
+        .uri()
+        .query()
+        .is_some_and(|query| query.segments().any(|(name, _)| name == "peer"))
+}
+
+/// The client's address, as passed on by a proxy in `trusted_proxies` if the
+/// request came through one.
+pub(crate) fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    forwarded_ip(request, &CONFIG.trusted_proxies)
+}
+
+/// Requests from a proxy in `trusted` get the address from `X-Forwarded-For`, read
+/// from the right so anything the client put there itself is skipped. Any other
+/// request gets the address of the connection, whatever its headers say.
+pub(crate) fn forwarded_ip(request: &Request<'_>, trusted: &[String]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| is_trusted(ip, trusted);
+
+    let remote = request.remote()?.ip();
+
+    if !is_trusted(&remote) {
+        return Some(remote);
+    }
+
+    let forwarded: Vec<IpAddr> = request
+        .headers()
+        .get("X-Forwarded-For")
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+
+    Some(
+        forwarded
+            .into_iter()
+            .rev()
+            .find(|ip| !is_trusted(ip))
+            .unwrap_or(remote),
+    )
+}
+
+/// `https` if a proxy in `trusted` says the client connected over it with
+/// `X-Forwarded-Proto`. The mirror doesn't serve TLS itself, so anything else is `http`.
+pub(crate) fn forwarded_scheme(request: &Request<'_>, trusted: &[String]) -> &'static str {
+    let is_https = request
+        .headers()
+        .get_one("X-Forwarded-Proto")
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"));
+
+    match request.remote() {
+        Some(remote) if is_https && is_trusted(&remote.ip(), trusted) => "https",
+        _ => "http",
+    }
+}
+
+fn is_trusted(ip: &IpAddr, trusted: &[String]) -> bool {
+    trusted
+        .iter()
+        .filter_map(|proxy| proxy.parse::<IpAddr>().ok())
+        .any(|proxy| proxy == *ip)
+}
+
+/// Country and continent codes of a client.
+pub(crate) type Location = (Option<String>, Option<String>);
+
+/// Where the client is, if the GeoIP database knows.
+pub(crate) fn locate(ip: IpAddr) -> Location {
+    let Some(Ok(record)) = GEOIP
+        .as_ref()
+        .map(|reader| reader.lookup::<geoip2::Country>(ip))
+    else {
+        return (None, None);
+    };
+
+    (
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_string),
+        record
+            .continent
+            .and_then(|continent| continent.code)
+            .map(str::to_string),
+    )
+}
+
+/// 2 for the client's country, 1 for its continent, 0 for anywhere else.
+fn proximity(region: &str, location: &Location) -> u8 {
+    let matches = |code: &Option<String>| {
+        code.as_ref()
+            .is_some_and(|code| !region.is_empty() && code.eq_ignore_ascii_case(region))
+    };
+
+    if matches(&location.0) {
+        2
+    } else if matches(&location.1) {
+        1
+    } else {
+        0
+    }
+}
+
+fn encode_path(key: &str) -> String {
+    key.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Picks the peer to send the download of a file to among the healthy ones that
+/// have it with the same size, and the same hash if it's known. Closer peers win,
+/// and ties are broken by weight, with this mirror (in `region`, with `weight`)
+/// taking part like any peer. `None` means this mirror serves the file, which it
+/// always does when the client's location is unknown.
+pub(crate) fn choose<'a>(
+    peers: impl Iterator<Item = (&'a PeerConfig, &'a PeerStatus)>,
+    key: &str,
+    size: u64,
+    sha256: Option<&str>,
+    location: &Location,
+    region: &str,
+    weight: u32,
+) -> Option<&'a PeerConfig> {
+    if location.0.is_none() && location.1.is_none() {
+        return None;
+    }
+
+    let candidates: Vec<(Option<&PeerConfig>, u32, u8)> = peers
+        .filter(|(config, status)| {
+            config.weight != 0
+                && status.healthy
+                && status
+                    .files
+                    .get(key)
+                    .is_some_and(|(peer_size, peer_sha256)| {
+                        *peer_size == size && sha256.is_none_or(|sha256| sha256 == peer_sha256)
+                    })
+        })
+        .map(|(config, _)| {
+            (
+                Some(config),
+                config.weight,
+                proximity(&config.region, location),
+            )
+        })
+        .chain((weight != 0).then(|| (None, weight, proximity(region, location))))
+        .collect();
+
+    let closest = candidates
+        .iter()
+        .map(|(_, _, proximity)| *proximity)
+        .max()?;
+
+    let candidates: Vec<(Option<&PeerConfig>, u32)> = candidates
+        .into_iter()
+        .filter(|(_, _, proximity)| *proximity == closest)
+        .map(|(config, weight, _)| (config, weight))
+        .collect();
+
+    let total: u32 = candidates.iter().map(|(_, weight)| weight).sum();
+    let mut pick = rand::rng().random_range(0..total);
+
+    candidates
+        .into_iter()
+        .find(|(_, weight)| {
+            if pick < *weight {
+                true
+            } else {
+                pick -= weight;
+                false
+            }
+        })
+        .and_then(|(config, _)| config)
+}
+
+impl PeerContext<'_> {
+    /// Where to redirect the download of a file (a storage key) to, if a peer
+    /// should serve it.
+    pub async fn redirect(&self, key: &Path, size: u64) -> Option<String> {
+        if CONFIG.peers.is_empty() || self.redirected {
+            return None;
+        }
+
+        let key = key.to_str()?;
+
+        let sha256 = self.manifest.read().await.as_ref().and_then(|manifest| {
+            manifest
+                .files
+                .iter()
+                .find(|file| file.path == key && file.size == size)
+                .map(|file| file.sha256.clone())
+        });
+
+        let location = self.client.map(locate).unwrap_or_default();
+        let peers = self.peers.read().await;
+
+        let peer = choose(
+            CONFIG.peers.iter().zip(peers.iter()),
+            key,
+            size,
+            sha256.as_deref(),
+            &location,
+            &CONFIG.region,
+            CONFIG.weight,
+        )?;
+
+        // Peers serve `peer` downloads themselves, so a client is never passed around
+        Some(format!(
+            "{}/{}?download&peer",
+            peer.url.trim_end_matches('/'),
+            encode_path(key)
+        ))
+    }
+}
+
+/// Fetches a peer's manifest, or the changes since `generated` if we have it already.
+async fn check(client: &Client, url: &str, generated: i64) -> Result<Manifest, String> {
+    let mut url = format!("{}/api/manifest", url.trim_end_matches('/'));
+
+    if generated != 0 {
+        url += &format!("?since={}", generated);
+    }
+
+    let body = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    serde_json::from_slice(&body).map_err(|e| e.to_string())
+}
+
+/// Checks every peer each minute, keeping track of which ones are up and what files
+/// they have. Peers need `enable_manifest` for this.
+pub async fn keep_checked(peers: Peers) {
+    let client = match Client::builder()
+        .user_agent(format!("MARMAK-Mirror/{}", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(30))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to set up peer checks: {:?}", e);
+            return;
+        }
+    };
+
+    loop {
+        for (index, peer) in CONFIG.peers.iter().enumerate() {
+            let generated = peers.read().await[index].generated;
+            let result = check(&client, &peer.url, generated).await;
+
+            let mut peers = peers.write().await;
+            let status = &mut peers[index];
+
+            status.last_checked = Some(OffsetDateTime::now_utc().unix_timestamp());
+
+            match result {
+                Ok(manifest) => {
+                    if !status.healthy {
+                        println!("Peer {} is up", peer.name);
+                    }
+
+                    status.apply(manifest);
+                    status.healthy = true;
+                    status.last_error = None;
+                }
+                Err(e) => {
+                    if status.healthy {
+                        println!("Peer {} is down: {}", peer.name, e);
+                    }
+
+                    status.healthy = false;
+                    status.last_error = Some(e);
+                }
+            }
+        }
+
+        sleep(Duration::from_secs(60)).await;
+    }
+}
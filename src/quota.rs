@@ -0,0 +1,70 @@
+use std::{collections::HashMap, sync::Arc};
+
+use rocket::http::Status;
+use tokio::sync::Mutex;
+
+use crate::{
+    api::{private_usage, upload_limits},
+    jwt::JWT,
+    FileSizes,
+};
+
+/// Space held for uploads that haven't finished yet, by user and then by upload.
+pub type Reservations = Arc<Mutex<HashMap<String, HashMap<String, u64>>>>;
+
+/// Holds `size` bytes of the user's private folder quota for `upload`, or fails with
+/// `InsufficientStorage` if that together with what's stored and what other uploads
+/// hold would go over it. Calling it again for the same upload replaces the amount,
+/// which is how the final size of an upload is checked before it's stored.
+pub async fn reserve(
+    reservations: &Reservations,
+    sizes: &FileSizes,
+    token: &JWT,
+    upload: &str,
+    size: u64,
+) -> Result<(), Status> {
+    let (_, folder_quota) = upload_limits(token);
+
+    if folder_quota == 0 {
+        return Ok(());
+    }
+
+    let mut reservations = reservations.lock().await;
+    let held = reservations.entry(token.claims.sub.clone()).or_default();
+
+    let others: u64 = held
+        .iter()
+        .filter(|(id, _)| id.as_str() != upload)
+        .map(|(_, size)| size)
+        .sum();
+
+    if private_usage(token, sizes).await + others + size > folder_quota {
+        return Err(Status::InsufficientStorage);
+    }
+
+    held.insert(upload.to_string(), size);
+
+    Ok(())
+}
+
+pub async fn release(reservations: &Reservations, user: &str, upload: &str) {
+    let mut reservations = reservations.lock().await;
+
+    if let Some(held) = reservations.get_mut(user) {
+        held.remove(upload);
+
+        if held.is_empty() {
+            reservations.remove(user);
+        }
+    }
+}
+
+/// Bytes currently held for the user's unfinished uploads.
+pub async fn reserved(reservations: &Reservations, user: &str) -> u64 {
+    reservations
+        .lock()
+        .await
+        .get(user)
+        .map(|held| held.values().sum())
+        .unwrap_or(0)
+}
@@ -0,0 +1,268 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use rocket::{
+    futures::{stream, TryStreamExt},
+    http::{ContentType, Status},
+    response::{self, Responder},
+    Request, Response,
+};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use uuid::Uuid;
+
+use crate::{
+    storage::{StorageReader, STORAGE},
+    utils::{http_date, parse_http_date},
+};
+
+/// Requests asking for more ranges than this get the whole file instead.
+const MAX_RANGES: usize = 16;
+
+/// A file served with `Range`, `ETag` and conditional request support, for
+/// standalone mode and storage backends that no proxy in front can read.
+pub struct RangedFile {
+    path: PathBuf,
+    /// Whether `path` is a key in the storage backend rather than a local file.
+    stored: bool,
+    size: u64,
+    /// Modification time as a Unix timestamp, and its sub-second part.
+    modified: (i64, u32),
+    content_type: ContentType,
+    cache_control: String,
+}
+
+impl RangedFile {
+    pub async fn open(path: &Path, cache_control: &str) -> io::Result<Self> {
+        let md = tokio::fs::metadata(path).await?;
+
+        if md.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Not a file"));
+        }
+
+        let modified = md
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| (d.as_secs() as i64, d.subsec_nanos()))
+            .unwrap_or((0, 0));
+
+        Ok(RangedFile {
+            path: path.to_path_buf(),
+            stored: false,
+            size: md.len(),
+            modified,
+            content_type: content_type(path),
+            cache_control: cache_control.to_string(),
+        })
+    }
+
+    /// Serves a file from the storage backend, fetching only the ranges asked for.
+    pub async fn stored(key: &Path, cache_control: &str) -> io::Result<Self> {
+        let md = STORAGE.stat(key).await?;
+
+        if md.is_dir {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Not a file"));
+        }
+
+        Ok(RangedFile {
+            path: key.to_path_buf(),
+            stored: true,
+            size: md.size,
+            modified: (md.modified.unwrap_or(0) as i64, 0),
+            content_type: content_type(key),
+            cache_control: cache_control.to_string(),
+        })
+    }
+
+    /// A strong validator: the file only keeps it while its size and mtime stay the same.
+    fn etag(&self) -> String {
+        format!(
+            "\"{:x}-{:x}{:08x}\"",
+            self.size, self.modified.0, self.modified.1
+        )
+    }
+
+    fn last_modified(&self) -> String {
+        http_date(self.modified.0)
+    }
+
+    fn is_not_modified(&self, req: &Request<'_>) -> bool {
+        if let Some(tags) = req.headers().get_one("If-None-Match") {
+            let etag = self.etag();
+
+            return tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag);
+        }
+
+        req.headers()
+            .get_one("If-Modified-Since")
+            .and_then(parse_http_date)
+            .is_some_and(|since| self.modified.0 <= since)
+    }
+
+    /// `If-Range` only lets a range through if the client's copy is still current.
+    fn range_applies(&self, req: &Request<'_>) -> bool {
+        match req.headers().get_one("If-Range").map(str::trim) {
+            None => true,
+            Some(tag) if tag.starts_with('"') => tag == self.etag(),
+            Some(date) => date == self.last_modified(),
+        }
+    }
+
+    fn segment(&self, (start, end): (u64, u64)) -> io::Result<StorageReader> {
+        if self.stored {
+            // Responders can't wait, so the object is only requested once the body is read
+            let key = self.path.clone();
+            let body =
+                stream::once(async move { STORAGE.open(&key, Some((start, Some(end)))).await })
+                    .map_ok(ReaderStream::new)
+                    .try_flatten();
+
+            return Ok(Box::pin(StreamReader::new(body)));
+        }
+
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+
+        Ok(Box::pin(
+            tokio::fs::File::from_std(file).take(end - start + 1),
+        ))
+    }
+}
+
+fn content_type(path: &Path) -> ContentType {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(ContentType::from_extension)
+        .unwrap_or(ContentType::Binary)
+}
+
+/// Parses a `Range` header into inclusive byte ranges. `None` means the header
+/// should be ignored; an empty list means none of the ranges can be satisfied.
+pub(crate) fn parse_ranges(header: &str, size: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',').map(str::trim) {
+        let (start, end) = spec.split_once('-')?;
+
+        let range = match (start.trim(), end.trim()) {
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                (suffix != 0 && size != 0).then(|| (size.saturating_sub(suffix), size - 1))
+            }
+            (start, end) => {
+                let start: u64 = start.parse().ok()?;
+                let end = match end {
+                    "" => u64::MAX,
+                    end => end.parse().ok()?,
+                };
+
+                if end < start {
+                    return None;
+                }
+
+                (start < size).then(|| (start, end.min(size - 1)))
+            }
+        };
+
+        ranges.extend(range);
+    }
+
+    (ranges.len() <= MAX_RANGES).then_some(ranges)
+}
+
+impl<'r> Responder<'r, 'static> for RangedFile {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let content_type = if self.content_type.is_html() {
+            ContentType::Plain
+        } else {
+            self.content_type.clone()
+        };
+
+        let mut builder = Response::build();
+        builder
+            .raw_header("Cache-Control", self.cache_control.clone())
+            .raw_header("ETag", self.etag())
+            .raw_header("Last-Modified", self.last_modified())
+            .raw_header("Accept-Ranges", "bytes");
+
+        if self.is_not_modified(req) {
+            return builder.status(Status::NotModified).ok();
+        }
+
+        let ranges = req
+            .headers()
+            .get_one("Range")
+            .filter(|_| self.range_applies(req))
+            .and_then(|header| parse_ranges(header, self.size));
+
+        let open_error = |e: io::Error| {
+            eprintln!("Failed to open {}: {:?}", self.path.display(), e);
+            Status::InternalServerError
+        };
+
+        match ranges.as_deref() {
+            None => builder
+                .header(content_type)
+                .raw_header("Content-Length", self.size.to_string())
+                .streamed_body(
+                    self.segment((0, self.size.saturating_sub(1)))
+                        .map_err(open_error)?,
+                )
+                .ok(),
+            Some([]) => builder
+                .status(Status::RangeNotSatisfiable)
+                .raw_header("Content-Range", format!("bytes */{}", self.size))
+                .ok(),
+            Some(&[range]) => builder
+                .status(Status::PartialContent)
+                .header(content_type)
+                .raw_header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", range.0, range.1, self.size),
+                )
+                .raw_header("Content-Length", (range.1 - range.0 + 1).to_string())
+                .streamed_body(self.segment(range).map_err(open_error)?)
+                .ok(),
+            Some(ranges) => {
+                let boundary = Uuid::new_v4().simple().to_string();
+                let mut length = 0;
+                let mut body: StorageReader = Box::pin(tokio::io::empty());
+
+                for &range in ranges {
+                    let part = format!(
+                        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                        boundary, content_type, range.0, range.1, self.size
+                    );
+
+                    length += part.len() as u64 + range.1 - range.0 + 1;
+                    body = Box::pin(
+                        body.chain(Cursor::new(part))
+                            .chain(self.segment(range).map_err(open_error)?),
+                    );
+                }
+
+                let end = format!("\r\n--{}--\r\n", boundary);
+                length += end.len() as u64;
+                body = Box::pin(body.chain(Cursor::new(end)));
+
+                builder
+                    .status(Status::PartialContent)
+                    .raw_header(
+                        "Content-Type",
+                        format!("multipart/byteranges; boundary={}", boundary),
+                    )
+                    .raw_header("Content-Length", length.to_string())
+                    .streamed_body(body)
+                    .ok()
+            }
+        }
+    }
+}
@@ -1,18 +1,26 @@
 use rocket::{
-    fs::NamedFile,
     http::{ContentType, Status},
     response::{self, Redirect, Responder},
     serde::json::Json,
-    Request,
+    Request, Response,
 };
 use rocket_dyn_templates::Template;
 
 use crate::{
     api::{
-        ApiInfoResponse, ApiShareResponse, MirrorFileWrapper, MusicFile, SearchFile, UploadFile,
-        UploadLimits, VideoFile,
+        ApiInfoResponse, ApiShareResponse, ArchiveFile, MirrorFileWrapper, MusicFile, SearchFile,
+        UploadFile, UploadLimits, VideoFile,
     },
+    dav::DAV_CLASSES,
     guards::HeaderFile,
+    inspector::ArchiveNode,
+    jobs::Job,
+    manifest::Manifest,
+    ranged::RangedFile,
+    stats::DownloadStats,
+    storage::StorageReader,
+    trash::TrashEntry,
+    tus::TUS_VERSION,
     MirrorFile, Sysinfo,
 };
 
@@ -35,7 +43,10 @@ pub enum IndexResponse {
     Template(Template),
     DirectFile((ContentType, Vec<u8>), String),
     HeaderFile(HeaderFile),
-    NamedFile(NamedFile, String),
+    RangedFile(RangedFile),
+    StreamedFile(StorageReader, ContentType, String),
+    /// A generated download, such as an archive, sent with the given file name.
+    Attachment(StorageReader, ContentType, String),
     Redirect(Redirect),
 }
 
@@ -69,18 +80,32 @@ impl<'r> Responder<'r, 'r> for IndexResponse {
 
                 Ok(res)
             }
-            IndexResponse::NamedFile(f, cache_control) => {
-                let mut res = f.respond_to(req)?;
-                res.set_raw_header("Cache-Control", cache_control);
-
-                if let Some(content_type) = res.headers().get_one("Content-Type") {
-                    if content_type.contains("text/html") {
-                        res.set_raw_header("Content-Type", "text/plain");
-                    }
-                }
+            IndexResponse::RangedFile(f) => f.respond_to(req),
+            IndexResponse::StreamedFile(reader, content_type, cache_control) => {
+                let content_type = if content_type.is_html() {
+                    ContentType::Plain
+                } else {
+                    content_type
+                };
 
-                Ok(res)
+                Response::build()
+                    .header(content_type)
+                    .raw_header("Cache-Control", cache_control)
+                    .streamed_body(reader)
+                    .ok()
             }
+            IndexResponse::Attachment(reader, content_type, file_name) => Response::build()
+                .header(content_type)
+                .raw_header("Cache-Control", "no-cache")
+                .raw_header(
+                    "Content-Disposition",
+                    format!(
+                        "attachment; filename=\"{}\"",
+                        file_name.replace(['"', '\\'], "_")
+                    ),
+                )
+                .streamed_body(reader)
+                .ok(),
             IndexResponse::Redirect(r) => r.respond_to(req),
         }
     }
@@ -91,6 +116,8 @@ pub enum ApiResponse {
     File(Json<MirrorFileWrapper>),
     MusicFile(Json<MusicFile>),
     VideoFile(Json<VideoFile>),
+    ArchiveFile(Json<ArchiveFile>),
+    ArchiveEntry(Json<ArchiveNode>),
     MessageStatus((Status, Json<ApiInfoResponse>)),
     ShareResponse((Status, Json<ApiShareResponse>)),
     Message(Json<ApiInfoResponse>),
@@ -98,10 +125,118 @@ pub enum ApiResponse {
     UploadFiles(Json<Vec<UploadFile>>),
     SearchResults(Json<Vec<SearchFile>>),
     UploadLimits(Json<UploadLimits>),
+    Trash(Json<Vec<TrashEntry>>),
+    Job((Status, Json<Job>)),
+    Manifest(Json<Manifest>),
+    Stats(Json<DownloadStats>),
 }
 
 pub type ApiResult = Result<ApiResponse, Status>;
 
+/// A bodiless tus response. `Tus-Resumable` is added to every one of them.
+pub struct TusResponse {
+    pub status: Status,
+    pub headers: Vec<(&'static str, String)>,
+}
+
+impl TusResponse {
+    pub fn new(status: Status) -> Self {
+        TusResponse {
+            status,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+impl From<Status> for TusResponse {
+    fn from(status: Status) -> Self {
+        TusResponse::new(status)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut builder = Response::build();
+
+        builder
+            .status(self.status)
+            .raw_header("Tus-Resumable", TUS_VERSION)
+            .raw_header("Cache-Control", "no-store");
+
+        for (name, value) in self.headers {
+            builder.raw_header(name, value);
+        }
+
+        builder.ok()
+    }
+}
+
+/// A WebDAV response, optionally with an XML body. Unauthorized ones ask for
+/// Basic credentials, since that's the only scheme most clients support.
+pub struct DavResponse {
+    pub status: Status,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Option<String>,
+}
+
+impl DavResponse {
+    pub fn new(status: Status) -> Self {
+        DavResponse {
+            status,
+            headers: Vec::new(),
+            body: None,
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    pub fn xml(mut self, body: String) -> Self {
+        self.body = Some(body);
+        self
+    }
+}
+
+impl From<Status> for DavResponse {
+    fn from(status: Status) -> Self {
+        DavResponse::new(status)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for DavResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut builder = Response::build();
+
+        builder
+            .status(self.status)
+            .raw_header("DAV", DAV_CLASSES)
+            .raw_header("Cache-Control", "no-store");
+
+        if self.status == Status::Unauthorized {
+            builder.raw_header("WWW-Authenticate", "Basic realm=\"MARMAK Mirror\"");
+        }
+
+        for (name, value) in self.headers {
+            builder.raw_header(name, value);
+        }
+
+        if let Some(body) = self.body {
+            builder
+                .raw_header("Content-Type", "application/xml; charset=utf-8")
+                .sized_body(body.len(), std::io::Cursor::new(body));
+        }
+
+        builder.ok()
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> Responder<'r, 'r> for ApiResponse {
     fn respond_to(self, req: &'r rocket::Request<'_>) -> rocket::response::Result<'r> {
@@ -126,6 +261,16 @@ impl<'r> Responder<'r, 'r> for ApiResponse {
                 res.set_raw_header("Cache-Control", "private");
                 Ok(res)
             }
+            ApiResponse::ArchiveFile(f) => {
+                let mut res = f.respond_to(req)?;
+                res.set_raw_header("Cache-Control", "private");
+                Ok(res)
+            }
+            ApiResponse::ArchiveEntry(e) => {
+                let mut res = e.respond_to(req)?;
+                res.set_raw_header("Cache-Control", "private");
+                Ok(res)
+            }
             ApiResponse::MessageStatus(m) => {
                 let mut res = m.respond_to(req)?;
                 res.set_raw_header("Cache-Control", "no-cache");
@@ -161,6 +306,26 @@ impl<'r> Responder<'r, 'r> for ApiResponse {
                 res.set_raw_header("Cache-Control", "no-cache");
                 Ok(res)
             }
+            ApiResponse::Trash(t) => {
+                let mut res = t.respond_to(req)?;
+                res.set_raw_header("Cache-Control", "no-cache");
+                Ok(res)
+            }
+            ApiResponse::Job(j) => {
+                let mut res = j.respond_to(req)?;
+                res.set_raw_header("Cache-Control", "no-cache");
+                Ok(res)
+            }
+            ApiResponse::Manifest(m) => {
+                let mut res = m.respond_to(req)?;
+                res.set_raw_header("Cache-Control", "no-cache");
+                Ok(res)
+            }
+            ApiResponse::Stats(s) => {
+                let mut res = s.respond_to(req)?;
+                res.set_raw_header("Cache-Control", "private");
+                Ok(res)
+            }
         }
     }
 }
@@ -0,0 +1,104 @@
+use std::{convert::Infallible, path::Path};
+
+use rocket::{
+    http::uri::Absolute,
+    request::{FromRequest, Outcome},
+    Request,
+};
+use rocket_db_pools::sqlx::{pool::PoolConnection, MySql};
+use serde::Serialize;
+
+use crate::{config::CONFIG, db::record_download, peers};
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct StatsEntry {
+    pub name: String,
+    pub downloads: u64,
+    pub bytes: u64,
+}
+
+/// Downloads over the last `days` days. `bytes` only counts what this mirror
+/// served itself, so archives and downloads sent to peers add nothing to it.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct DownloadStats {
+    pub days: u32,
+    pub downloads: u64,
+    pub bytes: u64,
+    pub daily: Vec<StatsEntry>,
+    pub files: Vec<StatsEntry>,
+    pub folders: Vec<StatsEntry>,
+    pub referrers: Vec<StatsEntry>,
+    pub countries: Vec<StatsEntry>,
+}
+
+/// The number of days statistics are asked for, a month by default and a year at most.
+pub fn period(days: Option<u32>) -> u32 {
+    days.unwrap_or(30).clamp(1, 366)
+}
+
+/// Where a download comes from, as far as `stats_buckets` wants it known.
+pub struct Visitor {
+    pub(crate) referrer: String,
+    pub(crate) country: String,
+    /// Whether the request continues a download from the middle of the file,
+    /// which was counted when it started.
+    pub(crate) resumed: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Visitor {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let bucket = |name: &str| CONFIG.stats_buckets.iter().any(|b| b == name);
+
+        let referrer = if bucket("referrer") {
+            request
+                .headers()
+                .get_one("Referer")
+                .and_then(|referrer| Absolute::parse(referrer).ok())
+                .and_then(|uri| {
+                    uri.authority()
+                        .map(|authority| authority.host().to_string())
+                })
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let country = if bucket("country") {
+            peers::client_ip(request)
+                .and_then(|ip| peers::locate(ip).0)
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let resumed = request
+            .headers()
+            .get_one("Range")
+            .is_some_and(|range| !range.trim().starts_with("bytes=0-"));
+
+        Outcome::Success(Visitor {
+            referrer,
+            country,
+            resumed,
+        })
+    }
+}
+
+/// Counts a download of a file or folder (a storage key) in today's statistics.
+pub async fn record(db: &mut PoolConnection<MySql>, key: &Path, bytes: u64, visitor: &Visitor) {
+    if visitor.resumed {
+        return;
+    }
+
+    record_download(
+        db,
+        &key.display().to_string(),
+        bytes,
+        &visitor.referrer,
+        &visitor.country,
+    )
+    .await;
+}
@@ -0,0 +1,721 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use rocket::{
+    futures::StreamExt,
+    time::{
+        format_description::well_known::{Rfc2822, Rfc3339},
+        OffsetDateTime,
+    },
+};
+use s3::{
+    command::Command, creds::Credentials, request::tokio_backend::ReqwestRequest, request::Request,
+    Bucket, Region,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+use crate::config::CONFIG;
+
+pub static STORAGE: Lazy<Storage> = Lazy::new(from_config);
+
+pub type Storage = Arc<dyn StorageBackend>;
+
+pub type StorageReader = Pin<Box<dyn AsyncRead + Send>>;
+
+#[derive(Debug, Deserialize, Clone, Serialize)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub path_style: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<u64>,
+}
+
+/// Storage for the mirror tree. Paths are relative to the root of the tree,
+/// so `private/user/file.txt` maps to `files/private/user/file.txt` locally.
+#[rocket::async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Lists the direct children of a directory.
+    async fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>>;
+
+    /// Lists every file below a directory, recursively.
+    async fn walk(&self, path: &Path) -> io::Result<Vec<(PathBuf, StorageEntry)>>;
+
+    async fn stat(&self, path: &Path) -> io::Result<StorageEntry>;
+
+    /// Opens a file for reading, optionally limited to an inclusive byte range.
+    async fn open(
+        &self,
+        path: &Path,
+        range: Option<(u64, Option<u64>)>,
+    ) -> io::Result<StorageReader>;
+
+    /// Writes a file from a reader, replacing it if it already exists.
+    async fn write(
+        &self,
+        path: &Path,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<u64>;
+
+    /// Moves a local file, such as an upload's temporary file, into storage.
+    async fn import(&self, path: &Path, source: &Path) -> io::Result<u64> {
+        let mut file = File::open(source).await?;
+        self.write(path, &mut file).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Copies a file, or a directory with everything inside it.
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    async fn delete(&self, path: &Path, recursive: bool) -> io::Result<()>;
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()>;
+
+    /// Returns the on-disk location of a path when the backend keeps files
+    /// locally, so they can be served with X-Send-File.
+    fn local_path(&self, path: &Path) -> Option<PathBuf>;
+
+    async fn exists(&self, path: &Path) -> bool {
+        self.stat(path).await.is_ok()
+    }
+}
+
+pub fn from_config() -> Storage {
+    if let Some(s3) = &CONFIG.s3 {
+        match S3Storage::new(s3) {
+            Ok(storage) => return Arc::new(storage),
+            Err(e) => eprintln!("Failed to set up S3 storage, using local files: {}", e),
+        }
+    }
+
+    Arc::new(LocalStorage::new("files"))
+}
+
+/// Strips the `files/` prefix from a path produced by `MirrorFile::get_real_path`.
+pub fn storage_key(path: &Path) -> PathBuf {
+    path.strip_prefix("files")
+        .unwrap_or(path)
+        .components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect()
+}
+
+/// Creates a directory and all of its missing parents.
+pub async fn create_dir_all(path: &Path) -> io::Result<()> {
+    let mut current = PathBuf::new();
+
+    for component in path.components() {
+        current.push(component);
+
+        match STORAGE.mkdir(&current).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn read_to_string(path: &Path) -> io::Result<String> {
+    let mut reader = STORAGE.open(&storage_key(path), None).await?;
+    let mut buffer = String::new();
+    reader.read_to_string(&mut buffer).await?;
+    Ok(buffer)
+}
+
+fn system_time_to_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+
+        if let Err(e) = std::fs::create_dir_all(&root) {
+            eprintln!("Failed to create {}: {:?}", root.display(), e);
+        }
+
+        LocalStorage { root }
+    }
+
+    fn resolve(&self, path: &Path) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// Hidden file next to `target` that a write goes to before being renamed into place.
+    fn temp_path(target: &Path) -> PathBuf {
+        let name = target
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        target.with_file_name(format!(".{}.{}.part", name, Uuid::new_v4()))
+    }
+
+    fn entry(name: String, md: &std::fs::Metadata) -> StorageEntry {
+        StorageEntry {
+            name,
+            is_dir: md.is_dir(),
+            size: md.len(),
+            modified: md.modified().ok().and_then(system_time_to_secs),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for LocalStorage {
+    async fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>> {
+        let mut entries = Vec::new();
+        let mut dir = fs::read_dir(self.resolve(path)).await?;
+
+        while let Some(entry) = dir.next_entry().await? {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+
+            // Follow symlinks like the rest of the mirror does.
+            if let Ok(md) = fs::metadata(entry.path()).await {
+                entries.push(Self::entry(name, &md));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    async fn walk(&self, path: &Path) -> io::Result<Vec<(PathBuf, StorageEntry)>> {
+        let root = self.resolve(path);
+        let base = self.root.clone();
+
+        tokio::task::spawn_blocking(move || {
+            walkdir::WalkDir::new(&root)
+                .into_iter()
+                .filter_map(Result::ok)
+                .filter_map(|entry| {
+                    let md = std::fs::metadata(entry.path()).ok()?;
+                    if !md.is_file() {
+                        return None;
+                    }
+                    let rel = entry.path().strip_prefix(&base).ok()?.to_path_buf();
+                    let name = entry.file_name().to_str()?.to_string();
+                    Some((rel, Self::entry(name, &md)))
+                })
+                .collect()
+        })
+        .await
+        .map_err(|e| Error::other(e.to_string()))
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<StorageEntry> {
+        let md = fs::metadata(self.resolve(path)).await?;
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_string();
+
+        Ok(Self::entry(name, &md))
+    }
+
+    async fn open(
+        &self,
+        path: &Path,
+        range: Option<(u64, Option<u64>)>,
+    ) -> io::Result<StorageReader> {
+        let mut file = File::open(self.resolve(path)).await?;
+
+        match range {
+            Some((start, end)) => {
+                file.seek(std::io::SeekFrom::Start(start)).await?;
+                match end {
+                    Some(end) => Ok(Box::pin(file.take(end.saturating_sub(start) + 1))),
+                    None => Ok(Box::pin(file)),
+                }
+            }
+            None => Ok(Box::pin(file)),
+        }
+    }
+
+    async fn write(
+        &self,
+        path: &Path,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<u64> {
+        let target = self.resolve(path);
+        let temp_path = Self::temp_path(&target);
+
+        let result = async {
+            let mut file = File::create(&temp_path).await?;
+            let written = io::copy(reader, &mut file).await?;
+            fs::rename(&temp_path, &target).await?;
+            Ok(written)
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        result
+    }
+
+    async fn import(&self, path: &Path, source: &Path) -> io::Result<u64> {
+        let size = fs::metadata(source).await?.len();
+
+        match fs::rename(source, self.resolve(path)).await {
+            Ok(_) => Ok(size),
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                let mut file = File::open(source).await?;
+                self.write(path, &mut file).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(self.resolve(from), self.resolve(to)).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = self.resolve(from);
+        let to = self.resolve(to);
+
+        if !fs::metadata(&from).await?.is_dir() {
+            return fs::copy(from, to).await.map(|_| ());
+        }
+
+        tokio::task::spawn_blocking(move || {
+            for entry in walkdir::WalkDir::new(&from) {
+                let entry = entry?;
+                let target = to.join(entry.path().strip_prefix(&from).map_err(Error::other)?);
+
+                if entry.file_type().is_dir() {
+                    std::fs::create_dir_all(&target)?;
+                } else {
+                    std::fs::copy(entry.path(), &target)?;
+                }
+            }
+
+            Ok(())
+        })
+        .await
+        .map_err(|e| Error::other(e.to_string()))?
+    }
+
+    async fn delete(&self, path: &Path, recursive: bool) -> io::Result<()> {
+        let full_path = self.resolve(path);
+
+        if fs::metadata(&full_path).await?.is_dir() {
+            if recursive {
+                fs::remove_dir_all(full_path).await
+            } else {
+                fs::remove_dir(full_path).await
+            }
+        } else {
+            fs::remove_file(full_path).await
+        }
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(self.resolve(path)).await
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(self.resolve(path))
+    }
+}
+
+/// S3-compatible object storage. Directories are common prefixes, and empty
+/// directories are kept alive with a zero-byte `dir/` marker object.
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+impl S3Storage {
+    pub fn new(config: &S3Config) -> Result<Self, s3::error::S3Error> {
+        let region = Region::Custom {
+            region: config.region.clone(),
+            endpoint: config.endpoint.clone(),
+        };
+        let credentials = Credentials::new(
+            Some(&config.access_key),
+            Some(&config.secret_key),
+            None,
+            None,
+            None,
+        )?;
+
+        let mut bucket = Bucket::new(&config.bucket, region, credentials)?;
+        if config.path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        let prefix = config.prefix.trim_matches('/');
+
+        Ok(S3Storage {
+            bucket,
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", prefix)
+            },
+        })
+    }
+
+    fn key(&self, path: &Path) -> String {
+        format!("{}{}", self.prefix, path.display())
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    fn dir_prefix(&self, path: &Path) -> String {
+        let key = self.key(path);
+        if key.is_empty() || key.ends_with('/') {
+            key
+        } else {
+            format!("{}/", key)
+        }
+    }
+
+    async fn keys_under(&self, prefix: &str) -> io::Result<Vec<(String, u64, Option<u64>)>> {
+        let results = self
+            .bucket
+            .list(prefix.to_string(), None)
+            .await
+            .map_err(map_s3_error)?;
+
+        Ok(results
+            .into_iter()
+            .flat_map(|r| r.contents)
+            .map(|o| (o.key, o.size, parse_s3_time(&o.last_modified)))
+            .collect())
+    }
+
+    /// Pairs every object below `from` with its key below `to`.
+    async fn key_pairs(&self, from: &Path, to: &Path) -> io::Result<Vec<(String, String)>> {
+        let entry = self.stat(from).await?;
+
+        if !entry.is_dir {
+            return Ok(vec![(self.key(from), self.key(to))]);
+        }
+
+        let from_prefix = self.dir_prefix(from);
+        let to_prefix = self.dir_prefix(to);
+
+        Ok(self
+            .keys_under(&from_prefix)
+            .await?
+            .into_iter()
+            .map(|(key, _, _)| {
+                let target = format!("{}{}", to_prefix, &key[from_prefix.len()..]);
+                (key, target)
+            })
+            .collect())
+    }
+
+    async fn copy_object(&self, source: &str, target: &str) -> io::Result<()> {
+        let code = self
+            .bucket
+            .copy_object_internal(source, target)
+            .await
+            .map_err(map_s3_error)?;
+        check_status(code)
+    }
+}
+
+fn map_s3_error(e: s3::error::S3Error) -> Error {
+    match e {
+        s3::error::S3Error::Io(e) => e,
+        s3::error::S3Error::HttpFailWithBody(404, _) => Error::from(ErrorKind::NotFound),
+        s3::error::S3Error::HttpFailWithBody(403, _) => Error::from(ErrorKind::PermissionDenied),
+        e => Error::other(e.to_string()),
+    }
+}
+
+fn check_status(code: u16) -> io::Result<()> {
+    match code {
+        200..=299 => Ok(()),
+        404 => Err(Error::from(ErrorKind::NotFound)),
+        401 | 403 => Err(Error::from(ErrorKind::PermissionDenied)),
+        code => Err(Error::other(format!(
+            "S3 request failed with status {}",
+            code
+        ))),
+    }
+}
+
+fn parse_s3_time(time: &str) -> Option<u64> {
+    OffsetDateTime::parse(time, &Rfc3339)
+        .or_else(|_| OffsetDateTime::parse(time, &Rfc2822))
+        .ok()
+        .map(|t| t.unix_timestamp().max(0) as u64)
+}
+
+fn last_segment(key: &str) -> String {
+    key.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[rocket::async_trait]
+impl StorageBackend for S3Storage {
+    async fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>> {
+        let prefix = self.dir_prefix(path);
+        let results = self
+            .bucket
+            .list(prefix.clone(), Some("/".into()))
+            .await
+            .map_err(map_s3_error)?;
+
+        let mut entries = Vec::new();
+
+        for result in results {
+            for common in result.common_prefixes.unwrap_or_default() {
+                entries.push(StorageEntry {
+                    name: last_segment(&common.prefix),
+                    is_dir: true,
+                    size: 0,
+                    modified: None,
+                });
+            }
+
+            for object in result.contents {
+                if object.key == prefix {
+                    continue;
+                }
+
+                entries.push(StorageEntry {
+                    name: last_segment(&object.key),
+                    is_dir: false,
+                    size: object.size,
+                    modified: parse_s3_time(&object.last_modified),
+                });
+            }
+        }
+
+        if entries.is_empty() && !prefix.is_empty() && !self.exists(path).await {
+            return Err(Error::from(ErrorKind::NotFound));
+        }
+
+        Ok(entries)
+    }
+
+    async fn walk(&self, path: &Path) -> io::Result<Vec<(PathBuf, StorageEntry)>> {
+        let prefix = self.dir_prefix(path);
+
+        Ok(self
+            .keys_under(&prefix)
+            .await?
+            .into_iter()
+            .filter(|(key, _, _)| !key.ends_with('/'))
+            .map(|(key, size, modified)| {
+                let rel = PathBuf::from(key.strip_prefix(&self.prefix).unwrap_or(&key));
+                let entry = StorageEntry {
+                    name: last_segment(&key),
+                    is_dir: false,
+                    size,
+                    modified,
+                };
+                (rel, entry)
+            })
+            .collect())
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<StorageEntry> {
+        let name = last_segment(&self.key(path));
+        let dir_prefix = self.dir_prefix(path);
+
+        if dir_prefix.is_empty() {
+            return Ok(StorageEntry {
+                name,
+                is_dir: true,
+                size: 0,
+                modified: None,
+            });
+        }
+
+        let (head, code) = self
+            .bucket
+            .head_object(self.key(path))
+            .await
+            .map_err(map_s3_error)?;
+
+        if check_status(code).is_ok() {
+            return Ok(StorageEntry {
+                name,
+                is_dir: false,
+                size: head.content_length.unwrap_or(0).max(0) as u64,
+                modified: head.last_modified.as_deref().and_then(parse_s3_time),
+            });
+        }
+
+        let (page, _) = self
+            .bucket
+            .list_page(dir_prefix, Some("/".into()), None, None, Some(1))
+            .await
+            .map_err(map_s3_error)?;
+
+        if page.contents.is_empty() && page.common_prefixes.unwrap_or_default().is_empty() {
+            return Err(Error::from(ErrorKind::NotFound));
+        }
+
+        Ok(StorageEntry {
+            name,
+            is_dir: true,
+            size: 0,
+            modified: None,
+        })
+    }
+
+    async fn open(
+        &self,
+        path: &Path,
+        range: Option<(u64, Option<u64>)>,
+    ) -> io::Result<StorageReader> {
+        let key = self.key(path);
+        let command = match range {
+            Some((start, end)) => Command::GetObjectRange { start, end },
+            None => Command::GetObject,
+        };
+
+        let request = ReqwestRequest::new(&self.bucket, &key, command)
+            .await
+            .map_err(map_s3_error)?;
+        let response = request
+            .response_data_to_stream()
+            .await
+            .map_err(map_s3_error)?;
+
+        check_status(response.status_code)?;
+
+        let stream = response.bytes.map(|chunk| chunk.map_err(map_s3_error));
+
+        Ok(Box::pin(StreamReader::new(stream)))
+    }
+
+    async fn write(
+        &self,
+        path: &Path,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<u64> {
+        let response = self
+            .bucket
+            .put_object_stream(reader, self.key(path))
+            .await
+            .map_err(map_s3_error)?;
+
+        check_status(response.status_code())?;
+
+        Ok(response.uploaded_bytes() as u64)
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        for (source, target) in self.key_pairs(from, to).await? {
+            self.copy_object(&source, &target).await?;
+
+            let response = self
+                .bucket
+                .delete_object(&source)
+                .await
+                .map_err(map_s3_error)?;
+            check_status(response.status_code())?;
+        }
+
+        Ok(())
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        for (source, target) in self.key_pairs(from, to).await? {
+            self.copy_object(&source, &target).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete(&self, path: &Path, recursive: bool) -> io::Result<()> {
+        let entry = self.stat(path).await?;
+
+        if !entry.is_dir {
+            let response = self
+                .bucket
+                .delete_object(self.key(path))
+                .await
+                .map_err(map_s3_error)?;
+            return check_status(response.status_code());
+        }
+
+        let prefix = self.dir_prefix(path);
+        let keys = self.keys_under(&prefix).await?;
+
+        if !recursive && keys.iter().any(|(key, _, _)| *key != prefix) {
+            return Err(Error::from(ErrorKind::DirectoryNotEmpty));
+        }
+
+        for (key, _, _) in keys {
+            let response = self
+                .bucket
+                .delete_object(&key)
+                .await
+                .map_err(map_s3_error)?;
+            check_status(response.status_code())?;
+        }
+
+        Ok(())
+    }
+
+    async fn mkdir(&self, path: &Path) -> io::Result<()> {
+        if self.exists(path).await {
+            return Err(Error::from(ErrorKind::AlreadyExists));
+        }
+
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !self.exists(parent).await {
+                return Err(Error::from(ErrorKind::NotFound));
+            }
+        }
+
+        let response = self
+            .bucket
+            .put_object(self.dir_prefix(path), &[])
+            .await
+            .map_err(map_s3_error)?;
+
+        check_status(response.status_code())
+    }
+
+    fn local_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+}
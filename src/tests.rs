@@ -53,6 +53,28 @@ Content-Disposition: form-data; name=\"path\"\r\n\r\n\
     assert_eq!(response.status(), Status::Ok);
 
     let _ = fs::remove_file("files/uploads/upload.txt");
+
+    // The upload form goes to the same place, creating the folder first
+    let form = |path: &str| {
+        client
+            .post("/upload")
+            .header(Header::new(
+                "Content-Type",
+                "multipart/form-data; boundary=TEST-BOUNDARY",
+            ))
+            .body(data.replace("/uploads/", path))
+            .dispatch()
+            .status()
+    };
+
+    assert_eq!(form("uploads/form-test"), Status::Ok);
+    assert_eq!(
+        fs::read_to_string("files/uploads/form-test/upload.txt").unwrap(),
+        "MARMAK Mirror testing!"
+    );
+    assert_eq!(form("uploads/../../outside"), Status::Forbidden);
+
+    let _ = fs::remove_dir_all("files/uploads/form-test");
 }
 
 #[test]
@@ -83,6 +105,578 @@ fn delete() {
     assert_eq!(response.status(), Status::NoContent);
 }
 
+#[test]
+fn move_and_copy() {
+    let _ = fs::create_dir_all("files/move-test/folder/");
+    let _ = fs::File::create("files/move-test/move.txt").expect("Failed to create file");
+
+    let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+    let response = client
+        .post("/api/copy")
+        .body("{\"source\":\"/move-test/move.txt\",\"destination\":\"/move-test/copy.txt\"}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(Path::new("files/move-test/move.txt").exists());
+    assert!(Path::new("files/move-test/copy.txt").exists());
+
+    let response = client
+        .post("/api/copy")
+        .body("{\"source\":\"/move-test/move.txt\",\"destination\":\"/move-test/copy.txt\"}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    let response = client
+        .post("/api/move")
+        .body("{\"source\":\"/move-test/copy.txt\",\"destination\":\"/move-test/folder\"}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(!Path::new("files/move-test/copy.txt").exists());
+    assert!(Path::new("files/move-test/folder/copy.txt").exists());
+
+    let response = client
+        .post("/api/move")
+        .body("{\"source\":\"/move-test\",\"destination\":\"/move-test/folder\"}")
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let _ = fs::remove_dir_all("files/move-test/");
+}
+
+#[test]
+fn trash() {
+    if !Path::new("files/").exists() {
+        let _ = fs::create_dir("files/");
+    }
+    let _ = fs::File::create("files/trash.txt").expect("Failed to create file");
+
+    let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+    let find_id = |client: &Client| {
+        let response = client.get("/api/trash").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let entries: Vec<serde_json::Value> = response.into_json().expect("valid trash listing");
+        entries
+            .iter()
+            .find(|e| e["original_path"] == "/trash.txt")
+            .and_then(|e| e["id"].as_str())
+            .map(str::to_string)
+            .expect("Deleted file not in the trash")
+    };
+
+    let response = client.delete("/api/trash.txt").dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    assert!(!Path::new("files/trash.txt").exists());
+
+    let id = find_id(&client);
+    let response = client.post(format!("/api/trash/{}/restore", id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(Path::new("files/trash.txt").exists());
+
+    let response = client.delete("/api/trash.txt").dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+
+    let id = find_id(&client);
+    let response = client.delete(format!("/api/trash/{}", id)).dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+
+    let response = client.post(format!("/api/trash/{}/restore", id)).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn trash_usage() {
+    use crate::trash;
+
+    let _ = fs::create_dir_all("files/private/trash-usage-test");
+    fs::write(
+        "files/private/trash-usage-test/a.txt",
+        "MARMAK Mirror testing!",
+    )
+    .unwrap();
+
+    // Deleted private files keep counting against the quota until they're purged
+    let entry = trash::move_to_trash(
+        "trash-usage-test",
+        Path::new("files/private/trash-usage-test/a.txt"),
+    )
+    .await
+    .unwrap();
+    assert_eq!(trash::usage("trash-usage-test").await, 22);
+
+    trash::restore(&entry).await.unwrap();
+    assert_eq!(trash::usage("trash-usage-test").await, 0);
+
+    let entry = trash::move_to_trash(
+        "trash-usage-test",
+        Path::new("files/private/trash-usage-test/a.txt"),
+    )
+    .await
+    .unwrap();
+    trash::purge(&entry, None).await.unwrap();
+    assert_eq!(trash::usage("trash-usage-test").await, 0);
+
+    let _ = fs::remove_dir_all("files/private/trash-usage-test");
+    let _ = fs::remove_dir_all("files/.trash/trash-usage-test");
+}
+
+#[test]
+fn file_index() {
+    let mut index = crate::file_index::FileIndex::default();
+
+    index.insert_file(Path::new("a/b/one.txt"), 10, None);
+    index.insert_file(Path::new("a/b/two.txt"), 5, None);
+    index.insert_file(Path::new("a/three.txt"), 1, None);
+    index.insert_file(Path::new(".trash/test/x/four.txt"), 100, None);
+    assert_eq!(index.size(Path::new("files/a")), Some(16));
+    assert_eq!(index.size(Path::new("files/a/b")), Some(15));
+    assert_eq!(index.size(Path::new("files/.trash")), None);
+
+    index.insert_file(Path::new("a/b/one.txt"), 20, None);
+    assert_eq!(index.size(Path::new("files/a")), Some(26));
+
+    index.remove(Path::new("a/b"));
+    assert_eq!(index.size(Path::new("files/a")), Some(1));
+    assert_eq!(index.size(Path::new("files/a/b/two.txt")), None);
+    assert_eq!(index.entries().count(), 2);
+}
+
+#[rocket::async_test]
+async fn file_index_cache() {
+    use std::sync::Arc;
+
+    use tokio::sync::RwLock;
+
+    use crate::file_index::{save, FileIndex};
+
+    let mut index = FileIndex::default();
+    index.insert_file(Path::new("cache/one.txt"), 10, Some(1700000000));
+    index.insert_file(Path::new("cache/sub/two.txt"), 5, None);
+
+    let path = Path::new("file_index_test.json");
+    save(&Arc::new(RwLock::new(index)), path).await.unwrap();
+
+    let loaded = FileIndex::load(path).unwrap();
+    let _ = fs::remove_file(path);
+
+    assert_eq!(loaded.size(Path::new("files/cache")), Some(15));
+    assert_eq!(loaded.size(Path::new("files/cache/sub/two.txt")), Some(5));
+    assert_eq!(loaded.entries().count(), 4);
+}
+
+#[rocket::async_test]
+async fn archive_stream() {
+    use std::io::Cursor;
+
+    use tokio::io::AsyncReadExt;
+
+    use crate::archiver::{collect, stream, ArchiveFormat, Viewer};
+
+    fs::create_dir_all("files/zip-test/folder").unwrap();
+    fs::write("files/zip-test/one.txt", "MARMAK Mirror testing!").unwrap();
+    fs::write("files/zip-test/folder/two.txt", "Hello").unwrap();
+    fs::write("files/zip-test/HIDDEN", "").unwrap();
+
+    let admin = Viewer {
+        user: Some("test"),
+        perms: Some(0),
+        skip_restricted: false,
+    };
+    let entries = collect(&[Path::new("files/zip-test").to_path_buf()], &admin)
+        .await
+        .unwrap();
+    assert_eq!(entries.iter().map(|entry| entry.size).sum::<u64>(), 27);
+
+    let mut buffer = Vec::new();
+    stream(entries.clone(), ArchiveFormat::Zip)
+        .read_to_end(&mut buffer)
+        .await
+        .unwrap();
+
+    let mut archive = zip::ZipArchive::new(Cursor::new(buffer)).unwrap();
+    let mut names: Vec<String> = archive.file_names().map(str::to_string).collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec!["zip-test/", "zip-test/folder/two.txt", "zip-test/one.txt"]
+    );
+    assert_eq!(archive.by_name("zip-test/one.txt").unwrap().size(), 22);
+
+    let mut buffer = Vec::new();
+    stream(entries.clone(), ArchiveFormat::TarGz)
+        .read_to_end(&mut buffer)
+        .await
+        .unwrap();
+
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(buffer.as_slice()));
+    let mut names: Vec<String> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec!["zip-test/", "zip-test/folder/two.txt", "zip-test/one.txt"]
+    );
+
+    // Tar entries keep the size they were listed with, and a file that got shorter
+    // or went away fails the download instead of ending it early
+    fs::write(
+        "files/zip-test/one.txt",
+        "MARMAK Mirror testing! Twice as long",
+    )
+    .unwrap();
+    let mut buffer = Vec::new();
+    stream(entries.clone(), ArchiveFormat::Tar)
+        .read_to_end(&mut buffer)
+        .await
+        .unwrap();
+    let mut archive = tar::Archive::new(buffer.as_slice());
+    let sizes: Vec<u64> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().size())
+        .collect();
+    assert!(sizes.contains(&22));
+
+    fs::write("files/zip-test/one.txt", "MARMAK").unwrap();
+    let mut buffer = Vec::new();
+    assert!(stream(entries.clone(), ArchiveFormat::Tar)
+        .read_to_end(&mut buffer)
+        .await
+        .is_err());
+
+    fs::remove_file("files/zip-test/one.txt").unwrap();
+    let mut buffer = Vec::new();
+    assert!(stream(entries, ArchiveFormat::Zip)
+        .read_to_end(&mut buffer)
+        .await
+        .is_err());
+
+    let user = Viewer {
+        perms: Some(1),
+        ..admin
+    };
+    let entries = collect(&[Path::new("files/zip-test").to_path_buf()], &user)
+        .await
+        .unwrap();
+    assert!(entries.is_empty());
+
+    let _ = fs::remove_dir_all("files/zip-test");
+
+    // Restricted folders and other users' private files are left out like in listings
+    fs::create_dir_all("files/zip-rules/locked").unwrap();
+    fs::write("files/zip-rules/open.txt", "a").unwrap();
+    fs::write("files/zip-rules/locked/RESTRICTED", "").unwrap();
+    fs::write("files/zip-rules/locked/secret.txt", "b").unwrap();
+    fs::create_dir_all("files/private/zip-other").unwrap();
+    fs::write("files/private/zip-other/diary.txt", "c").unwrap();
+    fs::create_dir_all("files/private/zip-own").unwrap();
+    fs::write("files/private/zip-own/notes.txt", "d").unwrap();
+
+    let names = |entries: Vec<crate::archiver::ArchiveEntry>| {
+        entries
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>()
+    };
+    let user = Viewer {
+        user: Some("zip-own"),
+        perms: Some(1),
+        skip_restricted: false,
+    };
+
+    let rules = [Path::new("files/zip-rules").to_path_buf()];
+    assert!(names(collect(&rules, &user).await.unwrap())
+        .contains(&"zip-rules/locked/secret.txt".to_string()));
+    let restricted = Viewer {
+        skip_restricted: true,
+        ..user
+    };
+    assert_eq!(
+        names(collect(&rules, &restricted).await.unwrap()),
+        vec!["zip-rules/", "zip-rules/open.txt"]
+    );
+
+    assert_eq!(
+        names(
+            collect(&[Path::new("files/private/zip-own").to_path_buf()], &user)
+                .await
+                .unwrap()
+        ),
+        vec!["zip-own/", "zip-own/notes.txt"]
+    );
+    assert!(
+        collect(&[Path::new("files/private/zip-other").to_path_buf()], &user)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    let _ = fs::remove_dir_all("files/zip-rules");
+    let _ = fs::remove_dir_all("files/private/zip-other");
+    let _ = fs::remove_dir_all("files/private/zip-own");
+}
+
+#[test]
+fn archive_inspector() {
+    use std::io::Write;
+
+    use zip::write::SimpleFileOptions;
+
+    fs::create_dir_all("files/inspect-test").unwrap();
+
+    let mut zip = zip::ZipWriter::new(fs::File::create("files/inspect-test/test.zip").unwrap());
+    zip.start_file("docs/readme.txt", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"MARMAK Mirror testing!").unwrap();
+    zip.start_file("docs/sub/two.txt", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"Hello").unwrap();
+    zip.start_file("top.txt", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"Hi").unwrap();
+    zip.finish().unwrap();
+
+    let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(
+        fs::File::create("files/inspect-test/test.tar.gz").unwrap(),
+        flate2::Compression::default(),
+    ));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    header.set_mtime(1_700_000_000);
+    tar.append_data(&mut header, "folder/two.txt", "Hello".as_bytes())
+        .unwrap();
+    tar.into_inner().unwrap().finish().unwrap();
+
+    let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+    let response = client.get("/api/inspect-test/test.zip").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let json: serde_json::Value = response.into_json().unwrap();
+    let entries = json["entries"].as_array().unwrap();
+
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["name"], "docs");
+    assert_eq!(entries[0]["is_dir"], true);
+    assert_eq!(entries[0]["size"], 27);
+    assert_eq!(entries[0]["children"][0]["path"], "docs/sub");
+    assert_eq!(entries[0]["children"][1]["path"], "docs/readme.txt");
+    assert_eq!(entries[0]["children"][1]["encrypted"], false);
+    assert_eq!(entries[1]["name"], "top.txt");
+    assert_eq!(entries[1]["size"], 2);
+
+    let response = client.get("/api/inspect-test/test.tar.gz").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let json: serde_json::Value = response.into_json().unwrap();
+    let file = &json["entries"][0]["children"][0];
+
+    assert_eq!(file["path"], "folder/two.txt");
+    assert_eq!(file["size"], 5);
+    assert_eq!(file["modified"], 1_700_000_000);
+    assert!(file["compressed_size"].is_null());
+
+    let response = client
+        .get("/inspect-test/test.zip")
+        .cookie(rocket::http::Cookie::new("viewers", "true"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.into_string().unwrap().contains("readme.txt"));
+
+    // Archives in a bucket are read with ranged requests instead of copied first
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    let len = fs::metadata("files/inspect-test/test.zip").unwrap().len();
+    let reader = crate::inspector::RangeReader::new(
+        "inspect-test/test.zip".into(),
+        len,
+        runtime.handle().clone(),
+    );
+    let mut zip = zip::ZipArchive::new(reader).unwrap();
+    let mut readme = String::new();
+    std::io::Read::read_to_string(&mut zip.by_name("docs/readme.txt").unwrap(), &mut readme)
+        .unwrap();
+    assert_eq!(readme, "MARMAK Mirror testing!");
+
+    let _ = fs::remove_dir_all("files/inspect-test");
+}
+
+#[test]
+fn archive_entries() {
+    use std::io::Write;
+
+    use zip::write::SimpleFileOptions;
+
+    fs::create_dir_all("files/entry-test").unwrap();
+
+    let mut zip = zip::ZipWriter::new(fs::File::create("files/entry-test/test.zip").unwrap());
+    zip.start_file("docs/readme.md", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"# MARMAK Mirror").unwrap();
+    zip.start_file("docs/two.txt", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"Hello").unwrap();
+    zip.finish().unwrap();
+
+    let mut tar = tar::Builder::new(fs::File::create("files/entry-test/test.tar").unwrap());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(22);
+    header.set_mode(0o644);
+    tar.append_data(
+        &mut header,
+        "folder/one.txt",
+        "MARMAK Mirror testing!".as_bytes(),
+    )
+    .unwrap();
+    tar.finish().unwrap();
+
+    let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+    let response = client.get("/entry-test/test.zip/docs/two.txt").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), "Hello");
+
+    let response = client
+        .get("/entry-test/test.tar/folder/one.txt?download")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), "MARMAK Mirror testing!");
+
+    let response = client
+        .get("/entry-test/test.zip/docs/readme.md")
+        .cookie(rocket::http::Cookie::new("viewers", "true"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response
+        .into_string()
+        .unwrap()
+        .contains("<h1>MARMAK Mirror</h1>"));
+
+    let response = client
+        .get("/api/entry-test/test.zip/docs/two.txt")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let json: serde_json::Value = response.into_json().unwrap();
+    assert_eq!(json["path"], "docs/two.txt");
+    assert_eq!(json["size"], 5);
+
+    let response = client.get("/entry-test/test.zip/missing.txt").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = client.get("/entry-test/test.zip/docs").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    let _ = fs::remove_dir_all("files/entry-test");
+}
+
+fn wait_for_job(client: &Client, id: &str) -> serde_json::Value {
+    for _ in 0..100 {
+        let job: serde_json::Value = client
+            .get(format!("/api/jobs/{}", id))
+            .dispatch()
+            .into_json()
+            .unwrap();
+
+        if job["state"] != "running" {
+            return job;
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    panic!("Job {} didn't finish", id);
+}
+
+#[test]
+fn extract_and_compress() {
+    use std::io::Write;
+
+    use zip::write::SimpleFileOptions;
+
+    let _ = fs::remove_dir_all("files/jobs-test");
+    fs::create_dir_all("files/jobs-test").unwrap();
+
+    let mut zip = zip::ZipWriter::new(fs::File::create("files/jobs-test/good.zip").unwrap());
+    zip.start_file("docs/one.txt", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"MARMAK Mirror testing!").unwrap();
+    zip.finish().unwrap();
+
+    let mut zip = zip::ZipWriter::new(fs::File::create("files/jobs-test/evil.zip").unwrap());
+    zip.start_file("fine.txt", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"Hello").unwrap();
+    zip.start_file("../../evil.txt", SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"Hello").unwrap();
+    zip.finish().unwrap();
+
+    let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+    let response = client
+        .post("/api/extract")
+        .body("{\"path\":\"jobs-test/good.zip\"}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Accepted);
+    let job: serde_json::Value = response.into_json().unwrap();
+    assert_eq!(job["target"], "/jobs-test/good");
+
+    let job = wait_for_job(&client, job["id"].as_str().unwrap());
+    assert_eq!(job["state"], "done");
+    assert_eq!(job["done"], 22);
+    assert_eq!(
+        fs::read_to_string("files/jobs-test/good/docs/one.txt").unwrap(),
+        "MARMAK Mirror testing!"
+    );
+
+    let response = client
+        .post("/api/extract")
+        .body("{\"path\":\"jobs-test/good.zip\"}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+
+    let response = client
+        .post("/api/extract")
+        .body("{\"path\":\"jobs-test/evil.zip\",\"target\":\"jobs-test/evil\"}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Accepted);
+    let job: serde_json::Value = response.into_json().unwrap();
+
+    let job = wait_for_job(&client, job["id"].as_str().unwrap());
+    assert_eq!(job["state"], "failed");
+    assert!(job["error"].as_str().unwrap().contains("unsafe path"));
+    assert!(!Path::new("files/jobs-test/evil").exists());
+    assert!(!Path::new("evil.txt").exists());
+
+    let response = client
+        .post("/api/compress")
+        .body("{\"paths\":[\"jobs-test/good\"],\"target\":\"jobs-test/out.tar.gz\"}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Accepted);
+    let job: serde_json::Value = response.into_json().unwrap();
+
+    let job = wait_for_job(&client, job["id"].as_str().unwrap());
+    assert_eq!(job["state"], "done");
+
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(
+        fs::File::open("files/jobs-test/out.tar.gz").unwrap(),
+    ));
+    let mut names: Vec<String> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["good/", "good/docs/one.txt"]);
+
+    let response = client.get("/api/jobs/missing").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    let _ = fs::remove_dir_all("files/jobs-test");
+}
+
 #[test]
 fn strings() {
     let languages: Vec<(String, String)> = crate::TranslationStore::new()
@@ -98,3 +692,1349 @@ fn strings() {
         assert_eq!(response.status(), Status::Ok)
     }
 }
+
+async fn check_storage_backend(storage: &dyn crate::storage::StorageBackend) {
+    use tokio::io::AsyncReadExt;
+
+    let root = Path::new("storage-test");
+    let _ = storage.delete(root, true).await;
+
+    storage
+        .mkdir(root)
+        .await
+        .expect("Failed to create directory");
+    storage
+        .write(
+            &root.join("file.txt"),
+            &mut "MARMAK Mirror testing!".as_bytes(),
+        )
+        .await
+        .expect("Failed to write file");
+
+    let entries = storage.list(root).await.expect("Failed to list directory");
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "file.txt");
+    assert_eq!(entries[0].size, 22);
+
+    let mut range = String::new();
+    storage
+        .open(&root.join("file.txt"), Some((7, Some(12))))
+        .await
+        .expect("Failed to open file")
+        .read_to_string(&mut range)
+        .await
+        .unwrap();
+    assert_eq!(range, "Mirror");
+
+    storage
+        .rename(&root.join("file.txt"), &root.join("renamed.txt"))
+        .await
+        .expect("Failed to rename file");
+    assert!(!storage.exists(&root.join("file.txt")).await);
+    assert!(
+        !storage
+            .stat(&root.join("renamed.txt"))
+            .await
+            .unwrap()
+            .is_dir
+    );
+    assert!(storage.stat(root).await.unwrap().is_dir);
+
+    storage
+        .mkdir(&root.join("folder"))
+        .await
+        .expect("Failed to create directory");
+    storage
+        .copy(&root.join("renamed.txt"), &root.join("folder/copy.txt"))
+        .await
+        .expect("Failed to copy file");
+    storage
+        .copy(&root.join("folder"), &root.join("folder2"))
+        .await
+        .expect("Failed to copy directory");
+    assert_eq!(
+        storage
+            .stat(&root.join("folder2/copy.txt"))
+            .await
+            .unwrap()
+            .size,
+        22
+    );
+    assert!(storage.exists(&root.join("renamed.txt")).await);
+
+    let source = std::env::temp_dir().join(format!("mirror-import-{}", uuid::Uuid::new_v4()));
+    fs::write(&source, "MARMAK Mirror testing!").unwrap();
+    assert_eq!(
+        storage
+            .import(&root.join("imported.txt"), &source)
+            .await
+            .expect("Failed to import file"),
+        22
+    );
+    let _ = fs::remove_file(&source);
+    assert_eq!(
+        storage.stat(&root.join("imported.txt")).await.unwrap().size,
+        22
+    );
+
+    assert!(storage.delete(root, false).await.is_err());
+    storage
+        .delete(root, true)
+        .await
+        .expect("Failed to delete directory");
+    assert!(!storage.exists(root).await);
+}
+
+#[rocket::async_test]
+async fn local_storage() {
+    let storage = crate::storage::LocalStorage::new("target/storage-test");
+    check_storage_backend(&storage).await;
+}
+
+#[rocket::async_test]
+async fn storage_markers() {
+    use std::sync::Arc;
+
+    use rocket::tokio::sync::RwLock;
+
+    use crate::{
+        file_index::{self, FileIndex},
+        MirrorFile,
+    };
+
+    let _ = fs::remove_dir_all("files/markertest");
+    fs::create_dir_all("files/markertest/hidden/inner").unwrap();
+    fs::create_dir_all("files/markertest/restricted").unwrap();
+    fs::write("files/markertest/hidden/HIDDEN", "").unwrap();
+    fs::write("files/markertest/restricted/RESTRICTED", "").unwrap();
+
+    let hidden = Path::new("files/markertest/hidden/inner");
+    assert!(MirrorFile::is_hidden(hidden, None).await);
+    assert!(MirrorFile::is_hidden(hidden, Some(1)).await);
+    assert!(!MirrorFile::is_hidden(hidden, Some(0)).await);
+    assert!(!MirrorFile::is_hidden(Path::new("files/markertest/restricted"), None).await);
+
+    assert!(MirrorFile::is_hidden_path(Path::new("/markertest/restricted/a.txt"), None).await);
+    assert!(!MirrorFile::is_hidden_path(Path::new("/markertest/restricted/a.txt"), Some(1)).await);
+    assert!(!MirrorFile::is_hidden_path(Path::new("/markertest/a.txt"), None).await);
+
+    // Markers are cached, and forgotten once the index sees them removed
+    let index = Arc::new(RwLock::new(FileIndex::default()));
+    file_index::refresh(&index, Path::new("files/markertest")).await;
+    fs::remove_file("files/markertest/hidden/HIDDEN").unwrap();
+    file_index::refresh(&index, Path::new("files/markertest")).await;
+    assert!(!MirrorFile::is_hidden(hidden, None).await);
+
+    let _ = fs::remove_dir_all("files/markertest");
+}
+
+/// Needs an S3-compatible server (such as MinIO), so it only runs with
+/// `cargo test -- --ignored` and `MIRROR_TEST_S3` holding the same JSON as `MIRROR_S3`.
+#[rocket::async_test]
+#[ignore]
+async fn s3_storage() {
+    let config = std::env::var("MIRROR_TEST_S3").expect("MIRROR_TEST_S3 isn't set");
+    let config = serde_json::from_str(&config).expect("Invalid MIRROR_TEST_S3");
+    let storage = crate::storage::S3Storage::new(&config).expect("valid S3 config");
+    check_storage_backend(&storage).await;
+}
+
+#[test]
+fn tus_upload() {
+    let _ = fs::create_dir_all("files/uploads/");
+    let _ = fs::remove_file("files/uploads/tus.txt");
+    let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+    let response = client.options("/api/tus").dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(response.headers().get_one("Tus-Version"), Some("1.0.0"));
+
+    let response = client
+        .post("/api/tus")
+        .header(Header::new("Upload-Length", "22"))
+        .dispatch();
+    assert_eq!(response.status(), Status::PreconditionFailed);
+
+    // "tus.txt" and "uploads"
+    let response = client
+        .post("/api/tus")
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .header(Header::new("Upload-Length", "22"))
+        .header(Header::new(
+            "Upload-Metadata",
+            "filename dHVzLnR4dA==,path dXBsb2Fkcw==",
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let location = response.headers().get_one("Location").unwrap().to_string();
+
+    let patch = |offset: &str, body: &str| {
+        client
+            .patch(location.clone())
+            .header(Header::new("Tus-Resumable", "1.0.0"))
+            .header(Header::new("Upload-Offset", offset.to_string()))
+            .header(Header::new(
+                "Content-Type",
+                "application/offset+octet-stream",
+            ))
+            .body(body)
+            .dispatch()
+    };
+
+    let response = patch("0", "MARMAK ");
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(response.headers().get_one("Upload-Offset"), Some("7"));
+
+    let response = patch("0", "MARMAK ");
+    assert_eq!(response.status(), Status::Conflict);
+
+    let response = client
+        .head(location.clone())
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Upload-Offset"), Some("7"));
+    assert_eq!(response.headers().get_one("Upload-Length"), Some("22"));
+
+    let response = patch("7", "Mirror testing!");
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(response.headers().get_one("Upload-Offset"), Some("22"));
+    assert_eq!(
+        fs::read_to_string("files/uploads/tus.txt").unwrap(),
+        "MARMAK Mirror testing!"
+    );
+
+    let response = client
+        .head(location.clone())
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = client
+        .post("/api/tus")
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .header(Header::new("Upload-Length", "22"))
+        .header(Header::new("Upload-Metadata", "filename dHVzLnR4dA=="))
+        .dispatch();
+    let location = response.headers().get_one("Location").unwrap().to_string();
+
+    let response = client
+        .delete(location.clone())
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+
+    let response = client
+        .head(location)
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    let _ = fs::remove_file("files/uploads/tus.txt");
+}
+
+#[rocket::async_test]
+async fn quota_reservations() {
+    use std::sync::Arc;
+
+    use rocket::tokio::sync::RwLock;
+
+    use crate::{file_index::FileIndex, jwt::JWT, quota};
+
+    let sizes = Arc::new(RwLock::new(FileIndex::default()));
+    let reservations = quota::Reservations::default();
+    // Regular users get a 2 GB private folder
+    let token = JWT::default();
+    let gb = 1_000_000_000;
+
+    quota::reserve(&reservations, &sizes, &token, "a", 1_500_000_000)
+        .await
+        .unwrap();
+    assert_eq!(
+        quota::reserve(&reservations, &sizes, &token, "b", gb).await,
+        Err(Status::InsufficientStorage)
+    );
+
+    quota::reserve(&reservations, &sizes, &token, "a", gb)
+        .await
+        .unwrap();
+    quota::reserve(&reservations, &sizes, &token, "b", gb)
+        .await
+        .unwrap();
+    assert_eq!(quota::reserved(&reservations, "Nobody").await, 2 * gb);
+
+    quota::release(&reservations, "Nobody", "a").await;
+    assert_eq!(quota::reserved(&reservations, "Nobody").await, gb);
+}
+
+#[rocket::async_test]
+async fn idle_reservations() {
+    use std::{sync::Arc, time::Duration};
+
+    use rocket::tokio::{sync::RwLock, time::sleep};
+
+    use crate::{file_index::FileIndex, jwt::JWT, quota, tus};
+
+    let sizes = Arc::new(RwLock::new(FileIndex::default()));
+    let reservations = quota::Reservations::default();
+    // A user of its own, so other tests' uploads in .chunks are left alone
+    let mut token = JWT::default();
+    token.claims.sub = "idle-reservations-test".into();
+
+    fs::create_dir_all(".chunks/idle-reservations-test/idle-upload").unwrap();
+    quota::reserve(&reservations, &sizes, &token, "chunked/idle-upload", 1000)
+        .await
+        .unwrap();
+    assert_eq!(
+        quota::reserved(&reservations, "idle-reservations-test").await,
+        1000
+    );
+    sleep(Duration::from_millis(20)).await;
+
+    // Uploads that never expire keep their data but free the quota they held
+    tus::reap(&reservations, Duration::from_millis(10), true)
+        .await
+        .unwrap();
+    assert_eq!(
+        quota::reserved(&reservations, "idle-reservations-test").await,
+        0
+    );
+    assert!(Path::new(".chunks/idle-reservations-test/idle-upload").is_dir());
+
+    let _ = fs::remove_dir_all(".chunks/idle-reservations-test");
+}
+
+#[test]
+fn upload_chunked() {
+    let _ = fs::create_dir_all("files/uploads/");
+    let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+    let chunk = |index: usize, body: &str, total_size: usize| {
+        let data = format!(
+            "--TEST-BOUNDARY\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"blob\"\r\n\
+Content-Type: application/octet-stream\r\n\r\n\
+{}\r\n\
+--TEST-BOUNDARY\r\n\
+Content-Disposition: form-data; name=\"fileid\"\r\n\r\n\
+chunked-test-{}\r\n\
+--TEST-BOUNDARY\r\n\
+Content-Disposition: form-data; name=\"filename\"\r\n\r\n\
+chunked.txt\r\n\
+--TEST-BOUNDARY\r\n\
+Content-Disposition: form-data; name=\"chunkindex\"\r\n\r\n\
+{}\r\n\
+--TEST-BOUNDARY\r\n\
+Content-Disposition: form-data; name=\"totalchunks\"\r\n\r\n\
+2\r\n\
+--TEST-BOUNDARY\r\n\
+Content-Disposition: form-data; name=\"totalsize\"\r\n\r\n\
+{}\r\n\
+--TEST-BOUNDARY--\r\n",
+            body, total_size, index, total_size
+        );
+
+        client
+            .post("/api/upload_chunked?path=uploads&share=false")
+            .header(Header::new(
+                "Content-Type",
+                "multipart/form-data; boundary=TEST-BOUNDARY",
+            ))
+            .body(data)
+            .dispatch()
+            .status()
+    };
+
+    assert_eq!(chunk(0, "MARMAK ", 22), Status::Ok);
+    assert_eq!(chunk(1, "Mirror testing!", 22), Status::Ok);
+    assert_eq!(
+        fs::read_to_string("files/uploads/chunked.txt").unwrap(),
+        "MARMAK Mirror testing!"
+    );
+
+    // Sending more than was declared throws the upload away
+    assert_eq!(
+        chunk(0, "MARMAK Mirror testing!", 10),
+        Status::PayloadTooLarge
+    );
+    assert!(!Path::new(".chunks/test/chunked-test-10").exists());
+
+    let _ = fs::remove_file("files/uploads/chunked.txt");
+}
+
+#[rocket::get("/ranged")]
+async fn ranged_file() -> Option<crate::ranged::RangedFile> {
+    crate::ranged::RangedFile::open(Path::new("files/ranged-test.txt"), "no-cache")
+        .await
+        .ok()
+}
+
+/// The same file read through the storage backend, the way buckets are served.
+#[rocket::get("/ranged-stored")]
+async fn ranged_stored_file() -> Option<crate::ranged::RangedFile> {
+    crate::ranged::RangedFile::stored(Path::new("ranged-test.txt"), "no-cache")
+        .await
+        .ok()
+}
+
+#[test]
+fn ranged_requests() {
+    use crate::ranged::parse_ranges;
+
+    assert_eq!(parse_ranges("bytes=0-499", 1000), Some(vec![(0, 499)]));
+    assert_eq!(
+        parse_ranges("bytes=500-, -100", 1000),
+        Some(vec![(500, 999), (900, 999)])
+    );
+    assert_eq!(parse_ranges("bytes=990-2000", 1000), Some(vec![(990, 999)]));
+    assert_eq!(parse_ranges("bytes=1000-", 1000), Some(vec![]));
+    assert_eq!(parse_ranges("bytes=5-1", 1000), None);
+    assert_eq!(parse_ranges("lines=1-2", 1000), None);
+
+    let _ = fs::create_dir_all("files");
+    fs::write("files/ranged-test.txt", "MARMAK Mirror testing!").unwrap();
+
+    let client = Client::tracked(
+        rocket::build().mount("/", rocket::routes![ranged_file, ranged_stored_file]),
+    )
+    .expect("valid rocket instance");
+
+    let response = client.get("/ranged").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Accept-Ranges"), Some("bytes"));
+    let etag = response.headers().get_one("ETag").unwrap().to_string();
+    let last_modified = response
+        .headers()
+        .get_one("Last-Modified")
+        .unwrap()
+        .to_string();
+
+    let response = client
+        .get("/ranged")
+        .header(Header::new("Range", "bytes=7-12"))
+        .dispatch();
+    assert_eq!(response.status(), Status::PartialContent);
+    assert_eq!(
+        response.headers().get_one("Content-Range"),
+        Some("bytes 7-12/22")
+    );
+    assert_eq!(response.into_string().unwrap(), "Mirror");
+
+    let response = client
+        .get("/ranged")
+        .header(Header::new("Range", "bytes=0-5,-8"))
+        .dispatch();
+    assert_eq!(response.status(), Status::PartialContent);
+    let body = response.into_string().unwrap();
+    assert!(body.contains("Content-Range: bytes 0-5/22\r\n\r\nMARMAK\r\n"));
+    assert!(body.contains("Content-Range: bytes 14-21/22\r\n\r\ntesting!\r\n"));
+
+    let response = client
+        .get("/ranged")
+        .header(Header::new("Range", "bytes=30-"))
+        .dispatch();
+    assert_eq!(response.status(), Status::RangeNotSatisfiable);
+
+    let response = client
+        .get("/ranged")
+        .header(Header::new("Range", "bytes=7-12"))
+        .header(Header::new("If-Range", "\"stale\""))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = client
+        .get("/ranged")
+        .header(Header::new("If-None-Match", etag))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotModified);
+
+    let response = client
+        .get("/ranged")
+        .header(Header::new("If-Modified-Since", last_modified))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotModified);
+
+    let response = client
+        .get("/ranged-stored")
+        .header(Header::new("Range", "bytes=7-12"))
+        .dispatch();
+    assert_eq!(response.status(), Status::PartialContent);
+    assert_eq!(
+        response.headers().get_one("Content-Range"),
+        Some("bytes 7-12/22")
+    );
+    let etag = response.headers().get_one("ETag").unwrap().to_string();
+    assert_eq!(response.into_string().unwrap(), "Mirror");
+
+    let response = client
+        .get("/ranged-stored")
+        .header(Header::new("Range", "bytes=0-5,-8"))
+        .dispatch();
+    let body = response.into_string().unwrap();
+    assert!(body.contains("Content-Range: bytes 14-21/22\r\n\r\ntesting!\r\n"));
+
+    let response = client
+        .get("/ranged-stored")
+        .header(Header::new("If-None-Match", etag))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotModified);
+
+    let _ = fs::remove_file("files/ranged-test.txt");
+}
+
+#[test]
+fn dav() {
+    use std::sync::Arc;
+
+    use rocket::tokio::sync::RwLock;
+
+    use crate::{dav, file_index::FileIndex, quota::Reservations};
+
+    let _ = fs::remove_dir_all("files/private/test/davtest");
+    let client = Client::tracked(
+        rocket::build()
+            .manage(Arc::new(RwLock::new(FileIndex::default())))
+            .manage(Reservations::default())
+            .attach(dav::build()),
+    )
+    .expect("valid rocket instance");
+
+    // WebDAV methods other than the standard ones arrive tunnelled through POST
+    let request = |method: &str, uri: &str| {
+        client
+            .post(uri.to_string())
+            .header(Header::new("X-HTTP-Method-Override", method.to_string()))
+    };
+
+    let response = client.options("/dav/").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("DAV"), Some("1, 2"));
+
+    let response = request("PROPFIND", "/dav/")
+        .header(Header::new("Depth", "0"))
+        .dispatch();
+    assert_eq!(response.status(), Status::MultiStatus);
+    assert!(response
+        .into_string()
+        .unwrap()
+        .contains("<D:href>/dav/</D:href>"));
+
+    let response = request("MKCOL", "/dav/private/davtest/nested/deeper").dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+    let response = request("MKCOL", "/dav/private/davtest").dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let response = request("MKCOL", "/dav/private/davtest").dispatch();
+    assert_eq!(response.status(), Status::MethodNotAllowed);
+
+    let response = client
+        .put("/dav/private/davtest/a.txt")
+        .body("MARMAK Mirror testing!")
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+    assert_eq!(
+        client.get("/dav/private/davtest/a.txt").dispatch().status(),
+        Status::Ok
+    );
+    assert_eq!(
+        fs::read_to_string("files/private/test/davtest/a.txt").unwrap(),
+        "MARMAK Mirror testing!"
+    );
+
+    let response = request("PROPFIND", "/dav/private/davtest/")
+        .header(Header::new("Depth", "1"))
+        .body(
+            "<?xml version=\"1.0\"?><propfind xmlns=\"DAV:\" xmlns:x=\"urn:test\">\
+<prop><getcontentlength/><x:color/></prop></propfind>",
+        )
+        .dispatch();
+    assert_eq!(response.status(), Status::MultiStatus);
+    let body = response.into_string().unwrap();
+    assert!(body.contains("<D:href>/dav/private/davtest/a.txt</D:href>"));
+    assert!(body.contains("<D:getcontentlength>22</D:getcontentlength>"));
+    assert!(body.contains("<P:color xmlns:P=\"urn:test\"/>"));
+    assert!(body.contains("HTTP/1.1 404 Not Found"));
+
+    let response = request("LOCK", "/dav/private/davtest/a.txt")
+        .body(
+            "<?xml version=\"1.0\"?><D:lockinfo xmlns:D=\"DAV:\"><D:lockscope><D:exclusive/></D:lockscope>\
+<D:locktype><D:write/></D:locktype><D:owner>tester</D:owner></D:lockinfo>",
+        )
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let lock_token = response
+        .headers()
+        .get_one("Lock-Token")
+        .unwrap()
+        .to_string();
+    assert!(response
+        .into_string()
+        .unwrap()
+        .contains("<D:owner>tester</D:owner>"));
+
+    let response = client
+        .put("/dav/private/davtest/a.txt")
+        .body("Overwritten")
+        .dispatch();
+    assert_eq!(response.status(), Status::Locked);
+    let response = client
+        .put("/dav/private/davtest/a.txt")
+        .header(Header::new("If", format!("({})", lock_token)))
+        .body("Overwritten")
+        .dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+
+    let response = request("MOVE", "/dav/private/davtest/a.txt")
+        .header(Header::new(
+            "Destination",
+            "http://localhost/dav/private/davtest/b%20c.txt",
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Locked);
+
+    let response = request("UNLOCK", "/dav/private/davtest/a.txt")
+        .header(Header::new("Lock-Token", lock_token))
+        .dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+
+    let response = request("MOVE", "/dav/private/davtest/a.txt")
+        .header(Header::new(
+            "Destination",
+            "http://localhost/dav/private/davtest/b%20c.txt",
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+    assert_eq!(
+        fs::read_to_string("files/private/test/davtest/b c.txt").unwrap(),
+        "Overwritten"
+    );
+
+    let response = request("COPY", "/dav/private/davtest/b%20c.txt")
+        .header(Header::new("Destination", "/dav/private/davtest/d.txt"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let response = request("COPY", "/dav/private/davtest/d.txt")
+        .header(Header::new("Destination", "/dav/private/davtest/b%20c.txt"))
+        .header(Header::new("Overwrite", "F"))
+        .dispatch();
+    assert_eq!(response.status(), Status::PreconditionFailed);
+
+    let response = client.delete("/dav/private/davtest").dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    assert!(!Path::new("files/private/test/davtest").exists());
+}
+
+#[rocket::async_test]
+async fn manifest() {
+    use std::sync::Arc;
+
+    use rocket::tokio::sync::RwLock;
+
+    use crate::{file_index::FileIndex, manifest};
+
+    let _ = fs::create_dir_all("files/manifest-test");
+    fs::write("files/manifest-test/a.txt", "MARMAK Mirror testing!").unwrap();
+    let _ = fs::create_dir_all("files/manifest-test/hidden");
+    fs::write("files/manifest-test/hidden/HIDDEN", "").unwrap();
+    fs::write("files/manifest-test/hidden/b.txt", "hidden").unwrap();
+
+    let mut index = FileIndex::default();
+    index.insert_file(Path::new("manifest-test/a.txt"), 22, Some(1));
+    index.insert_file(Path::new("manifest-test/hidden/b.txt"), 6, Some(1));
+    index.insert_file(Path::new("private/test/secret.txt"), 5, Some(1));
+    index.insert_file(Path::new("uploads/upload.txt"), 5, Some(1));
+    let index = Arc::new(RwLock::new(index));
+
+    let first = manifest::generate(&index, None, None).await;
+    assert_eq!(first.files.len(), 1);
+    assert_eq!(first.files[0].path, "manifest-test/a.txt");
+    assert_eq!(
+        first.files[0].sha256,
+        "f469bf8e7cbceb2aefcd0d139d68f6744046333a5698e3ba299555a15e4bad99"
+    );
+
+    // Unchanged files keep their hash without being read again
+    fs::remove_file("files/manifest-test/a.txt").unwrap();
+    let mut previous = first.clone();
+    previous.generated -= 10;
+    previous.tracked_since -= 10;
+    previous.files[0].changed -= 10;
+    let second = manifest::generate(&index, Some(&previous), None).await;
+    assert_eq!(second.files[0].sha256, first.files[0].sha256);
+    assert!(second.since(previous.generated).files.is_empty());
+
+    index.write().await.remove(Path::new("manifest-test/a.txt"));
+    let third = manifest::generate(&index, Some(&second), None).await;
+    assert!(third.files.is_empty());
+    assert_eq!(third.removed[0].path, "manifest-test/a.txt");
+
+    let delta = third.since(third.tracked_since);
+    assert!(delta.delta);
+    assert_eq!(delta.removed.len(), 1);
+    assert!(!third.since(third.tracked_since - 1).delta);
+
+    let _ = fs::remove_dir_all("files/manifest-test");
+}
+
+#[rocket::async_test]
+async fn upstream_sync() {
+    use std::sync::Arc;
+
+    use rocket::tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::RwLock,
+    };
+
+    use crate::{
+        file_index::FileIndex,
+        upstream::{sync, UpstreamConfig, UpstreamKind},
+    };
+
+    // A stand-in upstream, serving a directory index and a mirror listing
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    rocket::tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![0; 4096];
+            let read = stream.read(&mut request).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&request[..read]).to_string();
+            let mut line = request.split_whitespace();
+            let (method, path) = (line.next().unwrap_or(""), line.next().unwrap_or(""));
+
+            let body = match path {
+                "/pub/" => concat!(
+                    "<a href=\"../\">../</a><a href=\"?C=N;O=D\">Name</a>",
+                    "<a href=\"a.txt\">a.txt</a><a href=\"sub/\">sub/</a>",
+                    "<a href=\"skip.log\">skip.log</a><a href=\"bad.txt\">bad.txt</a>",
+                    "<a href=\"SHA256SUMS\">SHA256SUMS</a><a href=\"http://example.com/x\">x</a>",
+                    "<a href=\"gone.txt\">gone.txt</a>"
+                ),
+                "/pub/a.txt" | "/mirror/c.txt?download" => "MARMAK Mirror testing!",
+                "/mirror/d.zip?download" => "PK archive",
+                // Without `?download` a mirror shows what's inside the archive
+                "/mirror/d.zip" => "<html>d.zip</html>",
+                "/pub/skip.log" | "/pub/bad.txt" => "corrupted",
+                "/pub/SHA256SUMS" => concat!(
+                    "f469bf8e7cbceb2aefcd0d139d68f6744046333a5698e3ba299555a15e4bad99  a.txt\n",
+                    "0000000000000000000000000000000000000000000000000000000000000000  bad.txt\n"
+                ),
+                "/pub/sub/" => "<a href='b.iso'>b.iso</a>",
+                "/pub/sub/b.iso" => "ISO",
+                "/api/listing/mirror" => {
+                    r#"[{"name":"c.txt","ext":"txt","icon":"default","size":22,"downloads":null},{"name":"d.zip","ext":"zip","icon":"default","size":10,"downloads":null}]"#
+                }
+                _ => "",
+            };
+
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nLast-Modified: Mon, 01 Jan 2024 00:00:00 GMT\r\nConnection: close\r\n\r\n{}",
+                if body.is_empty() { "404 Not Found" } else { "200 OK" },
+                body.len(),
+                if method == "HEAD" { "" } else { body }
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    let _ = fs::remove_dir_all("files/upstream-test");
+    let sizes = Arc::new(RwLock::new(FileIndex::default()));
+
+    let mut upstream = UpstreamConfig {
+        name: "test".into(),
+        url: format!("http://{}/pub/", address),
+        kind: UpstreamKind::Http,
+        destination: "upstream-test/http".into(),
+        interval_minutes: 0,
+        include: vec![],
+        exclude: vec!["*.log".into()],
+        bandwidth_limit: 0,
+        delete: false,
+    };
+
+    // gone.txt fails its HEAD request, which doesn't stop the other files
+    let report = sync(&upstream, &sizes).await.unwrap();
+    assert_eq!(report.checked, 4);
+    assert_eq!(report.downloaded, 3);
+    assert_eq!(report.failed, 2);
+    assert_eq!(
+        fs::read_to_string("files/upstream-test/http/a.txt").unwrap(),
+        "MARMAK Mirror testing!"
+    );
+    assert_eq!(
+        fs::read_to_string("files/upstream-test/http/sub/b.iso").unwrap(),
+        "ISO"
+    );
+    assert!(!Path::new("files/upstream-test/http/skip.log").exists());
+    assert!(!Path::new("files/upstream-test/http/bad.txt").exists());
+    assert_eq!(
+        sizes
+            .read()
+            .await
+            .size(Path::new("files/upstream-test/http/a.txt")),
+        Some(22)
+    );
+
+    // Files already in sync aren't downloaded again
+    let report = sync(&upstream, &sizes).await.unwrap();
+    assert_eq!(report.downloaded, 0);
+
+    upstream.url = format!("http://{}/mirror", address);
+    upstream.kind = UpstreamKind::Mirror;
+    upstream.destination = "upstream-test/mirror".into();
+    let report = sync(&upstream, &sizes).await.unwrap();
+    assert_eq!(report.downloaded, 2);
+    assert_eq!(report.failed, 0);
+    assert!(Path::new("files/upstream-test/mirror/c.txt").exists());
+    assert_eq!(
+        fs::read_to_string("files/upstream-test/mirror/d.zip").unwrap(),
+        "PK archive"
+    );
+
+    // Only files that are gone upstream and match the filters are removed
+    fs::write("files/upstream-test/mirror/stale.txt", "stale").unwrap();
+    fs::write("files/upstream-test/mirror/keep.log", "excluded").unwrap();
+    upstream.delete = true;
+    let report = sync(&upstream, &sizes).await.unwrap();
+    assert_eq!(report.deleted, 1);
+    assert!(!Path::new("files/upstream-test/mirror/stale.txt").exists());
+    assert!(Path::new("files/upstream-test/mirror/keep.log").exists());
+    assert!(Path::new("files/upstream-test/mirror/c.txt").exists());
+
+    let _ = fs::remove_dir_all("files/upstream-test");
+}
+
+#[rocket::async_test]
+async fn checksums() {
+    use crate::{checksums, MirrorFile};
+    use std::path::PathBuf;
+
+    let _ = fs::create_dir_all("files/checksums-test");
+    fs::write("files/checksums-test/a.txt", "MARMAK Mirror testing!").unwrap();
+
+    let checksums = checksums::compute(Path::new("checksums-test/a.txt"))
+        .await
+        .unwrap();
+    assert_eq!(
+        checksums.sha256,
+        "f469bf8e7cbceb2aefcd0d139d68f6744046333a5698e3ba299555a15e4bad99"
+    );
+    assert_eq!(checksums.sha1, None);
+
+    // Files without checksums keep the API response as it was
+    let mut file = MirrorFile::new("a.txt");
+    assert!(!serde_json::to_string(&file).unwrap().contains("checksums"));
+    file.checksums = Some(checksums);
+    assert!(serde_json::to_string(&file)
+        .unwrap()
+        .contains("\"checksums\":{\"sha256\":\"f469bf8e"));
+
+    // Sweeps only look at files that are new or changed since the last one
+    let swept: checksums::Swept = [
+        (PathBuf::from("a.txt"), (1, Some(10))),
+        (PathBuf::from("b.txt"), (2, Some(20))),
+        (PathBuf::from("gone.txt"), (3, Some(30))),
+    ]
+    .into();
+    let files: checksums::Swept = [
+        (PathBuf::from("a.txt"), (1, Some(10))),
+        (PathBuf::from("b.txt"), (2, Some(25))),
+        (PathBuf::from("c.txt"), (4, None)),
+    ]
+    .into();
+    assert_eq!(
+        checksums::changed(&swept, &files),
+        vec![PathBuf::from("b.txt"), PathBuf::from("c.txt")]
+    );
+    assert!(checksums::changed(&files, &files).is_empty());
+
+    let _ = fs::remove_dir_all("files/checksums-test");
+}
+
+#[rocket::async_test]
+async fn torrents() {
+    use sha1::{Digest, Sha1};
+
+    use crate::{storage::STORAGE, torrent};
+
+    let _ = fs::create_dir_all("files/torrent-test");
+    fs::write("files/torrent-test/a.iso", "MARMAK Mirror testing!").unwrap();
+
+    let key = Path::new("torrent-test/a.iso");
+    let entry = STORAGE.stat(key).await.unwrap();
+    let pieces = torrent::compute(key, &entry).await.unwrap();
+    let url = "http://mirror.example/torrent-test/a.iso?download".to_string();
+
+    let mut info = b"4:infod6:lengthi22e4:name5:a.iso12:piece lengthi262144e6:pieces20:".to_vec();
+    info.extend(Sha1::digest("MARMAK Mirror testing!"));
+    info.extend(b"e8:url-listl49:http://mirror.example/torrent-test/a.iso?downloade");
+
+    let file = torrent::torrent("a.iso", 22, &pieces, vec![url.clone()]);
+    assert!(file.starts_with(b"d10:created by"));
+    assert!(file.windows(info.len()).any(|window| window == info));
+
+    let metalink = torrent::metalink(
+        "a.iso",
+        22,
+        None,
+        Some(&pieces),
+        vec![url],
+        "http://mirror.example/torrent-test/a.iso.torrent",
+    );
+    assert!(metalink.contains(&format!(
+        "<pieces length=\"262144\" type=\"sha-1\"><hash>{:x}</hash></pieces>",
+        Sha1::digest("MARMAK Mirror testing!")
+    )));
+    assert!(metalink
+        .contains("<url priority=\"1\">http://mirror.example/torrent-test/a.iso?download</url>"));
+    assert!(metalink.contains(">http://mirror.example/torrent-test/a.iso.torrent</metaurl>"));
+
+    let _ = fs::remove_dir_all("files/torrent-test");
+}
+
+#[test]
+fn torrent_links() {
+    use rocket::local::blocking::LocalRequest;
+
+    use crate::{guards::Host, peers, torrent};
+
+    // Links point back at the mirror with the scheme the client reached it over
+    let client = Client::tracked(rocket::build()).expect("valid rocket instance");
+    let key = Path::new("torrent-test/a.iso");
+    let trusted = ["127.0.0.1".to_string()];
+    let direct = client.get("/").remote("192.0.2.1:4000".parse().unwrap());
+    let proxied = client
+        .get("/")
+        .remote("127.0.0.1:4000".parse().unwrap())
+        .header(Header::new("X-Forwarded-Proto", "https"));
+    let spoofed = client
+        .get("/")
+        .remote("192.0.2.1:4000".parse().unwrap())
+        .header(Header::new("X-Forwarded-Proto", "https"));
+
+    let url = |request: &LocalRequest<'_>| {
+        let scheme = peers::forwarded_scheme(request.inner(), &trusted);
+        torrent::page_url(key, &Host("mirror.example", scheme).base_url())
+    };
+    assert_eq!(url(&direct), "http://mirror.example/torrent-test/a.iso");
+    assert_eq!(url(&proxied), "https://mirror.example/torrent-test/a.iso");
+    assert_eq!(url(&spoofed), "http://mirror.example/torrent-test/a.iso");
+}
+
+#[test]
+fn peer_redirects() {
+    use crate::{
+        manifest::{Manifest, ManifestFile, RemovedFile},
+        peers::{choose, is_from_peer, PeerConfig, PeerStatus},
+    };
+
+    let file = |path: &str, sha256: &str| ManifestFile {
+        path: path.into(),
+        size: 22,
+        modified: None,
+        sha256: sha256.into(),
+        changed: 0,
+    };
+    let peer = |name: &str, region: &str| PeerConfig {
+        name: name.into(),
+        url: format!("https://{}.example", name),
+        region: region.into(),
+        weight: 1,
+    };
+
+    let configs = [peer("de", "DE"), peer("us", "NA")];
+    let mut statuses: Vec<PeerStatus> = configs.iter().map(PeerStatus::new).collect();
+
+    for status in &mut statuses {
+        status.healthy = true;
+        status.apply(Manifest {
+            files: vec![file("a.iso", "aaa"), file("b.iso", "bbb")],
+            ..Default::default()
+        });
+    }
+
+    statuses[0].apply(Manifest {
+        delta: true,
+        files: vec![file("c.iso", "ccc")],
+        removed: vec![RemovedFile {
+            path: "b.iso".into(),
+            removed: 0,
+        }],
+        ..Default::default()
+    });
+
+    let germany = (Some("DE".to_string()), Some("EU".to_string()));
+    let poland = (Some("PL".to_string()), Some("EU".to_string()));
+    let pick = |statuses: &[PeerStatus],
+                key: &str,
+                sha256: Option<&str>,
+                location: &(Option<String>, Option<String>),
+                region: &str,
+                weight: u32| {
+        choose(
+            configs.iter().zip(statuses.iter()),
+            key,
+            22,
+            sha256,
+            location,
+            region,
+            weight,
+        )
+        .map(|peer| peer.name.clone())
+    };
+
+    assert_eq!(
+        pick(&statuses, "a.iso", Some("aaa"), &germany, "PL", 1),
+        Some("de".into())
+    );
+    assert_eq!(
+        pick(&statuses, "a.iso", Some("aaa"), &poland, "PL", 1),
+        None
+    );
+    assert_eq!(pick(&statuses, "a.iso", Some("xxx"), &germany, "", 1), None);
+    assert_eq!(
+        pick(&statuses, "b.iso", None, &germany, "", 0),
+        Some("us".into())
+    );
+    assert_eq!(pick(&statuses, "d.iso", None, &germany, "", 0), None);
+
+    // Clients that can't be located stay here
+    assert_eq!(
+        pick(&statuses, "c.iso", None, &Default::default(), "", 0),
+        None
+    );
+
+    // A peer as close as this mirror shares the downloads with it
+    let picks: Vec<Option<String>> = (0..100)
+        .map(|_| pick(&statuses, "a.iso", Some("aaa"), &germany, "DE", 1))
+        .collect();
+    assert!(picks.contains(&None));
+    assert!(picks.contains(&Some("de".into())));
+
+    statuses[0].healthy = false;
+    assert_eq!(
+        pick(&statuses, "a.iso", None, &germany, "", 0),
+        Some("us".into())
+    );
+
+    // Downloads a peer sent here are served here
+    let client = Client::tracked(rocket()).unwrap();
+    assert!(is_from_peer(client.get("/a.iso?download&peer").inner()));
+    assert!(!is_from_peer(client.get("/a.iso?download").inner()));
+}
+
+#[test]
+fn download_stats() {
+    use rocket::request::FromRequest;
+
+    use crate::stats::{period, Visitor};
+
+    assert_eq!(period(None), 30);
+    assert_eq!(period(Some(0)), 1);
+    assert_eq!(period(Some(5000)), 366);
+
+    let client = Client::tracked(rocket()).unwrap();
+
+    for (range, resumed) in [
+        (None, false),
+        (Some("bytes=0-"), false),
+        (Some("bytes=0-1023"), false),
+        (Some("bytes=1024-"), true),
+    ] {
+        let mut request = client
+            .get("/stats-test/a.iso?download")
+            .header(Header::new("Referer", "https://example.com/downloads"));
+
+        if let Some(range) = range {
+            request = request.header(Header::new("Range", range));
+        }
+
+        let visitor = rocket::execute(Visitor::from_request(request.inner())).unwrap();
+        assert_eq!(visitor.resumed, resumed);
+        // Buckets are off unless `stats_buckets` asks for them
+        assert_eq!(visitor.referrer, "");
+        assert_eq!(visitor.country, "");
+    }
+}
+
+#[rocket::async_test]
+async fn audit_log() {
+    use rocket::serde::json::Json;
+    use tokio::io::AsyncReadExt;
+
+    use crate::{
+        audit::{csv_field, export, status_of, AuditEntry, AuditFilter},
+        responders::{ApiResponse, IndexResponse},
+    };
+
+    let filter = AuditFilter {
+        actor: Some("test".into()),
+        ip: Some("".into()),
+        path: Some("a b/c".into()),
+        page: Some(2),
+        ..Default::default()
+    };
+    assert_eq!(filter.query(), "actor=test&path=a%20b%2Fc");
+
+    assert_eq!(status_of(&Err(Status::Created)), Status::Created);
+    assert_eq!(
+        status_of(&Ok(ApiResponse::Files(Json(Vec::new())))),
+        Status::Ok
+    );
+
+    let entries = vec![AuditEntry {
+        time: 1700000000,
+        actor: "test".into(),
+        ip: "127.0.0.1".into(),
+        action: "rename".into(),
+        path: "private/a,\"b\".txt -> private/c.txt".into(),
+        status: 200,
+    }];
+
+    let Ok(IndexResponse::Attachment(mut reader, _, name)) = export(&entries, "csv") else {
+        panic!("CSV export failed");
+    };
+    let mut csv = String::new();
+    reader.read_to_string(&mut csv).await.unwrap();
+
+    assert_eq!(name, "audit.csv");
+    assert_eq!(
+        csv,
+        "time,actor,ip,action,path,status\r\n2023-11-14 22:13:20,test,127.0.0.1,rename,\"private/a,\"\"b\"\".txt -> private/c.txt\",200\r\n"
+    );
+
+    assert!(export(&entries, "json").is_ok());
+    assert!(export(&entries, "xml").is_err());
+
+    // Spreadsheets mustn't run anything a user managed to get into the log
+    assert_eq!(csv_field("=1+1"), "'=1+1");
+    assert_eq!(csv_field("@SUM(A1,A2)"), "\"'@SUM(A1,A2)\"");
+    assert_eq!(csv_field("-2"), "'-2");
+    assert_eq!(csv_field("\tcmd"), "'\tcmd");
+    assert_eq!(csv_field("a-b.txt"), "a-b.txt");
+}
+
+#[test]
+fn metrics() {
+    use std::sync::Arc;
+
+    use rocket::{http::ContentType, tokio::sync::RwLock};
+
+    use crate::{file_index::FileIndex, metrics, peers};
+
+    let client = Client::tracked(
+        rocket::build()
+            .manage(Arc::new(RwLock::new(FileIndex::default())))
+            .attach(metrics::build()),
+    )
+    .expect("valid rocket instance");
+
+    assert_eq!(
+        client.get("/nothing-here").dispatch().status(),
+        Status::NotFound
+    );
+
+    let response = client.get("/metrics").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.content_type(),
+        Some(ContentType::new("text", "plain").with_params(("version", "0.0.4")))
+    );
+
+    let body = response.into_string().unwrap();
+    assert!(body.contains(
+        "mirror_http_requests_total{route=\"unmatched\",method=\"GET\",status=\"404\"} "
+    ));
+    assert!(body.contains("# TYPE mirror_http_request_duration_seconds histogram"));
+    assert!(body.contains("mirror_index_files 0"));
+    assert!(!body.contains("mirror_db_connections{"));
+
+    let body = client.get("/metrics").dispatch().into_string().unwrap();
+    assert!(body.contains(
+        "mirror_http_request_duration_seconds_count{route=\"metrics\",method=\"GET\"} 1"
+    ));
+
+    // Without trusted proxies, only the connection's address gets a client onto the allowlist
+    let allowlist = ["10.0.0.1".to_string()];
+    let spoofed = client
+        .get("/metrics")
+        .remote("192.0.2.1:4000".parse().unwrap())
+        .header(Header::new("X-Real-IP", "10.0.0.1"))
+        .header(Header::new("X-Forwarded-For", "10.0.0.1"));
+    assert!(!metrics::is_allowlisted(spoofed.inner(), &allowlist));
+
+    let allowed = client
+        .get("/metrics")
+        .remote("10.0.0.1:4000".parse().unwrap());
+    assert!(metrics::is_allowlisted(allowed.inner(), &allowlist));
+
+    // Behind a trusted proxy the client is the last address it didn't add itself
+    let trusted = ["127.0.0.1".to_string()];
+    let proxied = client
+        .get("/metrics")
+        .remote("127.0.0.1:4000".parse().unwrap())
+        .header(Header::new("X-Forwarded-For", "10.0.0.1, 192.0.2.1"));
+    assert_eq!(
+        peers::forwarded_ip(proxied.inner(), &trusted),
+        Some("192.0.2.1".parse().unwrap())
+    );
+    assert_eq!(
+        peers::forwarded_ip(proxied.inner(), &[]),
+        Some("127.0.0.1".parse().unwrap())
+    );
+    assert_eq!(
+        peers::forwarded_ip(spoofed.inner(), &trusted),
+        Some("192.0.2.1".parse().unwrap())
+    );
+}
+
+#[test]
+fn health() {
+    use std::{thread::sleep, time::Duration};
+
+    fs::create_dir_all("files").unwrap();
+    let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+    let response = client.get("/healthz").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.into_string().unwrap(),
+        "{\"status\":\"ok\",\"checks\":[]}"
+    );
+
+    // The index is built in the background after launch
+    let mut response = client.get("/readyz").dispatch();
+    for _ in 0..50 {
+        if response.status() == Status::Ok {
+            break;
+        }
+        sleep(Duration::from_millis(100));
+        response = client.get("/readyz").dispatch();
+    }
+
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.into_string().unwrap();
+    assert!(body.starts_with("{\"status\":\"ok\""));
+    for name in ["storage", "translations", "templates", "file_index"] {
+        assert!(body.contains(&format!("{{\"name\":\"{}\",\"ok\":true}}", name)));
+    }
+    assert!(!body.contains("\"db\""));
+
+    // Probes running at once each write their own file, and leave nothing behind
+    let probes = rocket::execute(rocket::futures::future::join_all(
+        (0..8).map(|_| crate::health::check_storage()),
+    ));
+    assert!(probes.iter().all(Result::is_ok));
+    assert_eq!(fs::read_dir("files/.trash/.readyz").unwrap().count(), 0);
+}
+
+#[test]
+fn partial_config() {
+    use crate::config::Config;
+
+    // A config.toml from before newer settings were added
+    let config: Config = toml::from_str(
+        "extensions = [\"zip\"]\nenable_login = true\njwt_secret = \"secret\"\n\n[max_upload_sizes]\n\"0\" = 1000\n",
+    )
+    .expect("older config should parse");
+
+    assert!(config.enable_login);
+    assert_eq!(config.jwt_secret, "secret");
+    assert_eq!(config.extensions, vec!["zip".to_string()]);
+    assert_eq!(
+        config.trash_retention_days,
+        Config::default().trash_retention_days
+    );
+    assert!(config.peers.is_empty());
+}
+
+#[test]
+fn orphan_helpers() {
+    use std::collections::HashSet;
+
+    use crate::{
+        db::{like_below, relink_candidates},
+        file_index::FileIndex,
+    };
+
+    assert_eq!(like_below("games"), "games/%");
+    assert_eq!(like_below("100%_done\\x"), "100\\%\\_done\\\\x/%");
+
+    let mut index = FileIndex::default();
+    index.insert_file(Path::new("old/setup.exe"), 10, None);
+    index.insert_file(Path::new("new/setup.exe"), 10, None);
+    index.insert_file(Path::new("new/readme.txt"), 1, None);
+
+    let known: HashSet<String> = ["old/setup.exe".to_string()].into();
+    assert_eq!(
+        relink_candidates("gone/setup.exe", &index, &known),
+        vec!["/new/setup.exe".to_string()]
+    );
+    assert!(relink_candidates("gone/other.exe", &index, &known).is_empty());
+}
+
+#[rocket::async_test]
+async fn anonymous_archive() {
+    use crate::archiver::{collect, Viewer};
+
+    let anonymous = Viewer {
+        user: None,
+        perms: None,
+        skip_restricted: true,
+    };
+
+    // A folder download from the root walks everyone's private folders
+    assert!(!anonymous.can_see_private(Path::new("private/alice/diary.txt")));
+    assert!(anonymous.can_see_private(Path::new("private")));
+    assert!(anonymous.can_see_private(Path::new("public/readme.txt")));
+
+    let alice = Viewer {
+        user: Some("alice"),
+        perms: Some(1),
+        skip_restricted: false,
+    };
+    assert!(alice.can_see_private(Path::new("private/alice/diary.txt")));
+    assert!(!alice.can_see_private(Path::new("private/bob/diary.txt")));
+
+    fs::create_dir_all("files/anon-zip/locked/deeper").unwrap();
+    fs::write("files/anon-zip/open.txt", "a").unwrap();
+    fs::write("files/anon-zip/locked/RESTRICTED", "").unwrap();
+    fs::write("files/anon-zip/locked/deeper/secret.txt", "b").unwrap();
+
+    let entries = collect(&[Path::new("files/anon-zip").to_path_buf()], &anonymous)
+        .await
+        .unwrap();
+    assert_eq!(
+        entries
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect::<Vec<_>>(),
+        vec!["anon-zip/", "anon-zip/open.txt"]
+    );
+    assert!(collect(
+        &[Path::new("files/anon-zip/locked/deeper").to_path_buf()],
+        &anonymous
+    )
+    .await
+    .unwrap()
+    .is_empty());
+
+    let _ = fs::remove_dir_all("files/anon-zip");
+}
+
+#[test]
+fn rar_listing() {
+    use crate::inspector::{flatten, read_7z_listing};
+
+    let output = "7-Zip 23.01 (x64)\n\nListing archive: files/game.rar\n\n--\nPath = files/game.rar\nType = Rar5\nPhysical Size = 120\n\n----------\nPath = docs\nFolder = +\nSize = 0\nPacked Size = 0\nModified = 2024-01-31 12:00:00\nEncrypted = -\n\nPath = docs/readme.txt\nFolder = -\nSize = 22\nPacked Size = 20\nModified = 2024-01-31 12:30:15.1234567\nEncrypted = -\n\nPath = setup.exe\nFolder = -\nSize = 100\nPacked Size = 90\nModified = 2024-01-31 12:00:00\nEncrypted = +\n\n";
+
+    let nodes = flatten(read_7z_listing(output));
+    let paths: Vec<&str> = nodes.iter().map(|node| node.path.as_str()).collect();
+    assert_eq!(paths, vec!["docs", "docs/readme.txt", "setup.exe"]);
+
+    assert!(nodes[0].is_dir);
+    assert_eq!(nodes[0].size, 22);
+    assert_eq!(nodes[1].compressed_size, Some(20));
+    assert_eq!(nodes[1].modified, Some(1706704215));
+    assert!(nodes[2].encrypted);
+}
@@ -0,0 +1,433 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use once_cell::sync::Lazy;
+use quick_xml::escape::escape;
+use rocket::{
+    http::{ContentType, Status},
+    time::OffsetDateTime,
+};
+use rocket_db_pools::sqlx::{pool::PoolConnection, MySql};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncReadExt};
+
+use crate::{
+    checksums,
+    config::CONFIG,
+    responders::{IndexResponse, IndexResult},
+    storage::{storage_key, StorageEntry, STORAGE},
+    trash::is_trash_key,
+    upstream,
+    utils::WorkQueue,
+    FileSizes, MirrorFile,
+};
+
+/// Folder the piece hashes of offered files are cached in.
+const PIECE_CACHE_DIR: &str = ".pieces";
+
+/// Files waiting for their pieces to be hashed, as storage keys.
+static QUEUE: Lazy<WorkQueue> = Lazy::new(WorkQueue::default);
+
+/// SHA-1 hashes of a file's pieces, for the size and mtime they were computed for.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Pieces {
+    size: u64,
+    modified: Option<u64>,
+    piece_length: u64,
+    /// The hashes one after another, base64 encoded.
+    hashes: String,
+}
+
+impl Pieces {
+    fn hashes(&self) -> Vec<u8> {
+        STANDARD.decode(&self.hashes).unwrap_or_default()
+    }
+}
+
+enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<&'static str, Bencode>),
+}
+
+impl Bencode {
+    fn string(value: &str) -> Self {
+        Bencode::Bytes(value.as_bytes().to_vec())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(value) => out.extend(format!("i{}e", value).as_bytes()),
+            Bencode::Bytes(value) => {
+                out.extend(format!("{}:", value.len()).as_bytes());
+                out.extend(value);
+            }
+            Bencode::List(items) => {
+                out.push(b'l');
+                items.iter().for_each(|item| item.encode(out));
+                out.push(b'e');
+            }
+            // Keys are kept sorted by the map, as the format requires
+            Bencode::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    Bencode::string(key).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+/// Whether `.meta4` and `.torrent` files are offered for a file (a `files/...` path).
+pub fn is_offered(path: &Path, size: u64) -> bool {
+    let key = storage_key(path);
+    let ext = MirrorFile::get_extension_from_path(&path.to_path_buf()).to_lowercase();
+
+    CONFIG.enable_torrents
+        && size >= CONFIG.torrent_min_size
+        && CONFIG.extensions.contains(&ext)
+        && !key.starts_with("private")
+        && !is_trash_key(&key)
+}
+
+/// Pieces about a megabyte or two in size for most files, without ending up with
+/// more than a few thousand of them for large ones.
+fn piece_length(size: u64) -> u64 {
+    let mut length = 256 * 1024;
+
+    while size / length > 2000 && length < 16 * 1024 * 1024 {
+        length *= 2;
+    }
+
+    length
+}
+
+fn cache_path(key: &Path) -> PathBuf {
+    let name = format!("{:x}", Sha256::digest(key.display().to_string()));
+    Path::new(PIECE_CACHE_DIR).join(name + ".json")
+}
+
+async fn load(key: &Path, entry: &StorageEntry) -> Option<Pieces> {
+    let cached = fs::read(cache_path(key)).await.ok()?;
+
+    serde_json::from_slice::<Pieces>(&cached)
+        .ok()
+        .filter(|pieces| pieces.size == entry.size && pieces.modified == entry.modified)
+}
+
+pub(crate) async fn compute(key: &Path, entry: &StorageEntry) -> io::Result<Pieces> {
+    let length = piece_length(entry.size);
+    let mut reader = STORAGE.open(key, None).await?;
+    let mut hashes = Vec::new();
+    let mut piece = vec![0; length as usize];
+
+    loop {
+        let mut filled = 0;
+
+        while filled < piece.len() {
+            let read = reader.read(&mut piece[filled..]).await?;
+
+            if read == 0 {
+                break;
+            }
+
+            filled += read;
+        }
+
+        if filled == 0 {
+            break;
+        }
+
+        hashes.extend(Sha1::digest(&piece[..filled]));
+
+        if filled < piece.len() {
+            break;
+        }
+    }
+
+    Ok(Pieces {
+        size: entry.size,
+        modified: entry.modified,
+        piece_length: length,
+        hashes: STANDARD.encode(hashes),
+    })
+}
+
+/// Makes sure a file's cached pieces match its current contents.
+async fn update(key: &Path) {
+    let Ok(entry) = STORAGE.stat(key).await else {
+        return;
+    };
+
+    if entry.is_dir || load(key, &entry).await.is_some() {
+        return;
+    }
+
+    let pieces = match compute(key, &entry).await {
+        Ok(pieces) => pieces,
+        Err(e) => {
+            eprintln!("Failed to hash pieces of {}: {:?}", key.display(), e);
+            return;
+        }
+    };
+
+    let saved = async {
+        fs::create_dir_all(PIECE_CACHE_DIR).await?;
+        fs::write(cache_path(key), serde_json::to_vec(&pieces)?).await
+    };
+
+    if let Err(e) = saved.await {
+        eprintln!("Failed to cache pieces of {}: {:?}", key.display(), e);
+    }
+}
+
+/// Whether a torrent can be made for the file yet. Files that aren't hashed are
+/// queued, so they're ready on a later request.
+pub async fn is_ready(path: &Path) -> bool {
+    let key = storage_key(path);
+
+    let Ok(entry) = STORAGE.stat(&key).await else {
+        return false;
+    };
+
+    let ready = load(&key, &entry).await.is_some();
+
+    if !ready {
+        QUEUE.push(key);
+    }
+
+    ready
+}
+
+/// The file's page on this mirror, which its `.torrent` and `.meta4` sit next to.
+pub(crate) fn page_url(key: &Path, base_url: &str) -> String {
+    let path = key
+        .iter()
+        .map(|segment| urlencoding::encode(&segment.to_string_lossy()).into_owned())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    format!("{}/{}", base_url, path)
+}
+
+/// Where the file itself can be downloaded: this mirror first, then the upstream
+/// it was synced from.
+fn urls(key: &Path, base_url: &str) -> Vec<String> {
+    let mut urls = vec![format!("{}?download", page_url(key, base_url))];
+    urls.extend(upstream::origin_url(key));
+    urls
+}
+
+pub(crate) fn torrent(name: &str, size: u64, pieces: &Pieces, urls: Vec<String>) -> Vec<u8> {
+    let mut info = BTreeMap::new();
+    info.insert("length", Bencode::Int(size as i64));
+    info.insert("name", Bencode::string(name));
+    info.insert("piece length", Bencode::Int(pieces.piece_length as i64));
+    info.insert("pieces", Bencode::Bytes(pieces.hashes()));
+
+    let mut torrent = BTreeMap::new();
+    torrent.insert(
+        "created by",
+        Bencode::string(&format!("MARMAK Mirror {}", env!("CARGO_PKG_VERSION"))),
+    );
+    torrent.insert(
+        "creation date",
+        Bencode::Int(OffsetDateTime::now_utc().unix_timestamp()),
+    );
+    torrent.insert("info", Bencode::Dict(info));
+    torrent.insert(
+        "url-list",
+        Bencode::List(urls.iter().map(|url| Bencode::string(url)).collect()),
+    );
+
+    if let Some(tracker) = CONFIG.torrent_trackers.first() {
+        torrent.insert("announce", Bencode::string(tracker));
+        torrent.insert(
+            "announce-list",
+            Bencode::List(
+                CONFIG
+                    .torrent_trackers
+                    .iter()
+                    .map(|tracker| Bencode::List(vec![Bencode::string(tracker)]))
+                    .collect(),
+            ),
+        );
+    }
+
+    let mut out = Vec::new();
+    Bencode::Dict(torrent).encode(&mut out);
+    out
+}
+
+pub(crate) fn metalink(
+    name: &str,
+    size: u64,
+    sha256: Option<String>,
+    pieces: Option<&Pieces>,
+    urls: Vec<String>,
+    torrent_url: &str,
+) -> String {
+    let mut file = format!("<size>{}</size>", size);
+
+    if let Some(sha256) = sha256 {
+        file += &format!("<hash type=\"sha-256\">{}</hash>", sha256);
+    }
+
+    if let Some(pieces) = pieces {
+        file += &format!("<pieces length=\"{}\" type=\"sha-1\">", pieces.piece_length);
+        for hash in pieces.hashes().chunks(20) {
+            file += "<hash>";
+            file.extend(hash.iter().map(|byte| format!("{:02x}", byte)));
+            file += "</hash>";
+        }
+        file += "</pieces>";
+    }
+
+    for (priority, url) in urls.iter().enumerate() {
+        file += &format!("<url priority=\"{}\">{}</url>", priority + 1, escape(url));
+    }
+
+    if pieces.is_some() {
+        file += &format!(
+            "<metaurl mediatype=\"torrent\" priority=\"1\">{}</metaurl>",
+            escape(torrent_url)
+        );
+    }
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<metalink xmlns=\"urn:ietf:params:xml:ns:metalink\"><generator>MARMAK Mirror/{}</generator><file name=\"{}\">{}</file></metalink>",
+        env!("CARGO_PKG_VERSION"),
+        escape(name),
+        file
+    )
+}
+
+/// Serves `<file>.meta4` and `<file>.torrent` for the files they're offered for, or
+/// `None` if `path` (a `files/...` path) isn't one of those.
+pub async fn send(
+    db: Option<&mut PoolConnection<MySql>>,
+    path: &Path,
+    base_url: &str,
+    is_logged_in: bool,
+) -> Option<IndexResult> {
+    let file_name = path.file_name()?.to_str()?;
+    let (name, is_torrent) = match file_name.strip_suffix(".torrent") {
+        Some(name) => (name, true),
+        None => (file_name.strip_suffix(".meta4")?, false),
+    };
+
+    let target = path.with_file_name(name);
+    let key = storage_key(&target);
+    let entry = STORAGE.stat(&key).await.ok()?;
+
+    if entry.is_dir || !is_offered(&target, entry.size) {
+        return None;
+    }
+
+    if MirrorFile::is_restricted(&target, is_logged_in).await {
+        return Some(Err(Status::Unauthorized));
+    }
+
+    let pieces = load(&key, &entry).await;
+
+    if pieces.is_none() {
+        QUEUE.push(key.clone());
+    }
+
+    let urls = urls(&key, base_url);
+
+    if is_torrent {
+        let Some(pieces) = pieces else {
+            return Some(Err(Status::ServiceUnavailable));
+        };
+
+        return Some(Ok(IndexResponse::DirectFile(
+            (
+                ContentType::new("application", "x-bittorrent"),
+                torrent(name, entry.size, &pieces, urls),
+            ),
+            "no-cache".into(),
+        )));
+    }
+
+    let sha256 = match db {
+        Some(db) => checksums::lookup(db, &target)
+            .await
+            .map(|checksums| checksums.sha256),
+        None => None,
+    };
+
+    Some(Ok(IndexResponse::DirectFile(
+        (
+            ContentType::new("application", "metalink4+xml"),
+            metalink(
+                name,
+                entry.size,
+                sha256,
+                pieces.as_ref(),
+                urls,
+                &format!("{}.torrent", page_url(&key, base_url)),
+            )
+            .into_bytes(),
+        ),
+        "no-cache".into(),
+    )))
+}
+
+/// Hashes the pieces of queued files as they come in, and of every offered file
+/// whenever the index changes. Cached pieces of files that are gone are removed.
+pub async fn work(index: FileSizes) {
+    let mut swept_at = None;
+
+    loop {
+        while let Some(key) = QUEUE.pop() {
+            update(&key).await;
+        }
+
+        let changes = index.read().await.changes();
+
+        if swept_at != Some(changes) {
+            let mut keys: Vec<PathBuf> = index
+                .read()
+                .await
+                .files()
+                .filter(|(key, size, _)| is_offered(&Path::new("files").join(key), *size))
+                .map(|(key, _, _)| key.to_path_buf())
+                .collect();
+            keys.sort();
+
+            for key in &keys {
+                update(key).await;
+
+                // Files someone is waiting for go first
+                while let Some(key) = QUEUE.pop() {
+                    update(&key).await;
+                }
+            }
+
+            let keep: HashSet<PathBuf> = keys.iter().map(|key| cache_path(key)).collect();
+
+            if let Ok(mut cached) = fs::read_dir(PIECE_CACHE_DIR).await {
+                while let Ok(Some(file)) = cached.next_entry().await {
+                    if !keep.contains(&file.path()) {
+                        let _ = fs::remove_file(file.path()).await;
+                    }
+                }
+            }
+
+            swept_at = Some(changes);
+        }
+
+        QUEUE.wait(Duration::from_secs(60)).await;
+    }
+}
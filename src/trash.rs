@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use once_cell::sync::Lazy;
+use rocket::{fairing::AdHoc, time::OffsetDateTime};
+use rocket_db_pools::{
+    sqlx::{pool::PoolConnection, MySql, MySqlPool},
+    Database,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{io, time::sleep};
+use uuid::Uuid;
+
+use crate::{
+    config::CONFIG,
+    db::{delete_file, FileDb},
+    storage::{self, storage_key, STORAGE},
+    MirrorFile,
+};
+
+/// Top-level folder of the storage tree that holds deleted items,
+/// laid out as `.trash/<owner>/<id>/<name>` with `.trash/<owner>/<id>.json` next to it.
+pub const TRASH_DIR: &str = ".trash";
+
+/// Size of the trashed items that came from each user's private folder, worked out
+/// when first needed and again after the trash changes.
+static USAGE: Lazy<Mutex<TrashUsage>> = Lazy::new(Mutex::default);
+
+#[derive(Default)]
+struct TrashUsage {
+    sizes: Option<HashMap<String, u64>>,
+    /// Bumped on every change, so a listing that raced one isn't kept.
+    changes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub id: String,
+    pub owner: String,
+    pub name: String,
+    pub original_path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub deleted_at: i64,
+}
+
+impl TrashEntry {
+    fn dir_key(&self) -> PathBuf {
+        Path::new(TRASH_DIR).join(&self.owner).join(&self.id)
+    }
+
+    /// Where the item is kept while it's in the trash.
+    pub fn item_key(&self) -> PathBuf {
+        self.dir_key().join(&self.name)
+    }
+
+    fn metadata_key(&self) -> PathBuf {
+        Path::new(TRASH_DIR)
+            .join(&self.owner)
+            .join(format!("{}.json", self.id))
+    }
+
+    pub fn original_key(&self) -> PathBuf {
+        storage_key(Path::new(self.original_path.trim_start_matches('/')))
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        CONFIG.trash_retention_days != 0
+            && now - self.deleted_at > CONFIG.trash_retention_days as i64 * 86400
+    }
+
+    /// The user whose private folder the item was deleted from.
+    fn private_owner(&self) -> Option<String> {
+        let key = self.original_key();
+        let mut components = key.components();
+
+        (components.next()?.as_os_str() == "private")
+            .then(|| components.next())
+            .flatten()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+    }
+}
+
+fn forget_usage() {
+    let mut usage = USAGE.lock().unwrap_or_else(|e| e.into_inner());
+    usage.sizes = None;
+    usage.changes += 1;
+}
+
+/// Space taken in the trash by items deleted from `user`'s private folder, which
+/// still counts against their quota until it's purged.
+pub async fn usage(user: &str) -> u64 {
+    let changes = {
+        let usage = USAGE.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(sizes) = usage.sizes.as_ref() {
+            return sizes.get(user).copied().unwrap_or(0);
+        }
+
+        usage.changes
+    };
+
+    let entries = match list(None).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to list the trash: {:?}", e);
+            return 0;
+        }
+    };
+
+    let mut sizes: HashMap<String, u64> = HashMap::new();
+    for entry in entries {
+        if let Some(owner) = entry.private_owner() {
+            *sizes.entry(owner).or_default() += entry.size;
+        }
+    }
+
+    let size = sizes.get(user).copied().unwrap_or(0);
+
+    let mut usage = USAGE.lock().unwrap_or_else(|e| e.into_inner());
+    if usage.changes == changes {
+        usage.sizes = Some(sizes);
+    }
+
+    size
+}
+
+/// Checks whether a storage key points into the trash area.
+pub fn is_trash_key(key: &Path) -> bool {
+    key.components()
+        .next()
+        .is_some_and(|c| c.as_os_str() == TRASH_DIR)
+}
+
+/// Moves a file or folder (a `files/...` path) into the trash of `owner`.
+pub async fn move_to_trash(owner: &str, path: &Path) -> io::Result<TrashEntry> {
+    let key = storage_key(path);
+
+    if is_trash_key(&key) {
+        return Err(Error::new(ErrorKind::InvalidInput, "Already in the trash"));
+    }
+
+    let md = STORAGE.stat(&key).await?;
+
+    let size = if md.is_dir {
+        STORAGE
+            .walk(&key)
+            .await?
+            .iter()
+            .map(|(_, entry)| entry.size)
+            .sum()
+    } else {
+        md.size
+    };
+
+    let entry = TrashEntry {
+        id: Uuid::new_v4().to_string(),
+        owner: owner.to_string(),
+        name: MirrorFile::get_name_from_path(&key),
+        original_path: Path::new("/").join(&key).display().to_string(),
+        is_dir: md.is_dir,
+        size,
+        deleted_at: OffsetDateTime::now_utc().unix_timestamp(),
+    };
+
+    storage::create_dir_all(&entry.dir_key()).await?;
+    STORAGE.rename(&key, &entry.item_key()).await?;
+
+    let metadata = serde_json::to_vec(&entry).map_err(Error::other)?;
+    STORAGE
+        .write(&entry.metadata_key(), &mut metadata.as_slice())
+        .await?;
+    forget_usage();
+
+    Ok(entry)
+}
+
+/// Lists trashed items of one user, or of everyone when `owner` is `None`.
+pub async fn list(owner: Option<&str>) -> io::Result<Vec<TrashEntry>> {
+    let owners = match owner {
+        Some(owner) => vec![owner.to_string()],
+        None => match STORAGE.list(Path::new(TRASH_DIR)).await {
+            Ok(entries) => entries
+                .into_iter()
+                .filter(|e| e.is_dir)
+                .map(|e| e.name)
+                .collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        },
+    };
+
+    let mut trash = Vec::new();
+
+    for owner in owners {
+        let owner_key = Path::new(TRASH_DIR).join(&owner);
+
+        let entries = match STORAGE.list(&owner_key).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        for entry in entries {
+            if entry.is_dir || !entry.name.ends_with(".json") {
+                continue;
+            }
+
+            let metadata = match storage::read_to_string(&owner_key.join(&entry.name)).await {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    eprintln!("Failed to read trash entry {}: {:?}", entry.name, e);
+                    continue;
+                }
+            };
+
+            match serde_json::from_str::<TrashEntry>(&metadata) {
+                Ok(trash_entry) => trash.push(trash_entry),
+                Err(e) => eprintln!("Invalid trash entry {}: {:?}", entry.name, e),
+            }
+        }
+    }
+
+    trash.sort_by_key(|entry| std::cmp::Reverse(entry.deleted_at));
+
+    Ok(trash)
+}
+
+pub async fn get(id: &str) -> Option<TrashEntry> {
+    list(None)
+        .await
+        .ok()?
+        .into_iter()
+        .find(|entry| entry.id == id)
+}
+
+/// Moves an item back to where it was deleted from and returns its `files/...` path.
+pub async fn restore(entry: &TrashEntry) -> io::Result<PathBuf> {
+    let original_key = entry.original_key();
+
+    if STORAGE.exists(&original_key).await {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            "A file with the same name already exists",
+        ));
+    }
+
+    if let Some(parent) = original_key.parent() {
+        storage::create_dir_all(parent).await?;
+    }
+
+    STORAGE.rename(&entry.item_key(), &original_key).await?;
+    purge(entry, None).await?;
+
+    Ok(Path::new("files").join(original_key))
+}
+
+/// Permanently deletes an item from the trash, along with its `files` rows.
+pub async fn purge(entry: &TrashEntry, db: Option<&mut PoolConnection<MySql>>) -> io::Result<()> {
+    match STORAGE.delete(&entry.dir_key(), true).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let deleted = STORAGE.delete(&entry.metadata_key(), false).await;
+    forget_usage();
+
+    if let Some(db) = db {
+        delete_file(db, &entry.item_key().display().to_string()).await;
+    }
+
+    deleted
+}
+
+async fn purge_expired(pool: Option<&MySqlPool>) {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    let entries = match list(None).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to list the trash: {:?}", e);
+            return;
+        }
+    };
+
+    for entry in entries.iter().filter(|entry| entry.is_expired(now)) {
+        let mut db = match pool {
+            Some(pool) => pool.acquire().await.ok(),
+            None => None,
+        };
+
+        if let Err(e) = purge(entry, db.as_mut()).await {
+            eprintln!("Failed to purge {} from the trash: {:?}", entry.id, e);
+        }
+    }
+}
+
+async fn clean_trash(pool: Option<MySqlPool>) {
+    loop {
+        if CONFIG.trash_retention_days != 0 {
+            purge_expired(pool.as_ref()).await;
+        }
+
+        sleep(tokio::time::Duration::from_secs(3600)).await;
+    }
+}
+
+pub fn build() -> AdHoc {
+    AdHoc::on_liftoff("Trash", |rocket| {
+        Box::pin(async move {
+            let pool = CONFIG
+                .enable_file_db
+                .then(|| FileDb::fetch(rocket).map(|db| MySqlPool::clone(db)))
+                .flatten();
+
+            tokio::spawn(clean_trash(pool));
+        })
+    })
+}
@@ -0,0 +1,655 @@
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use once_cell::sync::Lazy;
+use rocket::{
+    data::ToByteUnit,
+    http::{HeaderMap, Status},
+    request::{FromRequest, Outcome},
+    time::OffsetDateTime,
+    Data, Request, State,
+};
+use rocket_db_pools::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, time::sleep};
+use uuid::Uuid;
+
+use crate::{
+    api::{finish_upload, upload_limits, upload_target},
+    audit::Audit,
+    config::CONFIG,
+    db::FileDb,
+    file_index,
+    jwt::JWT,
+    metrics::METRICS,
+    quota::{self, Reservations},
+    responders::TusResponse,
+    storage::{self, storage_key, STORAGE},
+    utils::{http_date, UPLOAD_TEMP_DIR},
+    FileSizes, Host,
+};
+
+pub const TUS_VERSION: &str = "1.0.0";
+
+/// Folder used by the older `upload_chunked` endpoint, cleaned up by the same reaper.
+const CHUNKS_DIR: &str = ".chunks";
+
+/// When uploads never expire, how long one may sit idle before its quota is freed.
+const IDLE_RESERVATION: Duration = Duration::from_secs(24 * 3600);
+
+/// Uploads that currently have a request appending to them.
+static BUSY: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// State of an unfinished upload, saved next to its data as `<id>.json`.
+#[derive(Serialize, Deserialize)]
+struct TusUpload {
+    owner: String,
+    length: u64,
+    file_name: String,
+    user_path: String,
+    base_path: String,
+    share: Option<String>,
+    /// 0 when the upload never expires.
+    expires: i64,
+}
+
+impl TusUpload {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+}
+
+fn tus_dir() -> PathBuf {
+    Path::new(UPLOAD_TEMP_DIR).join("tus")
+}
+
+fn data_path(id: &str) -> PathBuf {
+    tus_dir().join(id)
+}
+
+fn info_path(id: &str) -> PathBuf {
+    tus_dir().join(format!("{}.json", id))
+}
+
+fn expiry() -> i64 {
+    if CONFIG.upload_expiry_hours == 0 {
+        0
+    } else {
+        OffsetDateTime::now_utc().unix_timestamp() + CONFIG.upload_expiry_hours as i64 * 3600
+    }
+}
+
+fn is_valid_id(id: &str) -> bool {
+    Uuid::parse_str(id).is_ok()
+}
+
+async fn load(id: &str) -> Option<TusUpload> {
+    if !is_valid_id(id) {
+        return None;
+    }
+
+    let info = fs::read_to_string(info_path(id)).await.ok()?;
+    serde_json::from_str(&info).ok()
+}
+
+async fn save(id: &str, upload: &TusUpload) -> Result<(), Status> {
+    let info = serde_json::to_string(upload).map_err(|_| Status::InternalServerError)?;
+
+    fs::write(info_path(id), info).await.map_err(|e| {
+        eprintln!("Failed to save upload {}: {:?}", id, e);
+        Status::InternalServerError
+    })
+}
+
+fn reservation(id: &str) -> String {
+    format!("tus/{}", id)
+}
+
+/// Drops an upload along with the quota it was holding.
+async fn remove(reservations: &Reservations, owner: &str, id: &str) {
+    let _ = fs::remove_file(data_path(id)).await;
+    let _ = fs::remove_file(info_path(id)).await;
+    quota::release(reservations, owner, &reservation(id)).await;
+}
+
+async fn offset(id: &str) -> Result<u64, Status> {
+    fs::metadata(data_path(id))
+        .await
+        .map(|md| md.len())
+        .map_err(|_| Status::NotFound)
+}
+
+/// Loads an upload for its owner, dropping it if it has expired.
+async fn find(reservations: &Reservations, id: &str, token: &JWT) -> Result<TusUpload, Status> {
+    let upload = load(id).await.ok_or(Status::NotFound)?;
+
+    if upload.owner != token.claims.sub {
+        return Err(Status::NotFound);
+    }
+
+    if upload.is_expired(OffsetDateTime::now_utc().unix_timestamp()) {
+        remove(reservations, &upload.owner, id).await;
+        return Err(Status::Gone);
+    }
+
+    Ok(upload)
+}
+
+fn with_expiry(response: TusResponse, expires: i64) -> TusResponse {
+    if expires == 0 {
+        response
+    } else {
+        response.header("Upload-Expires", http_date(expires))
+    }
+}
+
+/// Marks an upload as being written to until dropped.
+struct BusyGuard(String);
+
+impl BusyGuard {
+    fn acquire(id: &str) -> Option<Self> {
+        let mut busy = BUSY.lock().unwrap_or_else(|e| e.into_inner());
+
+        if busy.insert(id.to_string()) {
+            Some(BusyGuard(id.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        BUSY.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.0);
+    }
+}
+
+pub struct TusHeaders<'r>(&'r HeaderMap<'r>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(TusHeaders(request.headers()))
+    }
+}
+
+impl TusHeaders<'_> {
+    /// Refuses clients that speak another version of the protocol.
+    fn check_version(&self) -> Result<(), TusResponse> {
+        if self.0.get_one("Tus-Resumable") == Some(TUS_VERSION) {
+            Ok(())
+        } else {
+            Err(TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION))
+        }
+    }
+
+    fn number(&self, name: &str) -> Result<u64, Status> {
+        self.0
+            .get_one(name)
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or(Status::BadRequest)
+    }
+
+    /// Parses `Upload-Metadata`, a list of `key base64value` pairs.
+    fn metadata(&self, key: &str) -> Option<String> {
+        self.0
+            .get_one("Upload-Metadata")?
+            .split(',')
+            .filter_map(|pair| {
+                let mut parts = pair.trim().splitn(2, ' ');
+                Some((parts.next()?, parts.next().unwrap_or("")))
+            })
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, value)| STANDARD.decode(value.trim()).ok())
+            .and_then(|value| String::from_utf8(value).ok())
+    }
+}
+
+#[options("/tus")]
+fn tus_options(token: Result<JWT, Status>) -> TusResponse {
+    let response = TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", "creation,termination,expiration");
+
+    match token {
+        Ok(token) => response.header("Tus-Max-Size", upload_limits(&token).0),
+        Err(_) => response,
+    }
+}
+
+#[post("/tus")]
+async fn tus_create(
+    headers: TusHeaders<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+) -> TusResponse {
+    if let Err(response) = headers.check_version() {
+        return response;
+    }
+
+    create(headers, token, sizes, reservations)
+        .await
+        .unwrap_or_else(TusResponse::from)
+}
+
+async fn create(
+    headers: TusHeaders<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+) -> Result<TusResponse, Status> {
+    let token = token?;
+
+    let length = headers.number("Upload-Length")?;
+
+    let file_name = headers
+        .metadata("filename")
+        .or_else(|| headers.metadata("name"))
+        .ok_or(Status::BadRequest)?;
+
+    if file_name.is_empty() || file_name.contains(['/', '\\']) || file_name == ".." {
+        return Err(Status::BadRequest);
+    }
+
+    let (user_path, base_path) = upload_target(headers.metadata("path"), &token)?;
+
+    let (max_size, _) = upload_limits(&token);
+
+    if length > max_size {
+        return Err(Status::PayloadTooLarge);
+    }
+
+    let id = Uuid::new_v4().to_string();
+
+    if base_path.starts_with("files/private/") {
+        quota::reserve(reservations, sizes, &token, &reservation(&id), length).await?;
+    }
+
+    fs::create_dir_all(tus_dir()).await.map_err(|e| {
+        eprintln!("Failed to create the upload folder: {:?}", e);
+        Status::InternalServerError
+    })?;
+
+    let upload = TusUpload {
+        owner: token.claims.sub,
+        length,
+        file_name,
+        user_path,
+        base_path,
+        share: headers.metadata("share"),
+        expires: expiry(),
+    };
+
+    let created = async {
+        fs::File::create(data_path(&id))
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+        save(&id, &upload).await
+    }
+    .await;
+
+    if let Err(status) = created {
+        remove(reservations, &upload.owner, &id).await;
+        return Err(status);
+    }
+
+    Ok(with_expiry(
+        TusResponse::new(Status::Created).header("Location", format!("/api/tus/{}", id)),
+        upload.expires,
+    ))
+}
+
+#[head("/tus/<id>")]
+async fn tus_head(
+    id: &str,
+    headers: TusHeaders<'_>,
+    token: Result<JWT, Status>,
+    reservations: &State<Reservations>,
+) -> TusResponse {
+    if let Err(response) = headers.check_version() {
+        return response;
+    }
+
+    async {
+        let upload = find(reservations, id, &token?).await?;
+
+        Ok::<_, Status>(with_expiry(
+            TusResponse::new(Status::Ok)
+                .header("Upload-Offset", offset(id).await?)
+                .header("Upload-Length", upload.length),
+            upload.expires,
+        ))
+    }
+    .await
+    .unwrap_or_else(TusResponse::from)
+}
+
+#[patch("/tus/<id>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn tus_patch(
+    id: &str,
+    data: Data<'_>,
+    headers: TusHeaders<'_>,
+    host: Host<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
+) -> TusResponse {
+    if let Err(response) = headers.check_version() {
+        return response;
+    }
+
+    append(
+        None,
+        id,
+        data,
+        headers,
+        host,
+        token,
+        sizes,
+        reservations,
+        audit,
+    )
+    .await
+    .unwrap_or_else(TusResponse::from)
+}
+
+#[patch("/tus/<id>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+async fn tus_patch_db(
+    db: Connection<FileDb>,
+    id: &str,
+    data: Data<'_>,
+    headers: TusHeaders<'_>,
+    host: Host<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
+) -> TusResponse {
+    if let Err(response) = headers.check_version() {
+        return response;
+    }
+
+    append(
+        Some(db),
+        id,
+        data,
+        headers,
+        host,
+        token,
+        sizes,
+        reservations,
+        audit,
+    )
+    .await
+    .unwrap_or_else(TusResponse::from)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn append(
+    db: Option<Connection<FileDb>>,
+    id: &str,
+    data: Data<'_>,
+    headers: TusHeaders<'_>,
+    host: Host<'_>,
+    token: Result<JWT, Status>,
+    sizes: &State<FileSizes>,
+    reservations: &State<Reservations>,
+    audit: Audit<'_>,
+) -> Result<TusResponse, Status> {
+    let token = token?;
+
+    if headers.0.get_one("Content-Type") != Some("application/offset+octet-stream") {
+        return Err(Status::UnsupportedMediaType);
+    }
+
+    let mut upload = find(reservations, id, &token).await?;
+    let _busy = BusyGuard::acquire(id).ok_or(Status::Locked)?;
+
+    let is_private = upload.base_path.starts_with("files/private/");
+
+    // Reservations are only kept in memory, so take the space again after a restart
+    if is_private {
+        quota::reserve(reservations, sizes, &token, &reservation(id), upload.length).await?;
+    }
+
+    let current = offset(id).await?;
+    if headers.number("Upload-Offset")? != current {
+        return Err(Status::Conflict);
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(data_path(id))
+        .await
+        .map_err(|_| Status::NotFound)?;
+
+    let written = data
+        .open((upload.length - current).bytes())
+        .stream_to(&mut file)
+        .await;
+    drop(file);
+
+    let new_offset = offset(id).await?;
+
+    if let Err(e) = written {
+        eprintln!("Upload {} was interrupted: {:?}", id, e);
+    }
+    METRICS.uploaded(new_offset.saturating_sub(current));
+
+    upload.expires = expiry();
+    save(id, &upload).await?;
+
+    let response = with_expiry(
+        TusResponse::new(Status::NoContent).header("Upload-Offset", new_offset),
+        upload.expires,
+    );
+
+    if new_offset < upload.length {
+        return Ok(response);
+    }
+
+    let audit_path = format!("{}/{}", upload.user_path, upload.file_name);
+
+    // Other uploads may have finished since, so check against what's stored now
+    if is_private {
+        if let Err(status) =
+            quota::reserve(reservations, sizes, &token, &reservation(id), new_offset).await
+        {
+            remove(reservations, &upload.owner, id).await;
+            audit
+                .record(&token.claims.sub, "upload", &audit_path, status)
+                .await;
+            return Err(status);
+        }
+    }
+
+    let final_path = Path::new(&upload.base_path).join(&upload.file_name);
+
+    let imported = async {
+        storage::create_dir_all(&storage_key(Path::new(&upload.base_path))).await?;
+        STORAGE
+            .import(&storage_key(&final_path), &data_path(id))
+            .await
+    }
+    .await;
+
+    audit
+        .record(
+            &token.claims.sub,
+            "upload",
+            &audit_path,
+            match imported {
+                Ok(_) => Status::Created,
+                Err(_) => Status::InternalServerError,
+            },
+        )
+        .await;
+
+    if let Err(e) = imported {
+        remove(reservations, &upload.owner, id).await;
+        eprintln!("Failed to store upload {}: {:?}", id, e);
+        return Err(Status::InternalServerError);
+    }
+
+    file_index::refresh(sizes, &final_path).await;
+    remove(reservations, &upload.owner, id).await;
+
+    let file = finish_upload(
+        db,
+        upload.share.as_deref(),
+        &host,
+        &upload.user_path,
+        &upload.base_path,
+        &upload.file_name,
+        upload.length,
+    )
+    .await;
+
+    Ok(match file.url {
+        Some(url) => response.header("Upload-Url", url),
+        None => response,
+    })
+}
+
+#[delete("/tus/<id>")]
+async fn tus_delete(
+    id: &str,
+    headers: TusHeaders<'_>,
+    token: Result<JWT, Status>,
+    reservations: &State<Reservations>,
+) -> TusResponse {
+    if let Err(response) = headers.check_version() {
+        return response;
+    }
+
+    async {
+        let upload = find(reservations, id, &token?).await?;
+        let _busy = BusyGuard::acquire(id).ok_or(Status::Locked)?;
+
+        remove(reservations, &upload.owner, id).await;
+
+        Ok::<_, Status>(TusResponse::new(Status::NoContent))
+    }
+    .await
+    .unwrap_or_else(TusResponse::from)
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    if CONFIG.enable_file_db {
+        routes![tus_options, tus_create, tus_head, tus_patch_db, tus_delete]
+    } else {
+        routes![tus_options, tus_create, tus_head, tus_patch, tus_delete]
+    }
+}
+
+fn is_stale(modified: std::io::Result<SystemTime>, max_age: Duration) -> bool {
+    modified
+        .ok()
+        .and_then(|m| m.elapsed().ok())
+        .is_some_and(|age| age > max_age)
+}
+
+/// Removes expired tus uploads, along with `upload_chunked` folders that haven't
+/// received a chunk for as long. With `keep`, uploads that have been idle that long
+/// are left alone and only stop holding quota, which they take again when resumed.
+pub(crate) async fn reap(
+    reservations: &Reservations,
+    max_age: Duration,
+    keep: bool,
+) -> std::io::Result<()> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    if let Ok(mut entries) = fs::read_dir(tus_dir()).await {
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let id = name.trim_end_matches(".json");
+
+            if BUSY.lock().unwrap_or_else(|e| e.into_inner()).contains(id) {
+                continue;
+            }
+
+            match load(id).await {
+                Some(upload) => {
+                    if upload.is_expired(now) {
+                        remove(reservations, &upload.owner, id).await;
+                    } else if keep
+                        && is_stale(
+                            fs::metadata(data_path(id))
+                                .await
+                                .and_then(|md| md.modified()),
+                            max_age,
+                        )
+                    {
+                        quota::release(reservations, &upload.owner, &reservation(id)).await;
+                    }
+                }
+                // Data left without its state can't be resumed
+                None => {
+                    if !keep && is_stale(entry.metadata().await?.modified(), max_age) {
+                        let _ = fs::remove_file(entry.path()).await;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut users = match fs::read_dir(CHUNKS_DIR).await {
+        Ok(users) => users,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    while let Some(user) = users.next_entry().await? {
+        let Ok(mut uploads) = fs::read_dir(user.path()).await else {
+            continue;
+        };
+
+        while let Some(upload) = uploads.next_entry().await? {
+            if is_stale(upload.metadata().await?.modified(), max_age) {
+                if !keep {
+                    fs::remove_dir_all(upload.path()).await?;
+                }
+                quota::release(
+                    reservations,
+                    &user.file_name().to_string_lossy(),
+                    &format!("chunked/{}", upload.file_name().to_string_lossy()),
+                )
+                .await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn clean_uploads(reservations: Reservations) {
+    loop {
+        let result = if CONFIG.upload_expiry_hours != 0 {
+            let max_age = Duration::from_secs(CONFIG.upload_expiry_hours * 3600);
+
+            reap(&reservations, max_age, false).await
+        } else {
+            reap(&reservations, IDLE_RESERVATION, true).await
+        };
+
+        if let Err(e) = result {
+            eprintln!("Failed to clean up stale uploads: {:?}", e);
+        }
+
+        sleep(Duration::from_secs(3600)).await;
+    }
+}
@@ -0,0 +1,663 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use reqwest::{header, Client, Url};
+use rocket::time::OffsetDateTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt, sync::RwLock, time::sleep};
+use uuid::Uuid;
+
+use crate::{
+    config::CONFIG,
+    file_index,
+    manifest::Manifest,
+    storage::{self, storage_key, STORAGE},
+    utils::{parse_http_date, UPLOAD_TEMP_DIR},
+    FileSizes, MirrorFile,
+};
+
+/// Directory indexes nested deeper than this aren't followed.
+const MAX_DEPTH: usize = 32;
+
+/// An upstream that stops answering fails the sync instead of holding up the job
+/// until the next restart. Downloads can take as long as they need while data
+/// keeps coming in.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamKind {
+    /// A plain HTTP directory index, like the ones Apache and nginx generate.
+    #[default]
+    Http,
+    /// Another MARMAK Mirror, read through its `/api/listing`.
+    Mirror,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UpstreamConfig {
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub kind: UpstreamKind,
+    /// Folder under the mirror root the files are synced into.
+    pub destination: String,
+    /// Minutes between syncs, 0 to only sync when started from the admin page.
+    #[serde(default = "default_interval")]
+    pub interval_minutes: u64,
+    /// Globs matched against paths relative to `url`. Empty includes everything.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Download speed limit in bytes per second, 0 for none.
+    #[serde(default)]
+    pub bandwidth_limit: u64,
+    /// Remove local files that are gone upstream.
+    #[serde(default)]
+    pub delete: bool,
+}
+
+fn default_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct SyncReport {
+    pub checked: usize,
+    pub downloaded: usize,
+    pub bytes: u64,
+    pub deleted: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct UpstreamStatus {
+    pub name: String,
+    pub url: String,
+    pub destination: String,
+    pub running: bool,
+    pub last_started: Option<i64>,
+    pub last_finished: Option<i64>,
+    pub last_error: Option<String>,
+    pub report: SyncReport,
+}
+
+/// Status of every job in `upstreams`, in the same order.
+pub type Upstreams = Arc<RwLock<Vec<UpstreamStatus>>>;
+
+pub fn statuses() -> Upstreams {
+    Arc::new(RwLock::new(
+        CONFIG
+            .upstreams
+            .iter()
+            .map(|upstream| UpstreamStatus {
+                name: upstream.name.clone(),
+                url: upstream.url.clone(),
+                destination: upstream.destination.clone(),
+                running: false,
+                last_started: None,
+                last_finished: None,
+                last_error: None,
+                report: SyncReport::default(),
+            })
+            .collect(),
+    ))
+}
+
+struct RemoteFile {
+    /// Path relative to the job's `url`, with `/` separators.
+    path: String,
+    url: Url,
+    size: Option<u64>,
+    modified: Option<u64>,
+    sha256: Option<String>,
+}
+
+struct Filters {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl Filters {
+    fn new(upstream: &UpstreamConfig) -> io::Result<Self> {
+        let build = |patterns: &[String]| {
+            let mut set = GlobSetBuilder::new();
+
+            for pattern in patterns {
+                set.add(
+                    Glob::new(pattern).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?,
+                );
+            }
+
+            set.build()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+        };
+
+        Ok(Filters {
+            include: build(&upstream.include)?,
+            exclude: build(&upstream.exclude)?,
+        })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        (self.include.is_empty() || self.include.is_match(path)) && !self.exclude.is_match(path)
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    match (dir.is_empty(), name.is_empty()) {
+        (true, _) => name.to_string(),
+        (_, true) => dir.to_string(),
+        _ => format!("{}/{}", dir, name),
+    }
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Names that could step outside the destination folder are never synced.
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+async fn get(client: &Client, url: Url) -> io::Result<reqwest::Response> {
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(io::Error::other)
+}
+
+/// Pulls the `href` targets out of a directory index page.
+fn links(html: &str) -> Vec<String> {
+    html.split("href=")
+        .skip(1)
+        .filter_map(|rest| match rest.chars().next()? {
+            quote @ ('"' | '\'') => rest[1..].split(quote).next(),
+            _ => rest.split(|c: char| c.is_whitespace() || c == '>').next(),
+        })
+        .map(|link| link.replace("&amp;", "&"))
+        .collect()
+}
+
+/// Reads a `SHA256SUMS` file into file names and their hashes.
+fn parse_checksums(sums: &str) -> HashMap<String, String> {
+    sums.lines()
+        .filter_map(|line| {
+            let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+            let name = name.trim_start().trim_start_matches('*');
+
+            (hash.len() == 64).then(|| (name.to_string(), hash.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// Walks an HTTP directory index. Sizes and dates come from `HEAD` requests, and
+/// checksums from a `SHA256SUMS` file next to the files, if there is one. Files
+/// whose `HEAD` request fails are left out and counted in `report.failed`.
+async fn list_http(
+    client: &Client,
+    url: &str,
+    filters: &Filters,
+    report: &mut SyncReport,
+) -> io::Result<Vec<RemoteFile>> {
+    let mut root = Url::parse(url).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+
+    if !root.path().ends_with('/') {
+        root.set_path(&format!("{}/", root.path()));
+    }
+
+    let mut files = Vec::new();
+    let mut pending = vec![(root.clone(), String::new(), 0)];
+
+    while let Some((dir_url, dir, depth)) = pending.pop() {
+        let html = get(client, dir_url.clone())
+            .await?
+            .text()
+            .await
+            .map_err(io::Error::other)?;
+
+        let mut entries = HashSet::new();
+        let mut dir_files = Vec::new();
+        let mut checksums = HashMap::new();
+
+        for link in links(&html) {
+            let Ok(target) = dir_url.join(&link) else {
+                continue;
+            };
+
+            // Only direct children: no parent links, sorting links or other sites
+            let Some(name) = target
+                .as_str()
+                .strip_prefix(dir_url.as_str())
+                .filter(|_| target.query().is_none() && target.fragment().is_none())
+                .map(|name| name.to_string())
+            else {
+                continue;
+            };
+
+            let (name, is_dir) = match name.strip_suffix('/') {
+                Some(name) => (name.to_string(), true),
+                None => (name, false),
+            };
+
+            let Ok(name) = urlencoding::decode(&name).map(|n| n.into_owned()) else {
+                continue;
+            };
+
+            if !is_safe_name(&name) || !entries.insert(name.clone()) {
+                continue;
+            }
+
+            if is_dir {
+                if depth < MAX_DEPTH {
+                    pending.push((target, join(&dir, &name), depth + 1));
+                }
+                continue;
+            }
+
+            if name == "SHA256SUMS" {
+                if let Ok(response) = get(client, target.clone()).await {
+                    checksums = parse_checksums(&response.text().await.unwrap_or_default());
+                }
+            }
+
+            dir_files.push((target, name));
+        }
+
+        for (url, name) in dir_files {
+            let checksum = checksums.get(&name).cloned();
+            files.push((url, join(&dir, &name), checksum));
+        }
+    }
+
+    let mut remote = Vec::new();
+
+    for (url, path, checksum) in files {
+        if !filters.matches(&path) {
+            continue;
+        }
+
+        let response = match client
+            .head(url.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => response,
+            Err(e) => {
+                report.failed += 1;
+                eprintln!("Failed to sync {}: {:?}", url, e);
+                continue;
+            }
+        };
+
+        let headers = response.headers();
+
+        remote.push(RemoteFile {
+            path,
+            url,
+            size: headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse().ok()),
+            modified: headers
+                .get(header::LAST_MODIFIED)
+                .and_then(|value| parse_http_date(value.to_str().ok()?))
+                .map(|modified| modified as u64),
+            sha256: checksum,
+        });
+    }
+
+    Ok(remote)
+}
+
+/// Links straight to a file on another mirror, which otherwise shows a page for
+/// archives, media and other files it can display.
+fn download_url(base: &Url, path: &str) -> io::Result<Url> {
+    let mut url = base.join(&encode_path(path)).map_err(io::Error::other)?;
+    url.set_query(Some("download"));
+
+    Ok(url)
+}
+
+/// Walks another mirror's `/api/listing`. Its `/api/manifest`, when it keeps one,
+/// provides the checksums and dates.
+async fn list_mirror(client: &Client, url: &str, filters: &Filters) -> io::Result<Vec<RemoteFile>> {
+    let url = Url::parse(url).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    let root = urlencoding::decode(url.path().trim_matches('/'))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?
+        .into_owned();
+    let base = url.join("/").map_err(io::Error::other)?;
+
+    let manifest: HashMap<String, (Option<u64>, String)> =
+        match get(client, base.join("api/manifest").map_err(io::Error::other)?).await {
+            Ok(response) => response
+                .bytes()
+                .await
+                .ok()
+                .and_then(|body| serde_json::from_slice::<Manifest>(&body).ok())
+                .map(|manifest| {
+                    manifest
+                        .files
+                        .into_iter()
+                        .map(|file| (file.path, (file.modified, file.sha256)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+
+    let mut remote = Vec::new();
+    let mut pending = vec![(String::new(), 0)];
+
+    while let Some((dir, depth)) = pending.pop() {
+        let listing_url = base
+            .join(&format!("api/listing/{}", encode_path(&join(&root, &dir))))
+            .map_err(io::Error::other)?;
+
+        let body = get(client, listing_url)
+            .await?
+            .bytes()
+            .await
+            .map_err(io::Error::other)?;
+        let entries: Vec<MirrorFile> = serde_json::from_slice(&body)?;
+
+        for entry in entries {
+            if !is_safe_name(&entry.name) {
+                continue;
+            }
+
+            let path = join(&dir, &entry.name);
+
+            if entry.ext == "folder" {
+                if depth < MAX_DEPTH {
+                    pending.push((path, depth + 1));
+                }
+                continue;
+            }
+
+            if !filters.matches(&path) {
+                continue;
+            }
+
+            let remote_path = join(&root, &path);
+            let known = manifest.get(&remote_path);
+
+            remote.push(RemoteFile {
+                url: download_url(&base, &remote_path)?,
+                path,
+                size: Some(entry.size),
+                modified: known.and_then(|(modified, _)| *modified),
+                sha256: known.map(|(_, sha256)| sha256.clone()),
+            });
+        }
+    }
+
+    Ok(remote)
+}
+
+/// Whether the local copy can be kept: same size, and not older than the upstream file.
+async fn is_current(key: &Path, file: &RemoteFile) -> bool {
+    match STORAGE.stat(key).await {
+        Ok(local) if !local.is_dir => {
+            file.size.is_none_or(|size| size == local.size)
+                && file
+                    .modified
+                    .is_none_or(|modified| local.modified.is_some_and(|local| local >= modified))
+        }
+        _ => false,
+    }
+}
+
+/// Downloads a file into a temporary file, checking its size and checksum before
+/// it replaces the local copy.
+async fn download(
+    client: &Client,
+    file: &RemoteFile,
+    key: &Path,
+    bandwidth_limit: u64,
+) -> io::Result<u64> {
+    let temp_dir = Path::new(UPLOAD_TEMP_DIR).join("upstream");
+    fs::create_dir_all(&temp_dir).await?;
+    let temp_path = temp_dir.join(Uuid::new_v4().to_string());
+
+    let result = async {
+        let mut response = get(client, file.url.clone()).await?;
+        let mut temp_file = fs::File::create(&temp_path).await?;
+        let mut hasher = Sha256::new();
+        let mut written = 0_u64;
+        let started = Instant::now();
+
+        while let Some(chunk) = response.chunk().await.map_err(io::Error::other)? {
+            hasher.update(&chunk);
+            temp_file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+
+            if bandwidth_limit != 0 {
+                let due = Duration::from_secs_f64(written as f64 / bandwidth_limit as f64);
+                if let Some(ahead) = due.checked_sub(started.elapsed()) {
+                    sleep(ahead).await;
+                }
+            }
+        }
+
+        temp_file.flush().await?;
+        drop(temp_file);
+
+        if file.size.is_some_and(|size| size != written) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Size mismatch"));
+        }
+
+        if let Some(expected) = &file.sha256 {
+            if !expected.eq_ignore_ascii_case(&format!("{:x}", hasher.finalize())) {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Checksum mismatch"));
+            }
+        }
+
+        // Keep the upstream date, so the next sync sees the file as current
+        if let Some(modified) = file.modified {
+            std::fs::File::options()
+                .write(true)
+                .open(&temp_path)?
+                .set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+        }
+
+        if let Some(parent) = key.parent() {
+            storage::create_dir_all(parent).await?;
+        }
+
+        STORAGE.import(key, &temp_path).await?;
+
+        Ok(written)
+    }
+    .await;
+
+    let _ = fs::remove_file(&temp_path).await;
+
+    result
+}
+
+/// Where a file synced by one of the jobs (a storage key) can be found upstream.
+pub fn origin_url(key: &Path) -> Option<String> {
+    CONFIG.upstreams.iter().find_map(|upstream| {
+        let destination = storage_key(Path::new(upstream.destination.trim_matches('/')));
+        let relative = key.strip_prefix(&destination).ok()?.to_str()?;
+
+        if relative.is_empty() || !Filters::new(upstream).ok()?.matches(relative) {
+            return None;
+        }
+
+        let mut url = Url::parse(&upstream.url).ok()?;
+
+        let url = match upstream.kind {
+            UpstreamKind::Http => {
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+
+                url.join(&encode_path(relative)).ok()?
+            }
+            UpstreamKind::Mirror => {
+                let root = urlencoding::decode(url.path().trim_matches('/')).ok()?;
+                download_url(&url.join("/").ok()?, &join(&root, relative)).ok()?
+            }
+        };
+
+        Some(url.to_string())
+    })
+}
+
+/// Brings the job's destination folder up to date with its upstream.
+pub async fn sync(upstream: &UpstreamConfig, sizes: &FileSizes) -> io::Result<SyncReport> {
+    let filters = Filters::new(upstream)?;
+    let client = Client::builder()
+        .user_agent(format!("MARMAK-Mirror/{}", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(READ_TIMEOUT)
+        .build()
+        .map_err(io::Error::other)?;
+
+    let destination = storage_key(Path::new(upstream.destination.trim_matches('/')));
+
+    if destination.as_os_str().is_empty() || destination.starts_with("private") {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Invalid destination folder",
+        ));
+    }
+
+    let mut report = SyncReport::default();
+
+    let remote = match upstream.kind {
+        UpstreamKind::Http => list_http(&client, &upstream.url, &filters, &mut report).await?,
+        UpstreamKind::Mirror => list_mirror(&client, &upstream.url, &filters).await?,
+    };
+
+    for file in &remote {
+        let key = destination.join(&file.path);
+        report.checked += 1;
+
+        if is_current(&key, file).await {
+            continue;
+        }
+
+        match download(&client, file, &key, upstream.bandwidth_limit).await {
+            Ok(bytes) => {
+                report.downloaded += 1;
+                report.bytes += bytes;
+                file_index::refresh(sizes, &Path::new("files").join(&key)).await;
+            }
+            Err(e) => {
+                report.failed += 1;
+                eprintln!("Failed to sync {}: {:?}", file.url, e);
+            }
+        }
+    }
+
+    // A listing that's partly missing could remove files that still exist upstream
+    if upstream.delete && report.failed == 0 {
+        let listed: HashSet<PathBuf> = remote
+            .iter()
+            .map(|file| PathBuf::from(&file.path))
+            .collect();
+
+        for (key, _) in STORAGE.walk(&destination).await.unwrap_or_default() {
+            // Walking gives keys from the root of the tree
+            let Ok(path) = key.strip_prefix(&destination) else {
+                continue;
+            };
+            let Some(relative) = path.to_str() else {
+                continue;
+            };
+
+            if listed.contains(path) || !filters.matches(relative) {
+                continue;
+            }
+
+            match STORAGE.delete(&key, false).await {
+                Ok(_) => {
+                    report.deleted += 1;
+                    file_index::refresh(sizes, &Path::new("files").join(&key)).await;
+                }
+                Err(e) => eprintln!("Failed to remove {}: {:?}", key.display(), e),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Runs the job at `index` in `upstreams`, unless it's already running.
+pub async fn run(upstreams: &Upstreams, sizes: &FileSizes, index: usize) {
+    let Some(upstream) = CONFIG.upstreams.get(index) else {
+        return;
+    };
+
+    {
+        let mut statuses = upstreams.write().await;
+        let Some(status) = statuses.get_mut(index) else {
+            return;
+        };
+
+        if status.running {
+            return;
+        }
+
+        status.running = true;
+        status.last_started = Some(OffsetDateTime::now_utc().unix_timestamp());
+    }
+
+    let result = sync(upstream, sizes).await;
+
+    let mut statuses = upstreams.write().await;
+    let status = &mut statuses[index];
+
+    status.running = false;
+    status.last_finished = Some(OffsetDateTime::now_utc().unix_timestamp());
+
+    match result {
+        Ok(report) => {
+            status.last_error =
+                (report.failed != 0).then(|| format!("{} files failed", report.failed));
+            status.report = report;
+        }
+        Err(e) => {
+            eprintln!("Upstream sync {} failed: {:?}", upstream.name, e);
+            status.last_error = Some(e.to_string());
+        }
+    }
+}
+
+/// Runs every job with an interval on its own schedule.
+pub async fn schedule(upstreams: Upstreams, sizes: FileSizes) {
+    for (index, upstream) in CONFIG.upstreams.iter().enumerate() {
+        if upstream.interval_minutes == 0 {
+            continue;
+        }
+
+        let upstreams = Arc::clone(&upstreams);
+        let sizes = Arc::clone(&sizes);
+        let interval = Duration::from_secs(upstream.interval_minutes * 60);
+
+        tokio::spawn(async move {
+            loop {
+                run(&upstreams, &sizes, index).await;
+                sleep(interval).await;
+            }
+        });
+    }
+}
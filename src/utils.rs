@@ -1,37 +1,35 @@
 use std::{
-    collections::HashMap,
-    fs,
-    io::{Cursor, Error, ErrorKind},
+    collections::{HashMap, VecDeque},
+    io::{Error, ErrorKind},
     net::{Ipv4Addr, Ipv6Addr},
-    path::Path,
-    sync::Arc,
+    path::{Path, PathBuf},
+    sync::Mutex,
 };
 
 use rocket::{
     http::{Cookie, CookieJar, SameSite, Status},
-    time::{Duration, OffsetDateTime},
+    time::{format_description, Duration, OffsetDateTime, PrimitiveDateTime},
 };
 use rocket_dyn_templates::tera::{to_value, try_get_value, Value};
-use tokio::sync::RwLock;
-use zip::write::SimpleFileOptions;
-
-use crate::{config::CONFIG, FileEntry, MirrorFile};
+use rocket_multipart_form_data::{MultipartFormDataField, MultipartFormDataOptions, Repetition};
+use tokio::{sync::Notify, time::timeout};
+
+use crate::{
+    config::CONFIG,
+    storage::{storage_key, STORAGE},
+    trash::is_trash_key,
+    FileSizes, MirrorFile,
+};
 
-pub fn read_dirs(path: &str) -> Result<Vec<MirrorFile>, Error> {
+pub async fn read_dirs(path: &str) -> Result<Vec<MirrorFile>, Error> {
     let mut dir_list = Vec::new();
 
-    let paths = match fs::read_dir(path) {
-        Ok(paths) => paths,
-        Err(e) => return Err(e),
-    };
-
-    for file_path in paths {
-        let file_path = file_path?;
+    let key = storage_key(Path::new(path));
+    let entries = STORAGE.list(&key).await?;
 
-        if file_path.path().is_dir() {
-            if let Some(file_name) = file_path.file_name().to_str() {
-                dir_list.push(MirrorFile::new_folder(file_name));
-            }
+    for entry in entries {
+        if entry.is_dir && !is_trash_key(&key.join(&entry.name)) {
+            dir_list.push(MirrorFile::new_folder(&entry.name));
         }
     }
 
@@ -40,84 +38,54 @@ pub fn read_dirs(path: &str) -> Result<Vec<MirrorFile>, Error> {
 
 pub async fn read_dirs_async(
     path: &str,
-    sizes_state: &Arc<RwLock<Vec<FileEntry>>>,
+    sizes_state: &FileSizes,
 ) -> Result<Vec<MirrorFile>, Error> {
     let mut dir_list = Vec::new();
 
     let size_list = sizes_state.read().await;
 
-    let paths = match fs::read_dir(&path) {
-        Ok(paths) => paths,
-        Err(e) => return Err(e),
-    };
-
-    'main: for file_path in paths {
-        let file_path = file_path?;
-
-        if file_path.path().is_dir() {
-            let full_path = file_path.path();
+    let key = storage_key(Path::new(path));
+    let entries = STORAGE.list(&key).await?;
 
-            let subdir_paths = match fs::read_dir(&full_path) {
+    'main: for entry in entries {
+        if entry.is_dir && !is_trash_key(&key.join(&entry.name)) {
+            let subdir_entries = match STORAGE.list(&key.join(&entry.name)).await {
                 Ok(e) => e,
                 Err(_) => continue,
             };
 
             let mut icon = "folder";
 
-            for subdir_path in subdir_paths {
-                let subdir_path = subdir_path?;
-                if let Some(file_name) = subdir_path.file_name().to_str() {
-                    if file_name == "HIDDEN" {
-                        continue 'main;
-                    } else if file_name == "RESTRICTED" {
-                        icon = "lockedfolder";
-                    }
+            for subdir_entry in subdir_entries {
+                if subdir_entry.name == "HIDDEN" {
+                    continue 'main;
+                } else if subdir_entry.name == "RESTRICTED" {
+                    icon = "lockedfolder";
                 }
             }
 
-            let rel_path = full_path
-                .strip_prefix(std::env::current_dir()?)
-                .unwrap_or(&full_path)
-                .display()
-                .to_string();
-
             let folder_size = size_list
-                .iter()
-                .find(|entry| {
-                    entry.file.strip_suffix("/").unwrap_or_default().to_string() == rel_path
-                })
-                .map(|entry| entry.size)
+                .size(&Path::new(path).join(&entry.name))
                 .unwrap_or(0);
 
-            if let Some(file_name) = file_path.file_name().to_str() {
-                let mut mirror_file = MirrorFile::new_folder(file_name);
-                mirror_file.icon = icon.into();
-                mirror_file.size = folder_size;
-                dir_list.push(mirror_file);
-            }
+            let mut mirror_file = MirrorFile::new_folder(&entry.name);
+            mirror_file.icon = icon.into();
+            mirror_file.size = folder_size;
+            dir_list.push(mirror_file);
         }
     }
 
     Ok(dir_list)
 }
 
-pub fn read_files(path: &str) -> Result<Vec<MirrorFile>, Error> {
+pub async fn read_files(path: &str) -> Result<Vec<MirrorFile>, Error> {
     let mut file_list = Vec::new();
 
-    let paths = match fs::read_dir(&path) {
-        Ok(paths) => paths,
-        Err(e) => return Err(e),
-    };
-
-    for file_path in paths {
-        let file_path = file_path?;
+    let entries = STORAGE.list(&storage_key(Path::new(path))).await?;
 
-        if file_path.path().is_file() {
-            if let Some(file) = MirrorFile::load(&file_path.path()) {
-                file_list.push(file);
-            } else {
-                continue;
-            }
+    for entry in entries {
+        if !entry.is_dir {
+            file_list.push(MirrorFile::from_entry(&entry));
         }
     }
 
@@ -132,6 +100,34 @@ pub fn create_cookie<'a>(name: &'a str, value: &str) -> Cookie<'a> {
     cookie
 }
 
+/// Formats a Unix timestamp as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(timestamp: i64) -> String {
+    let time = OffsetDateTime::from_unix_timestamp(timestamp).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        &time.weekday().to_string()[..3],
+        time.day(),
+        &time.month().to_string()[..3],
+        time.year(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+/// Parses an HTTP date such as an `If-Modified-Since` value into a Unix timestamp.
+pub fn parse_http_date(date: &str) -> Option<i64> {
+    let format = format_description::parse(
+        "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT",
+    )
+    .ok()?;
+
+    PrimitiveDateTime::parse(date.trim(), &format)
+        .ok()
+        .map(|date| date.assume_utc().unix_timestamp())
+}
+
 pub fn get_root_domain<'a>(host: &str) -> String {
     if host.parse::<Ipv4Addr>().is_ok() || host.parse::<Ipv6Addr>().is_ok() {
         return CONFIG.fallback_root_domain.to_string();
@@ -148,27 +144,52 @@ pub fn get_root_domain<'a>(host: &str) -> String {
         .to_string();
 }
 
-pub fn add_path_to_zip(
-    zip_writer: &mut zip::ZipWriter<&mut Cursor<Vec<u8>>>,
-    base_path: &Path,
-    path: &Path,
-    options: SimpleFileOptions,
-) -> std::io::Result<()> {
-    if path.is_file() {
-        let mut file = std::fs::File::open(path)?;
-        let relative_path = path.strip_prefix(base_path).unwrap_or(path);
-        zip_writer.start_file(relative_path.to_string_lossy(), options)?;
-        std::io::copy(&mut file, zip_writer)?;
-    } else if path.is_dir() {
-        let relative_path = path.strip_prefix(base_path).unwrap_or(path);
-        let folder_name = format!("{}/", relative_path.to_string_lossy());
-        zip_writer.add_directory(folder_name, options)?;
-        for entry in std::fs::read_dir(path)? {
-            let entry = entry?;
-            add_path_to_zip(zip_writer, base_path, &entry.path(), options)?;
+/// Folder that multipart uploads are spooled to. It sits next to `files/` so
+/// finished uploads can be renamed into place instead of copied.
+pub const UPLOAD_TEMP_DIR: &str = ".uploads";
+
+/// Storage keys waiting for a background worker. A key is only queued once.
+#[derive(Default)]
+pub struct WorkQueue {
+    pending: Mutex<VecDeque<PathBuf>>,
+    notify: Notify,
+}
+
+impl WorkQueue {
+    pub fn push(&self, key: PathBuf) {
+        let mut pending = self.pending.lock().unwrap();
+
+        if !pending.contains(&key) {
+            pending.push_back(key);
+            self.notify.notify_one();
         }
     }
-    Ok(())
+
+    pub fn pop(&self) -> Option<PathBuf> {
+        self.pending.lock().unwrap().pop_front()
+    }
+
+    /// Waits until something is queued, or `limit` has passed.
+    pub async fn wait(&self, limit: std::time::Duration) {
+        let _ = timeout(limit, self.notify.notified()).await;
+    }
+}
+
+/// Form options for the upload endpoints. `size_limit` is enforced while the
+/// request is being read, so oversized files are cut off early.
+pub fn upload_form_options<'a>(size_limit: u64) -> MultipartFormDataOptions<'a> {
+    let mut options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::file("files")
+            .repetition(Repetition::infinite())
+            .size_limit(size_limit),
+        MultipartFormDataField::text("path"),
+    ]);
+
+    if std::fs::create_dir_all(UPLOAD_TEMP_DIR).is_ok() {
+        options.temporary_dir = PathBuf::from(UPLOAD_TEMP_DIR);
+    }
+
+    options
 }
 
 pub fn map_io_error_to_status(e: Error) -> Status {
@@ -183,39 +204,6 @@ pub fn map_io_error_to_status(e: Error) -> Status {
     }
 }
 
-pub fn parse_7z_output(output: &str) -> Vec<MirrorFile> {
-    let mut files = Vec::new();
-
-    for line in output.lines() {
-        if let Some(_idx) = line.find("..") {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-
-            if parts.len() < 5 {
-                continue;
-            }
-
-            let filename = if let Ok(_) = parts[4].parse::<u64>() {
-                parts[5..].join(" ")
-            } else {
-                parts[4..].join(" ")
-            };
-
-            let size: u64 = parts[3].parse().unwrap_or(0);
-
-            if size == 0 {
-                continue;
-            }
-
-            let mut mirror_file = MirrorFile::new(&filename);
-            mirror_file.size = size;
-
-            files.push(mirror_file);
-        }
-    }
-
-    files
-}
-
 pub fn format_size(bytes: u64, use_si: bool) -> String {
     if bytes == 0 {
         return "0 B".to_string();